@@ -20,8 +20,6 @@ while getopts ${OPTSTRING} opt; do
 	esac
 done
 
-INTERFACE_NAME="tun0"
-
 BIN_NAME="nust"
 if [ "$DEBUG" = true ] ; then
     cargo build
@@ -31,9 +29,8 @@ else
     CARGO_DIR="./target/release"
 fi
 sudo setcap cap_net_admin+ep $CARGO_DIR/$BIN_NAME # Give perms to do network stuff
-$CARGO_DIR/$BIN_NAME & # Start running main.rs
+# nust creates the interface, gives the host side its addresses and brings it up itself
+$CARGO_DIR/$BIN_NAME -c nust.toml & # Start running main.rs
 pid=$!
-sudo ip addr add 192.168.0.1/24 dev $INTERFACE_NAME
-sudo ip link set up dev $INTERFACE_NAME
 trap "kill $pid" INT TERM
 wait $pid
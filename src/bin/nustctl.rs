@@ -0,0 +1,40 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    process,
+};
+
+/// Where nust listens for commands unless -s is given
+const DEFAULT_SOCKET_PATH: &str = "/tmp/nust.sock";
+
+const USAGE: &str = "Usage: nustctl [-s <socket>] <command>
+  ss       List TCP connections and listening sockets like ss -tani
+  stats    Dump the counters like netstat -s
+  qdisc    Dump the queueing disciplines like tc -s class show
+  help     List the commands nust knows";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, command) = match &args[..] {
+        [flag, path, command @ ..] if flag == "-s" => (path.as_str(), command),
+        command => (DEFAULT_SOCKET_PATH, command),
+    };
+    if command.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+    if let Err(error) = run(path, &command.join(" ")) {
+        eprintln!("Could not talk to nust on {}: {}", path, error);
+        process::exit(1);
+    }
+}
+
+fn run(path: &str, command: &str) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    print!("{}", response);
+    Ok(())
+}
@@ -1,33 +1,375 @@
-use nust::checksum::ones_complement_sum_byte_buffer;
+use std::{
+    env, fs,
+    net::{IpAddr, SocketAddr},
+    process,
+};
+
+use nust::{
+    capture::PcapReader,
+    checksum::{craft_pseudo_header, Checksum},
+    device::{frame_ip_packet, NetDevice, TunDevice, DEFAULT_MTU},
+    ip::{IPHeader, FLAG_DONT_FRAGMENT},
+    ipv6::{ExtensionHeader, IPv6Header},
+    protocol::{
+        icmpv6, tcp, udp, ICMPBody, ICMPv6, ICMPv6Body, IPBody, TCPControlBits, ICMP, TCP, UDP,
+    },
+    IPPacket, IPv6Packet,
+};
+
+const USAGE: &str = "Usage:
+  proto decode [-v] <hex>            Dissect a raw IP packet given as hex
+  proto decode [-v] --pcap <file>    Dissect every packet of a raw IP pcap
+  proto checksum <hex>               Verify and recompute the checksums of a raw IP packet
+  proto craft [--tun <name>] <description>
+      Craft a packet and print it as hex, or send it out of a TUN device
+      tcp <flags> <src:port> -> <dst:port>   flags are comma separated, e.g. syn,ack
+      udp <src:port> -> <dst:port> [payload]
+      icmp echo <src> -> <dst>
+      IPv6 addresses with ports are written as [fd00::1]:80";
+
+/// TTL or hop limit of crafted packets
+const CRAFT_TTL: u8 = 64;
 
 fn main() {
-    let a = 0b01100010;
-    // let a = 0x45;
-    let b = a >> 5;
-    println!("a: {:#010b}", a);
-    println!("a: {}", a);
-    println!("b: {:#010b}", b);
-    println!("b: {}", b);
-
-    println!("{:b}", 3u8);
-
-    let buf = [4u8, 3u8, 2u8];
-    for chunk in buf.chunks(2) {
-        println!("{:?}", chunk)
-    }
-
-    let mut sum: u32 = 0x0001FFFF;
-    println!("first {:#032b}", sum);
-    while sum >> 16 != 0 {
-        sum = (sum & 0xFFFF) + (sum >> 16);
-        println!("{:#010b}", sum);
-    }
-
-    let buf = [
-        0xc0, 0xa8, 0x0, 0x1, 0xc0, 0xa8, 0x0, 0x2, 0x0, 0x6, 0x0, 40u8, 0xbd, 0x4a, 0x0, 0x50,
-        0x84, 0x78, 0x87, 0x58, 0x0, 0x0, 0x0, 0x0, 0xa0, 0x2, 0xfa, 0xf0, 0xce, 0x13, 0x0, 0x0,
-        0x2, 0x4, 0x5, 0xb4, 0x4, 0x2, 0x8, 0xa, 0x82, 0x7a, 0xb1, 0xc1, 0x0, 0x0, 0x0, 0x0, 0x1,
-        0x3, 0x3, 0x7,
-    ];
-    println!("{:x?}", ones_complement_sum_byte_buffer(&buf));
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(error) = run(&args) {
+        eprintln!("{}\n\n{}", error, USAGE);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (command, args) = args.split_first().ok_or("Missing command")?;
+    match command.as_str() {
+        "decode" => {
+            let verbose = args.iter().any(|arg| arg == "-v");
+            let args: Vec<&String> = args.iter().filter(|arg| *arg != "-v").collect();
+            match args[..] {
+                [flag, path] if flag == "--pcap" => {
+                    let capture = fs::read(path).map_err(|error| error.to_string())?;
+                    let mut reader =
+                        PcapReader::new(capture.as_slice()).map_err(|error| error.to_string())?;
+                    while let Some((_, frame)) =
+                        reader.next_frame().map_err(|error| error.to_string())?
+                    {
+                        println!("{}", decode(&frame, verbose));
+                    }
+                }
+                [hex] => println!("{}", decode(&parse_hex(hex)?, verbose)),
+                _ => return Err("Expected a hex string or --pcap <file>".to_string()),
+            }
+        }
+        "checksum" => {
+            let [hex] = args else {
+                return Err("Expected a hex string".to_string());
+            };
+            for line in checksums(&parse_hex(hex)?)? {
+                println!("{}", line);
+            }
+        }
+        "craft" => match args {
+            [flag, name, description @ ..] if flag == "--tun" => {
+                let packet = craft(description)?;
+                let mut tun =
+                    TunDevice::new(name, DEFAULT_MTU).map_err(|error| error.to_string())?;
+                tun.send(&frame_ip_packet(&packet))
+                    .map_err(|error| error.to_string())?;
+            }
+            description => println!("{}", to_hex(&craft(description)?)),
+        },
+        command => return Err(format!("Unknown command {}", command)),
+    }
+    Ok(())
+}
+
+/// Accepts hex with or without a 0x prefix, separated by whitespace, colons or commas
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let hex = hex.trim().trim_start_matches("0x");
+    let digits: Vec<char> = hex
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':' && *c != ',')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("Hex string has an odd number of digits".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("Invalid hex byte {}", pair))
+        })
+        .collect()
+}
+
+fn to_hex(buf: &[u8]) -> String {
+    buf.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode(buf: &[u8], verbose: bool) -> String {
+    let dissection = match buf.first().map(|byte| byte >> 4) {
+        Some(4) => IPPacket::from_byte_buffer(buf).map(|packet| match verbose {
+            true => format!("{:#}", packet),
+            false => packet.to_string(),
+        }),
+        Some(6) => IPv6Packet::from_byte_buffer(buf).map(|packet| match verbose {
+            true => format!("{:#}", packet),
+            false => packet.to_string(),
+        }),
+        _ => return "Not an IPv4 or IPv6 packet".to_string(),
+    };
+    dissection.unwrap_or_else(|error| format!("Could not parse packet: {:?}", error.kind()))
+}
+
+/// One line per checksum, with the correct value when it does not match
+fn checksums(buf: &[u8]) -> Result<Vec<String>, String> {
+    let mut lines = Vec::new();
+    let (source_addr, destination_addr, protocol, body) = match buf.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            let header_len = (buf[0] & 0b1111) as usize * 4;
+            let header = buf.get(..header_len).ok_or("Truncated IPv4 header")?;
+            lines.push(check("IPv4 header", header, 10, &[]));
+            let header = IPHeader::from_byte_buffer(header)
+                .or(IPHeader::from_byte_buffer(&with_checksum(header, 10)))
+                .map_err(|error| format!("{:?}", error.kind()))?;
+            let total_length = (header.total_length as usize).min(buf.len());
+            (
+                IpAddr::V4(header.source_addr),
+                IpAddr::V4(header.destination_addr),
+                header.protocol,
+                buf.get(header_len..total_length)
+                    .ok_or("Total length is shorter than the IPv4 header")?,
+            )
+        }
+        Some(6) => {
+            let header = IPv6Header::from_byte_buffer(buf).map_err(|_| "Truncated IPv6 header")?;
+            let (_, protocol, offset) =
+                ExtensionHeader::walk_chain(header.next_header, &buf[IPv6Header::LEN..])
+                    .map_err(|_| "Malformed extension headers")?;
+            let total_length = (IPv6Header::LEN + header.payload_length as usize).min(buf.len());
+            (
+                IpAddr::V6(header.source_addr),
+                IpAddr::V6(header.destination_addr),
+                protocol,
+                buf.get(IPv6Header::LEN + offset..total_length)
+                    .ok_or("Payload length is shorter than the extension headers")?,
+            )
+        }
+        _ => return Err("Not an IPv4 or IPv6 packet".to_string()),
+    };
+    let pseudo_header = |protocol| {
+        craft_pseudo_header(&source_addr, &destination_addr, protocol, body.len() as u16)
+            .map_err(|error| format!("{:?}", error.kind()))
+    };
+    lines.push(match protocol {
+        1 => check("ICMP", body, 2, &[]),
+        icmpv6::PROTOCOL => check("ICMPv6", body, 2, &pseudo_header(protocol)?),
+        tcp::PROTOCOL => check("TCP", body, 16, &pseudo_header(protocol)?),
+        udp::PROTOCOL if body.get(6..8) == Some(&[0x0, 0x0]) && source_addr.is_ipv4() => {
+            "UDP checksum not used".to_string()
+        }
+        udp::PROTOCOL => check("UDP", body, 6, &pseudo_header(protocol)?),
+        protocol => format!("No checksum known for protocol {}", protocol),
+    });
+    Ok(lines)
+}
+
+/// Compares the checksum at `offset` with the one computed over the pseudo header and `buf`
+fn check(name: &str, buf: &[u8], offset: usize, pseudo_header: &[u8]) -> String {
+    let Some(checksum) = buf.get(offset..offset + 2) else {
+        return format!("{} truncated", name);
+    };
+    let checksum = u16::from_be_bytes([checksum[0], checksum[1]]);
+    let correct = u16::from_be_bytes({
+        let fixed = with_checksum_over(buf, offset, pseudo_header);
+        [fixed[offset], fixed[offset + 1]]
+    });
+    match checksum == correct {
+        true => format!("{} checksum {:#06x} ok", name, checksum),
+        false => format!(
+            "{} checksum {:#06x} bad, should be {:#06x}",
+            name, checksum, correct
+        ),
+    }
+}
+
+/// Copy of `buf` with the checksum at `offset` recomputed
+fn with_checksum(buf: &[u8], offset: usize) -> Vec<u8> {
+    with_checksum_over(buf, offset, &[])
+}
+
+fn with_checksum_over(buf: &[u8], offset: usize, pseudo_header: &[u8]) -> Vec<u8> {
+    let mut fixed = buf.to_vec();
+    fixed[offset..offset + 2].copy_from_slice(&[0x0, 0x0]);
+    let checksum = Checksum::new().add(pseudo_header).add(&fixed).finish();
+    fixed[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    fixed
+}
+
+/// Builds a packet from a description like `tcp syn 192.168.0.1:1234 -> 192.168.0.2:80`
+fn craft(description: &[String]) -> Result<Vec<u8>, String> {
+    let words: Vec<&str> = description
+        .iter()
+        .flat_map(|arg| arg.split_whitespace())
+        .collect();
+    let (protocol, ip_body, source_addr, destination_addr) = match words[..] {
+        ["tcp", flags, source, "->", destination] => {
+            let source = parse_socket_addr(source)?;
+            let destination = parse_socket_addr(destination)?;
+            let control_bits = parse_tcp_flags(flags)?;
+            let tcp = TCP::builder(source.port(), destination.port())
+                .control_bits(control_bits)
+                .window(64240)
+                .build(&source.ip(), &destination.ip())
+                .map_err(|_| "Source and destination must be the same IP version")?;
+            (
+                tcp::PROTOCOL,
+                IPBody::TCP(tcp),
+                source.ip(),
+                destination.ip(),
+            )
+        }
+        ["udp", source, "->", destination, ref payload @ ..] => {
+            let source = parse_socket_addr(source)?;
+            let destination = parse_socket_addr(destination)?;
+            let udp = UDP::new(
+                &source.ip(),
+                &destination.ip(),
+                source.port(),
+                destination.port(),
+                payload.join(" ").into_bytes(),
+            )
+            .map_err(|_| "Source and destination must be the same IP version")?;
+            (
+                udp::PROTOCOL,
+                IPBody::UDP(udp),
+                source.ip(),
+                destination.ip(),
+            )
+        }
+        ["icmp", "echo", source, "->", destination] => {
+            let source = parse_ip_addr(source)?;
+            let destination = parse_ip_addr(destination)?;
+            match (source, destination) {
+                (IpAddr::V4(_), IpAddr::V4(_)) => {
+                    let body = ICMPBody::Echo {
+                        identifier: process::id() as u16,
+                        sequence_number: 1,
+                        data: Vec::new(),
+                    };
+                    (1, IPBody::ICMP(ICMP::new(8, 0, body)), source, destination)
+                }
+                (IpAddr::V6(source_addr), IpAddr::V6(destination_addr)) => {
+                    let body = ICMPv6Body::EchoRequest {
+                        identifier: process::id() as u16,
+                        sequence_number: 1,
+                        data: Vec::new(),
+                    };
+                    let icmp = ICMPv6::new(&source_addr, &destination_addr, 0, body);
+                    (icmpv6::PROTOCOL, IPBody::ICMPv6(icmp), source, destination)
+                }
+                _ => return Err("Source and destination must be the same IP version".to_string()),
+            }
+        }
+        _ => return Err(format!("Cannot craft '{}'", words.join(" "))),
+    };
+    match (source_addr, destination_addr) {
+        (IpAddr::V4(source_addr), IpAddr::V4(destination_addr)) => {
+            let ip_header = IPHeader::builder(source_addr, destination_addr)
+                .flags(FLAG_DONT_FRAGMENT)
+                .time_to_live(CRAFT_TTL);
+            Ok(IPPacket::from_body(ip_header, ip_body).to_byte_buffer())
+        }
+        (IpAddr::V6(source_addr), IpAddr::V6(destination_addr)) => {
+            let ip_header =
+                IPv6Header::new(0, 0, 0, protocol, CRAFT_TTL, source_addr, destination_addr);
+            Ok(IPv6Packet::new(ip_header, Vec::new(), protocol, ip_body).to_byte_buffer())
+        }
+        _ => Err("Source and destination must be the same IP version".to_string()),
+    }
+}
+
+fn parse_socket_addr(addr: &str) -> Result<SocketAddr, String> {
+    addr.parse()
+        .map_err(|_| format!("Invalid address and port {}", addr))
+}
+
+fn parse_ip_addr(addr: &str) -> Result<IpAddr, String> {
+    addr.parse()
+        .map_err(|_| format!("Invalid address {}", addr))
+}
+
+fn parse_tcp_flags(flags: &str) -> Result<TCPControlBits, String> {
+    flags
+        .split(',')
+        .try_fold(TCPControlBits::empty(), |control_bits, flag| {
+            let bit = TCPControlBits::from_name(&flag.to_uppercase())
+                .ok_or_else(|| format!("Unknown TCP flag {}", flag))?;
+            Ok(control_bits | bit)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PING: &str = "45000054 1b0b4000 40019e4a c0a80001 c0a80002 \
+        0800c066 000f0001 a2aad367 00000000 f7a30b00 00000000 10111213 14151617 \
+        18191a1b 1c1d1e1f 20212223 24252627 28292a2b 2c2d2e2f 30313233 34353637";
+
+    fn words(description: &str) -> Vec<String> {
+        description.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn decode_hex() {
+        let buf = parse_hex(PING).unwrap();
+        assert_eq!(buf.len(), 84);
+        assert_eq!(
+            decode(&buf, false),
+            "IP 192.168.0.1 > 192.168.0.2: ICMP echo request, id 15, seq 1, length 64"
+        );
+        assert!(parse_hex("0x4").is_err());
+        assert_eq!(parse_hex("0x45:00").unwrap(), [0x45, 0x0]);
+    }
+
+    #[test]
+    fn verify_checksums() {
+        let mut buf = parse_hex(PING).unwrap();
+        assert_eq!(
+            checksums(&buf).unwrap(),
+            ["IPv4 header checksum 0x9e4a ok", "ICMP checksum 0xc066 ok"]
+        );
+        buf[23] = 0x0; // ICMP checksum
+        buf[11] = 0x0; // IP header checksum
+        assert_eq!(
+            checksums(&buf).unwrap(),
+            [
+                "IPv4 header checksum 0x9e00 bad, should be 0x9e4a",
+                "ICMP checksum 0xc000 bad, should be 0xc066"
+            ]
+        );
+        buf[2..4].copy_from_slice(&[0x0, 0xa]); // Total length
+        assert!(checksums(&buf).is_err());
+    }
+
+    #[test]
+    fn craft_packets() {
+        let syn = craft(&words("tcp syn 192.168.0.1:1234 -> 192.168.0.2:80")).unwrap();
+        assert_eq!(
+            decode(&syn, false),
+            "IP 192.168.0.1.1234 > 192.168.0.2.80: Flags [S], seq 0, win 64240, length 0"
+        );
+        assert!(checksums(&syn)
+            .unwrap()
+            .iter()
+            .all(|line| line.ends_with("ok")));
+
+        let datagram = craft(&words("udp [fd00::1]:5000 -> [fd00::2]:53 hello")).unwrap();
+        assert_eq!(
+            decode(&datagram, false),
+            "IP6 fd00::1.5000 > fd00::2.53: UDP, length 5"
+        );
+        assert!(craft(&words("icmp echo 192.168.0.1 -> fd00::2")).is_err());
+        assert!(craft(&words("tcp bogus 192.168.0.1:1 -> 192.168.0.2:2")).is_err());
+    }
 }
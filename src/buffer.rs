@@ -0,0 +1,208 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use crate::device::PACKET_INFO_LEN;
+
+/// Room left in front of a packet for the headers prepended to it, the packet information,
+/// Ethernet, an IPv4 header with options and a TCP header with options
+pub const HEADROOM: usize = PACKET_INFO_LEN + 14 + 60 + 60;
+/// Buffers kept for reuse, further ones are freed
+const POOL_SIZE: usize = 256;
+
+/// A packet with room in front of it, so each layer prepends its header in place
+/// Returns its memory to the pool it came from when dropped
+pub struct PacketBuffer {
+    buf: Vec<u8>,
+    /// Where the packet starts in `buf`
+    head: usize,
+    pool: Option<BufferPool>,
+}
+impl PacketBuffer {
+    /// An empty packet with `headroom` bytes to prepend into
+    pub fn new(headroom: usize) -> Self {
+        Self::from_vec(vec![0u8; headroom], headroom)
+    }
+    fn from_vec(buf: Vec<u8>, head: usize) -> Self {
+        Self {
+            buf,
+            head,
+            pool: None,
+        }
+    }
+    pub fn headroom(&self) -> usize {
+        self.head
+    }
+    /// Makes room for `len` bytes in front of the packet, returning them for the header to be
+    /// written into, the packet is only moved if the headroom is too small
+    pub fn prepend(&mut self, len: usize) -> &mut [u8] {
+        if len > self.head {
+            let grow = len - self.head + HEADROOM;
+            self.buf.splice(0..0, std::iter::repeat_n(0u8, grow));
+            self.head += grow;
+        }
+        self.head -= len;
+        &mut self.buf[self.head..self.head + len]
+    }
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    /// Drops the end of the packet beyond `len` bytes
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(self.head + len);
+    }
+}
+impl Deref for PacketBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.head..]
+    }
+}
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.head..]
+    }
+}
+impl AsRef<[u8]> for PacketBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+impl AsMut<[u8]> for PacketBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+/// A packet which already exists, without headroom
+impl From<Vec<u8>> for PacketBuffer {
+    fn from(buf: Vec<u8>) -> Self {
+        Self::from_vec(buf, 0)
+    }
+}
+impl From<&[u8]> for PacketBuffer {
+    fn from(data: &[u8]) -> Self {
+        let mut buffer = Self::new(HEADROOM);
+        buffer.extend_from_slice(data);
+        buffer
+    }
+}
+impl Clone for PacketBuffer {
+    fn clone(&self) -> Self {
+        let mut buffer = match &self.pool {
+            Some(pool) => pool.get(),
+            None => Self::new(HEADROOM),
+        };
+        buffer.extend_from_slice(self);
+        buffer
+    }
+}
+impl PartialEq for PacketBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+impl fmt::Debug for PacketBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+/// Recycles the memory of packet buffers, so sending a packet does not allocate once the pool is
+/// warm, clones share the same buffers
+#[derive(Clone, Default)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// An empty packet with `HEADROOM` in front of it
+    pub fn get(&self) -> PacketBuffer {
+        let buf = self
+            .free
+            .lock()
+            .expect("Buffer pool lock is not poisoned")
+            .pop();
+        let mut buffer = match buf {
+            Some(mut buf) => {
+                buf.resize(HEADROOM, 0);
+                PacketBuffer::from_vec(buf, HEADROOM)
+            }
+            None => PacketBuffer::new(HEADROOM),
+        };
+        buffer.pool = Some(self.clone());
+        buffer
+    }
+    /// A copy of `data` in a buffer from the pool
+    pub fn copy(&self, data: &[u8]) -> PacketBuffer {
+        let mut buffer = self.get();
+        buffer.extend_from_slice(data);
+        buffer
+    }
+    /// Buffers waiting to be reused
+    pub fn len(&self) -> usize {
+        self.free
+            .lock()
+            .expect("Buffer pool lock is not poisoned")
+            .len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn put(&self, mut buf: Vec<u8>) {
+        let mut free = self.free.lock().expect("Buffer pool lock is not poisoned");
+        if free.len() < POOL_SIZE {
+            buf.clear();
+            free.push(buf);
+        }
+    }
+}
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("free", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prepend_and_reuse() {
+        let pool = BufferPool::new();
+        let mut packet = pool.get();
+        packet.extend_from_slice(b"data");
+        packet.prepend(2).copy_from_slice(b"tc");
+        packet.prepend(2).copy_from_slice(b"ip");
+        assert_eq!(&packet[..], b"iptcdata");
+        assert_eq!(packet.headroom(), HEADROOM - 4);
+        // Larger than the headroom left
+        packet.prepend(HEADROOM).fill(0xff);
+        assert_eq!(packet.len(), HEADROOM + 8);
+        assert!(packet.ends_with(b"iptcdata"));
+        packet.truncate(HEADROOM);
+        assert!(packet.iter().all(|byte| *byte == 0xff));
+
+        assert!(pool.is_empty());
+        let capacity = packet.buf.capacity();
+        drop(packet);
+        assert_eq!(pool.len(), 1);
+        let packet = pool.copy(b"again");
+        assert!(pool.is_empty());
+        assert_eq!(&packet[..], b"again");
+        assert_eq!(packet.buf.capacity(), capacity);
+        assert_eq!(PacketBuffer::from(b"again".to_vec()), packet);
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    io::{self, IoSlice, Read, Write},
+    os::fd::RawFd,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::device::{frame_ip_packet, NetDevice, PACKET_INFO_LEN};
+
+/// Largest frame written into a capture, longer frames are truncated
+pub const SNAPLEN: u32 = 65535;
+/// Largest snaplen libpcap writes, files claiming more are read with this one
+pub const MAX_SNAPLEN: u32 = 262144;
+/// Frames are buffered by the writer for at most this long, the event loop flushes the devices
+/// this often
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Link layer of the captured frames (https://www.tcpdump.org/linktypes.html)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkType {
+    /// TAP devices
+    Ethernet,
+    /// TUN devices, frames start with the IP header
+    RawIP,
+}
+impl LinkType {
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            Self::Ethernet => 1,
+            Self::RawIP => 101,
+        }
+    }
+    pub fn from_u32(link_type: u32) -> Option<Self> {
+        match link_type {
+            1 => Some(Self::Ethernet),
+            101 => Some(Self::RawIP),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureFormat {
+    /// Classic libpcap format, has no room for the direction of a frame
+    Pcap,
+    Pcapng,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Writes frames into a pcap or pcapng file, the file header is written on creation
+/// Give it a buffered writer, frames reach the file when it is flushed
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+    format: CaptureFormat,
+}
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(mut writer: W, format: CaptureFormat, link_type: LinkType) -> io::Result<Self> {
+        let header = match format {
+            CaptureFormat::Pcap => Self::pcap_header(link_type),
+            CaptureFormat::Pcapng => Self::pcapng_header(link_type),
+        };
+        writer.write_all(&header)?;
+        Ok(Self { writer, format })
+    }
+    pub fn write_frame(
+        &mut self,
+        timestamp: SystemTime,
+        direction: Direction,
+        frame: &[u8],
+    ) -> io::Result<()> {
+        self.write_frame_vectored(timestamp, direction, &[IoSlice::new(frame)])
+    }
+    /// Writes one frame gathered from `bufs`
+    pub fn write_frame_vectored(
+        &mut self,
+        timestamp: SystemTime,
+        direction: Direction,
+        bufs: &[IoSlice],
+    ) -> io::Result<()> {
+        let timestamp = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let frame_len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let captured: Vec<u8> = bufs
+            .iter()
+            .flat_map(|buf| buf.iter().copied())
+            .take(SNAPLEN as usize)
+            .collect();
+        let mut buf = Vec::new();
+        match self.format {
+            CaptureFormat::Pcap => {
+                buf.append(&mut (timestamp.as_secs() as u32).to_le_bytes().to_vec());
+                buf.append(&mut timestamp.subsec_micros().to_le_bytes().to_vec());
+                buf.append(&mut (captured.len() as u32).to_le_bytes().to_vec());
+                buf.append(&mut (frame_len as u32).to_le_bytes().to_vec());
+                buf.extend_from_slice(&captured);
+            }
+            CaptureFormat::Pcapng => {
+                // Enhanced Packet Block, timestamps in microseconds as the interface has no if_tsresol
+                let micros = timestamp.as_micros() as u64;
+                let padding = (4 - captured.len() % 4) % 4;
+                let total_length = (32 + captured.len() + padding + 12) as u32;
+                buf.append(&mut 0x6_u32.to_le_bytes().to_vec());
+                buf.append(&mut total_length.to_le_bytes().to_vec());
+                buf.append(&mut 0x0_u32.to_le_bytes().to_vec()); // Interface ID
+                buf.append(&mut ((micros >> 32) as u32).to_le_bytes().to_vec());
+                buf.append(&mut (micros as u32).to_le_bytes().to_vec());
+                buf.append(&mut (captured.len() as u32).to_le_bytes().to_vec());
+                buf.append(&mut (frame_len as u32).to_le_bytes().to_vec());
+                buf.extend_from_slice(&captured);
+                buf.append(&mut vec![0x0; padding]);
+                // epb_flags, the lowest 2 bits hold the direction
+                let flags: u32 = match direction {
+                    Direction::Inbound => 0b01,
+                    Direction::Outbound => 0b10,
+                };
+                buf.append(&mut 0x2_u16.to_le_bytes().to_vec());
+                buf.append(&mut 0x4_u16.to_le_bytes().to_vec());
+                buf.append(&mut flags.to_le_bytes().to_vec());
+                buf.append(&mut vec![0x0; 4]); // opt_endofopt
+                buf.append(&mut total_length.to_le_bytes().to_vec());
+            }
+        }
+        self.writer.write_all(&buf)
+    }
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+    fn pcap_header(link_type: LinkType) -> Vec<u8> {
+        let mut buf = 0xa1b2c3d4_u32.to_le_bytes().to_vec();
+        buf.append(&mut 2_u16.to_le_bytes().to_vec());
+        buf.append(&mut 4_u16.to_le_bytes().to_vec());
+        buf.append(&mut 0_i32.to_le_bytes().to_vec()); // thiszone
+        buf.append(&mut 0_u32.to_le_bytes().to_vec()); // sigfigs
+        buf.append(&mut SNAPLEN.to_le_bytes().to_vec());
+        buf.append(&mut (link_type.to_u16() as u32).to_le_bytes().to_vec());
+        buf
+    }
+    /// Section Header Block followed by a single Interface Description Block
+    fn pcapng_header(link_type: LinkType) -> Vec<u8> {
+        let mut buf = 0x0a0d0d0a_u32.to_le_bytes().to_vec();
+        buf.append(&mut 28_u32.to_le_bytes().to_vec());
+        buf.append(&mut 0x1a2b3c4d_u32.to_le_bytes().to_vec());
+        buf.append(&mut 1_u16.to_le_bytes().to_vec());
+        buf.append(&mut 0_u16.to_le_bytes().to_vec());
+        buf.append(&mut (-1_i64).to_le_bytes().to_vec()); // Section length is not known
+        buf.append(&mut 28_u32.to_le_bytes().to_vec());
+
+        buf.append(&mut 0x1_u32.to_le_bytes().to_vec());
+        buf.append(&mut 20_u32.to_le_bytes().to_vec());
+        buf.append(&mut link_type.to_u16().to_le_bytes().to_vec());
+        buf.append(&mut 0_u16.to_le_bytes().to_vec());
+        buf.append(&mut SNAPLEN.to_le_bytes().to_vec());
+        buf.append(&mut 20_u32.to_le_bytes().to_vec());
+        buf
+    }
+}
+
+/// Wraps a device, capturing every frame received from and sent to it
+/// The packet information header is not part of the captured frames
+pub struct CaptureDevice<D: NetDevice, W: Write> {
+    device: D,
+    writer: CaptureWriter<W>,
+}
+impl<D: NetDevice, W: Write> CaptureDevice<D, W> {
+    pub fn new(device: D, writer: CaptureWriter<W>) -> Self {
+        Self { device, writer }
+    }
+    pub fn into_inner(self) -> (D, CaptureWriter<W>) {
+        (self.device, self.writer)
+    }
+}
+impl<D: NetDevice, W: Write> NetDevice for CaptureDevice<D, W> {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+    fn mtu(&self) -> usize {
+        self.device.mtu()
+    }
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.device.recv(buf)?;
+        if len > PACKET_INFO_LEN {
+            self.writer.write_frame(
+                SystemTime::now(),
+                Direction::Inbound,
+                &buf[PACKET_INFO_LEN..len],
+            )?;
+        }
+        Ok(len)
+    }
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        if frame.len() > PACKET_INFO_LEN {
+            self.writer.write_frame(
+                SystemTime::now(),
+                Direction::Outbound,
+                &frame[PACKET_INFO_LEN..],
+            )?;
+        }
+        self.device.send(frame)
+    }
+    fn send_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        // The packet information header may be split over the first slices
+        let mut skip = PACKET_INFO_LEN;
+        let ip_packet: Vec<IoSlice> = bufs
+            .iter()
+            .filter_map(|buf| {
+                let start = skip.min(buf.len());
+                skip -= start;
+                (start < buf.len()).then(|| IoSlice::new(&buf[start..]))
+            })
+            .collect();
+        if !ip_packet.is_empty() {
+            self.writer
+                .write_frame_vectored(SystemTime::now(), Direction::Outbound, &ip_packet)?;
+        }
+        self.device.send_vectored(bufs)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.device.flush()
+    }
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.device.raw_fd()
+    }
+}
+
+/// Reads frames from a pcap file, in either byte order and with micro or nanosecond timestamps
+pub struct PcapReader<R: Read> {
+    reader: R,
+    link_type: LinkType,
+    big_endian: bool,
+    nanosecond: bool,
+    /// Records longer than this are rejected instead of being allocated
+    snaplen: u32,
+}
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let (big_endian, nanosecond) = match header[..4] {
+            [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+            [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+            [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+            [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Not a pcap file",
+                ))
+            }
+        };
+        let mut pcap_reader = Self {
+            reader,
+            link_type: LinkType::RawIP,
+            big_endian,
+            nanosecond,
+            snaplen: MAX_SNAPLEN,
+        };
+        pcap_reader.snaplen = pcap_reader.u32_from_bytes(&header[16..20]).min(MAX_SNAPLEN);
+        let link_type = pcap_reader.u32_from_bytes(&header[20..24]);
+        pcap_reader.link_type = LinkType::from_u32(link_type).ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported link type",
+        ))?;
+        Ok(pcap_reader)
+    }
+    pub fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+    /// Returns None at the end of the file
+    pub fn next_frame(&mut self) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        let mut record_header = [0u8; 16];
+        match self.reader.read_exact(&mut record_header) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+        let seconds = self.u32_from_bytes(&record_header[..4]) as u64;
+        let fraction = self.u32_from_bytes(&record_header[4..8]);
+        let captured_len = self.u32_from_bytes(&record_header[8..12]);
+        if captured_len > self.snaplen {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Record is longer than the snaplen",
+            ));
+        }
+        let mut frame = vec![0u8; captured_len as usize];
+        self.reader.read_exact(&mut frame)?;
+        let fraction = match self.nanosecond {
+            true => Duration::from_nanos(fraction as u64),
+            false => Duration::from_micros(fraction as u64),
+        };
+        Ok(Some((
+            UNIX_EPOCH + Duration::from_secs(seconds) + fraction,
+            frame,
+        )))
+    }
+    fn u32_from_bytes(&self, buf: &[u8]) -> u32 {
+        let bytes = [buf[0], buf[1], buf[2], buf[3]];
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+/// Feeds the frames of a raw IP pcap to the stack, capturing what the stack sends into another pcap
+/// Sent frames get the timestamp of the last received frame, so the output only depends on the input
+/// `recv` returns `UnexpectedEof` once every frame has been read
+pub struct ReplayDevice<R: Read, W: Write> {
+    name: String,
+    mtu: usize,
+    reader: PcapReader<R>,
+    writer: CaptureWriter<W>,
+    timestamp: SystemTime,
+}
+impl<R: Read, W: Write> ReplayDevice<R, W> {
+    pub fn new(name: &str, mtu: usize, reader: R, writer: W) -> io::Result<Self> {
+        let reader = PcapReader::new(reader)?;
+        if reader.link_type() != LinkType::RawIP {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Only raw IP captures can be replayed",
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            mtu,
+            reader,
+            writer: CaptureWriter::new(writer, CaptureFormat::Pcap, LinkType::RawIP)?,
+            timestamp: UNIX_EPOCH,
+        })
+    }
+    pub fn into_writer(self) -> W {
+        self.writer.into_inner()
+    }
+}
+impl<R: Read, W: Write> NetDevice for ReplayDevice<R, W> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((timestamp, ip_packet)) = self.reader.next_frame()? else {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        };
+        self.timestamp = timestamp;
+        let frame = frame_ip_packet(&ip_packet);
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(len)
+    }
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        if frame.len() > PACKET_INFO_LEN {
+            self.writer.write_frame(
+                self.timestamp,
+                Direction::Outbound,
+                &frame[PACKET_INFO_LEN..],
+            )?;
+        }
+        Ok(frame.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{frame_ip_packet, VirtualDevice, DEFAULT_MTU};
+
+    #[test]
+    fn pcap() {
+        let mut writer =
+            CaptureWriter::new(Vec::new(), CaptureFormat::Pcap, LinkType::RawIP).unwrap();
+        let timestamp = UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_123);
+        writer
+            .write_frame(timestamp, Direction::Inbound, &[0x45, 0x0, 0x0])
+            .unwrap();
+        let buf = writer.into_inner();
+        assert_eq!(buf.len(), 24 + 16 + 3);
+        assert_eq!(buf[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(buf[20..24], 101_u32.to_le_bytes());
+        assert_eq!(buf[24..28], 1_700_000_000_u32.to_le_bytes());
+        assert_eq!(buf[28..32], 123_u32.to_le_bytes());
+        assert_eq!(buf[32..36], 3_u32.to_le_bytes());
+        assert_eq!(buf[40..], [0x45, 0x0, 0x0]);
+    }
+
+    #[test]
+    fn pcapng() {
+        let mut writer =
+            CaptureWriter::new(Vec::new(), CaptureFormat::Pcapng, LinkType::Ethernet).unwrap();
+        writer
+            .write_frame(UNIX_EPOCH, Direction::Outbound, &[0xab; 5])
+            .unwrap();
+        let buf = writer.into_inner();
+        assert_eq!(buf[..4], [0x0a, 0x0d, 0x0d, 0x0a]);
+        assert_eq!(buf[36..38], 1_u16.to_le_bytes());
+        let block = &buf[48..];
+        assert_eq!(block[..4], 6_u32.to_le_bytes());
+        // 28 byte header, 8 bytes of padded data, 12 bytes of options and the trailing length
+        assert_eq!(block.len(), 52);
+        assert_eq!(block[4..8], 52_u32.to_le_bytes());
+        assert_eq!(block[48..52], 52_u32.to_le_bytes());
+        assert_eq!(block[36..44], [0x2, 0x0, 0x4, 0x0, 0x2, 0x0, 0x0, 0x0]);
+    }
+
+    #[test]
+    fn capture_device() {
+        let (device, mut peer) = VirtualDevice::pair("tun0", "host", DEFAULT_MTU);
+        let writer = CaptureWriter::new(Vec::new(), CaptureFormat::Pcap, LinkType::RawIP).unwrap();
+        let mut device = CaptureDevice::new(device, writer);
+        peer.send(&frame_ip_packet(&[0x45, 0x1])).unwrap();
+        let mut buf = [0u8; 64];
+        assert_eq!(device.recv(&mut buf).unwrap(), 6);
+        device.send(&frame_ip_packet(&[0x45, 0x2, 0x3])).unwrap();
+        let frame = frame_ip_packet(&[0x45, 0x4]);
+        let bufs = [IoSlice::new(&frame[..2]), IoSlice::new(&frame[2..])];
+        assert_eq!(device.send_vectored(&bufs).unwrap(), 6);
+        assert_eq!(peer.recv(&mut buf).unwrap(), 7);
+        assert_eq!(peer.recv(&mut buf).unwrap(), 6);
+        assert_eq!(buf[..6], frame);
+        let (_, writer) = device.into_inner();
+        let buf = writer.into_inner();
+        assert_eq!(buf.len(), 24 + 16 + 2 + 16 + 3 + 16 + 2);
+        assert_eq!(buf[40..42], [0x45, 0x1]);
+        assert_eq!(buf[58..61], [0x45, 0x2, 0x3]);
+        assert_eq!(buf[77..], [0x45, 0x4]);
+    }
+
+    #[test]
+    fn read_back() {
+        let mut writer =
+            CaptureWriter::new(Vec::new(), CaptureFormat::Pcap, LinkType::RawIP).unwrap();
+        let timestamp = UNIX_EPOCH + Duration::from_micros(1_700_000_000_500_000);
+        writer
+            .write_frame(timestamp, Direction::Inbound, &[0x45, 0x1])
+            .unwrap();
+        let buf = writer.into_inner();
+        let mut reader = PcapReader::new(buf.as_slice()).unwrap();
+        assert_eq!(reader.link_type(), LinkType::RawIP);
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some((timestamp, vec![0x45, 0x1]))
+        );
+        assert_eq!(reader.next_frame().unwrap(), None);
+        assert!(PcapReader::new(&[0x0; 24][..]).is_err());
+
+        // Captured length beyond the snaplen
+        let mut buf = buf;
+        buf[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = PcapReader::new(buf.as_slice()).unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+}
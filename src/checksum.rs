@@ -1,22 +1,172 @@
-/// Calculates the u16 one's complement sum of the entire buffer
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::ip::{IPPacketError, IPPacketErrorKind};
+
+/// Calculates the u16 one's complement sum of the entire buffer, 0 for an empty buffer
 /// Padding odd length byte with u8 0x0 to the right
 pub fn ones_complement_sum_byte_buffer(buf: &[u8]) -> u16 {
-    let mut sum: u32 = 0;
-    assert!(!buf.is_empty()); // Should panic if buffer passed in is empty, might change in the future
-
-    for chunk in buf.chunks(2) {
-        let num = match chunk {
-            [left, right] => u16::from_be_bytes([*left, *right]),
-            [left] => u16::from_be_bytes([*left, 0]),
-            _ => panic!("Should only have chunks of 1/2"),
-        };
-        sum += num as u32
-    }
-    // Fold the sum, 0xFF = u8
+    fold(sum_words(buf))
+}
+
+/// Sums `buf` as big endian 32 bit words, deferring the carries to `fold` (RFC 1071 2.C)
+/// A u64 cannot overflow for any buffer an IP packet fits in, and the loop over exact chunks is
+/// vectorized by the compiler where SIMD is available
+fn sum_words(buf: &[u8]) -> u64 {
+    let mut chunks = buf.chunks_exact(4);
+    let mut sum: u64 = chunks
+        .by_ref()
+        .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64)
+        .sum();
+    let mut last = [0u8; 4];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    sum += u32::from_be_bytes(last) as u64;
+    sum
+}
+
+/// Folds the carries back in until the sum fits in 16 bits
+fn fold(mut sum: u64) -> u16 {
     while sum >> 16 != 0 {
         sum = (sum & 0xFFFF) + (sum >> 16);
     }
-    sum as u16 // one's complement sum
+    sum as u16
+}
+
+/// Updates a checksum after a 16 bit field changed from `old` to `new` (RFC 1624 eqn. 3)
+pub fn incremental_update(checksum: u16, old: u16, new: u16) -> u16 {
+    !fold((!checksum) as u64 + (!old) as u64 + new as u64)
+}
+
+/// Updates a checksum after a 32 bit field, e.g. an address, changed from `old` to `new`
+pub fn incremental_update_u32(checksum: u16, old: u32, new: u32) -> u16 {
+    let [old_high, old_low] = [(old >> 16) as u16, old as u16];
+    let [new_high, new_low] = [(new >> 16) as u16, new as u16];
+    !fold(
+        (!checksum) as u64
+            + (!old_high) as u64
+            + (!old_low) as u64
+            + new_high as u64
+            + new_low as u64,
+    )
+}
+
+/// One's complement sum of the pseudo header followed by `buf`, without copying `buf`
+pub fn pseudo_header_sum(
+    source_address: &IpAddr,
+    destination_address: &IpAddr,
+    protocol: u8,
+    buf: &[u8],
+) -> Result<u16, IPPacketError> {
+    Ok(Checksum::new()
+        .add_pseudo_header(
+            source_address,
+            destination_address,
+            protocol,
+            buf.len() as u32,
+        )?
+        .add(buf)
+        .sum())
+}
+
+/// Streaming one's complement sum, fed the pseudo header, headers and data as separate pieces
+/// instead of copying them into one buffer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Checksum {
+    sum: u64,
+    /// The pieces added so far have an odd length
+    odd: bool,
+}
+impl Checksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds the bytes following the ones already added
+    pub fn add(&mut self, buf: &[u8]) -> &mut Self {
+        let sum = fold(sum_words(buf));
+        // After an odd length piece every byte lands in the other half of its word (RFC 1071 2.B)
+        self.sum += if self.odd { sum.swap_bytes() } else { sum } as u64;
+        self.odd ^= buf.len() % 2 == 1;
+        self
+    }
+    /// Adds the IPv4 (RFC 793) or IPv6 (RFC 8200 8.1) pseudo header depending on the addresses
+    pub fn add_pseudo_header(
+        &mut self,
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
+        protocol: u8,
+        length: u32,
+    ) -> Result<&mut Self, IPPacketError> {
+        match (source_address, destination_address) {
+            (IpAddr::V4(source_address), IpAddr::V4(destination_address)) => Ok(
+                self.add_ipv4_pseudo_header(source_address, destination_address, protocol, length)
+            ),
+            (IpAddr::V6(source_address), IpAddr::V6(destination_address)) => Ok(
+                self.add_ipv6_pseudo_header(source_address, destination_address, protocol, length)
+            ),
+            _ => Err(IPPacketError::new(IPPacketErrorKind::AddressFamilyMismatch)),
+        }
+    }
+    /// Adds the IPv4 pseudo header (RFC 793)
+    pub fn add_ipv4_pseudo_header(
+        &mut self,
+        source_address: &Ipv4Addr,
+        destination_address: &Ipv4Addr,
+        protocol: u8,
+        length: u32,
+    ) -> &mut Self {
+        self.add(&source_address.octets())
+            .add(&destination_address.octets())
+            .add(&[0x0, protocol])
+            .add(&(length as u16).to_be_bytes())
+    }
+    /// Adds the IPv6 pseudo header (RFC 8200 8.1)
+    pub fn add_ipv6_pseudo_header(
+        &mut self,
+        source_address: &Ipv6Addr,
+        destination_address: &Ipv6Addr,
+        protocol: u8,
+        length: u32,
+    ) -> &mut Self {
+        self.add(&source_address.octets())
+            .add(&destination_address.octets())
+            .add(&length.to_be_bytes())
+            .add(&[0x0, 0x0, 0x0, protocol])
+    }
+    /// One's complement sum of everything added, 0xFFFF when it included a correct checksum
+    pub fn sum(&self) -> u16 {
+        fold(self.sum)
+    }
+    /// Value of the checksum field, when it was 0 while summing
+    pub fn finish(&self) -> u16 {
+        !self.sum()
+    }
+}
+
+/// Crafts the IPv4 (RFC 793) or IPv6 (RFC 8200 8.1) pseudo header depending on the addresses
+pub fn craft_pseudo_header(
+    source_address: &IpAddr,
+    destination_address: &IpAddr,
+    protocol: u8,
+    length: u16,
+) -> Result<Vec<u8>, IPPacketError> {
+    Ok(match (source_address, destination_address) {
+        (IpAddr::V4(source_address), IpAddr::V4(destination_address)) => {
+            let mut pseudo_header = source_address.to_bits().to_be_bytes().to_vec();
+            pseudo_header.append(&mut destination_address.to_bits().to_be_bytes().to_vec());
+            pseudo_header.push(0x0);
+            pseudo_header.push(protocol);
+            pseudo_header.append(&mut length.to_be_bytes().to_vec());
+            pseudo_header
+        }
+        (IpAddr::V6(source_address), IpAddr::V6(destination_address)) => {
+            let mut pseudo_header = source_address.octets().to_vec();
+            pseudo_header.append(&mut destination_address.octets().to_vec());
+            pseudo_header.append(&mut (length as u32).to_be_bytes().to_vec());
+            pseudo_header.append(&mut vec![0x0; 3]);
+            pseudo_header.push(protocol);
+            pseudo_header
+        }
+        _ => return Err(IPPacketError::new(IPPacketErrorKind::AddressFamilyMismatch)),
+    })
 }
 
 #[cfg(test)]
@@ -36,4 +186,72 @@ mod tests {
             assert_eq!(0x1, ones_complement_sum_byte_buffer(&buf));
         }
     }
+
+    #[test]
+    fn wide_words_and_odd_lengths() {
+        assert_eq!(ones_complement_sum_byte_buffer(&[]), 0x0);
+        for len in 1..=9 {
+            let buf: Vec<u8> = (0..len).map(|byte| 0xF0 + byte).collect();
+            // Reference 16 bit at a time sum
+            let mut sum: u32 = 0;
+            for chunk in buf.chunks(2) {
+                sum += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32;
+            }
+            let expected = fold(sum as u64);
+            assert_eq!(ones_complement_sum_byte_buffer(&buf), expected);
+            // Split at every point, including after an odd number of bytes
+            for split in 0..=buf.len() {
+                let (left, right) = buf.split_at(split);
+                assert_eq!(Checksum::new().add(left).add(right).sum(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn streamed_pseudo_header() {
+        let source_address = IpAddr::V6("fd00::1".parse().unwrap());
+        let destination_address = IpAddr::V6("fd00::2".parse().unwrap());
+        let mut buf = craft_pseudo_header(&source_address, &destination_address, 6, 3).unwrap();
+        buf.extend_from_slice(&[0x1, 0x2, 0x3]);
+        assert_eq!(
+            pseudo_header_sum(&source_address, &destination_address, 6, &[0x1, 0x2, 0x3]).unwrap(),
+            ones_complement_sum_byte_buffer(&buf)
+        );
+    }
+
+    #[test]
+    fn mixed_address_families() {
+        let source_address = IpAddr::V4("192.168.0.1".parse().unwrap());
+        let destination_address = IpAddr::V6("fd00::2".parse().unwrap());
+        assert!(craft_pseudo_header(&source_address, &destination_address, 6, 3).is_err());
+        assert!(pseudo_header_sum(&source_address, &destination_address, 6, &[0x1]).is_err());
+    }
+
+    #[test]
+    fn incremental_update_address() {
+        let mut buf: [u8; 20] = [
+            0x45, 0x0, 0x0, 0x54, 0x1b, 0xb, 0x40, 0x0, 0x40, 0x1, 0x9e, 0x4a, 0xc0, 0xa8, 0x0,
+            0x1, 0xc0, 0xa8, 0x0, 0x2,
+        ];
+        let old = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+        let new = u32::from_be_bytes([203, 0, 113, 1]);
+        buf[12..16].copy_from_slice(&new.to_be_bytes());
+        let checksum = incremental_update_u32(0x9e4a, old, new);
+        buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(0xFFFF, ones_complement_sum_byte_buffer(&buf));
+    }
+
+    #[test]
+    fn incremental_update_ttl_decrement() {
+        let mut buf: [u8; 20] = [
+            0x45, 0x0, 0x0, 0x54, 0x1b, 0xb, 0x40, 0x0, 0x40, 0x1, 0x9e, 0x4a, 0xc0, 0xa8, 0x0,
+            0x1, 0xc0, 0xa8, 0x0, 0x2,
+        ];
+        let old = u16::from_be_bytes([buf[8], buf[9]]);
+        buf[8] -= 1;
+        let new = u16::from_be_bytes([buf[8], buf[9]]);
+        let checksum = incremental_update(0x9e4a, old, new);
+        buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(0xFFFF, ones_complement_sum_byte_buffer(&buf));
+    }
 }
@@ -0,0 +1,571 @@
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    path::Path,
+    time::Instant,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    connection::{ConnectionTable, TCPConfig},
+    device::{DEFAULT_MTU, PACKET_INFO_LEN},
+    filter::{Action, ConnectionState, Filter, Hook, Rule},
+    forward::Router,
+    ipv6::fragment::MINIMUM_MTU,
+    nat::Nat,
+    protocol::{
+        icmpv6::{
+            self,
+            ndp::{stable_interface_identifier, NeighborDiscovery},
+        },
+        tcp, udp,
+    },
+    qdisc::QdiscConfig,
+    route::{Interface, InterfaceAddress, RouteTable},
+    server::{Binding, UDPEchoServer, ECHO_PORT},
+    stack::Stack,
+};
+
+pub const USAGE: &str = "Usage: nust [options]
+  -c, --config <file>      Read the configuration from a TOML file, see nust.toml
+  -i, --interface <name>   Name of the TUN interface
+  -a, --address <cidr>     Our address on the interface, repeat for more
+      --mtu <bytes>        MTU of the interface
+      --forwarding         Forward packets which are not addressed to us
+      --capture <file>     Write every frame to a pcapng file, empty to disable
+      --listen <port>      Accept TCP connections on the port, repeat for more
+      --log <filter>       Log filter when NUST_LOG is not set, e.g. nust=debug
+  -h, --help               Print this help
+Options given on the command line override the configuration file";
+
+/// Everything the binary sets up at startup
+/// The defaults are one tun0 interface with 192.168.0.2/24 and fd00::2/64 whose host side gets
+/// 192.168.0.1/24 and fd00::1/64, a web port and every protocol and service enabled
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "interface")]
+    pub interfaces: Vec<InterfaceConfig>,
+    #[serde(rename = "route")]
+    pub routes: Vec<RouteConfig>,
+    /// Router mode, forward packets which are not addressed to us
+    pub forwarding: bool,
+    /// Translate the IPv4 packets forwarded out of an interface, none by default
+    pub nat: Option<NatConfig>,
+    /// Stateful packet filter, none by default
+    pub filter: Option<FilterConfig>,
+    /// Protocols answered when addressed to us
+    pub protocols: Vec<Protocol>,
+    pub services: Vec<Service>,
+    /// Ports accepting TCP connections on every address
+    pub listen: Vec<u16>,
+    /// Every frame sent and received is written to this pcapng file, empty to disable
+    pub capture: String,
+    /// Unix socket answering nustctl, empty to disable
+    pub control_socket: String,
+    /// Log filter when NUST_LOG is not set, it takes the same directives
+    pub log: String,
+    /// Seconds between counter dumps, 0 to disable
+    pub stats_interval: u64,
+    pub tcp: TCPConfig,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interfaces: vec![InterfaceConfig {
+                name: "tun0".to_string(),
+                mtu: DEFAULT_MTU,
+                addresses: vec![
+                    "192.168.0.2/24".parse().expect("Valid address"),
+                    "fd00::2/64".parse().expect("Valid address"),
+                ],
+                host_addresses: vec![
+                    "192.168.0.1/24".parse().expect("Valid address"),
+                    "fd00::1/64".parse().expect("Valid address"),
+                ],
+                qdisc: None,
+                autoconf: false,
+            }],
+            routes: Vec::new(),
+            forwarding: false,
+            nat: None,
+            filter: None,
+            protocols: vec![
+                Protocol::ICMP,
+                Protocol::ICMPv6,
+                Protocol::TCP,
+                Protocol::UDP,
+            ],
+            services: vec![Service::Echo],
+            capture: String::new(),
+            control_socket: "/tmp/nust.sock".to_string(),
+            log: "info".to_string(),
+            stats_interval: 60,
+            listen: vec![80],
+            tcp: TCPConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterfaceConfig {
+    pub name: String,
+    #[serde(default = "default_mtu")]
+    pub mtu: usize,
+    /// Our addresses on the interface
+    #[serde(default)]
+    pub addresses: Vec<InterfaceAddress>,
+    /// Given to the host side of the interface when bringing it up
+    #[serde(default)]
+    pub host_addresses: Vec<InterfaceAddress>,
+    /// Packets go straight to the device without one
+    pub qdisc: Option<QdiscConfig>,
+    /// Adds a link local address and one per prefix routers advertise, once they passed
+    /// duplicate address detection (RFC 4862)
+    #[serde(default)]
+    pub autoconf: bool,
+}
+fn default_mtu() -> usize {
+    DEFAULT_MTU
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// The default route when missing
+    pub destination: Option<InterfaceAddress>,
+    /// Required for the default route
+    pub gateway: Option<IpAddr>,
+    pub interface: String,
+    #[serde(default)]
+    pub metric: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NatConfig {
+    /// Packets forwarded out of this interface are translated
+    pub interface: String,
+    /// Shared by the hosts behind the interface, its first IPv4 address when missing
+    pub address: Option<Ipv4Addr>,
+}
+impl NatConfig {
+    fn build(&self, route_table: &RouteTable) -> io::Result<Nat> {
+        let interface = route_table
+            .interface(&self.interface)
+            .ok_or_else(|| invalid(format!("NAT through unknown interface {}", self.interface)))?;
+        let address = self.address.or_else(|| {
+            interface
+                .addresses
+                .iter()
+                .find_map(|address| match address.addr {
+                    IpAddr::V4(addr) => Some(addr),
+                    IpAddr::V6(_) => None,
+                })
+        });
+        let address = address
+            .ok_or_else(|| invalid(format!("NAT needs an IPv4 address on {}", self.interface)))?;
+        Ok(Nat::new(&self.interface, address))
+    }
+}
+
+/// A chain per hook of the packet filter
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub input: ChainConfig,
+    pub output: ChainConfig,
+    pub forward: ChainConfig,
+}
+impl FilterConfig {
+    fn build(&self) -> Filter {
+        let mut filter = Filter::new();
+        for (hook, config) in [
+            (Hook::Input, &self.input),
+            (Hook::Output, &self.output),
+            (Hook::Forward, &self.forward),
+        ] {
+            let chain = filter.chain_mut(hook);
+            chain.policy = config.policy;
+            for rule in &config.rules {
+                chain.append(rule.rule());
+            }
+        }
+        filter
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainConfig {
+    /// Applies when no rule matches
+    pub policy: Action,
+    /// Checked in order, the first match decides
+    #[serde(rename = "rule")]
+    pub rules: Vec<RuleConfig>,
+}
+impl Default for ChainConfig {
+    fn default() -> Self {
+        Self {
+            policy: Action::Accept,
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// A rule matches when every field that is set matches
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub source: Option<InterfaceAddress>,
+    pub destination: Option<InterfaceAddress>,
+    pub protocol: Option<Protocol>,
+    pub source_ports: Option<PortRange>,
+    pub destination_ports: Option<PortRange>,
+    pub icmp_type: Option<u8>,
+    /// Any of new, established, related and invalid
+    pub states: Option<Vec<ConnectionState>>,
+    pub action: Action,
+}
+impl RuleConfig {
+    fn rule(&self) -> Rule {
+        let prefix = |address: InterfaceAddress| (address.addr, address.prefix_length);
+        Rule {
+            source: self.source.map(prefix),
+            destination: self.destination.map(prefix),
+            protocol: self.protocol.map(Protocol::number),
+            source_ports: self.source_ports.clone().map(|ports| ports.0),
+            destination_ports: self.destination_ports.clone().map(|ports| ports.0),
+            icmp_type: self.icmp_type,
+            states: self.states.clone(),
+            ..Rule::new(self.action)
+        }
+    }
+}
+
+/// A port like "80" or an inclusive range of them like "1024-65535"
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange(pub RangeInclusive<u16>);
+impl TryFrom<String> for PortRange {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (start, end) = s.split_once('-').unwrap_or((&s, &s));
+        match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) if start <= end => Ok(Self(start..=end)),
+            _ => Err(format!("{} is not a port or a range of ports", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    ICMP,
+    ICMPv6,
+    TCP,
+    UDP,
+}
+impl Protocol {
+    pub fn number(self) -> u8 {
+        match self {
+            Self::ICMP => 1,
+            Self::ICMPv6 => icmpv6::PROTOCOL,
+            Self::TCP => tcp::PROTOCOL,
+            Self::UDP => udp::PROTOCOL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Service {
+    /// Answers ICMP and ICMPv6 echo requests
+    Echo,
+    /// Sends UDP datagrams to port 7 back
+    #[serde(rename = "udp-echo")]
+    UDPEcho,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+            .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))
+    }
+    pub fn from_toml(s: &str) -> io::Result<Self> {
+        let config: Self =
+            toml::from_str(s).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        config.validate()?;
+        Ok(config)
+    }
+    /// Reads the file given with -c, or starts from the defaults, then applies the other options
+    /// Interface options apply to the first interface
+    pub fn from_args(args: &[String]) -> io::Result<Self> {
+        let mut config = match args.iter().position(|arg| arg == "-c" || arg == "--config") {
+            Some(index) => Self::from_file(Path::new(value(args, index)?))?,
+            None => Self::default(),
+        };
+        let mut addresses = Vec::new();
+        let mut listen = Vec::new();
+        let mut index = 0;
+        while index < args.len() {
+            match args[index].as_str() {
+                "-c" | "--config" => {}
+                "-i" | "--interface" => config.interface()?.name = value(args, index)?.to_string(),
+                "-a" | "--address" => addresses.push(value(args, index)?.parse().map_err(invalid)?),
+                "--mtu" => {
+                    config.interface()?.mtu = value(args, index)?
+                        .parse()
+                        .map_err(|_| invalid(format!("Invalid MTU {}", args[index + 1])))?
+                }
+                "--capture" => config.capture = value(args, index)?.to_string(),
+                "--listen" => listen.push(
+                    value(args, index)?
+                        .parse()
+                        .map_err(|_| invalid(format!("Invalid port {}", args[index + 1])))?,
+                ),
+                "--log" => config.log = value(args, index)?.to_string(),
+                "--forwarding" => {
+                    config.forwarding = true;
+                    index += 1;
+                    continue;
+                }
+                arg => return Err(invalid(format!("Unknown option {}", arg))),
+            }
+            index += 2;
+        }
+        if !addresses.is_empty() {
+            config.interface()?.addresses = addresses;
+        }
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+    /// Rejects values the stack cannot run with
+    fn validate(&self) -> io::Result<()> {
+        for interface in &self.interfaces {
+            // Every interface carries IPv6, whose minimum MTU also fits an IPv4 MSS of 536
+            if !(MINIMUM_MTU as usize..=u16::MAX as usize).contains(&interface.mtu) {
+                return Err(invalid(format!(
+                    "MTU {} of {} is not between {} and {}",
+                    interface.mtu,
+                    interface.name,
+                    MINIMUM_MTU,
+                    u16::MAX
+                )));
+            }
+        }
+        if self.tcp.initial_window == 0 {
+            return Err(invalid(
+                "The initial window needs at least one segment".to_string(),
+            ));
+        }
+        Ok(())
+    }
+    fn interface(&mut self) -> io::Result<&mut InterfaceConfig> {
+        self.interfaces
+            .first_mut()
+            .ok_or_else(|| invalid("No interface configured".to_string()))
+    }
+    /// Routes to the subnets of the interface addresses and the configured routes
+    pub fn route_table(&self) -> io::Result<RouteTable> {
+        let mut route_table = RouteTable::new();
+        for interface in &self.interfaces {
+            route_table.add_interface(Interface::new(
+                &interface.name,
+                interface.mtu,
+                interface.addresses.clone(),
+            ));
+        }
+        for route in &self.routes {
+            if route_table.interface(&route.interface).is_none() {
+                return Err(invalid(format!(
+                    "Route through unknown interface {}",
+                    route.interface
+                )));
+            }
+            match (route.destination, route.gateway) {
+                (Some(destination), gateway) => route_table.add_static_route(
+                    destination.addr,
+                    destination.prefix_length,
+                    gateway,
+                    &route.interface,
+                    route.metric,
+                ),
+                (None, Some(gateway)) => {
+                    route_table.add_default_route(gateway, &route.interface, route.metric)
+                }
+                (None, None) => {
+                    return Err(invalid("The default route needs a gateway".to_string()))
+                }
+            }
+        }
+        Ok(route_table)
+    }
+    pub fn stack(&self) -> io::Result<Stack> {
+        let mut router = Router::new(self.route_table()?, self.forwarding);
+        if let Some(nat) = &self.nat {
+            router.nat = Some(nat.build(&router.route_table)?);
+        }
+        router.filter = self.filter.as_ref().map(FilterConfig::build);
+        let mut connections = ConnectionTable::with_config(self.tcp.clone());
+        for port in &self.listen {
+            connections.listen(
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *port),
+                self.tcp.backlog,
+            );
+            connections.listen(
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), *port),
+                self.tcp.backlog,
+            );
+        }
+        let mut stack = Stack::with_connections(router, connections);
+        for interface in &self.interfaces {
+            if let Some(qdisc) = &interface.qdisc {
+                stack
+                    .egress
+                    .lock()
+                    .expect("Egress lock is not poisoned")
+                    .attach(&interface.name, qdisc.build());
+            }
+            if interface.autoconf {
+                // TUN interfaces have no MAC address to derive the interface identifier from
+                let nd = NeighborDiscovery::new(stable_interface_identifier(&interface.name), None);
+                stack.autoconfigure(&interface.name, nd, Instant::now());
+            }
+        }
+        stack.protocols = self
+            .protocols
+            .iter()
+            .map(|protocol| protocol.number())
+            .collect();
+        if !self.services.contains(&Service::Echo) {
+            stack.services.unregister(Binding::ICMP);
+            stack.services.unregister(Binding::ICMPv6);
+        }
+        if self.services.contains(&Service::UDPEcho) {
+            stack
+                .services
+                .register(Binding::UDP(ECHO_PORT), Box::new(UDPEchoServer {}));
+        }
+        Ok(stack)
+    }
+    /// Large enough for a frame of any interface
+    pub fn buffer_size(&self) -> usize {
+        self.interfaces
+            .iter()
+            .map(|interface| interface.mtu)
+            .max()
+            .unwrap_or(DEFAULT_MTU)
+            + PACKET_INFO_LEN
+    }
+}
+
+/// The value following the option at `index`
+fn value(args: &[String], index: usize) -> io::Result<&str> {
+    args.get(index + 1)
+        .map(String::as_str)
+        .ok_or_else(|| invalid(format!("{} needs a value", args[index])))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qdisc::QdiscKind;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn example_file() {
+        let config = Config::from_toml(include_str!("../nust.toml")).unwrap();
+        assert_eq!(config.interfaces[0].name, "tun0");
+        assert_eq!(
+            config.interfaces[0].host_addresses[0],
+            InterfaceAddress::new("192.168.0.1".parse().unwrap(), 24)
+        );
+        let route_table = config.route_table().unwrap();
+        let next_hop = route_table.lookup(&"10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(next_hop.addr, "192.168.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(config.listen, [80]);
+        assert_eq!(config.tcp.backlog, 128);
+        assert_eq!(
+            config.interfaces[0].qdisc.as_ref().unwrap().kind,
+            QdiscKind::FqCodel
+        );
+        let stack = config.stack().unwrap();
+        assert!(stack.egress.lock().unwrap().get("tun0").is_some());
+        let nat = stack.router.nat.as_ref().unwrap();
+        assert_eq!(nat.outside_addr, Ipv4Addr::new(192, 168, 0, 2));
+        let filter = stack.router.filter.as_ref().unwrap();
+        assert_eq!(filter.input.policy, Action::Drop);
+        assert_eq!(filter.input.rules[3].destination_ports, Some(80..=80));
+        assert_eq!(filter.forward.policy, Action::Accept);
+    }
+
+    #[test]
+    fn defaults_and_overrides() {
+        let config = Config::from_toml("forwarding = true\n[tcp]\nbacklog = 8\n").unwrap();
+        assert!(config.forwarding);
+        assert_eq!(config.interfaces, Config::default().interfaces);
+        assert_eq!(config.tcp.msl_secs, TCPConfig::default().msl_secs);
+        let config = Config::from_toml("services = [\"udp-echo\"]").unwrap();
+        assert_eq!(config.services, [Service::UDPEcho]);
+
+        let config = Config::from_args(&args(
+            "-i tun7 -a 10.0.0.2/8 --mtu 9000 --listen 22 --listen 23",
+        ))
+        .unwrap();
+        assert_eq!(config.interfaces[0].name, "tun7");
+        assert_eq!(config.interfaces[0].addresses.len(), 1);
+        assert_eq!(config.buffer_size(), 9000 + PACKET_INFO_LEN);
+        assert_eq!(config.listen, [22, 23]);
+        assert_eq!(
+            config
+                .stack()
+                .unwrap()
+                .connections
+                .lock()
+                .unwrap()
+                .listeners()
+                .len(),
+            4
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(Config::from_toml("fowarding = true").is_err());
+        assert!(
+            Config::from_toml("[[interface]]\nname = \"tun0\"\naddresses = [\"10.0.0.1\"]")
+                .is_err()
+        );
+        assert!(Config::from_toml("[[route]]\ninterface = \"tun0\"")
+            .unwrap()
+            .route_table()
+            .is_err());
+        assert!(Config::from_args(&args("-a 10.0.0.1/33")).is_err());
+        assert!(Config::from_args(&args("--mtu")).is_err());
+        assert!(Config::from_args(&args("--mtu 576")).is_err());
+        assert!(Config::from_toml("[tcp]\ninitial_window = 0").is_err());
+        assert!(Config::from_toml(
+            "[[filter.input.rule]]\ndestination_ports = \"90-80\"\naction = \"drop\""
+        )
+        .is_err());
+        assert!(Config::from_toml("[nat]\ninterface = \"eth0\"")
+            .unwrap()
+            .stack()
+            .is_err());
+        assert!(Config::from_args(&args("--bogus")).is_err());
+    }
+}
@@ -0,0 +1,1549 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeSet, HashMap, VecDeque},
+    fmt,
+    hash::{BuildHasher, DefaultHasher, Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    ip::ECN,
+    protocol::{TCPControlBits, TCPOption, TCP},
+    stats::Stats,
+    timer::TimerWheel,
+};
+
+/// Listen queue length when none is given, like SOMAXCONN
+pub const DEFAULT_BACKLOG: usize = 128;
+/// Receive buffer of each connection, also the largest window advertised
+pub const RECEIVE_BUFFER: usize = 65535;
+/// Data queued but not yet acknowledged, per connection
+pub const SEND_BUFFER: usize = 65535;
+/// RTO before the first RTT measurement (RFC 6298 2.1)
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// Lower bound of the RTO (RFC 6298 2.4)
+pub const MIN_RTO: Duration = Duration::from_secs(1);
+/// Upper bound of the RTO when backing off (RFC 6298 2.5)
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+/// Retransmissions of a segment before the connection is given up, like tcp_retries2
+pub const MAX_RETRANSMITS: u32 = 15;
+/// Longest an ACK is held back waiting for a second segment (RFC 1122 4.2.3.2)
+pub const DELAYED_ACK: Duration = Duration::from_millis(200);
+/// Idle time before the first keepalive probe (RFC 1122 4.2.3.6)
+pub const KEEPALIVE_IDLE: Duration = Duration::from_secs(7200);
+/// Spacing of unanswered keepalive probes and how many are sent before giving up, like Linux
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(75);
+pub const KEEPALIVE_PROBES: u32 = 9;
+/// Maximum segment lifetime, connections stay in TIME-WAIT for twice this (RFC 793 3.3)
+pub const MSL: Duration = Duration::from_secs(30);
+/// Initial congestion window in segments (RFC 6928)
+const INITIAL_WINDOW: u32 = 10;
+/// Send MSS when the peer sends no MSS option (RFC 1122 4.2.2.6), also the smallest we send
+/// with as every host accepts 576 byte datagrams
+const DEFAULT_SEND_MSS: u16 = 536;
+/// Tick of the clock added to the initial sequence numbers (RFC 6528 3)
+const ISS_TICK: Duration = Duration::from_micros(4);
+
+/// Tunables of the TCP implementation, the defaults are the constants above
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TCPConfig {
+    pub backlog: usize,
+    pub receive_buffer: usize,
+    pub send_buffer: usize,
+    pub initial_rto_ms: u64,
+    pub min_rto_ms: u64,
+    pub max_retransmits: u32,
+    /// 0 acknowledges every segment right away
+    pub delayed_ack_ms: u64,
+    pub msl_secs: u64,
+    /// In segments
+    pub initial_window: u32,
+    /// Keepalives must default to off (RFC 1122 4.2.3.6)
+    pub keepalive: bool,
+    pub keepalive_idle_secs: u64,
+    pub keepalive_interval_secs: u64,
+    pub keepalive_probes: u32,
+    /// Accepts ECN when the peer asks for it in its SYN (RFC 3168 6.1.1)
+    pub ecn: bool,
+}
+impl Default for TCPConfig {
+    fn default() -> Self {
+        Self {
+            backlog: DEFAULT_BACKLOG,
+            receive_buffer: RECEIVE_BUFFER,
+            send_buffer: SEND_BUFFER,
+            initial_rto_ms: INITIAL_RTO.as_millis() as u64,
+            min_rto_ms: MIN_RTO.as_millis() as u64,
+            max_retransmits: MAX_RETRANSMITS,
+            delayed_ack_ms: DELAYED_ACK.as_millis() as u64,
+            msl_secs: MSL.as_secs(),
+            initial_window: INITIAL_WINDOW,
+            keepalive: false,
+            keepalive_idle_secs: KEEPALIVE_IDLE.as_secs(),
+            keepalive_interval_secs: KEEPALIVE_INTERVAL.as_secs(),
+            keepalive_probes: KEEPALIVE_PROBES,
+            ecn: true,
+        }
+    }
+}
+
+/// Timers of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TCPTimer {
+    /// Retransmits the oldest unacknowledged segment (RFC 6298)
+    Retransmit,
+    /// Sends an ACK which was held back (RFC 1122 4.2.3.2)
+    DelayedAck,
+    /// Probes an idle connection (RFC 1122 4.2.3.6)
+    Keepalive,
+    /// Forgets the connection after 2 MSL in TIME-WAIT (RFC 793 3.5)
+    TimeWait,
+}
+impl TCPTimer {
+    const ALL: [Self; 4] = [
+        Self::Retransmit,
+        Self::DelayedAck,
+        Self::Keepalive,
+        Self::TimeWait,
+    ];
+}
+
+/// Connection states (RFC 793 3.2), listening sockets are kept apart as `Listener`s
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TCPState {
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+impl fmt::Display for TCPState {
+    /// The names used by ss
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::SynReceived => "SYN-RECV",
+            Self::Established => "ESTAB",
+            Self::FinWait1 => "FIN-WAIT-1",
+            Self::FinWait2 => "FIN-WAIT-2",
+            Self::CloseWait => "CLOSE-WAIT",
+            Self::Closing => "CLOSING",
+            Self::LastAck => "LAST-ACK",
+            Self::TimeWait => "TIME-WAIT",
+            Self::Closed => "CLOSED",
+        };
+        f.pad(name)
+    }
+}
+
+/// Local and remote address of a connection
+pub type ConnectionKey = (SocketAddr, SocketAddr);
+
+/// `a < b` in sequence number space (RFC 793 3.3)
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// Value of the MSS option in the raw TCP options, if there is one
+pub fn mss_option(options: &[u8]) -> Option<u16> {
+    let mut index = 0;
+    while index < options.len() {
+        match options[index] {
+            0 => return None,
+            1 => index += 1,
+            kind => {
+                let len = *options.get(index + 1)? as usize;
+                if kind == 2 && len == 4 {
+                    let value = options.get(index + 2..index + 4)?;
+                    return Some(u16::from_be_bytes([value[0], value[1]]));
+                }
+                index += len.max(2);
+            }
+        }
+    }
+    None
+}
+
+/// Transmission control block (RFC 793 3.2)
+#[derive(Debug)]
+pub struct TCB {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub state: TCPState,
+    /// The listener the connection was opened through
+    pub listener: Option<SocketAddr>,
+    pub iss: u32,
+    pub snd_una: u32,
+    pub snd_nxt: u32,
+    pub snd_wnd: u16,
+    pub irs: u32,
+    pub rcv_nxt: u32,
+    /// Largest segment the peer accepts
+    pub mss: u16,
+    /// Largest segment we accept, from the MTU of the interface the SYN came in through
+    pub local_mss: u16,
+    /// Data not yet acknowledged by the peer
+    pub send_queue: VecDeque<u8>,
+    /// Data received but not yet read
+    pub receive_queue: VecDeque<u8>,
+    pub receive_buffer: usize,
+    /// Congestion window and slow start threshold in bytes (RFC 5681)
+    pub cwnd: u32,
+    pub ssthresh: u32,
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    pub rto: Duration,
+    /// Retransmissions of the oldest unacknowledged segment
+    pub backoff: u32,
+    /// Retransmissions over the lifetime of the connection
+    pub retransmits: u32,
+    /// ECN was negotiated in the handshake (RFC 3168 6.1.1)
+    pub ecn: bool,
+    /// A CE mark arrived, our ACKs carry ECE until the peer answers with CWR
+    ece: bool,
+    /// The window was reduced for an ECE, the next new data segment carries CWR
+    cwr: bool,
+    /// ECE is not reacted to again until data sent after this is acknowledged
+    ecn_recover: u32,
+    /// Sequence number whose acknowledgment completes the RTT measurement in progress
+    rtt_timed: Option<(u32, Instant)>,
+    /// The application closed the connection, the FIN goes after the queued data
+    fin_queued: bool,
+    fin_sent: bool,
+    /// Unanswered keepalive probes
+    keepalive_probes: u32,
+    /// Deadlines of the running timers, indexed by `TCPTimer`
+    timers: [Option<Instant>; 4],
+    /// Deadlines last put on the timer wheel
+    scheduled: [Option<Instant>; 4],
+    /// Tasks waiting for the connection to change
+    wakers: Vec<Waker>,
+}
+impl TCB {
+    /// Passive open, answering the SYN of `remote`
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        local: SocketAddr,
+        remote: SocketAddr,
+        listener: SocketAddr,
+        iss: u32,
+        syn: &TCP,
+        mtu: usize,
+        config: &TCPConfig,
+        now: Instant,
+    ) -> Self {
+        let local_mss = Self::local_mss(&local, mtu);
+        let mss = syn
+            .options
+            .as_deref()
+            .and_then(mss_option)
+            .unwrap_or(DEFAULT_SEND_MSS)
+            // Only guards against 0, segments never exceed what the peer said it accepts
+            .max(1)
+            .min(local_mss);
+        Self {
+            local,
+            remote,
+            state: TCPState::SynReceived,
+            listener: Some(listener),
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: syn.window,
+            irs: syn.sequence_number,
+            rcv_nxt: syn.sequence_number.wrapping_add(1),
+            mss,
+            local_mss,
+            send_queue: VecDeque::new(),
+            receive_queue: VecDeque::new(),
+            receive_buffer: config.receive_buffer,
+            cwnd: config.initial_window.max(1) * mss as u32,
+            ssthresh: u32::MAX,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: Duration::from_millis(config.initial_rto_ms),
+            backoff: 0,
+            retransmits: 0,
+            ecn: config.ecn
+                && syn
+                    .control_bits
+                    .contains(TCPControlBits::ECE | TCPControlBits::CWR),
+            ece: false,
+            cwr: false,
+            ecn_recover: iss,
+            rtt_timed: Some((iss.wrapping_add(1), now)),
+            fin_queued: false,
+            fin_sent: false,
+            keepalive_probes: 0,
+            // For the SYN-ACK
+            timers: [
+                Some(now + Duration::from_millis(config.initial_rto_ms)),
+                None,
+                None,
+                None,
+            ],
+            scheduled: [None; 4],
+            wakers: Vec::new(),
+        }
+    }
+    /// MSS we advertise, the MTU without the IP and TCP headers
+    fn local_mss(local: &SocketAddr, mtu: usize) -> u16 {
+        let ip_header_len = if local.is_ipv4() { 20 } else { 40 };
+        mtu.saturating_sub(ip_header_len + 20)
+            .clamp(DEFAULT_SEND_MSS as usize, u16::MAX as usize) as u16
+    }
+    pub fn key(&self) -> ConnectionKey {
+        (self.local, self.remote)
+    }
+    /// Whether the peer sent its FIN, nothing is received after the receive queue
+    pub fn fin_received(&self) -> bool {
+        matches!(
+            self.state,
+            TCPState::CloseWait | TCPState::Closing | TCPState::LastAck | TCPState::TimeWait
+        )
+    }
+    /// Bytes sent but not yet acknowledged
+    pub fn in_flight(&self) -> u32 {
+        self.snd_nxt.wrapping_sub(self.snd_una)
+    }
+    /// Deadline of the timer if it is running
+    pub fn timer(&self, timer: TCPTimer) -> Option<Instant> {
+        self.timers[timer as usize]
+    }
+    fn set_timer(&mut self, timer: TCPTimer, deadline: Option<Instant>) {
+        self.timers[timer as usize] = deadline;
+    }
+    /// Bytes of the send queue which were sent but not yet acknowledged
+    fn data_in_flight(&self) -> usize {
+        let mut in_flight = self.in_flight();
+        if self.snd_una == self.iss {
+            // The SYN
+            in_flight -= 1;
+        }
+        if self.fin_sent && self.snd_una != self.snd_nxt {
+            in_flight -= 1;
+        }
+        in_flight as usize
+    }
+    /// Whether there is data or a FIN which was not sent yet
+    fn has_pending(&self) -> bool {
+        self.send_queue.len() > self.data_in_flight() || self.fin_queued && !self.fin_sent
+    }
+    fn receive_window(&self) -> u16 {
+        (self.receive_buffer - self.receive_queue.len()).min(u16::MAX as usize) as u16
+    }
+    fn segment(
+        &self,
+        sequence_number: u32,
+        control_bits: TCPControlBits,
+        options: &[TCPOption],
+        data: Vec<u8>,
+    ) -> TCP {
+        let control_bits = match self.ece && control_bits.contains(TCPControlBits::ACK) {
+            true => control_bits | TCPControlBits::ECE,
+            false => control_bits,
+        };
+        let mut segment = TCP::builder(self.local.port(), self.remote.port())
+            .sequence_number(sequence_number)
+            .acknowledgment_number(self.rcv_nxt)
+            .control_bits(control_bits)
+            .window(self.receive_window())
+            .data(data);
+        for option in options {
+            segment = segment.option(*option);
+        }
+        segment
+            .build(&self.local.ip(), &self.remote.ip())
+            .expect("Connection addresses come from one IP header")
+    }
+    /// An ACK sent right away, which makes a delayed ACK unnecessary
+    fn ack(&mut self) -> TCP {
+        self.set_timer(TCPTimer::DelayedAck, None);
+        self.segment(self.snd_nxt, TCPControlBits::ACK, &[], Vec::new())
+    }
+    /// Carries ECE alone when agreeing to use ECN (RFC 3168 6.1.1)
+    fn syn_ack(&self) -> TCP {
+        let options = [TCPOption::MSS(self.local_mss)];
+        let control_bits = match self.ecn {
+            true => TCPControlBits::SYN | TCPControlBits::ACK | TCPControlBits::ECE,
+            false => TCPControlBits::SYN | TCPControlBits::ACK,
+        };
+        self.segment(self.iss, control_bits, &options, Vec::new())
+    }
+    /// Codepoint of the IP packet carrying a segment, only new data is ECN capable, not pure
+    /// ACKs, control segments or retransmissions (RFC 3168 6.1.4, 6.1.5)
+    fn ecn_codepoint(&self, tcp: &TCP) -> ECN {
+        match self.ecn && !tcp.data.is_empty() {
+            true => ECN::ECT0,
+            false => ECN::NotECT,
+        }
+    }
+    /// Updates SRTT, RTTVAR and the RTO with a new measurement (RFC 6298 2.2, 2.3)
+    fn update_rtt(&mut self, sample: Duration, min_rto: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let difference = srtt.abs_diff(sample);
+                self.rttvar = self.rttvar * 3 / 4 + difference / 4;
+                self.srtt = Some(srtt * 7 / 8 + sample / 8);
+            }
+        }
+        let srtt = self.srtt.expect("Set above");
+        self.rto = (srtt + self.rttvar * 4).max(min_rto);
+    }
+    /// Processes an acknowledgment of new data
+    fn acknowledge(&mut self, ack: u32, config: &TCPConfig, now: Instant) {
+        let mut acked = ack.wrapping_sub(self.snd_una);
+        if self.snd_una == self.iss {
+            // The SYN takes a sequence number but is not in the send queue
+            acked -= 1;
+        }
+        if self.fin_sent && ack == self.snd_nxt {
+            acked -= 1;
+        }
+        let acked = (acked as usize).min(self.send_queue.len());
+        self.send_queue.drain(..acked);
+        self.snd_una = ack;
+        self.backoff = 0;
+        if let Some((sequence_number, sent)) = self.rtt_timed {
+            if seq_le(sequence_number, ack) {
+                self.update_rtt(now - sent, Duration::from_millis(config.min_rto_ms));
+                self.rtt_timed = None;
+            }
+        }
+        // Slow start, then congestion avoidance (RFC 5681 3.1)
+        let mss = self.mss as u32;
+        self.cwnd = match self.cwnd < self.ssthresh {
+            true => self.cwnd.saturating_add((acked as u32).min(mss)),
+            false => self
+                .cwnd
+                .saturating_add((mss * mss / self.cwnd.max(1)).max(1)),
+        };
+        // Stopped once everything is acknowledged, restarted otherwise (RFC 6298 5.2, 5.3)
+        let deadline = (self.snd_una != self.snd_nxt).then(|| now + self.rto);
+        self.set_timer(TCPTimer::Retransmit, deadline);
+    }
+    /// The peer echoed a CE mark, the window is halved like after a loss but without
+    /// retransmitting, at most once per window of data (RFC 3168 6.1.2)
+    fn congestion_experienced(&mut self) {
+        let mss = self.mss as u32;
+        self.ssthresh = (self.cwnd / 2).max(2 * mss);
+        self.cwnd = self.ssthresh;
+        self.ecn_recover = self.snd_nxt;
+        self.cwr = true;
+    }
+    /// SEGMENT ARRIVES for a synchronized connection (RFC 793 3.9), out of order segments are
+    /// dropped and answered with an ACK for the data we expect
+    fn receive(&mut self, tcp: &TCP, ecn: ECN, config: &TCPConfig, now: Instant) -> Option<TCP> {
+        let control_bits = tcp.control_bits;
+        if control_bits.contains(TCPControlBits::RST) {
+            // Only an exact match resets the connection (RFC 5961 3.2)
+            if tcp.sequence_number == self.rcv_nxt {
+                self.state = TCPState::Closed;
+            }
+            return None;
+        }
+        if control_bits.contains(TCPControlBits::SYN) {
+            if self.state == TCPState::SynReceived && tcp.sequence_number == self.irs {
+                // Our SYN-ACK was lost
+                return Some(self.syn_ack());
+            }
+            // Challenge ACK (RFC 5961 4.2)
+            return Some(self.ack());
+        }
+        if !control_bits.contains(TCPControlBits::ACK) {
+            return None;
+        }
+        if self.ecn {
+            // CWR first, so a CE mark on the same segment is echoed again (RFC 3168 6.1.3)
+            if control_bits.contains(TCPControlBits::CWR) {
+                self.ece = false;
+            }
+            if ecn == ECN::CE {
+                self.ece = true;
+            }
+        }
+        let ack = tcp.acknowledgment_number;
+        if self.state == TCPState::SynReceived {
+            if ack != self.snd_nxt {
+                return Some(self.segment(ack, TCPControlBits::RST, &[], Vec::new()));
+            }
+            self.state = TCPState::Established;
+        }
+        if config.keepalive && self.state != TCPState::TimeWait {
+            self.keepalive_probes = 0;
+            let idle = Duration::from_secs(config.keepalive_idle_secs);
+            self.set_timer(TCPTimer::Keepalive, Some(now + idle));
+        }
+        if seq_lt(self.snd_una, ack) && seq_le(ack, self.snd_nxt) {
+            self.acknowledge(ack, config, now);
+        } else if seq_lt(self.snd_nxt, ack) {
+            // Acknowledges something not yet sent
+            return Some(self.ack());
+        }
+        if self.ecn && control_bits.contains(TCPControlBits::ECE) && seq_lt(self.ecn_recover, ack) {
+            self.congestion_experienced();
+        }
+        self.snd_wnd = tcp.window;
+        if self.fin_sent && self.snd_una == self.snd_nxt {
+            self.state = match self.state {
+                TCPState::FinWait1 => TCPState::FinWait2,
+                TCPState::Closing => self.enter_time_wait(config, now),
+                TCPState::LastAck => TCPState::Closed,
+                state => state,
+            };
+        }
+        let segment_len = tcp.data.len() + control_bits.contains(TCPControlBits::FIN) as usize;
+        if tcp.sequence_number != self.rcv_nxt {
+            return (segment_len > 0).then(|| self.ack());
+        }
+        let mut should_ack = false;
+        if !tcp.data.is_empty() {
+            if !matches!(
+                self.state,
+                TCPState::Established | TCPState::FinWait1 | TCPState::FinWait2
+            ) {
+                // The peer already sent its FIN
+                return None;
+            }
+            let accepted = tcp
+                .data
+                .len()
+                .min(self.receive_buffer - self.receive_queue.len());
+            self.receive_queue.extend(&tcp.data[..accepted]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+            if accepted < tcp.data.len() {
+                // The FIN, if any, is beyond the window
+                return Some(self.ack());
+            }
+            should_ack = true;
+        }
+        if control_bits.contains(TCPControlBits::FIN) {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.state = match self.state {
+                TCPState::Established => TCPState::CloseWait,
+                TCPState::FinWait1 => TCPState::Closing,
+                TCPState::FinWait2 => self.enter_time_wait(config, now),
+                state => state,
+            };
+            return Some(self.ack());
+        }
+        if !should_ack {
+            return None;
+        }
+        // Every second segment is acknowledged right away, a single one once the delayed ACK
+        // timer fires (RFC 1122 4.2.3.2, RFC 5681 4.2)
+        let delay = Duration::from_millis(config.delayed_ack_ms);
+        if !delay.is_zero() && self.timer(TCPTimer::DelayedAck).is_none() {
+            self.set_timer(TCPTimer::DelayedAck, Some(now + delay));
+            return None;
+        }
+        Some(self.ack())
+    }
+    fn enter_time_wait(&mut self, config: &TCPConfig, now: Instant) -> TCPState {
+        self.timers = [None; 4];
+        let two_msl = Duration::from_secs(config.msl_secs) * 2;
+        self.set_timer(TCPTimer::TimeWait, Some(now + two_msl));
+        TCPState::TimeWait
+    }
+    /// Queues our FIN, which goes out after the queued data
+    fn close(&mut self, now: Instant) -> Vec<TCP> {
+        self.state = match self.state {
+            TCPState::SynReceived | TCPState::Established => TCPState::FinWait1,
+            TCPState::CloseWait => TCPState::LastAck,
+            _ => return Vec::new(),
+        };
+        self.fin_queued = true;
+        self.transmit(now)
+    }
+    /// Sends the queued data the send and congestion windows allow, then the FIN once everything
+    /// else is sent
+    fn transmit(&mut self, now: Instant) -> Vec<TCP> {
+        let mut segments = Vec::new();
+        if self.state == TCPState::SynReceived {
+            return segments;
+        }
+        let window = (self.snd_wnd as u32).min(self.cwnd) as usize;
+        loop {
+            let sent = self.data_in_flight();
+            let len = (self.send_queue.len() - sent)
+                .min(self.mss as usize)
+                .min(window.saturating_sub(sent));
+            if len == 0 {
+                break;
+            }
+            let data: Vec<u8> = self.send_queue.range(sent..sent + len).copied().collect();
+            let mut control_bits = match sent + len == self.send_queue.len() {
+                true => TCPControlBits::PSH | TCPControlBits::ACK,
+                false => TCPControlBits::ACK,
+            };
+            if std::mem::take(&mut self.cwr) {
+                control_bits |= TCPControlBits::CWR;
+            }
+            segments.push(self.segment(self.snd_nxt, control_bits, &[], data));
+            self.sent(len as u32, now);
+        }
+        if self.fin_queued && !self.fin_sent && self.data_in_flight() == self.send_queue.len() {
+            segments.push(self.segment(
+                self.snd_nxt,
+                TCPControlBits::FIN | TCPControlBits::ACK,
+                &[],
+                Vec::new(),
+            ));
+            self.sent(1, now);
+            self.fin_sent = true;
+        }
+        if !segments.is_empty() {
+            // The ACK went along with the data
+            self.set_timer(TCPTimer::DelayedAck, None);
+        }
+        segments
+    }
+    fn sent(&mut self, len: u32, now: Instant) {
+        if self.rtt_timed.is_none() && self.backoff == 0 {
+            self.rtt_timed = Some((self.snd_nxt.wrapping_add(len), now));
+        }
+        self.snd_nxt = self.snd_nxt.wrapping_add(len);
+        if self.timer(TCPTimer::Retransmit).is_none() {
+            self.set_timer(TCPTimer::Retransmit, Some(now + self.rto));
+        }
+    }
+    /// Handles a timer which fired, returning the segments to send
+    fn expire(&mut self, timer: TCPTimer, config: &TCPConfig, now: Instant) -> Vec<TCP> {
+        self.set_timer(timer, None);
+        match timer {
+            TCPTimer::Retransmit => self.retransmit(config, now).into_iter().collect(),
+            TCPTimer::DelayedAck => vec![self.ack()],
+            TCPTimer::Keepalive => self.keepalive(config, now).into_iter().collect(),
+            TCPTimer::TimeWait => {
+                self.state = TCPState::Closed;
+                Vec::new()
+            }
+        }
+    }
+    /// Resends the oldest unacknowledged segment (RFC 6298 5.4 to 5.7)
+    fn retransmit(&mut self, config: &TCPConfig, now: Instant) -> Option<TCP> {
+        if self.backoff >= config.max_retransmits {
+            debug!(local = %self.local, remote = %self.remote, "retransmission limit reached");
+            self.state = TCPState::Closed;
+            return None;
+        }
+        let segment = if self.state == TCPState::SynReceived {
+            self.syn_ack()
+        } else if self.data_in_flight() > 0 {
+            let len = self.data_in_flight().min(self.mss as usize);
+            let data = self.send_queue.range(..len).copied().collect();
+            self.segment(self.snd_una, TCPControlBits::ACK, &[], data)
+        } else if self.fin_sent && self.snd_una != self.snd_nxt {
+            self.segment(
+                self.snd_una,
+                TCPControlBits::FIN | TCPControlBits::ACK,
+                &[],
+                Vec::new(),
+            )
+        } else {
+            return None;
+        };
+        self.backoff += 1;
+        self.retransmits += 1;
+        // Karn's algorithm, retransmitted segments give no RTT samples (RFC 6298 3)
+        self.rtt_timed = None;
+        // Loss window (RFC 5681 3.1 equation 4)
+        let mss = self.mss as u32;
+        self.ssthresh = (self.in_flight() / 2).max(2 * mss);
+        self.cwnd = mss;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.set_timer(TCPTimer::Retransmit, Some(now + self.rto));
+        debug!(
+            local = %self.local,
+            remote = %self.remote,
+            sequence_number = segment.sequence_number,
+            backoff = self.backoff,
+            rto = ?self.rto,
+            "retransmit"
+        );
+        Some(segment)
+    }
+    /// Sends a probe the peer answers with an ACK, or gives up when too many went unanswered
+    fn keepalive(&mut self, config: &TCPConfig, now: Instant) -> Option<TCP> {
+        if !matches!(self.state, TCPState::Established | TCPState::CloseWait) {
+            return None;
+        }
+        if self.in_flight() > 0 {
+            // Not idle, the retransmission timer watches the peer
+            let idle = Duration::from_secs(config.keepalive_idle_secs);
+            self.set_timer(TCPTimer::Keepalive, Some(now + idle));
+            return None;
+        }
+        if self.keepalive_probes >= config.keepalive_probes {
+            debug!(local = %self.local, remote = %self.remote, "keepalive timed out");
+            self.state = TCPState::Closed;
+            return None;
+        }
+        self.keepalive_probes += 1;
+        let interval = Duration::from_secs(config.keepalive_interval_secs);
+        self.set_timer(TCPTimer::Keepalive, Some(now + interval));
+        // A sequence number the peer already acknowledged (RFC 1122 4.2.3.6)
+        Some(self.segment(
+            self.snd_nxt.wrapping_sub(1),
+            TCPControlBits::ACK,
+            &[],
+            Vec::new(),
+        ))
+    }
+}
+
+/// A socket in the LISTEN state
+#[derive(Debug)]
+pub struct Listener {
+    pub local: SocketAddr,
+    /// Connections which are being or have been established, waiting to be accepted
+    pub backlog: usize,
+    pub accept_queue: VecDeque<ConnectionKey>,
+    /// Tasks waiting for a connection to accept
+    wakers: Vec<Waker>,
+}
+
+/// Secret hashed into the initial sequence numbers, random for each table
+#[derive(Debug)]
+struct ISSSecret(u64);
+impl Default for ISSSecret {
+    fn default() -> Self {
+        Self(RandomState::new().hash_one(0))
+    }
+}
+
+/// Every TCP connection and listening socket of the stack
+#[derive(Debug, Default)]
+pub struct ConnectionTable {
+    config: TCPConfig,
+    listeners: HashMap<SocketAddr, Listener>,
+    connections: HashMap<ConnectionKey, TCB>,
+    /// Timers of every connection, checked against the TCB when they fire
+    timers: TimerWheel<(ConnectionKey, TCPTimer, Instant)>,
+    /// Connections with data or a FIN waiting to be sent
+    pending: BTreeSet<ConnectionKey>,
+    /// Retransmissions of all connections so far
+    retransmits: u64,
+    /// Where passive opens are counted, the stack shares its own
+    stats: Arc<Stats>,
+    /// Secret of the initial sequence numbers and when their clock started
+    iss_secret: ISSSecret,
+    iss_epoch: Option<Instant>,
+}
+impl ConnectionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_config(config: TCPConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+    pub fn config(&self) -> &TCPConfig {
+        &self.config
+    }
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.stats = stats;
+    }
+    /// Accepts connections to `local`, which may have an unspecified address to listen on all of them
+    pub fn listen(&mut self, local: SocketAddr, backlog: usize) {
+        self.listeners.insert(
+            local,
+            Listener {
+                local,
+                backlog,
+                accept_queue: VecDeque::new(),
+                wakers: Vec::new(),
+            },
+        );
+    }
+    /// Stops listening, returning the connections which were never accepted
+    pub fn unlisten(&mut self, local: SocketAddr) -> Vec<ConnectionKey> {
+        let Some(listener) = self.listeners.remove(&local) else {
+            return Vec::new();
+        };
+        listener.wakers.into_iter().for_each(Waker::wake);
+        let mut orphans: Vec<ConnectionKey> = listener.accept_queue.into();
+        for tcb in self.connections.values_mut() {
+            if tcb.listener == Some(local) {
+                tcb.listener = None;
+                if tcb.state == TCPState::SynReceived {
+                    orphans.push(tcb.key());
+                }
+            }
+        }
+        orphans
+    }
+    /// Takes the oldest established connection of the listener
+    pub fn accept(&mut self, local: SocketAddr) -> Option<ConnectionKey> {
+        self.listeners.get_mut(&local)?.accept_queue.pop_front()
+    }
+    /// Takes everything received on the connection so far
+    pub fn read(&mut self, key: ConnectionKey) -> Vec<u8> {
+        self.connections
+            .get_mut(&key)
+            .map(|tcb| tcb.receive_queue.drain(..).collect())
+            .unwrap_or_default()
+    }
+    /// Moves as much of the received data as fits into `buf`
+    pub fn read_into(&mut self, key: ConnectionKey, buf: &mut [u8]) -> usize {
+        let Some(tcb) = self.connections.get_mut(&key) else {
+            return 0;
+        };
+        let len = buf.len().min(tcb.receive_queue.len());
+        for (byte, received) in buf.iter_mut().zip(tcb.receive_queue.drain(..len)) {
+            *byte = received;
+        }
+        len
+    }
+    /// Wakes `waker` the next time the connection changes, data or a FIN arrives, data is
+    /// acknowledged or the connection goes away
+    pub fn wake_on_change(&mut self, key: ConnectionKey, waker: &Waker) {
+        match self.connections.get_mut(&key) {
+            Some(tcb) if !tcb.wakers.iter().any(|other| other.will_wake(waker)) => {
+                tcb.wakers.push(waker.clone())
+            }
+            Some(_) => {}
+            None => waker.wake_by_ref(),
+        }
+    }
+    /// Wakes `waker` once the listener has a connection to accept
+    pub fn wake_on_accept(&mut self, local: SocketAddr, waker: &Waker) {
+        match self.listeners.get_mut(&local) {
+            Some(listener) if !listener.wakers.iter().any(|other| other.will_wake(waker)) => {
+                listener.wakers.push(waker.clone())
+            }
+            Some(_) => {}
+            None => waker.wake_by_ref(),
+        }
+    }
+    /// Queues data to send on the next poll, returning how much fits in the send buffer
+    pub fn write(&mut self, key: ConnectionKey, data: &[u8]) -> usize {
+        let Some(tcb) = self.connections.get_mut(&key) else {
+            return 0;
+        };
+        if !matches!(tcb.state, TCPState::Established | TCPState::CloseWait) || tcb.fin_queued {
+            return 0;
+        }
+        let len = data
+            .len()
+            .min(self.config.send_buffer.saturating_sub(tcb.send_queue.len()));
+        tcb.send_queue.extend(&data[..len]);
+        self.pending.insert(key);
+        len
+    }
+    /// Starts closing the connection, returning the segments to send and the ECN codepoint of
+    /// their packets, the FIN unless data is still waiting for the windows
+    pub fn close(&mut self, key: ConnectionKey, now: Instant) -> Vec<(TCP, ECN)> {
+        let Some(tcb) = self.connections.get_mut(&key) else {
+            return Vec::new();
+        };
+        let previous = tcb.state;
+        let segments = tcb.close(now);
+        let segments = segments
+            .into_iter()
+            .map(|tcp| {
+                let ecn = tcb.ecn_codepoint(&tcp);
+                (tcp, ecn)
+            })
+            .collect();
+        debug!(local = %key.0, remote = %key.1, "connection closing");
+        self.update(key, previous, now);
+        segments
+    }
+    pub fn get(&self, key: ConnectionKey) -> Option<&TCB> {
+        self.connections.get(&key)
+    }
+    /// Connections sorted by address
+    pub fn connections(&self) -> Vec<&TCB> {
+        let mut connections: Vec<&TCB> = self.connections.values().collect();
+        connections.sort_by_key(|tcb| tcb.key());
+        connections
+    }
+    /// Listening sockets sorted by address
+    pub fn listeners(&self) -> Vec<&Listener> {
+        let mut listeners: Vec<&Listener> = self.listeners.values().collect();
+        listeners.sort_by_key(|listener| listener.local);
+        listeners
+    }
+    pub fn retransmits(&self) -> u64 {
+        self.retransmits
+    }
+    /// When the next timer is due
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+    /// Fires the timers which are due and sends the queued data the windows allow, returning the
+    /// segments to send, their connections and the ECN codepoint of their packets
+    pub fn poll(&mut self, now: Instant) -> Vec<(ConnectionKey, TCP, ECN)> {
+        let mut segments = Vec::new();
+        for (key, timer, deadline) in self.timers.advance(now) {
+            let Some(tcb) = self.connections.get_mut(&key) else {
+                continue;
+            };
+            if tcb.scheduled[timer as usize] == Some(deadline) {
+                tcb.scheduled[timer as usize] = None;
+            }
+            // Stopped or restarted since
+            if tcb.timer(timer) != Some(deadline) {
+                continue;
+            }
+            let (previous, retransmits) = (tcb.state, tcb.retransmits);
+            let expired = tcb.expire(timer, &self.config, now);
+            segments.extend(expired.into_iter().map(|tcp| (key, tcp, ECN::NotECT)));
+            self.retransmits += (tcb.retransmits - retransmits) as u64;
+            self.update(key, previous, now);
+        }
+        for key in std::mem::take(&mut self.pending) {
+            if let Some(tcb) = self.connections.get_mut(&key) {
+                let previous = tcb.state;
+                for tcp in tcb.transmit(now) {
+                    let ecn = tcb.ecn_codepoint(&tcp);
+                    segments.push((key, tcp, ecn));
+                }
+                self.update(key, previous, now);
+            }
+        }
+        segments
+    }
+    /// Bookkeeping after the TCB of `key` changed, its timers, queues and state
+    fn update(&mut self, key: ConnectionKey, previous: TCPState, now: Instant) {
+        let Some(tcb) = self.connections.get_mut(&key) else {
+            return;
+        };
+        let (local, remote) = key;
+        let state = tcb.state;
+        std::mem::take(&mut tcb.wakers)
+            .into_iter()
+            .for_each(Waker::wake);
+        if state != previous {
+            debug!(%local, %remote, from = %previous, to = %state, "TCP state transition");
+        }
+        if state == TCPState::Closed {
+            self.connections.remove(&key);
+            self.pending.remove(&key);
+            return;
+        }
+        if previous == TCPState::SynReceived && state != TCPState::SynReceived {
+            if let Some(listener) = tcb
+                .listener
+                .and_then(|local| self.listeners.get_mut(&local))
+            {
+                listener.accept_queue.push_back(key);
+                listener.wakers.drain(..).for_each(Waker::wake);
+            }
+        }
+        for timer in TCPTimer::ALL {
+            let Some(deadline) = tcb.timer(timer) else {
+                continue;
+            };
+            if tcb.scheduled[timer as usize] != Some(deadline) {
+                tcb.scheduled[timer as usize] = Some(deadline);
+                self.timers.insert(now, deadline, (key, timer, deadline));
+            }
+        }
+        if tcb.has_pending() {
+            self.pending.insert(key);
+        }
+    }
+    /// Listener for connections to `local`, preferring one bound to the exact address
+    fn listener_for(&self, local: SocketAddr) -> Option<SocketAddr> {
+        let unspecified = match local.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        [local, SocketAddr::new(unspecified, local.port())]
+            .into_iter()
+            .find(|local| self.listeners.contains_key(local))
+    }
+    /// Handles a segment addressed to us, `ecn` being the codepoint of the packet it came in
+    /// and `mtu` the MTU of the interface it came in through, returning the segment to answer with
+    pub fn segment(
+        &mut self,
+        source_addr: IpAddr,
+        destination_addr: IpAddr,
+        tcp: &TCP,
+        ecn: ECN,
+        mtu: usize,
+        now: Instant,
+    ) -> Option<TCP> {
+        let local = SocketAddr::new(destination_addr, tcp.destination_port);
+        let remote = SocketAddr::new(source_addr, tcp.source_port);
+        if let Some(tcb) = self.connections.get_mut(&(local, remote)) {
+            let previous = tcb.state;
+            let reply = tcb.receive(tcp, ecn, &self.config, now);
+            self.update((local, remote), previous, now);
+            return reply;
+        }
+        if tcp.control_bits.contains(TCPControlBits::RST) {
+            return None;
+        }
+        if tcp.control_bits.is_syn_only() {
+            if let Some(listener) = self.listener_for(local) {
+                return self.passive_open(local, remote, listener, tcp, mtu, now);
+            }
+        }
+        Self::reset(local, remote, tcp)
+    }
+    fn passive_open(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        listener: SocketAddr,
+        syn: &TCP,
+        mtu: usize,
+        now: Instant,
+    ) -> Option<TCP> {
+        let half_open = self
+            .connections
+            .values()
+            .filter(|tcb| tcb.listener == Some(listener) && tcb.state == TCPState::SynReceived)
+            .count();
+        let listener_state = &self.listeners[&listener];
+        if half_open + listener_state.accept_queue.len() >= listener_state.backlog {
+            // The peer retransmits its SYN later, like Linux with a full queue
+            debug!(%listener, "listen queue full");
+            return None;
+        }
+        let iss = self.iss((local, remote), now);
+        let tcb = TCB::new(local, remote, listener, iss, syn, mtu, &self.config, now);
+        debug!(%local, %remote, "TCP passive open");
+        self.stats.tcp.passive_opens.increment();
+        let syn_ack = tcb.syn_ack();
+        self.connections.insert((local, remote), tcb);
+        self.update((local, remote), TCPState::SynReceived, now);
+        Some(syn_ack)
+    }
+    /// Replaces the random secret of the initial sequence numbers, so replays give the same output
+    pub fn set_iss_secret(&mut self, secret: u64) {
+        self.iss_secret = ISSSecret(secret);
+    }
+    /// Clock plus a hash of the connection and a secret, so the peer cannot guess it (RFC 6528 3)
+    fn iss(&mut self, key: ConnectionKey, now: Instant) -> u32 {
+        let epoch = *self.iss_epoch.get_or_insert(now);
+        let clock = ((now - epoch).as_micros() / ISS_TICK.as_micros()) as u32;
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.iss_secret.0.hash(&mut hasher);
+        clock.wrapping_add(hasher.finish() as u32)
+    }
+    /// Answer to a segment for no connection (RFC 793 3.4)
+    fn reset(local: SocketAddr, remote: SocketAddr, tcp: &TCP) -> Option<TCP> {
+        let (sequence_number, acknowledgment_number, control_bits) =
+            if tcp.control_bits.contains(TCPControlBits::ACK) {
+                (tcp.acknowledgment_number, 0, TCPControlBits::RST)
+            } else {
+                let segment_len = tcp.data.len() as u32 + tcp.control_bits.sequence_len();
+                (
+                    0,
+                    tcp.sequence_number.wrapping_add(segment_len),
+                    TCPControlBits::RST | TCPControlBits::ACK,
+                )
+            };
+        TCP::builder(local.port(), remote.port())
+            .sequence_number(sequence_number)
+            .acknowledgment_number(acknowledgment_number)
+            .control_bits(control_bits)
+            .build(&local.ip(), &remote.ip())
+            .ok()
+    }
+}
+
+impl fmt::Display for ConnectionTable {
+    /// Laid out like `ss -tani`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<11}{:<7}{:<7}{:<40}Peer Address:Port",
+            "State", "Recv-Q", "Send-Q", "Local Address:Port"
+        )?;
+        for listener in self.listeners() {
+            writeln!(
+                f,
+                "{:<11}{:<7}{:<7}{:<40}*:*",
+                "LISTEN",
+                listener.accept_queue.len(),
+                listener.backlog,
+                listener.local.to_string(),
+            )?;
+        }
+        for tcb in self.connections() {
+            writeln!(
+                f,
+                "{:<11}{:<7}{:<7}{:<40}{}",
+                tcb.state,
+                tcb.receive_queue.len(),
+                tcb.send_queue.len(),
+                tcb.local.to_string(),
+                tcb.remote
+            )?;
+            write!(f, "\t")?;
+            if tcb.ecn {
+                write!(f, " ecn")?;
+            }
+            write!(f, " rto:{}", tcb.rto.as_millis())?;
+            if let Some(srtt) = tcb.srtt {
+                write!(
+                    f,
+                    " rtt:{:.3}/{:.3}",
+                    srtt.as_secs_f64() * 1000.0,
+                    tcb.rttvar.as_secs_f64() * 1000.0
+                )?;
+            }
+            let mss = (tcb.mss as u32).max(1);
+            write!(f, " mss:{} cwnd:{}", tcb.mss, tcb.cwnd / mss)?;
+            if tcb.ssthresh != u32::MAX {
+                write!(f, " ssthresh:{}", tcb.ssthresh / mss)?;
+            }
+            writeln!(
+                f,
+                " snd_wnd:{} retrans:{}/{}",
+                tcb.snd_wnd, tcb.backoff, tcb.retransmits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEFAULT_MTU;
+
+    const CLIENT: &str = "192.168.0.1:48458";
+    const SERVER: &str = "192.168.0.2:80";
+
+    fn segment(sequence_number: u32, ack: u32, control_bits: TCPControlBits, data: &[u8]) -> TCP {
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let server: SocketAddr = SERVER.parse().unwrap();
+        TCP::builder(client.port(), server.port())
+            .sequence_number(sequence_number)
+            .acknowledgment_number(ack)
+            .control_bits(control_bits)
+            .window(64240)
+            .option(TCPOption::MSS(1460))
+            .data(data.to_vec())
+            .build(&client.ip(), &server.ip())
+            .unwrap()
+    }
+
+    fn send(table: &mut ConnectionTable, tcp: &TCP, now: Instant) -> Option<TCP> {
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let server: SocketAddr = SERVER.parse().unwrap();
+        table.segment(client.ip(), server.ip(), tcp, ECN::NotECT, DEFAULT_MTU, now)
+    }
+
+    #[test]
+    fn passive_open_and_close() {
+        let key = (SERVER.parse().unwrap(), CLIENT.parse().unwrap());
+        let mut table = ConnectionTable::new();
+        table.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let now = Instant::now();
+
+        let syn_ack = send(&mut table, &segment(1000, 0, TCPControlBits::SYN, &[]), now).unwrap();
+        assert_eq!(
+            syn_ack.control_bits,
+            TCPControlBits::SYN | TCPControlBits::ACK
+        );
+        assert_eq!(syn_ack.acknowledgment_number, 1001);
+        assert_eq!(table.get(key).unwrap().state, TCPState::SynReceived);
+        assert_eq!(table.accept("0.0.0.0:80".parse().unwrap()), None);
+
+        let later = now + Duration::from_millis(100);
+        let iss = syn_ack.sequence_number;
+        assert!(send(
+            &mut table,
+            &segment(1001, iss.wrapping_add(1), TCPControlBits::ACK, &[]),
+            later
+        )
+        .is_none());
+        let tcb = table.get(key).unwrap();
+        assert_eq!(tcb.state, TCPState::Established);
+        assert_eq!(tcb.srtt, Some(Duration::from_millis(100)));
+        assert_eq!(tcb.rto, MIN_RTO);
+        assert_eq!(table.accept("0.0.0.0:80".parse().unwrap()), Some(key));
+
+        // The ACK is delayed
+        assert!(send(
+            &mut table,
+            &segment(1001, iss.wrapping_add(1), TCPControlBits::ACK, b"hello"),
+            later
+        )
+        .is_none());
+        assert!(table
+            .get(key)
+            .unwrap()
+            .timer(TCPTimer::DelayedAck)
+            .is_some());
+        // Retransmission of data we already have
+        let ack = send(
+            &mut table,
+            &segment(1001, iss.wrapping_add(1), TCPControlBits::ACK, b"hello"),
+            later,
+        )
+        .unwrap();
+        assert_eq!(ack.acknowledgment_number, 1006);
+        assert!(table
+            .get(key)
+            .unwrap()
+            .timer(TCPTimer::DelayedAck)
+            .is_none());
+        assert_eq!(table.read(key), b"hello");
+
+        let ack = send(
+            &mut table,
+            &segment(
+                1006,
+                iss.wrapping_add(1),
+                TCPControlBits::FIN | TCPControlBits::ACK,
+                &[],
+            ),
+            later,
+        )
+        .unwrap();
+        assert_eq!(ack.acknowledgment_number, 1007);
+        assert_eq!(table.get(key).unwrap().state, TCPState::CloseWait);
+        let [fin] = &table.close(key, later)[..] else {
+            panic!("Nothing to send but the FIN");
+        };
+        assert_eq!(
+            fin.0.control_bits,
+            TCPControlBits::FIN | TCPControlBits::ACK
+        );
+        assert!(send(
+            &mut table,
+            &segment(1007, iss.wrapping_add(2), TCPControlBits::ACK, &[]),
+            later
+        )
+        .is_none());
+        assert!(table.get(key).is_none());
+    }
+
+    #[test]
+    fn reset_without_listener() {
+        let mut table = ConnectionTable::new();
+        let now = Instant::now();
+        let reset = send(&mut table, &segment(1000, 0, TCPControlBits::SYN, &[]), now).unwrap();
+        assert_eq!(
+            reset.control_bits,
+            TCPControlBits::RST | TCPControlBits::ACK
+        );
+        assert_eq!(
+            (reset.sequence_number, reset.acknowledgment_number),
+            (0, 1001)
+        );
+        let reset = send(
+            &mut table,
+            &segment(1000, 555, TCPControlBits::ACK, &[]),
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            (reset.control_bits, reset.sequence_number),
+            (TCPControlBits::RST, 555)
+        );
+        assert!(send(&mut table, &segment(1000, 0, TCPControlBits::RST, &[]), now).is_none());
+    }
+
+    #[test]
+    fn timers() {
+        let key = (SERVER.parse().unwrap(), CLIENT.parse().unwrap());
+        let mut table = ConnectionTable::with_config(TCPConfig {
+            keepalive: true,
+            keepalive_idle_secs: 10,
+            keepalive_interval_secs: 1,
+            keepalive_probes: 2,
+            ..TCPConfig::default()
+        });
+        table.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let now = Instant::now();
+        let iss = send(&mut table, &segment(1000, 0, TCPControlBits::SYN, &[]), now)
+            .unwrap()
+            .sequence_number;
+        send(
+            &mut table,
+            &segment(1001, iss.wrapping_add(1), TCPControlBits::ACK, &[]),
+            now,
+        );
+
+        assert_eq!(table.write(key, &[0xab; 3000]), 3000);
+        let segments = table.poll(now);
+        let lengths: Vec<usize> = segments.iter().map(|(_, tcp, _)| tcp.data.len()).collect();
+        assert_eq!(lengths, [1460, 1460, 80]);
+        assert_eq!(
+            segments[2].1.control_bits,
+            TCPControlBits::PSH | TCPControlBits::ACK
+        );
+
+        // Only the oldest segment is retransmitted, with the loss window and a doubled RTO
+        assert!(table.poll(now + Duration::from_millis(999)).is_empty());
+        let retransmitted = table.poll(now + MIN_RTO);
+        assert_eq!(retransmitted.len(), 1);
+        assert_eq!(retransmitted[0].1.sequence_number, iss.wrapping_add(1));
+        let tcb = table.get(key).unwrap();
+        assert_eq!((tcb.cwnd, tcb.rto, tcb.retransmits), (1460, MIN_RTO * 2, 1));
+        assert_eq!(table.retransmits(), 1);
+
+        let later = now + Duration::from_millis(1500);
+        send(
+            &mut table,
+            &segment(1001, iss.wrapping_add(3001), TCPControlBits::ACK, &[]),
+            later,
+        );
+        let tcb = table.get(key).unwrap();
+        assert_eq!((tcb.in_flight(), tcb.backoff), (0, 0));
+        assert!(tcb.timer(TCPTimer::Retransmit).is_none());
+
+        assert!(send(
+            &mut table,
+            &segment(1001, iss.wrapping_add(3001), TCPControlBits::ACK, b"hi"),
+            later
+        )
+        .is_none());
+        let delayed = table.poll(later + DELAYED_ACK);
+        assert_eq!(delayed[0].1.acknowledgment_number, 1003);
+
+        // Two unanswered probes, then the connection is given up
+        let probe = table.poll(later + Duration::from_secs(10));
+        assert_eq!(probe[0].1.sequence_number, iss.wrapping_add(3000));
+        assert_eq!(table.poll(later + Duration::from_secs(11)).len(), 1);
+        assert!(table.poll(later + Duration::from_secs(12)).is_empty());
+        assert!(table.get(key).is_none());
+    }
+
+    #[test]
+    fn ecn() {
+        let key: ConnectionKey = (SERVER.parse().unwrap(), CLIENT.parse().unwrap());
+        let (client, server) = (key.1.ip(), key.0.ip());
+        let mut table = ConnectionTable::new();
+        table.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let now = Instant::now();
+        let syn_ack = send(
+            &mut table,
+            &segment(
+                1000,
+                0,
+                TCPControlBits::SYN | TCPControlBits::ECE | TCPControlBits::CWR,
+                &[],
+            ),
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            syn_ack.control_bits,
+            TCPControlBits::SYN | TCPControlBits::ACK | TCPControlBits::ECE
+        );
+        let iss = syn_ack.sequence_number;
+        send(
+            &mut table,
+            &segment(1001, iss.wrapping_add(1), TCPControlBits::ACK, &[]),
+            now,
+        );
+        assert!(table.get(key).unwrap().ecn);
+
+        // CE marks are echoed until the peer reduces its window
+        let data = segment(
+            1001,
+            iss.wrapping_add(1),
+            TCPControlBits::PSH | TCPControlBits::ACK,
+            b"hi",
+        );
+        assert!(table
+            .segment(client, server, &data, ECN::CE, DEFAULT_MTU, now)
+            .is_none());
+        let later = now + DELAYED_ACK;
+        let [(_, ack, ecn)] = &table.poll(later)[..] else {
+            panic!("Only the delayed ACK");
+        };
+        assert_eq!(
+            (ack.control_bits, *ecn),
+            (TCPControlBits::ACK | TCPControlBits::ECE, ECN::NotECT)
+        );
+        send(
+            &mut table,
+            &segment(
+                1003,
+                iss.wrapping_add(1),
+                TCPControlBits::CWR | TCPControlBits::ACK,
+                b"!",
+            ),
+            later,
+        );
+        assert!(!table.get(key).unwrap().ece);
+
+        // New data is ECN capable, an ECE halves the window once and the next data carries CWR
+        table.write(key, &[0xab; 3000]);
+        let segments = table.poll(later);
+        assert!(segments.iter().all(|(_, _, ecn)| *ecn == ECN::ECT0));
+        send(
+            &mut table,
+            &segment(
+                1004,
+                iss.wrapping_add(1461),
+                TCPControlBits::ECE | TCPControlBits::ACK,
+                &[],
+            ),
+            later,
+        );
+        let tcb = table.get(key).unwrap();
+        assert_eq!((tcb.cwnd, tcb.ssthresh), (8030, 8030));
+        send(
+            &mut table,
+            &segment(
+                1004,
+                iss.wrapping_add(2921),
+                TCPControlBits::ECE | TCPControlBits::ACK,
+                &[],
+            ),
+            later,
+        );
+        assert_eq!(table.get(key).unwrap().ssthresh, 8030);
+        table.write(key, b"more");
+        let [(_, data, ECN::ECT0)] = &table.poll(later)[..] else {
+            panic!("The new data as ECT(0)");
+        };
+        assert_eq!(
+            data.control_bits,
+            TCPControlBits::CWR | TCPControlBits::PSH | TCPControlBits::ACK
+        );
+
+        // Not negotiated when turned off
+        let mut table = ConnectionTable::with_config(TCPConfig {
+            ecn: false,
+            ..TCPConfig::default()
+        });
+        table.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let syn_ack = send(
+            &mut table,
+            &segment(
+                1000,
+                0,
+                TCPControlBits::SYN | TCPControlBits::ECE | TCPControlBits::CWR,
+                &[],
+            ),
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            syn_ack.control_bits,
+            TCPControlBits::SYN | TCPControlBits::ACK
+        );
+    }
+
+    #[test]
+    fn zero_mss_is_clamped() {
+        let key = (SERVER.parse().unwrap(), CLIENT.parse().unwrap());
+        let mut table = ConnectionTable::with_config(TCPConfig {
+            initial_window: 0,
+            ..TCPConfig::default()
+        });
+        table.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let server: SocketAddr = SERVER.parse().unwrap();
+        let syn = TCP::builder(client.port(), server.port())
+            .sequence_number(1000)
+            .control_bits(TCPControlBits::SYN)
+            .window(64240)
+            .option(TCPOption::MSS(0))
+            .build(&client.ip(), &server.ip())
+            .unwrap();
+        let now = Instant::now();
+        send(&mut table, &syn, now).unwrap();
+        let tcb = table.get(key).unwrap();
+        assert_eq!((tcb.mss, tcb.cwnd), (1, 1));
+        // The SYN-ACK times out, the loss window is still one segment
+        assert_eq!(table.poll(now + INITIAL_RTO).len(), 1);
+        assert_eq!(table.get(key).unwrap().cwnd, 1);
+        assert!(table.to_string().contains("mss:1 cwnd:1"));
+    }
+
+    #[test]
+    fn small_peer_mss_is_kept() {
+        let key = (SERVER.parse().unwrap(), CLIENT.parse().unwrap());
+        let mut table = ConnectionTable::new();
+        table.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let server: SocketAddr = SERVER.parse().unwrap();
+        let syn = TCP::builder(client.port(), server.port())
+            .sequence_number(1000)
+            .control_bits(TCPControlBits::SYN)
+            .window(64240)
+            .option(TCPOption::MSS(500))
+            .build(&client.ip(), &server.ip())
+            .unwrap();
+        let now = Instant::now();
+        let iss = send(&mut table, &syn, now).unwrap().sequence_number;
+        let ack = segment(1001, iss.wrapping_add(1), TCPControlBits::ACK, &[]);
+        send(&mut table, &ack, now);
+        assert_eq!(table.get(key).unwrap().mss, 500);
+        table.write(key, &[0x0; 2000]);
+        let segments = table.poll(now);
+        assert_eq!(segments.len(), 4);
+        assert!(segments.iter().all(|(_, tcp, _)| tcp.data.len() <= 500));
+    }
+
+    #[test]
+    fn mss_from_interface_mtu() {
+        let key = (SERVER.parse().unwrap(), CLIENT.parse().unwrap());
+        let mut table = ConnectionTable::new();
+        table.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let server: SocketAddr = SERVER.parse().unwrap();
+        let syn = segment(1000, 0, TCPControlBits::SYN, &[]);
+        let now = Instant::now();
+        let syn_ack = table
+            .segment(client.ip(), server.ip(), &syn, ECN::NotECT, 1280, now)
+            .unwrap();
+        assert_eq!(mss_option(syn_ack.options.as_deref().unwrap()), Some(1240));
+        assert_eq!(table.get(key).unwrap().mss, 1240);
+    }
+
+    #[test]
+    fn iss_is_keyed() {
+        let now = Instant::now();
+        let iss = |table: &mut ConnectionTable| {
+            table.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+            send(table, &segment(1000, 0, TCPControlBits::SYN, &[]), now)
+                .unwrap()
+                .sequence_number
+        };
+        // Another secret for the same connection at the same time
+        assert_ne!(
+            iss(&mut ConnectionTable::new()),
+            iss(&mut ConnectionTable::new())
+        );
+    }
+
+    #[test]
+    fn display() {
+        let mut table = ConnectionTable::new();
+        table.listen("0.0.0.0:80".parse().unwrap(), 2);
+        let now = Instant::now();
+        send(&mut table, &segment(1000, 0, TCPControlBits::SYN, &[]), now).unwrap();
+        assert_eq!(
+            table.to_string(),
+            "State      Recv-Q Send-Q Local Address:Port                      Peer Address:Port\n\
+             LISTEN     0      2      0.0.0.0:80                              *:*\n\
+             SYN-RECV   0      0      192.168.0.2:80                          192.168.0.1:48458\n\
+             \t rto:1000 mss:1460 cwnd:10 snd_wnd:64240 retrans:0/0\n"
+        );
+    }
+}
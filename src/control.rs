@@ -0,0 +1,112 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use tracing::warn;
+
+use crate::{connection::ConnectionTable, qdisc::Egress, stats::Stats};
+
+/// What the control socket can look at while the stack runs
+#[derive(Clone)]
+pub struct Control {
+    pub connections: Arc<Mutex<ConnectionTable>>,
+    pub egress: Arc<Mutex<Egress>>,
+    pub stats: Arc<Stats>,
+}
+impl Control {
+    pub fn new(
+        connections: Arc<Mutex<ConnectionTable>>,
+        egress: Arc<Mutex<Egress>>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Self {
+            connections,
+            egress,
+            stats,
+        }
+    }
+    /// Answers one command line
+    pub fn execute(&self, command: &str) -> String {
+        match command.trim() {
+            "ss" => self
+                .connections
+                .lock()
+                .expect("Connection table lock is not poisoned")
+                .to_string(),
+            "stats" => self.stats.to_string(),
+            "qdisc" => self
+                .egress
+                .lock()
+                .expect("Egress lock is not poisoned")
+                .to_string(),
+            "help" | "" => "Commands: ss, stats, qdisc, help\n".to_string(),
+            command => format!("Unknown command {}, try help\n", command),
+        }
+    }
+    /// Serves commands on the Unix socket at `path` from a thread, one command per client
+    pub fn spawn(self, path: &Path) -> io::Result<JoinHandle<()>> {
+        // A socket left over from an earlier run
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(error) = stream.and_then(|stream| self.serve(stream)) {
+                    warn!(%error, "control socket client failed");
+                }
+            }
+        }))
+    }
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut command = String::new();
+        BufReader::new(&stream).read_line(&mut command)?;
+        (&stream).write_all(self.execute(&command).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, io::Read, process};
+
+    use super::*;
+    use crate::{
+        connection::DEFAULT_BACKLOG,
+        qdisc::{Fifo, DEFAULT_LIMIT},
+    };
+
+    #[test]
+    fn command_socket() {
+        let connections = Arc::new(Mutex::new(ConnectionTable::new()));
+        connections
+            .lock()
+            .unwrap()
+            .listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let mut egress = Egress::new();
+        egress.attach("tun0", Box::new(Fifo::new(DEFAULT_LIMIT)));
+        let control = Control::new(
+            connections,
+            Arc::new(Mutex::new(egress)),
+            Arc::new(Stats::new()),
+        );
+        assert!(control.execute("stats\n").starts_with("Ip:\n"));
+        assert!(control.execute("qdisc").starts_with("tun0: backlog 0p\n"));
+        assert!(control
+            .execute("bogus")
+            .starts_with("Unknown command bogus"));
+
+        let path = env::temp_dir().join(format!("nust-control-{}.sock", process::id()));
+        control.spawn(&path).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"ss\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.lines().nth(1).unwrap().starts_with("LISTEN"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+pub mod ifconfig;
+
+use std::{
+    io::{self, IoSlice},
+    os::fd::{AsRawFd, RawFd},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use tun_tap::{Iface, Mode};
+
+use crate::ip::mark_ce;
+
+pub use ifconfig::configure_interface;
+
+/// Ethernet MTU, used when the device does not tell us otherwise
+pub const DEFAULT_MTU: usize = 1500;
+/// Size of the packet information header TUN devices put in front of each packet
+pub const PACKET_INFO_LEN: usize = 4;
+
+/// Something packets can be received from and sent to
+/// Frames include the 4 byte packet information header (flags and EtherType) in front of the IP packet
+pub trait NetDevice {
+    fn name(&self) -> &str;
+    fn mtu(&self) -> usize;
+    /// Blocks until a frame arrives, returning the number of bytes written into `buf`
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize>;
+    /// Sends one frame gathered from `bufs`, so headers kept apart do not have to be copied in
+    /// front of the packet, concatenates them unless the device can write them as they are
+    fn send_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let frame: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        self.send(&frame)
+    }
+    /// Writes out what the device buffered, e.g. the frames of a capture
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Descriptor which polls readable when a frame is waiting, None for devices which are not
+    /// backed by one and always have to be tried
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl<D: NetDevice + ?Sized> NetDevice for Box<D> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+    fn mtu(&self) -> usize {
+        (**self).mtu()
+    }
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).recv(buf)
+    }
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        (**self).send(frame)
+    }
+    fn send_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        (**self).send_vectored(bufs)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+}
+
+/// Packet information header for the IP version of the packet, no flags and the EtherType
+pub fn packet_info(ip_packet: &[u8]) -> [u8; PACKET_INFO_LEN] {
+    let ether_type: u16 = match ip_packet.first().map(|byte| byte >> 4) {
+        Some(6) => 0x86DD,
+        _ => 0x0800,
+    };
+    let [high, low] = ether_type.to_be_bytes();
+    [0x0, 0x0, high, low]
+}
+
+/// Prepends the packet information header for the IP version of the packet
+pub fn frame_ip_packet(ip_packet: &[u8]) -> Vec<u8> {
+    [&packet_info(ip_packet), ip_packet].concat()
+}
+
+pub struct TunDevice {
+    iface: Iface,
+    mtu: usize,
+}
+impl TunDevice {
+    pub fn new(name: &str, mtu: usize) -> io::Result<Self> {
+        Ok(Self {
+            iface: Iface::new(name, Mode::Tun)?,
+            mtu,
+        })
+    }
+    /// `recv` returns `WouldBlock` instead of blocking when no frame is waiting
+    pub fn set_nonblocking(&self) -> io::Result<()> {
+        self.iface.set_non_blocking()
+    }
+}
+impl NetDevice for TunDevice {
+    fn name(&self) -> &str {
+        self.iface.name()
+    }
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.iface.recv(buf)
+    }
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        self.iface.send(frame)
+    }
+    fn send_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec and bufs outlives the call
+        let len = unsafe {
+            libc::writev(
+                self.iface.as_raw_fd(),
+                bufs.as_ptr().cast(),
+                bufs.len() as libc::c_int,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.iface.as_raw_fd())
+    }
+}
+
+/// In memory point to point link, for building virtual topologies in tests
+/// `recv` returns `WouldBlock` instead of blocking when no frame is waiting
+pub struct VirtualDevice {
+    name: String,
+    mtu: usize,
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    /// Marks the ECN capable packets sent from this end as congestion experienced
+    mark_ce: bool,
+}
+impl VirtualDevice {
+    /// Creates both ends of a link, frames sent on one end are received on the other
+    pub fn pair(name: &str, peer_name: &str, mtu: usize) -> (Self, Self) {
+        let (sender, peer_receiver) = channel();
+        let (peer_sender, receiver) = channel();
+        (
+            Self {
+                name: name.to_string(),
+                mtu,
+                sender,
+                receiver,
+                mark_ce: false,
+            },
+            Self {
+                name: peer_name.to_string(),
+                mtu,
+                sender: peer_sender,
+                receiver: peer_receiver,
+                mark_ce: false,
+            },
+        )
+    }
+    /// Marks the ECN capable packets sent from this end as congestion experienced, like a
+    /// congested router does instead of dropping them (RFC 3168 5)
+    pub fn set_ce_marking(&mut self, mark_ce: bool) {
+        self.mark_ce = mark_ce;
+    }
+}
+impl NetDevice for VirtualDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let frame = self
+            .receiver
+            .try_recv()
+            .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(len)
+    }
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        let mut sent = frame.to_vec();
+        if self.mark_ce && sent.len() > PACKET_INFO_LEN {
+            mark_ce(&mut sent[PACKET_INFO_LEN..]);
+        }
+        self.sender
+            .send(sent)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(frame.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_pair() {
+        let (mut a, mut b) = VirtualDevice::pair("veth0", "veth1", DEFAULT_MTU);
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            b.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        let frame = frame_ip_packet(&[0x45, 0x0]);
+        assert_eq!(frame, [0x0, 0x0, 0x8, 0x0, 0x45, 0x0]);
+        a.send(&frame).unwrap();
+        assert_eq!(b.recv(&mut buf).unwrap(), 6);
+        assert_eq!(buf[..6], frame);
+    }
+}
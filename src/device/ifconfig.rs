@@ -0,0 +1,117 @@
+use std::{
+    ffi::c_int,
+    io, mem,
+    net::IpAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use crate::route::InterfaceAddress;
+
+/// Sets the MTU, gives the host side of the interface its addresses and brings it up, what
+/// `ip link set mtu`, `ip addr add` and `ip link set up` do
+/// Needs CAP_NET_ADMIN, addresses which are already assigned are skipped
+pub fn configure_interface(
+    name: &str,
+    mtu: usize,
+    addresses: &[InterfaceAddress],
+) -> io::Result<()> {
+    let inet = socket(libc::AF_INET)?;
+    let mut request = interface_request(name)?;
+    request.ifr_ifru.ifru_mtu = mtu as c_int;
+    ioctl(&inet, libc::SIOCSIFMTU, &mut request)?;
+    for address in addresses {
+        let result = match address.addr {
+            IpAddr::V4(_) => add_ipv4_address(&inet, name, address),
+            IpAddr::V6(_) => add_ipv6_address(name, address),
+        };
+        match result {
+            Err(error) if error.raw_os_error() == Some(libc::EEXIST) => {}
+            result => result?,
+        }
+    }
+    let mut request = interface_request(name)?;
+    ioctl(&inet, libc::SIOCGIFFLAGS, &mut request)?;
+    // SAFETY: SIOCGIFFLAGS filled in the flags
+    unsafe { request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as i16 };
+    ioctl(&inet, libc::SIOCSIFFLAGS, &mut request)
+}
+
+fn socket(domain: c_int) -> io::Result<OwnedFd> {
+    // SAFETY: Plain socket call, the descriptor is owned from here on
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a valid descriptor nothing else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn ioctl<T>(socket: &OwnedFd, request: libc::c_ulong, argument: &mut T) -> io::Result<()> {
+    // SAFETY: Every request used here takes a pointer to the type it is given
+    let result = unsafe { libc::ioctl(socket.as_raw_fd(), request as _, argument as *mut T) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn interface_request(name: &str) -> io::Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Interface name {} is too long", name),
+        ));
+    }
+    // SAFETY: ifreq is plain data, all zeroes is a valid value
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    for (byte, name_byte) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *byte = name_byte as libc::c_char;
+    }
+    Ok(request)
+}
+
+fn sockaddr(addr: [u8; 4]) -> libc::sockaddr {
+    let sockaddr_in = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from_ne_bytes(addr),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: sockaddr_in and sockaddr have the same size
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sockaddr_in) }
+}
+
+fn add_ipv4_address(inet: &OwnedFd, name: &str, address: &InterfaceAddress) -> io::Result<()> {
+    let IpAddr::V4(addr) = address.addr else {
+        unreachable!("Called with IPv4 addresses only");
+    };
+    let mut request = interface_request(name)?;
+    request.ifr_ifru.ifru_addr = sockaddr(addr.octets());
+    ioctl(inet, libc::SIOCSIFADDR, &mut request)?;
+    let netmask = u32::MAX
+        .checked_shl(32 - address.prefix_length.min(32) as u32)
+        .unwrap_or(0);
+    let mut request = interface_request(name)?;
+    request.ifr_ifru.ifru_netmask = sockaddr(netmask.to_be_bytes());
+    ioctl(inet, libc::SIOCSIFNETMASK, &mut request)
+}
+
+fn add_ipv6_address(name: &str, address: &InterfaceAddress) -> io::Result<()> {
+    let IpAddr::V6(addr) = address.addr else {
+        unreachable!("Called with IPv6 addresses only");
+    };
+    let inet6 = socket(libc::AF_INET6)?;
+    let mut request = interface_request(name)?;
+    ioctl(&inet6, libc::SIOCGIFINDEX, &mut request)?;
+    let mut in6_request = libc::in6_ifreq {
+        ifr6_addr: libc::in6_addr {
+            s6_addr: addr.octets(),
+        },
+        ifr6_prefixlen: address.prefix_length as u32,
+        // SAFETY: SIOCGIFINDEX filled in the index
+        ifr6_ifindex: unsafe { request.ifr_ifru.ifru_ifindex },
+    };
+    ioctl(&inet6, libc::SIOCSIFADDR, &mut in6_request)
+}
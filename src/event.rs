@@ -0,0 +1,407 @@
+use std::{
+    ffi::c_int,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    capture::FLUSH_INTERVAL,
+    device::{NetDevice, PACKET_INFO_LEN},
+    stack::Stack,
+    timer::Clock,
+};
+
+/// Frames taken from one device per turn, so a busy device can not starve the others and timers
+pub const BUDGET: usize = 64;
+
+/// Set by SIGINT and SIGTERM once `stop_on_signals` installed the handler
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Makes `EventLoop::run` flush the devices and return on SIGINT or SIGTERM, instead of the
+/// process being killed with frames still buffered
+pub fn stop_on_signals() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let handler = stop as extern "C" fn(c_int) as libc::sighandler_t;
+        // SAFETY: The handler only stores to an atomic, which is async signal safe
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn stop(_signal: c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+/// Wakes the event loop from another thread, e.g. after writing to a connection
+#[derive(Debug, Clone)]
+pub struct Waker {
+    eventfd: Arc<OwnedFd>,
+}
+impl Waker {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: Plain eventfd call, the descriptor is owned from here on
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            // SAFETY: fd is a valid descriptor nothing else owns
+            eventfd: Arc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
+        })
+    }
+    pub fn wake(&self) -> io::Result<()> {
+        let value = 1u64.to_ne_bytes();
+        // SAFETY: Writes the 8 bytes of value
+        let result = unsafe { libc::write(self.eventfd.as_raw_fd(), value.as_ptr().cast(), 8) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    /// Clears pending wakeups
+    fn reset(&self) {
+        let mut value = [0u8; 8];
+        // SAFETY: Reads at most 8 bytes into value, nothing to read is fine
+        unsafe { libc::read(self.eventfd.as_raw_fd(), value.as_mut_ptr().cast(), 8) };
+    }
+}
+
+/// Multiplexes device readiness, protocol timers and application wakeups with poll(2)
+/// Every turn receives what the devices have waiting, then fires the timers which are due at the
+/// time of the clock, so with a mock clock and in memory devices a run is deterministic
+/// TUN devices have no link layer, so there are no ARP timers
+pub struct EventLoop<C: Clock> {
+    pub stack: Stack,
+    pub devices: Vec<Box<dyn NetDevice>>,
+    clock: C,
+    waker: Waker,
+    buf: Vec<u8>,
+    /// When the devices write out what they buffered next
+    next_flush: Instant,
+}
+impl<C: Clock> EventLoop<C> {
+    /// Devices backed by a descriptor have to be non blocking
+    pub fn new(stack: Stack, devices: Vec<Box<dyn NetDevice>>, clock: C) -> io::Result<Self> {
+        let mtu = devices.iter().map(|device| device.mtu()).max().unwrap_or(0);
+        let next_flush = clock.now() + FLUSH_INTERVAL;
+        Ok(Self {
+            stack,
+            devices,
+            clock,
+            waker: Waker::new()?,
+            buf: vec![0u8; mtu + PACKET_INFO_LEN],
+            next_flush,
+        })
+    }
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+    /// Runs until an error or a signal `stop_on_signals` handles, then flushes the devices
+    pub fn run(&mut self) -> io::Result<()> {
+        let result = loop {
+            if STOP.load(Ordering::Relaxed) {
+                break Ok(());
+            }
+            if let Err(error) = self.turn(None) {
+                break Err(error);
+            }
+        };
+        let flushed = self.flush();
+        result.and(flushed)
+    }
+    /// Waits until a device is readable, a timer is due, the loop is woken or `max_wait` passed,
+    /// then handles everything which is ready
+    pub fn turn(&mut self, max_wait: Option<Duration>) -> io::Result<()> {
+        let now = self.clock.now();
+        let until_deadline = [self.stack.next_deadline(), Some(self.next_flush)]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now));
+        let mut timeout = [until_deadline, max_wait].into_iter().flatten().min();
+        if self.devices.iter().any(|device| device.raw_fd().is_none()) {
+            // Those can not be waited on
+            timeout = Some(Duration::ZERO);
+        }
+        self.wait(timeout)?;
+        for index in 0..self.devices.len() {
+            for _ in 0..BUDGET {
+                let len = match self.devices[index].recv(&mut self.buf) {
+                    Ok(len) => len,
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(error) => return Err(error),
+                };
+                if len <= PACKET_INFO_LEN {
+                    continue;
+                }
+                let now = self.clock.now();
+                let verdicts = self.stack.receive(
+                    self.devices[index].name(),
+                    &self.buf[PACKET_INFO_LEN..len],
+                    now,
+                );
+                self.stack.transmit(&mut self.devices, verdicts, now)?;
+            }
+        }
+        let now = self.clock.now();
+        let verdicts = self.stack.poll(now);
+        self.stack.transmit(&mut self.devices, verdicts, now)?;
+        if now >= self.next_flush {
+            self.next_flush = now + FLUSH_INTERVAL;
+            self.flush()?;
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        for device in &mut self.devices {
+            device.flush()?;
+        }
+        Ok(())
+    }
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let mut fds: Vec<libc::pollfd> = self
+            .devices
+            .iter()
+            .filter_map(|device| device.raw_fd())
+            .chain([self.waker.eventfd.as_raw_fd()])
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // Rounded up, waking before the deadline would only mean waiting again
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_micros().div_ceil(1000).min(c_int::MAX as u128) as c_int
+        });
+        // SAFETY: fds is a valid array of fds.len() pollfds
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if result < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+        self.waker.reset();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufWriter, Write},
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::{
+        capture::{CaptureDevice, CaptureFormat, CaptureWriter, LinkType},
+        connection::{ConnectionTable, TCPConfig, DEFAULT_BACKLOG},
+        device::{frame_ip_packet, VirtualDevice, DEFAULT_MTU},
+        forward::Router,
+        ip::ECN,
+        protocol::{IPBody, TCPControlBits, TCP},
+        route::{Interface, InterfaceAddress, RouteTable},
+        test_util::{tcp_packet, CLIENT, SERVER},
+        timer::MockClock,
+        IPPacket,
+    };
+
+    fn segment(sequence_number: u32, ack: u32, control_bits: TCPControlBits) -> Vec<u8> {
+        frame_ip_packet(&tcp_packet(
+            CLIENT,
+            SERVER,
+            sequence_number,
+            ack,
+            control_bits,
+            &[],
+        ))
+    }
+
+    fn receive(peer: &mut VirtualDevice) -> Option<TCP> {
+        receive_with_ecn(peer).map(|(tcp, _)| tcp)
+    }
+
+    fn receive_with_ecn(peer: &mut VirtualDevice) -> Option<(TCP, ECN)> {
+        let mut buf = [0u8; DEFAULT_MTU + PACKET_INFO_LEN];
+        let len = peer.recv(&mut buf).ok()?;
+        let ip_packet = IPPacket::from_byte_buffer(&buf[PACKET_INFO_LEN..len]).unwrap();
+        match ip_packet.body {
+            IPBody::TCP(tcp) => Some((tcp, ip_packet.header.ecn())),
+            _ => None,
+        }
+    }
+
+    fn event_loop(device: impl NetDevice + 'static, clock: MockClock) -> EventLoop<MockClock> {
+        let mut route_table = RouteTable::new();
+        route_table.add_interface(Interface::new(
+            "tun0",
+            DEFAULT_MTU,
+            vec![InterfaceAddress::new("192.168.0.2".parse().unwrap(), 24)],
+        ));
+        let mut connections = ConnectionTable::with_config(TCPConfig::default());
+        connections.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        let stack = Stack::with_connections(Router::new(route_table, false), connections);
+        EventLoop::new(stack, vec![Box::new(device)], clock).unwrap()
+    }
+
+    #[test]
+    fn timers_and_wakeups() {
+        let (device, mut peer) = VirtualDevice::pair("tun0", "host", DEFAULT_MTU);
+        let clock = MockClock::new(Instant::now());
+        let mut event_loop = event_loop(device, clock.clone());
+
+        peer.send(&segment(1000, 0, TCPControlBits::SYN)).unwrap();
+        event_loop.turn(None).unwrap();
+        let syn_ack = receive(&mut peer).unwrap();
+        assert_eq!(
+            syn_ack.control_bits,
+            TCPControlBits::SYN | TCPControlBits::ACK
+        );
+
+        // Lost, retransmitted after the initial RTO
+        clock.advance(Duration::from_millis(999));
+        event_loop.turn(None).unwrap();
+        assert!(receive(&mut peer).is_none());
+        clock.advance(Duration::from_millis(1));
+        event_loop.turn(None).unwrap();
+        let retransmitted = receive(&mut peer).unwrap();
+        assert_eq!(retransmitted.to_byte_buffer(), syn_ack.to_byte_buffer());
+        let stats = &event_loop.stack.router.stats;
+        assert_eq!(stats.tcp.retrans_segs.get(), 1);
+        assert_eq!(stats.tcp.passive_opens.get(), 1);
+
+        let iss = syn_ack.sequence_number;
+        peer.send(&segment(1001, iss.wrapping_add(1), TCPControlBits::ACK))
+            .unwrap();
+        event_loop.turn(None).unwrap();
+        let key = {
+            let mut connections = event_loop.stack.connections.lock().unwrap();
+            let key = connections.accept("0.0.0.0:80".parse().unwrap()).unwrap();
+            assert_eq!(connections.write(key, b"hello"), 5);
+            key
+        };
+        event_loop.waker().wake().unwrap();
+        event_loop.turn(None).unwrap();
+        let data = receive(&mut peer).unwrap();
+        assert_eq!(data.control_bits, TCPControlBits::PSH | TCPControlBits::ACK);
+        assert_eq!(data.data, b"hello");
+        assert_eq!(
+            event_loop
+                .stack
+                .connections
+                .lock()
+                .unwrap()
+                .get(key)
+                .unwrap()
+                .in_flight(),
+            5
+        );
+    }
+
+    /// A file whose contents can be read while a capture writes to it
+    #[derive(Clone, Default)]
+    struct SharedFile(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flushes_captures() {
+        let (device, mut peer) = VirtualDevice::pair("tun0", "host", DEFAULT_MTU);
+        let file = SharedFile::default();
+        let writer = CaptureWriter::new(
+            BufWriter::new(file.clone()),
+            CaptureFormat::Pcap,
+            LinkType::RawIP,
+        )
+        .unwrap();
+        let clock = MockClock::new(Instant::now());
+        let mut event_loop = event_loop(CaptureDevice::new(device, writer), clock.clone());
+
+        peer.send(&segment(1000, 0, TCPControlBits::SYN)).unwrap();
+        event_loop.turn(None).unwrap();
+        assert!(receive(&mut peer).is_some());
+        assert!(file.0.lock().unwrap().is_empty());
+        // Written out once the interval passed, even without more traffic
+        clock.advance(FLUSH_INTERVAL);
+        event_loop.turn(None).unwrap();
+        let captured = file.0.lock().unwrap().len();
+        assert!(captured > 24 + 2 * 40);
+    }
+
+    #[test]
+    fn ecn_over_a_marking_link() {
+        let (mut device, mut peer) = VirtualDevice::pair("tun0", "host", DEFAULT_MTU);
+        device.set_ce_marking(true);
+        let clock = MockClock::new(Instant::now());
+        let mut event_loop = event_loop(device, clock.clone());
+
+        peer.send(&segment(
+            1000,
+            0,
+            TCPControlBits::SYN | TCPControlBits::ECE | TCPControlBits::CWR,
+        ))
+        .unwrap();
+        event_loop.turn(None).unwrap();
+        let (syn_ack, ecn) = receive_with_ecn(&mut peer).unwrap();
+        assert_eq!(
+            (syn_ack.control_bits, ecn),
+            (
+                TCPControlBits::SYN | TCPControlBits::ACK | TCPControlBits::ECE,
+                ECN::NotECT
+            )
+        );
+        let iss = syn_ack.sequence_number;
+        peer.send(&segment(1001, iss.wrapping_add(1), TCPControlBits::ACK))
+            .unwrap();
+        event_loop.turn(None).unwrap();
+        let (key, cwnd) = {
+            let mut connections = event_loop.stack.connections.lock().unwrap();
+            let key = connections.accept("0.0.0.0:80".parse().unwrap()).unwrap();
+            connections.write(key, b"hello");
+            (key, connections.get(key).unwrap().cwnd)
+        };
+        event_loop.waker().wake().unwrap();
+        event_loop.turn(None).unwrap();
+        // Sent as ECT(0), marked on the way
+        let (data, ecn) = receive_with_ecn(&mut peer).unwrap();
+        assert_eq!((data.data.as_slice(), ecn), (&b"hello"[..], ECN::CE));
+
+        peer.send(&segment(
+            1001,
+            iss.wrapping_add(6),
+            TCPControlBits::ECE | TCPControlBits::ACK,
+        ))
+        .unwrap();
+        event_loop.turn(None).unwrap();
+        {
+            let mut connections = event_loop.stack.connections.lock().unwrap();
+            connections.write(key, b"again");
+            let tcb = connections.get(key).unwrap();
+            // Halved without a retransmission
+            assert_eq!((tcb.cwnd, tcb.ssthresh), ((cwnd + 5) / 2, (cwnd + 5) / 2));
+            assert_eq!(tcb.retransmits, 0);
+        }
+        event_loop.waker().wake().unwrap();
+        event_loop.turn(None).unwrap();
+        let data = receive(&mut peer).unwrap();
+        assert_eq!(
+            data.control_bits,
+            TCPControlBits::CWR | TCPControlBits::PSH | TCPControlBits::ACK
+        );
+    }
+}
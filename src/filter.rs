@@ -0,0 +1,770 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    ip::IPHeader,
+    ipv6::{ExtensionHeader, ExtensionHeaderBody, IPv6Header},
+    nat::{ICMP_TIMEOUT, TCP_ESTABLISHED_TIMEOUT, TCP_TRANSITORY_TIMEOUT, UDP_TIMEOUT},
+    protocol::{icmpv6, tcp, udp, IPBody, TCPControlBits, TCP},
+    route::mask,
+    IPPacket, IPv6Packet,
+};
+
+const ICMP: u8 = 1;
+
+/// Where in the stack a packet is being filtered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hook {
+    /// Received and addressed to us
+    Input,
+    /// Generated by us
+    Output,
+    /// Received and routed to another host
+    Forward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Accept,
+    /// Silently discard
+    Drop,
+    /// Discard and tell the sender, with a TCP RST or an ICMP port unreachable
+    Reject,
+}
+
+/// Connection tracking state of a packet, as seen before the packet updates the table
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    /// Starts a connection, or belongs to one which has not seen a reply yet
+    New,
+    Established,
+    /// ICMP error about a tracked connection
+    Related,
+    /// Cannot be tracked, e.g. a TCP segment without SYN for an unknown connection or a non initial fragment
+    Invalid,
+}
+
+/// One direction of a connection, ICMP echo uses the identifier as both ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub protocol: u8,
+    pub source: (IpAddr, u16),
+    pub destination: (IpAddr, u16),
+}
+impl Flow {
+    pub fn reversed(&self) -> Self {
+        Self {
+            protocol: self.protocol,
+            source: self.destination,
+            destination: self.source,
+        }
+    }
+}
+
+/// The fields rules match on, pulled out of a raw IPv4 or IPv6 packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketInfo {
+    pub source_addr: IpAddr,
+    pub destination_addr: IpAddr,
+    /// Upper layer protocol, after any IPv6 extension headers
+    pub protocol: u8,
+    /// TTL or hop limit
+    pub time_to_live: u8,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    pub control_bits: Option<TCPControlBits>,
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    /// Identifier of ICMP echo requests and replies
+    pub icmp_identifier: Option<u16>,
+    /// For ICMP errors, the packet which caused the error
+    pub quoted: Option<Box<PacketInfo>>,
+}
+impl PacketInfo {
+    /// Truncated packets are accepted as long as the headers are there, so quoted packets can be parsed
+    pub fn from_byte_buffer(buf: &[u8]) -> Option<Self> {
+        match buf.first().map(|byte| byte >> 4) {
+            Some(4) => Self::from_ipv4(buf),
+            Some(6) => Self::from_ipv6(buf),
+            _ => None,
+        }
+    }
+    fn from_ipv4(buf: &[u8]) -> Option<Self> {
+        let header_len = (buf.first()? & 0b1111) as usize * 4;
+        if header_len < 20 || buf.len() < header_len {
+            return None;
+        }
+        let is_first_fragment = u16::from_be_bytes([buf[6], buf[7]]) & 0x1FFF == 0;
+        let body = if is_first_fragment {
+            &buf[header_len..]
+        } else {
+            &[]
+        };
+        let source_addr = Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15]);
+        let destination_addr = Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19]);
+        Some(Self::with_body(
+            IpAddr::V4(source_addr),
+            IpAddr::V4(destination_addr),
+            buf[9],
+            buf[8],
+            body,
+        ))
+    }
+    fn from_ipv6(buf: &[u8]) -> Option<Self> {
+        let header = IPv6Header::from_byte_buffer(buf).ok()?;
+        let (extension_headers, protocol, offset) =
+            ExtensionHeader::walk_chain(header.next_header, &buf[IPv6Header::LEN..]).ok()?;
+        let offset = IPv6Header::LEN + offset;
+        let is_first_fragment = extension_headers.iter().all(|extension_header| {
+            !matches!(extension_header.body,
+                ExtensionHeaderBody::Fragment { fragment_offset, .. } if fragment_offset != 0)
+        });
+        let body = match buf.get(offset..) {
+            Some(body) if is_first_fragment => body,
+            _ => &[],
+        };
+        Some(Self::with_body(
+            IpAddr::V6(header.source_addr),
+            IpAddr::V6(header.destination_addr),
+            protocol,
+            header.hop_limit,
+            body,
+        ))
+    }
+    /// `body` is empty when the transport header is not in this packet
+    fn with_body(
+        source_addr: IpAddr,
+        destination_addr: IpAddr,
+        protocol: u8,
+        time_to_live: u8,
+        body: &[u8],
+    ) -> Self {
+        let mut info = Self {
+            source_addr,
+            destination_addr,
+            protocol,
+            time_to_live,
+            source_port: None,
+            destination_port: None,
+            control_bits: None,
+            icmp_type: None,
+            icmp_code: None,
+            icmp_identifier: None,
+            quoted: None,
+        };
+        match protocol {
+            tcp::PROTOCOL | udp::PROTOCOL if body.len() >= 4 => {
+                info.source_port = Some(u16::from_be_bytes([body[0], body[1]]));
+                info.destination_port = Some(u16::from_be_bytes([body[2], body[3]]));
+                if protocol == tcp::PROTOCOL && body.len() >= 14 {
+                    let control_bits = u16::from_be_bytes([body[12], body[13]]) & 0x1FF;
+                    info.control_bits = Some(TCPControlBits::from_bits_retain(control_bits));
+                }
+            }
+            ICMP | icmpv6::PROTOCOL if body.len() >= 8 => {
+                info.icmp_type = Some(body[0]);
+                info.icmp_code = Some(body[1]);
+                let is_echo = match protocol {
+                    ICMP => matches!(body[0], 0 | 8),
+                    _ => matches!(body[0], 128 | 129),
+                };
+                if is_echo {
+                    info.icmp_identifier = Some(u16::from_be_bytes([body[4], body[5]]));
+                } else if info.is_icmp_error() {
+                    info.quoted = Self::from_byte_buffer(&body[8..]).map(Box::new);
+                }
+            }
+            _ => {}
+        }
+        info
+    }
+    pub fn is_icmp_error(&self) -> bool {
+        match (self.protocol, self.icmp_type) {
+            (ICMP, Some(_type)) => matches!(_type, 3 | 4 | 5 | 11 | 12),
+            (icmpv6::PROTOCOL, Some(_type)) => _type < 128,
+            _ => false,
+        }
+    }
+    /// None when there is nothing to tell connections apart by
+    pub fn flow(&self) -> Option<Flow> {
+        let (source_port, destination_port) = match self.protocol {
+            tcp::PROTOCOL | udp::PROTOCOL => (self.source_port?, self.destination_port?),
+            ICMP | icmpv6::PROTOCOL => (self.icmp_identifier?, self.icmp_identifier?),
+            _ => (0, 0),
+        };
+        Some(Flow {
+            protocol: self.protocol,
+            source: (self.source_addr, source_port),
+            destination: (self.destination_addr, destination_port),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Connection {
+    replied: bool,
+    /// TCP FIN or RST seen
+    closing: bool,
+    last_seen: Instant,
+}
+impl Connection {
+    fn timeout(&self, protocol: u8) -> Duration {
+        match protocol {
+            tcp::PROTOCOL if self.replied && !self.closing => TCP_ESTABLISHED_TIMEOUT,
+            tcp::PROTOCOL => TCP_TRANSITORY_TIMEOUT,
+            udp::PROTOCOL => UDP_TIMEOUT,
+            _ => ICMP_TIMEOUT,
+        }
+    }
+}
+
+/// Connection tracking table, keyed by the flow of the packet which opened the connection
+#[derive(Debug, Default)]
+pub struct ConnTrack {
+    connections: HashMap<Flow, Connection>,
+    /// No connection expires before this, connections seen since may live longer
+    next_expiry: Option<Instant>,
+}
+impl ConnTrack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+    pub fn state(&self, info: &PacketInfo) -> ConnectionState {
+        if info.is_icmp_error() {
+            let Some(flow) = info.quoted.as_ref().and_then(|quoted| quoted.flow()) else {
+                return ConnectionState::Invalid;
+            };
+            return match self.find(&flow) {
+                Some(_) => ConnectionState::Related,
+                None => ConnectionState::Invalid,
+            };
+        }
+        let Some(flow) = info.flow() else {
+            return ConnectionState::Invalid;
+        };
+        match self.find(&flow) {
+            Some((connection, true)) if !connection.replied => ConnectionState::New,
+            Some(_) => ConnectionState::Established,
+            // Only a SYN can open a TCP connection
+            None if info.protocol == tcp::PROTOCOL
+                && !info
+                    .control_bits
+                    .is_some_and(|control_bits| control_bits.is_syn_only()) =>
+            {
+                ConnectionState::Invalid
+            }
+            None => ConnectionState::New,
+        }
+    }
+    /// Records an accepted packet, opening a connection if it is new
+    pub fn track(&mut self, info: &PacketInfo, now: Instant) {
+        if info.is_icmp_error() {
+            return;
+        }
+        let Some(flow) = info.flow() else {
+            return;
+        };
+        let closing = info
+            .control_bits
+            .is_some_and(|control_bits| control_bits.is_fin_or_rst());
+        if let Some(connection) = self.connections.get_mut(&flow.reversed()) {
+            if !connection.replied {
+                debug!(?flow, "connection established");
+            }
+            connection.replied = true;
+            connection.closing |= closing;
+            connection.last_seen = now;
+            // Closing shortens the timeout
+            let expiry = now + connection.timeout(flow.protocol);
+            self.schedule_expiry(expiry);
+        } else if let Some(connection) = self.connections.get_mut(&flow) {
+            connection.closing |= closing;
+            connection.last_seen = now;
+            let expiry = now + connection.timeout(flow.protocol);
+            self.schedule_expiry(expiry);
+        } else if self.state(info) == ConnectionState::New {
+            debug!(?flow, "connection tracked");
+            let connection = Connection {
+                replied: false,
+                closing,
+                last_seen: now,
+            };
+            self.schedule_expiry(now + connection.timeout(flow.protocol));
+            self.connections.insert(flow, connection);
+        }
+    }
+    /// When `expire` has connections to check
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_expiry
+    }
+    /// Forgets connections which have been idle for longer than their timeout, only looking
+    /// through them once the earliest one may have timed out
+    pub fn expire(&mut self, now: Instant) {
+        if self.next_expiry.is_none_or(|next_expiry| now < next_expiry) {
+            return;
+        }
+        self.connections.retain(|flow, connection| {
+            let alive = now < connection.last_seen + connection.timeout(flow.protocol);
+            if !alive {
+                debug!(?flow, "connection expired");
+            }
+            alive
+        });
+        self.next_expiry = self
+            .connections
+            .iter()
+            .map(|(flow, connection)| connection.last_seen + connection.timeout(flow.protocol))
+            .min();
+    }
+    fn schedule_expiry(&mut self, expiry: Instant) {
+        self.next_expiry = Some(self.next_expiry.map_or(expiry, |next| next.min(expiry)));
+    }
+    /// Returns the connection and whether the flow is in its original direction
+    fn find(&self, flow: &Flow) -> Option<(&Connection, bool)> {
+        if let Some(connection) = self.connections.get(flow) {
+            return Some((connection, true));
+        }
+        self.connections
+            .get(&flow.reversed())
+            .map(|connection| (connection, false))
+    }
+}
+
+/// A rule matches when every field that is set matches, unset fields match anything
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// Address and prefix length
+    pub source: Option<(IpAddr, u8)>,
+    pub destination: Option<(IpAddr, u8)>,
+    pub protocol: Option<u8>,
+    pub source_ports: Option<RangeInclusive<u16>>,
+    pub destination_ports: Option<RangeInclusive<u16>>,
+    /// Mask and value, matches when the TCP control bits under the mask equal the value
+    pub control_bits: Option<(TCPControlBits, TCPControlBits)>,
+    pub icmp_type: Option<u8>,
+    /// Matches when the connection state is any of these
+    pub states: Option<Vec<ConnectionState>>,
+    pub action: Action,
+}
+impl Rule {
+    /// A rule matching every packet, narrow it down with struct update syntax
+    pub fn new(action: Action) -> Self {
+        Self {
+            source: None,
+            destination: None,
+            protocol: None,
+            source_ports: None,
+            destination_ports: None,
+            control_bits: None,
+            icmp_type: None,
+            states: None,
+            action,
+        }
+    }
+    pub fn matches(&self, info: &PacketInfo, state: ConnectionState) -> bool {
+        let prefix_matches = |prefix: &Option<(IpAddr, u8)>, addr: &IpAddr| {
+            prefix.is_none_or(|(prefix, prefix_length)| {
+                prefix.is_ipv4() == addr.is_ipv4()
+                    && mask(addr, prefix_length) == mask(&prefix, prefix_length)
+            })
+        };
+        let port_matches = |ports: &Option<RangeInclusive<u16>>, port: Option<u16>| match ports {
+            Some(ports) => port.is_some_and(|port| ports.contains(&port)),
+            None => true,
+        };
+        prefix_matches(&self.source, &info.source_addr)
+            && prefix_matches(&self.destination, &info.destination_addr)
+            && self
+                .protocol
+                .is_none_or(|protocol| protocol == info.protocol)
+            && port_matches(&self.source_ports, info.source_port)
+            && port_matches(&self.destination_ports, info.destination_port)
+            && self.control_bits.is_none_or(|(mask, value)| {
+                info.control_bits
+                    .is_some_and(|control_bits| control_bits & mask == value)
+            })
+            && self
+                .icmp_type
+                .is_none_or(|_type| info.icmp_type == Some(_type))
+            && self
+                .states
+                .as_ref()
+                .is_none_or(|states| states.contains(&state))
+    }
+}
+
+/// Rules are checked in order, the first match decides, the policy applies when none match
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chain {
+    pub rules: Vec<Rule>,
+    pub policy: Action,
+}
+impl Chain {
+    pub fn new(policy: Action) -> Self {
+        Self {
+            rules: Vec::new(),
+            policy,
+        }
+    }
+    pub fn append(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+    pub fn evaluate(&self, info: &PacketInfo, state: ConnectionState) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(info, state))
+            .map_or(self.policy, |rule| rule.action)
+    }
+}
+
+/// Stateful packet filter with a chain per hook, everything is accepted by default
+pub struct Filter {
+    pub input: Chain,
+    pub output: Chain,
+    pub forward: Chain,
+    pub conntrack: ConnTrack,
+}
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Filter {
+    pub fn new() -> Self {
+        Self {
+            input: Chain::new(Action::Accept),
+            output: Chain::new(Action::Accept),
+            forward: Chain::new(Action::Accept),
+            conntrack: ConnTrack::new(),
+        }
+    }
+    pub fn chain_mut(&mut self, hook: Hook) -> &mut Chain {
+        match hook {
+            Hook::Input => &mut self.input,
+            Hook::Output => &mut self.output,
+            Hook::Forward => &mut self.forward,
+        }
+    }
+    /// Runs the raw IP packet through the chain of the hook, tracking it if it is accepted
+    /// Packets which cannot be parsed are dropped
+    pub fn filter(&mut self, hook: Hook, buf: &[u8], now: Instant) -> Action {
+        let Some(info) = PacketInfo::from_byte_buffer(buf) else {
+            return Action::Drop;
+        };
+        let state = self.conntrack.state(&info);
+        let action = self.chain_mut(hook).evaluate(&info, state);
+        match action {
+            Action::Accept => self.conntrack.track(&info, now),
+            action => debug!(?hook, ?state, ?action, "filtered"),
+        }
+        action
+    }
+}
+
+/// Builds the RST answering a raw TCP packet (RFC 793 3.4), None if it is not TCP or is a RST itself
+pub fn tcp_reset(buf: &[u8]) -> Option<Vec<u8>> {
+    let info = PacketInfo::from_byte_buffer(buf)?;
+    let control_bits = info.control_bits?;
+    if control_bits.contains(TCPControlBits::RST) {
+        return None;
+    }
+    let (header_len, segment_len) = match info.source_addr {
+        IpAddr::V4(_) => {
+            let header_len = (buf[0] & 0b1111) as usize * 4;
+            let total_length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            (
+                header_len,
+                total_length.min(buf.len()).checked_sub(header_len)?,
+            )
+        }
+        IpAddr::V6(_) => {
+            let header = IPv6Header::from_byte_buffer(buf).ok()?;
+            let (_, _, offset) =
+                ExtensionHeader::walk_chain(header.next_header, &buf[IPv6Header::LEN..]).ok()?;
+            let total_length = IPv6Header::LEN + header.payload_length as usize;
+            let header_len = IPv6Header::LEN + offset;
+            (
+                header_len,
+                total_length.min(buf.len()).checked_sub(header_len)?,
+            )
+        }
+    };
+    let segment = &buf[header_len..];
+    let sequence_number = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+    let acknowledgment_number =
+        u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]);
+    let data_len = segment_len.saturating_sub((segment[12] >> 4) as usize * 4);
+    let (sequence_number, acknowledgment_number, control_bits) =
+        if control_bits.contains(TCPControlBits::ACK) {
+            (acknowledgment_number, 0, TCPControlBits::RST)
+        } else {
+            let len = data_len as u32 + control_bits.sequence_len();
+            (
+                0,
+                sequence_number.wrapping_add(len),
+                TCPControlBits::RST | TCPControlBits::ACK,
+            )
+        };
+    let tcp = TCP::builder(info.destination_port?, info.source_port?)
+        .sequence_number(sequence_number)
+        .acknowledgment_number(acknowledgment_number)
+        .control_bits(control_bits)
+        .build(&info.destination_addr, &info.source_addr)
+        .ok()?;
+    let ip_body = IPBody::TCP(tcp);
+    let reset = match (info.destination_addr, info.source_addr) {
+        (IpAddr::V4(source_addr), IpAddr::V4(destination_addr)) => {
+            let ip_header = IPHeader::builder(source_addr, destination_addr);
+            IPPacket::from_body(ip_header, ip_body).to_byte_buffer()
+        }
+        (IpAddr::V6(source_addr), IpAddr::V6(destination_addr)) => {
+            let ip_header =
+                IPv6Header::new(0, 0, 0, tcp::PROTOCOL, 64, source_addr, destination_addr);
+            IPv6Packet::new(ip_header, Vec::new(), tcp::PROTOCOL, ip_body).to_byte_buffer()
+        }
+        _ => return None,
+    };
+    Some(reset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use super::*;
+    use crate::{
+        protocol::{ICMPBody, UDP},
+        test_util::tcp_packet,
+    };
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+    fn ipv4(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, ip_body: IPBody) -> Vec<u8> {
+        let ip_header = IPHeader::from_body(
+            4,
+            0,
+            1,
+            0,
+            0,
+            64,
+            protocol,
+            source,
+            destination,
+            None,
+            ip_body.len() as u16,
+        );
+        IPPacket::new(ip_header, ip_body).to_byte_buffer()
+    }
+
+    fn segment(
+        source: (Ipv4Addr, u16),
+        destination: (Ipv4Addr, u16),
+        control_bits: TCPControlBits,
+        data: Vec<u8>,
+    ) -> Vec<u8> {
+        tcp_packet(
+            SocketAddrV4::new(source.0, source.1),
+            SocketAddrV4::new(destination.0, destination.1),
+            1000,
+            2000,
+            control_bits,
+            &data,
+        )
+    }
+
+    /// Allows established traffic and new connections to port 22 only
+    fn filter() -> Filter {
+        let mut filter = Filter::new();
+        filter.input.policy = Action::Drop;
+        filter.input.append(Rule {
+            states: Some(vec![ConnectionState::Established, ConnectionState::Related]),
+            ..Rule::new(Action::Accept)
+        });
+        filter.input.append(Rule {
+            protocol: Some(tcp::PROTOCOL),
+            destination_ports: Some(22..=22),
+            control_bits: Some((
+                TCPControlBits::SYN | TCPControlBits::ACK,
+                TCPControlBits::SYN,
+            )),
+            ..Rule::new(Action::Accept)
+        });
+        filter.input.append(Rule {
+            protocol: Some(tcp::PROTOCOL),
+            ..Rule::new(Action::Reject)
+        });
+        filter
+    }
+
+    #[test]
+    fn packet_info() {
+        let buf = segment(
+            (CLIENT, 40000),
+            (SERVER, 22),
+            TCPControlBits::SYN,
+            Vec::new(),
+        );
+        let info = PacketInfo::from_byte_buffer(&buf).unwrap();
+        assert_eq!(info.source_port, Some(40000));
+        assert_eq!(info.destination_port, Some(22));
+        assert_eq!(info.control_bits, Some(TCPControlBits::SYN));
+        assert_eq!(info.time_to_live, 64);
+        assert_eq!(
+            info.flow().unwrap().reversed().source,
+            (IpAddr::V4(SERVER), 22)
+        );
+    }
+
+    #[test]
+    fn rule_order_and_policy() {
+        let now = Instant::now();
+        let mut filter = filter();
+        let syn = segment(
+            (CLIENT, 40000),
+            (SERVER, 22),
+            TCPControlBits::SYN,
+            Vec::new(),
+        );
+        assert_eq!(filter.filter(Hook::Input, &syn, now), Action::Accept);
+        let syn = segment(
+            (CLIENT, 40001),
+            (SERVER, 80),
+            TCPControlBits::SYN,
+            Vec::new(),
+        );
+        assert_eq!(filter.filter(Hook::Input, &syn, now), Action::Reject);
+        let ip_body = IPBody::UDP(
+            UDP::new(
+                &IpAddr::V4(CLIENT),
+                &IpAddr::V4(SERVER),
+                5000,
+                53,
+                Vec::new(),
+            )
+            .unwrap(),
+        );
+        let datagram = ipv4(CLIENT, SERVER, udp::PROTOCOL, ip_body);
+        assert_eq!(filter.filter(Hook::Input, &datagram, now), Action::Drop);
+        // Other hooks keep their accept policy
+        assert_eq!(filter.filter(Hook::Forward, &datagram, now), Action::Accept);
+    }
+
+    #[test]
+    fn established_and_related() {
+        let now = Instant::now();
+        let mut filter = filter();
+        // Outgoing connection from the server, the input chain only sees the replies
+        let syn = segment(
+            (SERVER, 50000),
+            (CLIENT, 443),
+            TCPControlBits::SYN,
+            Vec::new(),
+        );
+        assert_eq!(filter.filter(Hook::Output, &syn, now), Action::Accept);
+        let syn_ack = segment(
+            (CLIENT, 443),
+            (SERVER, 50000),
+            TCPControlBits::SYN | TCPControlBits::ACK,
+            Vec::new(),
+        );
+        assert_eq!(
+            filter
+                .conntrack
+                .state(&PacketInfo::from_byte_buffer(&syn_ack).unwrap()),
+            ConnectionState::Established
+        );
+        assert_eq!(filter.filter(Hook::Input, &syn_ack, now), Action::Accept);
+        assert_eq!(filter.conntrack.len(), 1);
+
+        // ICMP error quoting the connection
+        let icmp = crate::protocol::ICMP::new(
+            3,
+            4,
+            ICMPBody::DestinationUnreachable {
+                next_hop_mtu: 576,
+                data: ICMPBody::original_datagram(&syn),
+            },
+        );
+        let error = ipv4(CLIENT, SERVER, ICMP, IPBody::ICMP(icmp));
+        assert_eq!(filter.filter(Hook::Input, &error, now), Action::Accept);
+
+        // An ACK for an unknown connection is invalid and falls through to the reject rule
+        let ack = segment(
+            (CLIENT, 443),
+            (SERVER, 50001),
+            TCPControlBits::ACK,
+            Vec::new(),
+        );
+        assert_eq!(filter.filter(Hook::Input, &ack, now), Action::Reject);
+
+        let later = now + TCP_ESTABLISHED_TIMEOUT;
+        filter.conntrack.expire(later);
+        assert_eq!(filter.filter(Hook::Input, &syn_ack, later), Action::Reject);
+        assert!(filter.conntrack.is_empty());
+    }
+
+    #[test]
+    fn reset() {
+        let syn = segment(
+            (CLIENT, 40000),
+            (SERVER, 80),
+            TCPControlBits::SYN,
+            vec![0xab; 3],
+        );
+        let reset = IPPacket::from_byte_buffer(&tcp_reset(&syn).unwrap()).unwrap();
+        assert_eq!(reset.header.source_addr, SERVER);
+        assert_eq!(reset.header.destination_addr, CLIENT);
+        let IPBody::TCP(tcp) = reset.body else {
+            panic!("Should be TCP");
+        };
+        assert_eq!(tcp.control_bits, TCPControlBits::RST | TCPControlBits::ACK);
+        assert_eq!(tcp.sequence_number, 0);
+        assert_eq!(tcp.acknowledgment_number, 1004);
+        assert_eq!(tcp.destination_port, 40000);
+
+        let ack = segment(
+            (CLIENT, 40000),
+            (SERVER, 80),
+            TCPControlBits::ACK,
+            Vec::new(),
+        );
+        let reset = IPPacket::from_byte_buffer(&tcp_reset(&ack).unwrap()).unwrap();
+        let IPBody::TCP(tcp) = reset.body else {
+            panic!("Should be TCP");
+        };
+        assert_eq!(tcp.control_bits, TCPControlBits::RST);
+        assert_eq!(tcp.sequence_number, 2000);
+        let rst = segment(
+            (CLIENT, 40000),
+            (SERVER, 80),
+            TCPControlBits::RST,
+            Vec::new(),
+        );
+        assert!(tcp_reset(&rst).is_none());
+        // Total length shorter than the header
+        let mut short = segment(
+            (CLIENT, 40000),
+            (SERVER, 80),
+            TCPControlBits::SYN,
+            Vec::new(),
+        );
+        short[2..4].copy_from_slice(&10_u16.to_be_bytes());
+        assert!(tcp_reset(&short).is_none());
+    }
+}
@@ -0,0 +1,845 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::{
+    buffer::{BufferPool, PacketBuffer},
+    filter::{self, Action, Filter, Hook},
+    ip::{self, IPHeader, Ipv4Packet, FLAG_DONT_FRAGMENT},
+    ipv6::{Fragmenter, IPv6Header},
+    nat::Nat,
+    protocol::{icmpv6, ICMPBody, ICMPv6, ICMPv6Body, IPBody, ICMP},
+    route::RouteTable,
+    stats::Stats,
+    IPPacket, IPv6Packet,
+};
+
+/// TTL / hop limit of ICMP errors generated while forwarding
+const ERROR_TTL: u8 = 64;
+
+#[derive(Debug, PartialEq)]
+pub enum DropReason {
+    Malformed,
+    ForwardingDisabled,
+    /// Not forwarded and no ICMP error is sent back, e.g. errors about ICMP errors
+    Silent,
+    /// An ICMP error was wanted but there is no route back to the source
+    NoRouteToSource,
+    /// Leaving through the NAT outside interface but could not be translated
+    Untranslatable,
+    /// Dropped or rejected by the packet filter
+    Filtered,
+    /// Locally generated packet with no route to its destination
+    NoRoute,
+    /// Locally generated IPv6 packet which can not be fragmented to fit the path MTU
+    CannotFragment,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// Addressed to us, to be handled by the local stack
+    Local,
+    /// Raw IP packets to send out of the interface
+    Transmit {
+        interface: String,
+        packets: Vec<PacketBuffer>,
+    },
+    Drop(DropReason),
+}
+
+/// Decides what happens to each received packet, forwarding it when router mode is enabled
+pub struct Router {
+    pub route_table: RouteTable,
+    /// Packets not addressed to us are dropped unless this is set
+    pub forwarding: bool,
+    /// IPv4 packets forwarded out of the outside interface are translated when set
+    pub nat: Option<Nat>,
+    /// Packets go through the input, forward and output chains when set
+    pub filter: Option<Filter>,
+    /// Shared so the counters can be read while the router runs
+    pub stats: Arc<Stats>,
+    /// Outgoing packets are built in buffers from here
+    pub pool: BufferPool,
+    /// Fragments the IPv6 packets we originate to the path MTU learnt from Packet Too Big
+    pub fragmenter: Fragmenter,
+}
+impl Router {
+    pub fn new(route_table: RouteTable, forwarding: bool) -> Self {
+        Self {
+            route_table,
+            forwarding,
+            nat: None,
+            filter: None,
+            stats: Arc::new(Stats::new()),
+            pool: BufferPool::new(),
+            fragmenter: Fragmenter::new(),
+        }
+    }
+    /// Forgets the NAT mappings and tracked connections which timed out
+    pub fn poll(&mut self, now: Instant) {
+        if let Some(nat) = &mut self.nat {
+            nat.expire(now);
+        }
+        if let Some(filter) = &mut self.filter {
+            filter.conntrack.expire(now);
+        }
+    }
+    /// When `poll` has something to do next
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let nat = self.nat.as_ref().and_then(Nat::next_deadline);
+        let conntrack = self
+            .filter
+            .as_ref()
+            .and_then(|filter| filter.conntrack.next_deadline());
+        nat.into_iter().chain(conntrack).min()
+    }
+    /// Takes the raw bytes of a received IP packet
+    pub fn route(&mut self, buf: &[u8], now: Instant) -> Verdict {
+        self.stats.ip.in_receives.increment();
+        let verdict = match buf.first().map(|byte| byte >> 4) {
+            Some(4) => self.route_ipv4(buf, now),
+            Some(6) => self.route_ipv6(buf, now),
+            _ => Verdict::Drop(DropReason::Malformed),
+        };
+        match verdict {
+            Verdict::Drop(DropReason::Malformed) => self.stats.ip.in_hdr_errors.increment(),
+            Verdict::Drop(DropReason::ForwardingDisabled) => {
+                self.stats.ip.in_addr_errors.increment()
+            }
+            Verdict::Drop(DropReason::Filtered) => self.stats.ip.in_discards.increment(),
+            _ => {}
+        }
+        verdict
+    }
+    /// Takes a locally generated IP packet, deciding which interface it leaves through
+    pub fn output(&mut self, packet: PacketBuffer, now: Instant) -> Verdict {
+        self.stats.ip.out_requests.increment();
+        let buf = &packet[..];
+        if let Some(verdict) = self.apply_filter(Hook::Output, buf, now) {
+            return verdict;
+        }
+        let destination = match buf.first().map(|byte| byte >> 4) {
+            Some(4) if buf.len() >= 20 => IpAddr::V4([buf[16], buf[17], buf[18], buf[19]].into()),
+            Some(6) => match IPv6Header::from_byte_buffer(buf) {
+                Ok(header) => IpAddr::V6(header.destination_addr),
+                Err(_) => return Verdict::Drop(DropReason::Malformed),
+            },
+            _ => return Verdict::Drop(DropReason::Malformed),
+        };
+        let Some(next_hop) = self.route_table.lookup(&destination) else {
+            self.stats.ip.out_no_routes.increment();
+            return Verdict::Drop(DropReason::NoRoute);
+        };
+        let interface = next_hop.interface.name.clone();
+        let mtu = next_hop.interface.mtu;
+        let packets = match destination {
+            IpAddr::V4(_) => self.fragment(packet, mtu),
+            IpAddr::V6(destination) => match self.fragment_ipv6(packet, destination, mtu, now) {
+                Some(packets) => packets,
+                None => return Verdict::Drop(DropReason::CannotFragment),
+            },
+        };
+        Verdict::Transmit { interface, packets }
+    }
+    fn route_ipv4(&mut self, buf: &[u8], now: Instant) -> Verdict {
+        let Ok(packet) = Ipv4Packet::new_checked(buf) else {
+            return Verdict::Drop(DropReason::Malformed);
+        };
+        let header_len = packet.header_len();
+        let Ok(header) = IPHeader::from_byte_buffer(&buf[..header_len]) else {
+            return Verdict::Drop(DropReason::Malformed);
+        };
+        // Replies to translated packets are forwarded back inside
+        let mut translated = buf[..header.total_length as usize].to_vec();
+        let is_translated = self.forwarding
+            && self
+                .nat
+                .as_mut()
+                .is_some_and(|nat| nat.translate_inbound(&mut translated, now));
+        let (buf, header) = match is_translated {
+            true => {
+                let header = IPHeader::from_byte_buffer(&translated[..header_len])
+                    .expect("Translated header is still valid");
+                (translated.as_slice(), header)
+            }
+            false => (buf, header),
+        };
+        let destination = IpAddr::V4(header.destination_addr);
+        if self.route_table.is_local(&destination)
+            || header.destination_addr.is_broadcast()
+            || header.destination_addr.is_multicast()
+        {
+            return self
+                .apply_filter(Hook::Input, buf, now)
+                .unwrap_or(Verdict::Local);
+        }
+        if !self.forwarding {
+            return Verdict::Drop(DropReason::ForwardingDisabled);
+        }
+        if header.time_to_live <= 1 {
+            return self.icmp_error(buf, &header, 11, 0, |data| ICMPBody::TimeExceeded { data });
+        }
+        let Some(next_hop) = self.route_table.lookup(&destination) else {
+            // Network unreachable
+            return self.icmp_error(buf, &header, 3, 0, |data| {
+                ICMPBody::DestinationUnreachable {
+                    next_hop_mtu: 0,
+                    data,
+                }
+            });
+        };
+        let mtu = next_hop.interface.mtu;
+        let interface = next_hop.interface.name.clone();
+        if header.total_length as usize > mtu && header.flags & FLAG_DONT_FRAGMENT != 0 {
+            // Fragmentation needed and DF set
+            return self.icmp_error(buf, &header, 3, 4, |data| {
+                ICMPBody::DestinationUnreachable {
+                    next_hop_mtu: mtu as u16,
+                    data,
+                }
+            });
+        }
+        let mut packet = self.pool.copy(&buf[..header.total_length as usize]);
+        Self::decrement_ttl(&mut packet);
+        // Filtered before source translation, so both directions are seen with inside addresses
+        if let Some(verdict) = self.apply_filter(Hook::Forward, &packet, now) {
+            return verdict;
+        }
+        if let Some(nat) = self.nat.as_mut() {
+            if nat.outside_interface == interface && !nat.translate_outbound(&mut packet, now) {
+                return Verdict::Drop(DropReason::Untranslatable);
+            }
+        }
+        self.stats.ip.forw_datagrams.increment();
+        Verdict::Transmit {
+            interface,
+            packets: self.fragment(packet, mtu),
+        }
+    }
+    fn route_ipv6(&mut self, buf: &[u8], now: Instant) -> Verdict {
+        let Ok(header) = IPv6Header::from_byte_buffer(buf) else {
+            return Verdict::Drop(DropReason::Malformed);
+        };
+        let total_length = IPv6Header::LEN + header.payload_length as usize;
+        if buf.len() < total_length {
+            return Verdict::Drop(DropReason::Malformed);
+        }
+        let destination = IpAddr::V6(header.destination_addr);
+        if self.route_table.is_local(&destination) || header.destination_addr.is_multicast() {
+            return self
+                .apply_filter(Hook::Input, buf, now)
+                .unwrap_or(Verdict::Local);
+        }
+        if !self.forwarding {
+            return Verdict::Drop(DropReason::ForwardingDisabled);
+        }
+        if header.source_addr.is_unicast_link_local()
+            || header.destination_addr.is_unicast_link_local()
+        {
+            // Link local addresses must not leave the link (RFC 4291 2.5.6)
+            return Verdict::Drop(DropReason::Silent);
+        }
+        if header.hop_limit <= 1 {
+            return self.icmpv6_error(buf, &header, 0, |invoking_packet| {
+                ICMPv6Body::TimeExceeded { invoking_packet }
+            });
+        }
+        let Some(next_hop) = self.route_table.lookup(&destination) else {
+            // No route to destination
+            return self.icmpv6_error(buf, &header, 0, |invoking_packet| {
+                ICMPv6Body::DestinationUnreachable { invoking_packet }
+            });
+        };
+        let mtu = next_hop.interface.mtu;
+        let interface = next_hop.interface.name.clone();
+        if total_length > mtu {
+            // Routers never fragment IPv6 packets
+            return self.icmpv6_error(buf, &header, 0, |invoking_packet| {
+                ICMPv6Body::PacketTooBig {
+                    mtu: mtu as u32,
+                    invoking_packet,
+                }
+            });
+        }
+        let mut packet = self.pool.copy(&buf[..total_length]);
+        packet[7] -= 1; // Hop limit, there is no header checksum to update
+        if let Some(verdict) = self.apply_filter(Hook::Forward, &packet, now) {
+            return verdict;
+        }
+        self.stats.ip.forw_datagrams.increment();
+        Verdict::Transmit {
+            interface,
+            packets: vec![packet],
+        }
+    }
+    /// Returns the verdict if the filter does not accept the packet
+    fn apply_filter(&mut self, hook: Hook, buf: &[u8], now: Instant) -> Option<Verdict> {
+        match self.filter.as_mut()?.filter(hook, buf, now) {
+            Action::Accept => None,
+            Action::Drop => Some(Verdict::Drop(DropReason::Filtered)),
+            Action::Reject => Some(self.reject(buf)),
+        }
+    }
+    /// Answers with a RST for TCP, otherwise with a port unreachable
+    fn reject(&self, buf: &[u8]) -> Verdict {
+        if let Some(reset) = filter::tcp_reset(buf) {
+            self.stats.tcp.out_segs.increment();
+            self.stats.tcp.out_rsts.increment();
+            return match self.output_route(&reset) {
+                Some(interface) => Verdict::Transmit {
+                    interface,
+                    packets: vec![reset.into()],
+                },
+                None => Verdict::Drop(DropReason::NoRouteToSource),
+            };
+        }
+        let verdict = match buf[0] >> 4 {
+            4 => match IPHeader::from_byte_buffer(&buf[..(buf[0] & 0b1111) as usize * 4]) {
+                Ok(header) => self.icmp_error(buf, &header, 3, 3, |data| {
+                    ICMPBody::DestinationUnreachable {
+                        next_hop_mtu: 0,
+                        data,
+                    }
+                }),
+                Err(_) => Verdict::Drop(DropReason::Malformed),
+            },
+            _ => match IPv6Header::from_byte_buffer(buf) {
+                Ok(header) => self.icmpv6_error(buf, &header, 4, |invoking_packet| {
+                    ICMPv6Body::DestinationUnreachable { invoking_packet }
+                }),
+                Err(_) => Verdict::Drop(DropReason::Malformed),
+            },
+        };
+        match verdict {
+            // Errors about errors are never sent, the packet is still filtered
+            Verdict::Drop(DropReason::Silent) => Verdict::Drop(DropReason::Filtered),
+            verdict => verdict,
+        }
+    }
+    /// Fragments the raw IPv4 packet to fit the MTU, counting the fragments created
+    /// Packets which fit are sent from the buffer they are in
+    fn fragment(&self, mut packet: PacketBuffer, mtu: usize) -> Vec<PacketBuffer> {
+        let total_length = Ipv4Packet::new_unchecked(&packet[..]).total_length() as usize;
+        if total_length <= mtu {
+            packet.truncate(total_length);
+            return vec![packet];
+        }
+        let packets = ip::fragment(&packet, mtu);
+        self.stats.ip.frag_oks.increment();
+        self.stats.ip.frag_creates.add(packets.len() as u64);
+        packets.into_iter().map(PacketBuffer::from).collect()
+    }
+    /// Fragments the raw IPv6 packet we originate to fit the path MTU, only the source
+    /// fragments IPv6 packets (RFC 8200 5)
+    fn fragment_ipv6(
+        &mut self,
+        packet: PacketBuffer,
+        destination: Ipv6Addr,
+        mtu: usize,
+        now: Instant,
+    ) -> Option<Vec<PacketBuffer>> {
+        // Packets which fit are sent from the buffer they are in
+        if packet.len() <= self.fragmenter.path_mtu(&destination, mtu as u32, now) as usize {
+            return Some(vec![packet]);
+        }
+        let Some(packets) = self.fragmenter.fragment(&packet, mtu, now) else {
+            self.stats.ip.frag_fails.increment();
+            return None;
+        };
+        if packets.len() > 1 {
+            self.stats.ip.frag_oks.increment();
+            self.stats.ip.frag_creates.add(packets.len() as u64);
+        }
+        Some(packets.into_iter().map(PacketBuffer::from).collect())
+    }
+    /// Interface to send a packet we generated out of
+    fn output_route(&self, buf: &[u8]) -> Option<String> {
+        let info = filter::PacketInfo::from_byte_buffer(buf)?;
+        let next_hop = self.route_table.lookup(&info.destination_addr)?;
+        Some(next_hop.interface.name.clone())
+    }
+    /// Decrements the TTL of the raw IPv4 packet, updating the header checksum incrementally
+    pub fn decrement_ttl(packet: &mut [u8]) {
+        Ipv4Packet::new_unchecked(packet).decrement_ttl();
+    }
+    fn icmp_error(
+        &self,
+        buf: &[u8],
+        header: &IPHeader,
+        _type: u8,
+        code: u8,
+        body: impl FnOnce(Vec<u8>) -> ICMPBody,
+    ) -> Verdict {
+        // Never send errors about ICMP errors or non initial fragments (RFC 1122 3.2.2)
+        let header_len = header.ihl as usize * 4;
+        let is_icmp_error =
+            header.protocol == 1 && matches!(buf.get(header_len), Some(3 | 4 | 5 | 11 | 12));
+        if is_icmp_error || header.fragment_offset != 0 {
+            return Verdict::Drop(DropReason::Silent);
+        }
+        let source = IpAddr::V4(header.source_addr);
+        let Some(next_hop) = self.route_table.lookup(&source) else {
+            return Verdict::Drop(DropReason::NoRouteToSource);
+        };
+        let Some(IpAddr::V4(error_source)) = next_hop.source_addr else {
+            return Verdict::Drop(DropReason::NoRouteToSource);
+        };
+        let icmp = ICMP::new(_type, code, body(ICMPBody::original_datagram(buf)));
+        self.stats.icmp.count_out(icmp._type);
+        let ip_header = IPHeader::builder(error_source, header.source_addr).time_to_live(ERROR_TTL);
+        let mut packet = self.pool.get();
+        IPPacket::from_body(ip_header, IPBody::ICMP(icmp)).prepend_to(&mut packet);
+        Verdict::Transmit {
+            interface: next_hop.interface.name.clone(),
+            packets: vec![packet],
+        }
+    }
+    fn icmpv6_error(
+        &self,
+        buf: &[u8],
+        header: &IPv6Header,
+        code: u8,
+        body: impl FnOnce(Vec<u8>) -> ICMPv6Body,
+    ) -> Verdict {
+        let is_icmp_error = header.next_header == icmpv6::PROTOCOL
+            && matches!(buf.get(IPv6Header::LEN), Some(_type) if *_type < 128);
+        if is_icmp_error || header.source_addr.is_unspecified() {
+            return Verdict::Drop(DropReason::Silent);
+        }
+        let source = IpAddr::V6(header.source_addr);
+        let Some(next_hop) = self.route_table.lookup(&source) else {
+            return Verdict::Drop(DropReason::NoRouteToSource);
+        };
+        let Some(IpAddr::V6(error_source)) = next_hop.source_addr else {
+            return Verdict::Drop(DropReason::NoRouteToSource);
+        };
+        let icmp = ICMPv6::new(
+            &error_source,
+            &header.source_addr,
+            code,
+            body(ICMPv6Body::invoking_packet(buf)),
+        );
+        self.stats.icmpv6.count_out(icmp._type);
+        let ip_header = IPv6Header::new(
+            0,
+            0,
+            0,
+            icmpv6::PROTOCOL,
+            ERROR_TTL,
+            error_source,
+            header.source_addr,
+        );
+        let mut packet = self.pool.get();
+        IPv6Packet::new(
+            ip_header,
+            Vec::new(),
+            icmpv6::PROTOCOL,
+            IPBody::ICMPv6(icmp),
+        )
+        .prepend_to(&mut packet);
+        Verdict::Transmit {
+            interface: next_hop.interface.name.clone(),
+            packets: vec![packet],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::{
+        device::{frame_ip_packet, NetDevice, VirtualDevice, PACKET_INFO_LEN},
+        protocol::{TCPControlBits, ICMP},
+        route::{Interface, InterfaceAddress},
+    };
+
+    /// A router with its devices, forwarding whatever it receives
+    struct Node {
+        router: Router,
+        devices: Vec<VirtualDevice>,
+    }
+    impl Node {
+        fn new(interfaces: Vec<(VirtualDevice, &str, u8)>, forwarding: bool) -> Self {
+            let mut route_table = RouteTable::new();
+            let mut devices = Vec::new();
+            for (device, addr, prefix_length) in interfaces {
+                route_table.add_interface(Interface::new(
+                    device.name(),
+                    device.mtu(),
+                    vec![InterfaceAddress::new(addr.parse().unwrap(), prefix_length)],
+                ));
+                devices.push(device);
+            }
+            Self {
+                router: Router::new(route_table, forwarding),
+                devices,
+            }
+        }
+        /// Processes every waiting frame, returning the verdicts
+        fn step(&mut self) -> Vec<Verdict> {
+            let mut verdicts = Vec::new();
+            let mut buf = [0u8; 2048];
+            for index in 0..self.devices.len() {
+                while let Ok(len) = self.devices[index].recv(&mut buf) {
+                    let verdict = self
+                        .router
+                        .route(&buf[PACKET_INFO_LEN..len], Instant::now());
+                    if let Verdict::Transmit { interface, packets } = &verdict {
+                        let device = self
+                            .devices
+                            .iter_mut()
+                            .find(|device| device.name() == interface)
+                            .unwrap();
+                        for packet in packets {
+                            device.send(&frame_ip_packet(packet)).unwrap();
+                        }
+                    }
+                    verdicts.push(verdict);
+                }
+            }
+            verdicts
+        }
+    }
+
+    /// host_a (10.0.1.2) - r1 - (10.0.2.0/24, mtu 600) - r2 - host_b (10.0.3.2)
+    fn topology() -> (VirtualDevice, Node, Node, VirtualDevice) {
+        let (host_a, r1_eth0) = VirtualDevice::pair("host_a", "eth0", 1500);
+        let (r1_eth1, r2_eth0) = VirtualDevice::pair("eth1", "eth0", 600);
+        let (r2_eth1, host_b) = VirtualDevice::pair("eth1", "host_b", 1500);
+        let mut r1 = Node::new(
+            vec![(r1_eth0, "10.0.1.1", 24), (r1_eth1, "10.0.2.1", 24)],
+            true,
+        );
+        r1.router
+            .route_table
+            .add_default_route("10.0.2.2".parse().unwrap(), "eth1", 0);
+        r1.router.route_table.add_address(
+            "eth0",
+            InterfaceAddress::new("fd00:1::1".parse().unwrap(), 64),
+        );
+        r1.router.route_table.add_address(
+            "eth1",
+            InterfaceAddress::new("fd00:2::1".parse().unwrap(), 64),
+        );
+        let mut r2 = Node::new(
+            vec![(r2_eth0, "10.0.2.2", 24), (r2_eth1, "10.0.3.1", 24)],
+            true,
+        );
+        r2.router
+            .route_table
+            .add_default_route("10.0.2.1".parse().unwrap(), "eth0", 0);
+        (host_a, r1, r2, host_b)
+    }
+
+    fn echo(ttl: u8, flags: u8, data_len: usize) -> Vec<u8> {
+        let ip_body = IPBody::ICMP(ICMP::new(
+            8,
+            0,
+            ICMPBody::Echo {
+                identifier: 0x1,
+                sequence_number: 0x1,
+                data: vec![0xab; data_len],
+            },
+        ));
+        let ip_header = IPHeader::from_body(
+            4,
+            0,
+            0x1234,
+            flags,
+            0,
+            ttl,
+            1,
+            Ipv4Addr::new(10, 0, 1, 2),
+            Ipv4Addr::new(10, 0, 3, 2),
+            None,
+            ip_body.len() as u16,
+        );
+        IPPacket::new(ip_header, ip_body).to_byte_buffer()
+    }
+
+    fn recv_all(device: &mut VirtualDevice) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut buf = [0u8; 2048];
+        while let Ok(len) = device.recv(&mut buf) {
+            packets.push(buf[PACKET_INFO_LEN..len].to_vec());
+        }
+        packets
+    }
+
+    #[test]
+    fn forwards_across_two_routers() {
+        let (mut host_a, mut r1, mut r2, mut host_b) = topology();
+        host_a.send(&frame_ip_packet(&echo(64, 0, 56))).unwrap();
+        r1.step();
+        r2.step();
+        let packets = recv_all(&mut host_b);
+        assert_eq!(packets.len(), 1);
+        let packet = IPPacket::from_byte_buffer(&packets[0]).unwrap();
+        assert_eq!(packet.header.time_to_live, 62);
+    }
+
+    #[test]
+    fn local_and_disabled_forwarding() {
+        let (_, mut r1, _, _) = topology();
+        let mut buf = echo(64, 0, 8);
+        assert_eq!(
+            r1.router.route(&buf, Instant::now()),
+            Verdict::Transmit {
+                interface: "eth1".to_string(),
+                packets: {
+                    let mut packet = buf.clone();
+                    Router::decrement_ttl(&mut packet);
+                    vec![packet.into()]
+                }
+            }
+        );
+        let mut router = Router::new(r1.router.route_table, false);
+        assert_eq!(
+            router.route(&buf, Instant::now()),
+            Verdict::Drop(DropReason::ForwardingDisabled)
+        );
+        // Addressed to the router
+        let mut packet = Ipv4Packet::new_checked(&mut buf[..]).unwrap();
+        packet.set_destination_addr(Ipv4Addr::new(10, 0, 2, 1));
+        packet.fill_checksum();
+        assert!(packet.verify_checksum());
+        assert_eq!(router.route(&buf, Instant::now()), Verdict::Local);
+    }
+
+    #[test]
+    fn nat_on_outside_interface() {
+        let (mut host_a, mut r1, mut r2, mut host_b) = topology();
+        r1.router.nat = Some(Nat::new("eth1", Ipv4Addr::new(10, 0, 2, 1)));
+        host_a.send(&frame_ip_packet(&echo(64, 0, 56))).unwrap();
+        r1.step();
+        r2.step();
+        let packets = recv_all(&mut host_b);
+        let request = IPPacket::from_byte_buffer(&packets[0]).unwrap();
+        assert_eq!(request.header.source_addr, Ipv4Addr::new(10, 0, 2, 1));
+        let IPBody::ICMP(ICMP {
+            body: ICMPBody::Echo {
+                identifier, data, ..
+            },
+            ..
+        }) = request.body
+        else {
+            panic!("Should be an echo request");
+        };
+        assert_ne!(identifier, 0x1);
+
+        let ip_body = IPBody::ICMP(ICMP::new(
+            0,
+            0,
+            ICMPBody::EchoReply {
+                identifier,
+                sequence_number: 0x1,
+                data,
+            },
+        ));
+        let ip_header = IPHeader::from_body(
+            4,
+            0,
+            0x4321,
+            0,
+            0,
+            64,
+            1,
+            request.header.destination_addr,
+            request.header.source_addr,
+            None,
+            ip_body.len() as u16,
+        );
+        let reply = IPPacket::new(ip_header, ip_body).to_byte_buffer();
+        host_b.send(&frame_ip_packet(&reply)).unwrap();
+        r2.step();
+        r1.step();
+        let packets = recv_all(&mut host_a);
+        let reply = IPPacket::from_byte_buffer(&packets[0]).unwrap();
+        assert_eq!(reply.header.destination_addr, Ipv4Addr::new(10, 0, 1, 2));
+        assert!(matches!(
+            reply.body,
+            IPBody::ICMP(ICMP {
+                body: ICMPBody::EchoReply {
+                    identifier: 0x1,
+                    ..
+                },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn forward_chain_rejects_with_reset() {
+        let (mut host_a, mut r1, mut r2, mut host_b) = topology();
+        let mut filter = Filter::new();
+        filter.forward.append(filter::Rule {
+            protocol: Some(6),
+            destination_ports: Some(80..=80),
+            ..filter::Rule::new(Action::Reject)
+        });
+        r1.router.filter = Some(filter);
+        let segment = |destination_port| {
+            let source = Ipv4Addr::new(10, 0, 1, 2);
+            let destination = Ipv4Addr::new(10, 0, 3, 2);
+            let ip_body = IPBody::TCP(
+                crate::protocol::TCP::new(
+                    &IpAddr::V4(source),
+                    &IpAddr::V4(destination),
+                    6,
+                    40000,
+                    destination_port,
+                    100,
+                    0,
+                    0,
+                    TCPControlBits::SYN,
+                    64240,
+                    0,
+                    None,
+                    Vec::new(),
+                )
+                .unwrap(),
+            );
+            let ip_header = IPHeader::from_body(
+                4,
+                0,
+                1,
+                0,
+                0,
+                64,
+                6,
+                source,
+                destination,
+                None,
+                ip_body.len() as u16,
+            );
+            frame_ip_packet(&IPPacket::new(ip_header, ip_body).to_byte_buffer())
+        };
+        host_a.send(&segment(80)).unwrap();
+        host_a.send(&segment(22)).unwrap();
+        assert!(matches!(
+            r1.step()[..],
+            [Verdict::Transmit { .. }, Verdict::Transmit { .. }]
+        ));
+        r2.step();
+        assert_eq!(recv_all(&mut host_b).len(), 1);
+        let packets = recv_all(&mut host_a);
+        assert_eq!(packets.len(), 1);
+        let reset = IPPacket::from_byte_buffer(&packets[0]).unwrap();
+        assert_eq!(reset.header.source_addr, Ipv4Addr::new(10, 0, 3, 2));
+        let IPBody::TCP(tcp) = reset.body else {
+            panic!("Should be a RST");
+        };
+        assert_eq!(tcp.control_bits, TCPControlBits::RST | TCPControlBits::ACK);
+        assert_eq!(tcp.acknowledgment_number, 101);
+    }
+
+    #[test]
+    fn time_exceeded_when_ttl_runs_out() {
+        let (mut host_a, mut r1, mut r2, mut host_b) = topology();
+        host_a.send(&frame_ip_packet(&echo(2, 0, 56))).unwrap();
+        r1.step();
+        let verdicts = r2.step();
+        assert!(matches!(&verdicts[0], Verdict::Transmit { interface, .. } if interface == "eth0"));
+        assert!(recv_all(&mut host_b).is_empty());
+        r1.step();
+        let packets = recv_all(&mut host_a);
+        assert_eq!(packets.len(), 1);
+        let packet = IPPacket::from_byte_buffer(&packets[0]).unwrap();
+        assert_eq!(packet.header.source_addr, Ipv4Addr::new(10, 0, 2, 2));
+        match packet.body {
+            IPBody::ICMP(icmp) => {
+                assert_eq!(icmp._type, 11);
+                assert!(matches!(icmp.body, ICMPBody::TimeExceeded { data } if data.len() == 28));
+            }
+            _ => panic!("Should be an ICMP message"),
+        }
+    }
+
+    #[test]
+    fn fragments_for_smaller_egress_mtu() {
+        let (mut host_a, mut r1, mut r2, mut host_b) = topology();
+        host_a.send(&frame_ip_packet(&echo(64, 0, 1000))).unwrap();
+        r1.step();
+        r2.step();
+        let packets = recv_all(&mut host_b);
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|packet| packet.len() <= 600));
+        let data_len: usize = packets.iter().map(|packet| packet.len() - 20).sum();
+        assert_eq!(data_len, 8 + 1000);
+    }
+
+    #[test]
+    fn fragmentation_needed_with_dont_fragment() {
+        let (mut host_a, mut r1, _, _) = topology();
+        host_a
+            .send(&frame_ip_packet(&echo(64, FLAG_DONT_FRAGMENT, 1000)))
+            .unwrap();
+        r1.step();
+        let packets = recv_all(&mut host_a);
+        let packet = IPPacket::from_byte_buffer(&packets[0]).unwrap();
+        match packet.body {
+            IPBody::ICMP(icmp) => {
+                assert_eq!((icmp._type, icmp.code), (3, 4));
+                assert!(matches!(
+                    icmp.body,
+                    ICMPBody::DestinationUnreachable {
+                        next_hop_mtu: 600,
+                        ..
+                    }
+                ));
+            }
+            _ => panic!("Should be an ICMP message"),
+        }
+    }
+
+    #[test]
+    fn ipv6_hop_limit_and_packet_too_big() {
+        let (_, r1, _, _) = topology();
+        let mut router = r1.router;
+        let source: Ipv6Addr = "fd00:1::2".parse().unwrap();
+        let destination: Ipv6Addr = "fd00:2::2".parse().unwrap();
+        let packet = |hop_limit: u8, data_len: usize| {
+            let icmp = ICMPv6::new(
+                &source,
+                &destination,
+                0,
+                ICMPv6Body::EchoRequest {
+                    identifier: 1,
+                    sequence_number: 1,
+                    data: vec![0; data_len],
+                },
+            );
+            IPv6Packet::new(
+                IPv6Header::new(0, 0, 0, 0, hop_limit, source, destination),
+                Vec::new(),
+                icmpv6::PROTOCOL,
+                IPBody::ICMPv6(icmp),
+            )
+            .to_byte_buffer()
+        };
+        match router.route(&packet(64, 100), Instant::now()) {
+            Verdict::Transmit { interface, packets } => {
+                assert_eq!(interface, "eth1");
+                assert_eq!(packets[0][7], 63);
+            }
+            verdict => panic!("Unexpected {:?}", verdict),
+        }
+        for (buf, expected_type) in [(packet(1, 100), 3), (packet(64, 700), 2)] {
+            match router.route(&buf, Instant::now()) {
+                Verdict::Transmit { interface, packets } => {
+                    assert_eq!(interface, "eth0");
+                    let reply = IPv6Packet::from_byte_buffer(&packets[0]).unwrap();
+                    assert_eq!(reply.header.destination_addr, source);
+                    assert!(
+                        matches!(reply.body, IPBody::ICMPv6(icmp) if icmp._type == expected_type)
+                    );
+                }
+                verdict => panic!("Unexpected {:?}", verdict),
+            }
+        }
+    }
+}
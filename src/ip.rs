@@ -1,7 +1,13 @@
-use std::net::Ipv4Addr;
+use std::{fmt, net::Ipv4Addr};
 
-use crate::{checksum, protocol::IPBody};
+use crate::{
+    buffer::PacketBuffer,
+    checksum,
+    protocol::{indent, protocol_name, IPBody},
+};
 
+/// Owned IPv4 packet, parsing copies the options and body out of the buffer
+/// Forwarding, NAT and filtering work on the buffer through `Ipv4Packet` instead
 pub struct IPPacket {
     pub header: IPHeader,
     pub body: IPBody,
@@ -11,17 +17,160 @@ impl IPPacket {
         Self { header, body }
     }
     pub fn from_byte_buffer(buf: &[u8]) -> Result<Self, IPPacketError> {
-        let ihl = IPHeader::get_ihl(buf[0]);
-        let header = IPHeader::from_byte_buffer(&buf[..(ihl * 4) as usize])?;
-        let body = IPBody::from_byte_buffer(&header, &buf[(ihl * 4) as usize..])?;
+        let packet = Ipv4Packet::new_checked(buf)?;
+        let header_len = packet.header_len();
+        let header = IPHeader::from_byte_buffer(&buf[..header_len])?;
+        let body = IPBody::from_byte_buffer(&header, &buf[header_len..])?;
         Ok(Self { header, body })
     }
+    /// Allocates a new buffer, `prepend_to` writes into a pooled one
     pub fn to_byte_buffer(&self) -> Vec<u8> {
         let mut buf = self.header.to_byte_buffer();
-        buf.append(&mut self.body.to_byte_buffer());
+        buf.extend_from_slice(&self.body.to_byte_buffer());
         buf
     }
+    /// Wraps `body` in the header being built, taking the protocol and length from the body
+    pub fn from_body(header: IPHeaderBuilder, body: IPBody) -> Self {
+        let header = header.protocol(body.protocol()).build(body.len() as u16);
+        Self::new(header, body)
+    }
+    /// Answer to `incoming`, echoing its header with the addresses swapped
+    pub fn reply_to(incoming: &IPPacket, body: IPBody) -> Self {
+        let header = &incoming.header;
+        let mut reply = IPHeader::builder(header.destination_addr, header.source_addr)
+            // Whether the reply is ECN capable is up to its transport
+            .type_of_service(ECN::NotECT.apply(header.type_of_service))
+            .identification(header.identification)
+            .flags(header.flags)
+            .fragment_offset(header.fragment_offset)
+            .time_to_live(header.time_to_live);
+        if let Some(options) = &header.options {
+            reply = reply.options(options.clone());
+        }
+        Self::from_body(reply, body)
+    }
+    /// Puts the packet in front of the bytes already in `buf`, each layer writing its header
+    /// into the headroom
+    pub fn prepend_to(&self, buf: &mut PacketBuffer) {
+        self.body.prepend_to(buf);
+        self.header.write(buf.prepend(self.header.len()));
+    }
+}
+/// TTL of headers built without one
+pub const DEFAULT_TTL: u8 = 64;
+/// Don't Fragment and More Fragments bits of `IPHeader.flags`
+pub const FLAG_DONT_FRAGMENT: u8 = 0b010;
+pub const FLAG_MORE_FRAGMENTS: u8 = 0b001;
+
+/// ECN codepoint, the low 2 bits of the IPv4 type of service and the IPv6 traffic class, the
+/// upper 6 bits being the DSCP (RFC 3168 5, RFC 2474 3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ECN {
+    /// Not ECN-Capable Transport
+    NotECT = 0b00,
+    ECT1 = 0b01,
+    ECT0 = 0b10,
+    /// Congestion Experienced, set by a router instead of dropping an ECN capable packet
+    CE = 0b11,
+}
+impl ECN {
+    /// Codepoint of a type of service or traffic class byte
+    pub fn of(type_of_service: u8) -> Self {
+        match type_of_service & 0b11 {
+            0b00 => Self::NotECT,
+            0b01 => Self::ECT1,
+            0b10 => Self::ECT0,
+            _ => Self::CE,
+        }
+    }
+    /// `type_of_service` with its codepoint replaced by this one
+    pub fn apply(self, type_of_service: u8) -> u8 {
+        (type_of_service & !0b11) | self as u8
+    }
+    pub fn is_ect(self) -> bool {
+        matches!(self, Self::ECT0 | Self::ECT1)
+    }
 }
+
+/// Sets the ECN codepoint of an ECN capable IPv4 or IPv6 packet to CE, returning whether it was
+/// ECN capable, otherwise it has to be dropped to signal congestion (RFC 3168 5)
+pub fn mark_ce(ip_packet: &mut [u8]) -> bool {
+    match ip_packet.first().map(|byte| byte >> 4) {
+        Some(4) => match Ipv4Packet::new_checked(&mut *ip_packet) {
+            Ok(mut packet) if packet.ecn().is_ect() => {
+                packet.set_ecn(ECN::CE);
+                true
+            }
+            _ => false,
+        },
+        // The ECN bits of the traffic class are in the second byte
+        Some(6) if ip_packet.len() >= 2 && ECN::of(ip_packet[1] >> 4).is_ect() => {
+            ip_packet[1] |= (ECN::CE as u8) << 4;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Splits the raw bytes of an IPv4 packet into fragments that fit in the MTU (RFC 791)
+/// Only options with the copied flag set are repeated after the first fragment
+pub fn fragment(buf: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+    let total_length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if total_length <= mtu {
+        return vec![buf[..total_length].to_vec()];
+    }
+    let header_len = IPHeader::get_ihl(buf[0]) as usize * 4;
+    let flags = IPHeader::get_flag(buf[6]);
+    let fragment_offset = IPHeader::get_fragment_offset([buf[6], buf[7]]) as usize;
+    let data = &buf[header_len..total_length];
+
+    let mut copied_options = Vec::new();
+    let mut index = 20;
+    while index < header_len {
+        let option_type = buf[index];
+        let option_len = match option_type {
+            0 => break, // End of option list
+            1 => 1,     // No operation
+            _ => (buf[index + 1] as usize).max(2),
+        };
+        if option_type >> 7 == 1 {
+            copied_options.extend_from_slice(&buf[index..(index + option_len).min(header_len)]);
+        }
+        index += option_len;
+    }
+    copied_options.resize(copied_options.len().div_ceil(4) * 4, 0x0);
+
+    let mut fragments = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let mut header = if offset == 0 {
+            buf[..header_len].to_vec()
+        } else {
+            let mut header = buf[..20].to_vec();
+            header.extend_from_slice(&copied_options);
+            header[0] = (4 << 4) + (header.len() / 4) as u8;
+            header
+        };
+        // Every fragment except the last carries a multiple of 8 bytes
+        let max_data_len = (mtu - header.len()) / 8 * 8;
+        let end = (offset + max_data_len).min(data.len());
+        let more_fragments = end < data.len() || flags & FLAG_MORE_FRAGMENTS != 0;
+        let new_flags = (flags & FLAG_DONT_FRAGMENT) + more_fragments as u8;
+        let new_offset = (fragment_offset + offset / 8) as u16;
+        let length = (header.len() + end - offset) as u16;
+        header[2..4].copy_from_slice(&length.to_be_bytes());
+        header[6] = (new_flags << 5) + (new_offset >> 8) as u8;
+        header[7] = (new_offset & 0xFF) as u8;
+        header[10..12].copy_from_slice(&[0x0, 0x0]);
+        let header_checksum = !checksum::ones_complement_sum_byte_buffer(&header);
+        header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+        header.extend_from_slice(&data[offset..end]);
+        fragments.push(header);
+        offset = end;
+    }
+    fragments
+}
+
 #[derive(Debug)]
 pub struct IPPacketError {
     kind: IPPacketErrorKind,
@@ -30,12 +179,32 @@ impl IPPacketError {
     pub fn new(kind: IPPacketErrorKind) -> Self {
         Self { kind }
     }
+    pub fn kind(&self) -> &IPPacketErrorKind {
+        &self.kind
+    }
 }
 #[derive(Debug)]
 pub enum IPPacketErrorKind {
     IPHeaderChecksumError,
     ICMPChecksumError,
+    /// Shorter than the header of its type
+    ICMPLengthError,
+    ICMPv6ChecksumError,
+    /// Shorter than its type or the lengths in it require
+    ICMPv6LengthError,
+    /// The buffer is shorter than the header or the total length
+    IPLengthError,
     TCPChecksumError,
+    /// The buffer is shorter than the data offset
+    TCPLengthError,
+    /// More than the 40 bytes of options the data offset has room for
+    TCPOptionsTooLong,
+    UDPChecksumError,
+    /// The buffer is shorter than the UDP length, or the length than the header
+    UDPLengthError,
+    IPv6HeaderError,
+    /// The source and destination addresses are of different IP versions
+    AddressFamilyMismatch,
     NotImplementedYet,
 }
 
@@ -93,6 +262,7 @@ impl IPHeader {
         ip_header.checksum = header_checksum;
         ip_header
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn from_body(
         version: u8, // 4 bits
         type_of_service: u8,
@@ -107,7 +277,7 @@ impl IPHeader {
         body_length: u16,
     ) -> Self {
         let ihl: u8 = if let Some(option) = &options {
-            5 + (option.len() as u8 + 4 - option.len() as u8 % 4) / 4
+            5 + option.len().div_ceil(4) as u8
         } else {
             5
         };
@@ -156,24 +326,34 @@ impl IPHeader {
     }
     /// Creates the byte buffer using the values in the header
     fn to_byte_buffer(&self) -> Vec<u8> {
-        let mut buf = [((self.version << 4) + self.ihl), self.type_of_service].to_vec();
-        buf.append(&mut self.total_length.to_be_bytes().to_vec());
-        buf.append(&mut self.identification.to_be_bytes().to_vec());
-        buf.push((self.flags << 5) + (self.fragment_offset >> 8) as u8);
-        buf.push((self.fragment_offset & 0xFF) as u8);
-        buf.push(self.time_to_live);
-        buf.push(self.protocol);
-        buf.append(&mut self.checksum.to_be_bytes().to_vec());
-        buf.append(&mut self.source_addr.to_bits().to_be_bytes().to_vec());
-        buf.append(&mut self.destination_addr.to_bits().to_be_bytes().to_vec());
-        if let Some(options) = &self.options {
-            buf.append(&mut options.clone());
-            if options.len() % 4 != 0 {
-                buf.append(&mut vec![0u8; 4 - options.len() % 4]);
-            }
-        }
+        let mut buf = vec![0x0; self.len()];
+        self.write(&mut buf);
         buf
     }
+    /// Size of the header with its options padded to 32 bits
+    fn len(&self) -> usize {
+        20 + self
+            .options
+            .as_ref()
+            .map_or(0, |options| options.len().div_ceil(4) * 4)
+    }
+    /// Writes the header into `out`, which is `len` bytes long
+    fn write(&self, out: &mut [u8]) {
+        out[0] = (self.version << 4) + self.ihl;
+        out[1] = self.type_of_service;
+        out[2..4].copy_from_slice(&self.total_length.to_be_bytes());
+        out[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        out[6] = (self.flags << 5) + (self.fragment_offset >> 8) as u8;
+        out[7] = (self.fragment_offset & 0xFF) as u8;
+        out[8] = self.time_to_live;
+        out[9] = self.protocol;
+        out[10..12].copy_from_slice(&self.checksum.to_be_bytes());
+        out[12..16].copy_from_slice(&self.source_addr.octets());
+        out[16..20].copy_from_slice(&self.destination_addr.octets());
+        let options = self.options.as_deref().unwrap_or_default();
+        out[20..20 + options.len()].copy_from_slice(options);
+        out[20 + options.len()..].fill(0x0);
+    }
 
     fn get_version(x: u8) -> u8 {
         // Extracts from the first byte
@@ -191,6 +371,332 @@ impl IPHeader {
         x[0] &= 0b00011111;
         u16::from_be_bytes(x)
     }
+    /// Builder defaulting to no type of service, identification or flags, TTL 64 and no options
+    pub fn builder(source_addr: Ipv4Addr, destination_addr: Ipv4Addr) -> IPHeaderBuilder {
+        IPHeaderBuilder {
+            type_of_service: 0,
+            identification: 0,
+            flags: 0,
+            fragment_offset: 0,
+            time_to_live: DEFAULT_TTL,
+            protocol: 0,
+            source_addr,
+            destination_addr,
+            options: None,
+        }
+    }
+    /// Differentiated services codepoint, the upper 6 bits of the type of service
+    pub fn dscp(&self) -> u8 {
+        self.type_of_service >> 2
+    }
+    pub fn ecn(&self) -> ECN {
+        ECN::of(self.type_of_service)
+    }
+}
+
+/// Builds an `IPHeader`, computing the IHL, total length and checksum
+#[derive(Debug, Clone)]
+pub struct IPHeaderBuilder {
+    type_of_service: u8,
+    identification: u16,
+    flags: u8,
+    fragment_offset: u16,
+    time_to_live: u8,
+    protocol: u8,
+    source_addr: Ipv4Addr,
+    destination_addr: Ipv4Addr,
+    options: Option<Vec<u8>>,
+}
+impl IPHeaderBuilder {
+    pub fn type_of_service(mut self, type_of_service: u8) -> Self {
+        self.type_of_service = type_of_service;
+        self
+    }
+    /// Sets the ECN codepoint, keeping the DSCP
+    pub fn ecn(mut self, ecn: ECN) -> Self {
+        self.type_of_service = ecn.apply(self.type_of_service);
+        self
+    }
+    pub fn identification(mut self, identification: u16) -> Self {
+        self.identification = identification;
+        self
+    }
+    /// `FLAG_DONT_FRAGMENT` and `FLAG_MORE_FRAGMENTS`
+    pub fn flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+    pub fn fragment_offset(mut self, fragment_offset: u16) -> Self {
+        self.fragment_offset = fragment_offset;
+        self
+    }
+    pub fn time_to_live(mut self, time_to_live: u8) -> Self {
+        self.time_to_live = time_to_live;
+        self
+    }
+    /// Not needed when the header is built by `IPPacket::from_body`, which takes it from the body
+    pub fn protocol(mut self, protocol: u8) -> Self {
+        self.protocol = protocol;
+        self
+    }
+    /// Raw options, padded to 32 bits
+    pub fn options(mut self, options: Vec<u8>) -> Self {
+        self.options = Some(options);
+        self
+    }
+    /// Header in front of a body of `body_length` bytes
+    pub fn build(self, body_length: u16) -> IPHeader {
+        IPHeader::from_body(
+            4,
+            self.type_of_service,
+            self.identification,
+            self.flags,
+            self.fragment_offset,
+            self.time_to_live,
+            self.protocol,
+            self.source_addr,
+            self.destination_addr,
+            self.options,
+            body_length,
+        )
+    }
+}
+
+/// Borrowed view of a raw IPv4 packet, reading and writing the header fields in place without
+/// parsing it into an `IPPacket`
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Packet<T> {
+    buffer: T,
+}
+impl<T: AsRef<[u8]>> Ipv4Packet<T> {
+    /// Field accesses panic if the buffer is shorter than the header
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+    /// Checks the buffer holds the whole header and the total length
+    pub fn new_checked(buffer: T) -> Result<Self, IPPacketError> {
+        let packet = Self::new_unchecked(buffer);
+        let len = packet.buffer.as_ref().len();
+        if len < 20
+            || packet.version() != 4
+            || packet.header_len() < 20
+            || packet.header_len() > packet.total_length() as usize
+            || packet.total_length() as usize > len
+        {
+            return Err(IPPacketError::new(IPPacketErrorKind::IPLengthError));
+        }
+        Ok(packet)
+    }
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+    fn field(&self, offset: usize) -> u16 {
+        let buf = self.buffer.as_ref();
+        u16::from_be_bytes([buf[offset], buf[offset + 1]])
+    }
+    pub fn version(&self) -> u8 {
+        IPHeader::get_version(self.buffer.as_ref()[0])
+    }
+    pub fn ihl(&self) -> u8 {
+        IPHeader::get_ihl(self.buffer.as_ref()[0])
+    }
+    pub fn header_len(&self) -> usize {
+        self.ihl() as usize * 4
+    }
+    pub fn type_of_service(&self) -> u8 {
+        self.buffer.as_ref()[1]
+    }
+    pub fn ecn(&self) -> ECN {
+        ECN::of(self.type_of_service())
+    }
+    pub fn total_length(&self) -> u16 {
+        self.field(2)
+    }
+    pub fn identification(&self) -> u16 {
+        self.field(4)
+    }
+    pub fn flags(&self) -> u8 {
+        IPHeader::get_flag(self.buffer.as_ref()[6])
+    }
+    pub fn fragment_offset(&self) -> u16 {
+        self.field(6) & 0x1FFF
+    }
+    pub fn time_to_live(&self) -> u8 {
+        self.buffer.as_ref()[8]
+    }
+    pub fn protocol(&self) -> u8 {
+        self.buffer.as_ref()[9]
+    }
+    pub fn checksum(&self) -> u16 {
+        self.field(10)
+    }
+    pub fn source_addr(&self) -> Ipv4Addr {
+        let buf = self.buffer.as_ref();
+        Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15])
+    }
+    pub fn destination_addr(&self) -> Ipv4Addr {
+        let buf = self.buffer.as_ref();
+        Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19])
+    }
+    pub fn options(&self) -> &[u8] {
+        &self.buffer.as_ref()[20..self.header_len()]
+    }
+    /// The body up to the total length, without any link layer padding
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len()..self.total_length() as usize]
+    }
+    pub fn verify_checksum(&self) -> bool {
+        checksum::ones_complement_sum_byte_buffer(&self.buffer.as_ref()[..self.header_len()])
+            == 0xFFFF
+    }
+}
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv4Packet<T> {
+    fn set_field(&mut self, offset: usize, value: u16) {
+        self.buffer.as_mut()[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+    pub fn set_type_of_service(&mut self, type_of_service: u8) {
+        self.buffer.as_mut()[1] = type_of_service;
+    }
+    pub fn set_total_length(&mut self, total_length: u16) {
+        self.set_field(2, total_length);
+    }
+    pub fn set_identification(&mut self, identification: u16) {
+        self.set_field(4, identification);
+    }
+    pub fn set_flags(&mut self, flags: u8) {
+        let buf = self.buffer.as_mut();
+        buf[6] = (flags << 5) | (buf[6] & 0b11111);
+    }
+    pub fn set_fragment_offset(&mut self, fragment_offset: u16) {
+        let flags = self.flags();
+        self.set_field(6, ((flags as u16) << 13) | (fragment_offset & 0x1FFF));
+    }
+    pub fn set_time_to_live(&mut self, time_to_live: u8) {
+        self.buffer.as_mut()[8] = time_to_live;
+    }
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.buffer.as_mut()[9] = protocol;
+    }
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.set_field(10, checksum);
+    }
+    pub fn set_source_addr(&mut self, addr: Ipv4Addr) {
+        self.buffer.as_mut()[12..16].copy_from_slice(&addr.octets());
+    }
+    pub fn set_destination_addr(&mut self, addr: Ipv4Addr) {
+        self.buffer.as_mut()[16..20].copy_from_slice(&addr.octets());
+    }
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let (start, end) = (self.header_len(), self.total_length() as usize);
+        &mut self.buffer.as_mut()[start..end]
+    }
+    /// Recalculates the header checksum after fields were set
+    pub fn fill_checksum(&mut self) {
+        self.set_checksum(0x0);
+        let header_len = self.header_len();
+        let header_checksum =
+            !checksum::ones_complement_sum_byte_buffer(&self.buffer.as_ref()[..header_len]);
+        self.set_checksum(header_checksum);
+    }
+    /// Sets the ECN codepoint, updating the header checksum incrementally (RFC 1624)
+    pub fn set_ecn(&mut self, ecn: ECN) {
+        let old = self.field(0);
+        self.set_type_of_service(ecn.apply(self.type_of_service()));
+        let header_checksum = checksum::incremental_update(self.checksum(), old, self.field(0));
+        self.set_checksum(header_checksum);
+    }
+    /// Decrements the TTL, updating the header checksum incrementally (RFC 1624)
+    /// A TTL of 0 stays 0, the caller drops the packet before it gets there
+    pub fn decrement_ttl(&mut self) {
+        let old = self.field(8);
+        self.set_time_to_live(self.time_to_live().saturating_sub(1));
+        let header_checksum = checksum::incremental_update(self.checksum(), old, self.field(8));
+        self.set_checksum(header_checksum);
+    }
+}
+
+impl fmt::Display for IPPacket {
+    /// `IP 192.168.0.1.48458 > 192.168.0.2.80: Flags [S], ...`, or the tree of every layer with `{:#}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            return write!(
+                f,
+                "{:#}\n{}",
+                self.header,
+                indent(&format!("{:#}", self.body))
+            );
+        }
+        match self.body.ports() {
+            Some((source_port, destination_port)) => write!(
+                f,
+                "IP {}.{} > {}.{}: {}",
+                self.header.source_addr,
+                source_port,
+                self.header.destination_addr,
+                destination_port,
+                self.body
+            ),
+            None => write!(
+                f,
+                "IP {} > {}: {}",
+                self.header.source_addr, self.header.destination_addr, self.body
+            ),
+        }
+    }
+}
+impl fmt::Display for IPHeader {
+    /// `192.168.0.1 > 192.168.0.2: tos 0x0, ttl 64, ...`, or a tree of every field with `{:#}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = match (
+            self.flags & FLAG_DONT_FRAGMENT != 0,
+            self.flags & FLAG_MORE_FRAGMENTS != 0,
+        ) {
+            (false, false) => "none",
+            (true, false) => "DF",
+            (false, true) => "+",
+            (true, true) => "DF+",
+        };
+        let protocol = protocol_name(self.protocol);
+        if !f.alternate() {
+            return write!(
+                f,
+                "{} > {}: tos {:#x}, ttl {}, id {}, offset {}, flags [{}], proto {} ({}), length {}",
+                self.source_addr,
+                self.destination_addr,
+                self.type_of_service,
+                self.time_to_live,
+                self.identification,
+                self.fragment_offset * 8,
+                flags,
+                protocol,
+                self.protocol,
+                self.total_length
+            );
+        }
+        writeln!(f, "IPv4 {} > {}", self.source_addr, self.destination_addr)?;
+        writeln!(
+            f,
+            "  version {}, ihl {}, tos {:#x}, length {}",
+            self.version, self.ihl, self.type_of_service, self.total_length
+        )?;
+        writeln!(
+            f,
+            "  id {:#06x}, flags [{}], offset {}, ttl {}",
+            self.identification,
+            flags,
+            self.fragment_offset * 8,
+            self.time_to_live
+        )?;
+        write!(
+            f,
+            "  proto {} ({}), checksum {:#06x}",
+            protocol, self.protocol, self.checksum
+        )?;
+        if let Some(options) = &self.options {
+            write!(f, "\n  options {} bytes", options.len())?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -273,6 +779,217 @@ mod tests {
             assert_eq!(ip_header.ihl, 6);
             assert_eq!(ip_header.total_length, 34);
             assert_eq!(ip_header.to_byte_buffer()[20..24], [0x5, 0x5, 0x5, 0x0]);
+
+            let ip_header = IPHeader::builder(
+                Ipv4Addr::from_bits(0xc0a80001),
+                Ipv4Addr::from_bits(0xc0a80002),
+            )
+            .options(vec![0x94, 0x4, 0x0, 0x0])
+            .build(10);
+            assert_eq!(ip_header.ihl, 6);
+            assert_eq!(ip_header.total_length, 34);
+            assert_eq!(ip_header.time_to_live, DEFAULT_TTL);
+            assert!(IPHeader::from_byte_buffer(&ip_header.to_byte_buffer()).is_ok());
+        }
+    }
+
+    mod ipv4_packet_tests {
+        use super::*;
+        use crate::{
+            buffer::{BufferPool, HEADROOM},
+            protocol::{tcp, TCPControlBits, TCP},
+        };
+
+        #[test]
+        fn reads_and_writes_in_place() {
+            let mut buf: [u8; 24] = [
+                0x45, 0x0, 0x0, 0x18, 0x1b, 0xb, 0x40, 0x0, 0x40, 0x1, 0x9e, 0x86, 0xc0, 0xa8, 0x0,
+                0x1, 0xc0, 0xa8, 0x0, 0x2, 0x8, 0x0, 0xab, 0xcd,
+            ];
+            let packet = Ipv4Packet::new_checked(&buf[..]).unwrap();
+            assert!(packet.verify_checksum());
+            assert_eq!(packet.flags(), FLAG_DONT_FRAGMENT);
+            assert_eq!(packet.time_to_live(), 64);
+            assert_eq!(packet.source_addr(), Ipv4Addr::new(192, 168, 0, 1));
+            assert!(packet.options().is_empty());
+            assert_eq!(packet.payload(), [0x8, 0x0, 0xab, 0xcd]);
+
+            let mut packet = Ipv4Packet::new_checked(&mut buf[..]).unwrap();
+            packet.decrement_ttl();
+            assert!(packet.verify_checksum());
+            let ttl = packet.time_to_live();
+            packet.set_time_to_live(0);
+            packet.fill_checksum();
+            packet.decrement_ttl();
+            assert_eq!(packet.time_to_live(), 0);
+            assert!(packet.verify_checksum());
+            packet.set_time_to_live(ttl);
+            packet.set_fragment_offset(0x10);
+            packet.set_flags(FLAG_MORE_FRAGMENTS);
+            packet.payload_mut()[0] = 0x0;
+            packet.fill_checksum();
+            let header = IPHeader::from_byte_buffer(&buf[..20]).unwrap();
+            assert_eq!(header.time_to_live, 63);
+            assert_eq!(
+                (header.flags, header.fragment_offset),
+                (FLAG_MORE_FRAGMENTS, 0x10)
+            );
+            assert_eq!(buf[20], 0x0);
+
+            // Shorter than the total length
+            let error = Ipv4Packet::new_checked(&buf[..23]).unwrap_err();
+            assert!(matches!(error.kind(), IPPacketErrorKind::IPLengthError));
+            assert!(IPPacket::from_byte_buffer(&buf[..10]).is_err());
+        }
+
+        #[test]
+        fn ecn_codepoint() {
+            let header =
+                IPHeader::builder(Ipv4Addr::new(192, 168, 0, 1), Ipv4Addr::new(192, 168, 0, 2))
+                    .type_of_service(0xb8)
+                    .ecn(ECN::ECT0)
+                    .build(0);
+            assert_eq!((header.dscp(), header.ecn()), (46, ECN::ECT0));
+            let mut buf = header.to_byte_buffer();
+            let mut packet = Ipv4Packet::new_checked(&mut buf[..]).unwrap();
+            packet.set_ecn(ECN::CE);
+            assert!(packet.verify_checksum());
+            assert_eq!((packet.type_of_service(), packet.ecn()), (0xbb, ECN::CE));
+        }
+
+        #[test]
+        fn prepends_each_layer() {
+            let source_addr = Ipv4Addr::new(192, 168, 0, 1);
+            let destination_addr = Ipv4Addr::new(192, 168, 0, 2);
+            let tcp = TCP::new(
+                &source_addr.into(),
+                &destination_addr.into(),
+                tcp::PROTOCOL,
+                48458,
+                80,
+                1,
+                0,
+                0,
+                TCPControlBits::SYN,
+                64240,
+                0,
+                Some(vec![0x2, 0x4, 0x5, 0xb4, 0x1]),
+                b"data".to_vec(),
+            )
+            .unwrap();
+            let ip_body = IPBody::TCP(tcp);
+            let ip_header = IPHeader::from_body(
+                4,
+                0,
+                0,
+                FLAG_DONT_FRAGMENT,
+                0,
+                64,
+                tcp::PROTOCOL,
+                source_addr,
+                destination_addr,
+                Some(vec![0x1, 0x1, 0x1]),
+                ip_body.len() as u16,
+            );
+            let packet = IPPacket::new(ip_header, ip_body);
+            let mut buf = BufferPool::new().get();
+            packet.prepend_to(&mut buf);
+            assert_eq!(&buf[..], packet.to_byte_buffer());
+            assert_eq!(buf.headroom(), HEADROOM - buf.len());
+            assert!(IPPacket::from_byte_buffer(&buf).is_ok());
+        }
+    }
+
+    mod fragment_tests {
+        use super::*;
+
+        fn packet(options: Option<Vec<u8>>, data_len: usize) -> Vec<u8> {
+            let header = IPHeader::from_body(
+                0x4,
+                0x0,
+                0x1b0b,
+                0b000,
+                0x0,
+                0x40,
+                0x11,
+                Ipv4Addr::from_bits(0xc0a80001),
+                Ipv4Addr::from_bits(0xc0a80002),
+                options,
+                data_len as u16,
+            );
+            let mut buf = header.to_byte_buffer();
+            buf.extend((0..data_len).map(|i| i as u8));
+            buf
+        }
+
+        #[test]
+        fn fits_in_mtu() {
+            let buf = packet(None, 100);
+            assert_eq!(fragment(&buf, 1500), vec![buf]);
+        }
+
+        #[test]
+        fn splits_data_on_8_byte_boundaries() {
+            let buf = packet(None, 1000);
+            let fragments = fragment(&buf, 500);
+            assert_eq!(fragments.len(), 3);
+            let mut data = Vec::new();
+            for (index, fragment) in fragments.iter().enumerate() {
+                let header = IPHeader::from_byte_buffer(&fragment[..20]).unwrap();
+                assert!(fragment.len() <= 500);
+                assert_eq!(header.total_length as usize, fragment.len());
+                assert_eq!(header.fragment_offset as usize * 8, data.len());
+                assert_eq!(
+                    header.flags & FLAG_MORE_FRAGMENTS != 0,
+                    index != fragments.len() - 1
+                );
+                data.extend_from_slice(&fragment[20..]);
+            }
+            assert_eq!(data, buf[20..]);
+        }
+
+        #[test]
+        fn only_copied_options_are_repeated() {
+            // Security option (copied) followed by a record route option (not copied)
+            let options = vec![0x82, 0x3, 0x0, 0x7, 0x3, 0x0];
+            let buf = packet(Some(options), 100);
+            let fragments = fragment(&buf, 80);
+            assert_eq!(IPHeader::get_ihl(fragments[0][0]), 7);
+            assert_eq!(IPHeader::get_ihl(fragments[1][0]), 6);
+            assert_eq!(fragments[1][20..24], [0x82, 0x3, 0x0, 0x0]);
+            assert!(IPHeader::from_byte_buffer(&fragments[1][..24]).is_ok());
+        }
+    }
+
+    mod display_tests {
+        use super::*;
+        use crate::capture::PcapReader;
+
+        #[test]
+        fn ping_and_syn() {
+            let capture = std::fs::read("tests/data/ping_syn.pcap").unwrap();
+            let mut reader = PcapReader::new(capture.as_slice()).unwrap();
+            let (_, ping) = reader.next_frame().unwrap().unwrap();
+            let ping = IPPacket::from_byte_buffer(&ping).unwrap();
+            assert_eq!(
+                ping.to_string(),
+                "IP 192.168.0.1 > 192.168.0.2: ICMP echo request, id 15, seq 1, length 64"
+            );
+            assert_eq!(
+                format!("{:#}", ping),
+                "IPv4 192.168.0.1 > 192.168.0.2\n  \
+                 version 4, ihl 5, tos 0x0, length 84\n  \
+                 id 0x1b0b, flags [DF], offset 0, ttl 64\n  \
+                 proto ICMP (1), checksum 0x9e4a\n  \
+                 ICMP type 8, code 0, checksum 0xc066\n    \
+                 echo request, id 15, seq 1\n    \
+                 data 56 bytes"
+            );
+            let (_, syn) = reader.next_frame().unwrap().unwrap();
+            let syn = IPPacket::from_byte_buffer(&syn).unwrap();
+            assert!(syn
+                .to_string()
+                .starts_with("IP 192.168.0.1.48458 > 192.168.0.2.80: Flags [S], seq 2222491480"));
         }
     }
 }
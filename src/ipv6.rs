@@ -0,0 +1,340 @@
+pub mod extension;
+pub mod fragment;
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use crate::{
+    buffer::PacketBuffer,
+    ip::{IPPacketError, IPPacketErrorKind, ECN},
+    protocol::{indent, protocol_name, IPBody},
+};
+
+pub use extension::{ExtensionHeader, ExtensionHeaderBody};
+pub use fragment::{Fragmenter, Reassembler};
+
+pub struct IPv6Packet {
+    pub header: IPv6Header,
+    pub extension_headers: Vec<ExtensionHeader>,
+    pub body: IPBody,
+}
+impl IPv6Packet {
+    /// Creates a new packet, setting the payload length and the next header chain for you
+    /// `protocol` is the upper layer protocol number of the body
+    pub fn new(
+        header: IPv6Header,
+        extension_headers: Vec<ExtensionHeader>,
+        protocol: u8,
+        body: IPBody,
+    ) -> Self {
+        let mut header = header;
+        let mut extension_headers = extension_headers;
+        // Relink the chain so each header points to the one after it
+        let mut next_header = protocol;
+        for extension_header in extension_headers.iter_mut().rev() {
+            extension_header.next_header = next_header;
+            next_header = extension_header.header_type();
+        }
+        header.next_header = next_header;
+        header.payload_length = (extension_headers
+            .iter()
+            .map(|extension_header| extension_header.len())
+            .sum::<usize>()
+            + body.len()) as u16;
+        Self {
+            header,
+            extension_headers,
+            body,
+        }
+    }
+    pub fn from_byte_buffer(buf: &[u8]) -> Result<Self, IPPacketError> {
+        let header = IPv6Header::from_byte_buffer(buf)?;
+        let end = IPv6Header::LEN + header.payload_length as usize;
+        if buf.len() < end {
+            return Err(IPPacketError::new(IPPacketErrorKind::IPv6HeaderError));
+        }
+        let (extension_headers, protocol, offset) =
+            ExtensionHeader::walk_chain(header.next_header, &buf[IPv6Header::LEN..end])?;
+        if extension_headers
+            .iter()
+            .any(|extension_header| extension_header.is_fragment())
+        {
+            // Fragments need to go through the fragment::Reassembler before the body can be parsed
+            return Err(IPPacketError::new(IPPacketErrorKind::NotImplementedYet));
+        }
+        let body = IPBody::from_protocol(
+            protocol,
+            &IpAddr::V6(header.source_addr),
+            &IpAddr::V6(header.destination_addr),
+            &buf[IPv6Header::LEN + offset..end],
+        )?;
+        Ok(Self {
+            header,
+            extension_headers,
+            body,
+        })
+    }
+    pub fn to_byte_buffer(&self) -> Vec<u8> {
+        let mut buf = self.header.to_byte_buffer();
+        for extension_header in &self.extension_headers {
+            buf.append(&mut extension_header.to_byte_buffer());
+        }
+        buf.append(&mut self.body.to_byte_buffer());
+        buf
+    }
+    /// Answer to `incoming`, echoing its traffic class, flow label and hop limit with the
+    /// addresses swapped and without extension headers, the ECN codepoint is left to the
+    /// transport of the reply
+    pub fn reply_to(incoming: &IPv6Packet, body: IPBody) -> Self {
+        let header = &incoming.header;
+        let protocol = body.protocol();
+        let reply = IPv6Header::new(
+            ECN::NotECT.apply(header.traffic_class),
+            header.flow_label,
+            0,
+            protocol,
+            header.hop_limit,
+            header.destination_addr,
+            header.source_addr,
+        );
+        Self::new(reply, Vec::new(), protocol, body)
+    }
+    /// Puts the packet in front of the bytes already in `buf`, each layer writing its header
+    /// into the headroom
+    pub fn prepend_to(&self, buf: &mut PacketBuffer) {
+        self.body.prepend_to(buf);
+        for extension_header in self.extension_headers.iter().rev() {
+            let extension_header = extension_header.to_byte_buffer();
+            buf.prepend(extension_header.len())
+                .copy_from_slice(&extension_header);
+        }
+        self.header.write(buf.prepend(IPv6Header::LEN));
+    }
+    /// The upper layer protocol number, found at the end of the extension header chain
+    pub fn protocol(&self) -> u8 {
+        match self.extension_headers.last() {
+            Some(extension_header) => extension_header.next_header,
+            None => self.header.next_header,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IPv6Header {
+    pub version: u8, // 4 bits
+    pub traffic_class: u8,
+    pub flow_label: u32, // 20 bits
+    pub payload_length: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub source_addr: Ipv6Addr,
+    pub destination_addr: Ipv6Addr,
+}
+impl IPv6Header {
+    /// Size of the fixed IPv6 header in bytes
+    pub const LEN: usize = 40;
+
+    pub fn new(
+        traffic_class: u8,
+        flow_label: u32, // 20 bits
+        payload_length: u16,
+        next_header: u8,
+        hop_limit: u8,
+        source_addr: Ipv6Addr,
+        destination_addr: Ipv6Addr,
+    ) -> Self {
+        Self {
+            version: 6,
+            traffic_class,
+            flow_label: flow_label & 0xFFFFF,
+            payload_length,
+            next_header,
+            hop_limit,
+            source_addr,
+            destination_addr,
+        }
+    }
+    /// Differentiated services codepoint, the upper 6 bits of the traffic class
+    pub fn dscp(&self) -> u8 {
+        self.traffic_class >> 2
+    }
+    pub fn ecn(&self) -> ECN {
+        ECN::of(self.traffic_class)
+    }
+    /// Parsing from raw bytes buffer
+    pub fn from_byte_buffer(buf: &[u8]) -> Result<Self, IPPacketError> {
+        if buf.len() < Self::LEN || buf[0] >> 4 != 6 {
+            return Err(IPPacketError::new(IPPacketErrorKind::IPv6HeaderError));
+        }
+        let first_word = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let mut source_addr = [0u8; 16];
+        source_addr.copy_from_slice(&buf[8..24]);
+        let mut destination_addr = [0u8; 16];
+        destination_addr.copy_from_slice(&buf[24..40]);
+        Ok(Self {
+            version: (first_word >> 28) as u8,
+            traffic_class: ((first_word >> 20) & 0xFF) as u8,
+            flow_label: first_word & 0xFFFFF,
+            payload_length: u16::from_be_bytes([buf[4], buf[5]]),
+            next_header: buf[6],
+            hop_limit: buf[7],
+            source_addr: Ipv6Addr::from(source_addr),
+            destination_addr: Ipv6Addr::from(destination_addr),
+        })
+    }
+    /// Creates the byte buffer using the values in the header
+    pub fn to_byte_buffer(&self) -> Vec<u8> {
+        let mut buf = vec![0x0; Self::LEN];
+        self.write(&mut buf);
+        buf
+    }
+    /// Writes the header into `out`, which is `LEN` bytes long
+    fn write(&self, out: &mut [u8]) {
+        let first_word = ((self.version as u32) << 28)
+            + ((self.traffic_class as u32) << 20)
+            + (self.flow_label & 0xFFFFF);
+        out[0..4].copy_from_slice(&first_word.to_be_bytes());
+        out[4..6].copy_from_slice(&self.payload_length.to_be_bytes());
+        out[6] = self.next_header;
+        out[7] = self.hop_limit;
+        out[8..24].copy_from_slice(&self.source_addr.octets());
+        out[24..40].copy_from_slice(&self.destination_addr.octets());
+    }
+}
+
+impl fmt::Display for IPv6Packet {
+    /// `IP6 fd00::1.48458 > fd00::2.80: Flags [S], ...`, or the tree of every layer with `{:#}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "{:#}", self.header)?;
+            for extension_header in &self.extension_headers {
+                write!(f, "\n  {}", extension_header)?;
+            }
+            return write!(f, "\n{}", indent(&format!("{:#}", self.body)));
+        }
+        match self.body.ports() {
+            Some((source_port, destination_port)) => write!(
+                f,
+                "IP6 {}.{} > {}.{}: {}",
+                self.header.source_addr,
+                source_port,
+                self.header.destination_addr,
+                destination_port,
+                self.body
+            ),
+            None => write!(
+                f,
+                "IP6 {} > {}: {}",
+                self.header.source_addr, self.header.destination_addr, self.body
+            ),
+        }
+    }
+}
+impl fmt::Display for IPv6Header {
+    /// `fd00::1 > fd00::2: flowlabel 0x0, hlim 64, ...`, or a tree of every field with `{:#}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let next_header = protocol_name(self.next_header);
+        if !f.alternate() {
+            return write!(
+                f,
+                "{} > {}: class {:#x}, flowlabel {:#x}, hlim {}, next-header {} ({}), payload length {}",
+                self.source_addr,
+                self.destination_addr,
+                self.traffic_class,
+                self.flow_label,
+                self.hop_limit,
+                next_header,
+                self.next_header,
+                self.payload_length
+            );
+        }
+        writeln!(f, "IPv6 {} > {}", self.source_addr, self.destination_addr)?;
+        writeln!(
+            f,
+            "  version {}, class {:#x}, flowlabel {:#07x}",
+            self.version, self.traffic_class, self.flow_label
+        )?;
+        write!(
+            f,
+            "  payload length {}, next-header {} ({}), hlim {}",
+            self.payload_length, next_header, self.next_header, self.hop_limit
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TCP SYN from fd00::1 port 48458 to fd00::2 port 80, MSS/SACK/TS/WS options
+    const SYN_PACKET: [u8; 80] = [
+        0x60, 0x0c, 0x1a, 0x2b, 0x0, 0x28, 0x6, 0x40, 0xfd, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+        0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1, 0xfd, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+        0x0, 0x0, 0x0, 0x0, 0x2, 0xbd, 0x4a, 0x0, 0x50, 0x84, 0x78, 0x87, 0x58, 0x0, 0x0, 0x0, 0x0,
+        0xa0, 0x2, 0xfa, 0xf0, 0x55, 0x77, 0x0, 0x0, 0x2, 0x4, 0x5, 0xa0, 0x4, 0x2, 0x8, 0xa, 0x82,
+        0x7a, 0xb1, 0xc1, 0x0, 0x0, 0x0, 0x0, 0x1, 0x3, 0x3, 0x7,
+    ];
+
+    #[test]
+    fn header_from_byte_buffer() {
+        let header = IPv6Header::from_byte_buffer(&SYN_PACKET).unwrap();
+        assert_eq!(header.version, 6);
+        assert_eq!(header.traffic_class, 0x00);
+        assert_eq!(header.flow_label, 0xc1a2b);
+        assert_eq!(header.payload_length, 40);
+        assert_eq!(header.next_header, 6);
+        assert_eq!(header.hop_limit, 64);
+        assert_eq!(header.source_addr, "fd00::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(
+            header.destination_addr,
+            "fd00::2".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(header.to_byte_buffer(), SYN_PACKET[..40]);
+    }
+
+    #[test]
+    fn header_rejects_ipv4() {
+        let mut buf = SYN_PACKET;
+        buf[0] = 0x45;
+        assert!(IPv6Header::from_byte_buffer(&buf).is_err());
+        assert!(IPv6Header::from_byte_buffer(&SYN_PACKET[..39]).is_err());
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = IPv6Packet::from_byte_buffer(&SYN_PACKET).unwrap();
+        assert!(packet.extension_headers.is_empty());
+        assert_eq!(packet.protocol(), 6);
+        assert!(matches!(packet.body, IPBody::TCP(_)));
+        assert_eq!(packet.to_byte_buffer(), SYN_PACKET);
+    }
+
+    #[test]
+    fn packet_with_extension_headers() {
+        let packet = IPv6Packet::from_byte_buffer(&SYN_PACKET).unwrap();
+        let packet = IPv6Packet::new(
+            packet.header,
+            vec![
+                ExtensionHeader::new(ExtensionHeaderBody::HopByHop {
+                    options: vec![0x1, 0x4, 0x0, 0x0, 0x0, 0x0],
+                }),
+                ExtensionHeader::new(ExtensionHeaderBody::DestinationOptions {
+                    options: vec![0x1, 0x4, 0x0, 0x0, 0x0, 0x0],
+                }),
+            ],
+            6,
+            packet.body,
+        );
+        assert_eq!(packet.header.next_header, 0);
+        assert_eq!(packet.extension_headers[0].next_header, 60);
+        assert_eq!(packet.extension_headers[1].next_header, 6);
+        assert_eq!(packet.header.payload_length, 56);
+
+        let reparsed = IPv6Packet::from_byte_buffer(&packet.to_byte_buffer()).unwrap();
+        assert_eq!(reparsed.extension_headers, packet.extension_headers);
+        assert_eq!(reparsed.protocol(), 6);
+        assert_eq!(reparsed.to_byte_buffer(), packet.to_byte_buffer());
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+
+use crate::ip::{IPPacketError, IPPacketErrorKind};
+
+pub const HOP_BY_HOP: u8 = 0;
+pub const ROUTING: u8 = 43;
+pub const FRAGMENT: u8 = 44;
+pub const DESTINATION_OPTIONS: u8 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtensionHeader {
+    pub next_header: u8,
+    pub body: ExtensionHeaderBody,
+}
+impl ExtensionHeader {
+    /// Creates a new extension header, the next header is filled in when it is put into a packet
+    pub fn new(body: ExtensionHeaderBody) -> Self {
+        Self {
+            next_header: 0,
+            body,
+        }
+    }
+    /// Walks the chain of extension headers starting from the fixed header's next header
+    /// Returns the headers, the upper layer protocol number and the offset of the upper layer body
+    /// Stops at a Fragment header, everything after it belongs to the fragmentable part
+    pub fn walk_chain(
+        next_header: u8,
+        buf: &[u8],
+    ) -> Result<(Vec<Self>, u8, usize), IPPacketError> {
+        let mut extension_headers = Vec::new();
+        let mut next_header = next_header;
+        let mut offset = 0;
+        while Self::is_extension_header(next_header) {
+            if next_header == HOP_BY_HOP && !extension_headers.is_empty() {
+                // Hop-by-Hop Options is only allowed right after the fixed header
+                return Err(IPPacketError::new(IPPacketErrorKind::IPv6HeaderError));
+            }
+            let extension_header = Self::from_byte_buffer(next_header, &buf[offset..])?;
+            offset += extension_header.len();
+            next_header = extension_header.next_header;
+            let is_fragment = extension_header.is_fragment();
+            extension_headers.push(extension_header);
+            if is_fragment {
+                break;
+            }
+        }
+        Ok((extension_headers, next_header, offset))
+    }
+    pub fn is_extension_header(next_header: u8) -> bool {
+        matches!(
+            next_header,
+            HOP_BY_HOP | ROUTING | FRAGMENT | DESTINATION_OPTIONS
+        )
+    }
+    /// `header_type` is the next header value which pointed to this header
+    pub fn from_byte_buffer(header_type: u8, buf: &[u8]) -> Result<Self, IPPacketError> {
+        if buf.len() < 8 {
+            return Err(IPPacketError::new(IPPacketErrorKind::IPv6HeaderError));
+        }
+        let next_header = buf[0];
+        // Length in 8 octet units, not including the first 8 octets
+        let len = if header_type == FRAGMENT {
+            8
+        } else {
+            (buf[1] as usize + 1) * 8
+        };
+        if buf.len() < len {
+            return Err(IPPacketError::new(IPPacketErrorKind::IPv6HeaderError));
+        }
+        let body = match header_type {
+            HOP_BY_HOP => ExtensionHeaderBody::HopByHop {
+                options: buf[2..len].to_vec(),
+            },
+            ROUTING => ExtensionHeaderBody::Routing {
+                routing_type: buf[2],
+                segments_left: buf[3],
+                data: buf[4..len].to_vec(),
+            },
+            FRAGMENT => ExtensionHeaderBody::Fragment {
+                fragment_offset: u16::from_be_bytes([buf[2], buf[3]]) >> 3,
+                more_fragments: buf[3] & 0b1 == 1,
+                identification: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            },
+            DESTINATION_OPTIONS => ExtensionHeaderBody::DestinationOptions {
+                options: buf[2..len].to_vec(),
+            },
+            _ => return Err(IPPacketError::new(IPPacketErrorKind::NotImplementedYet)),
+        };
+        Ok(Self { next_header, body })
+    }
+    pub fn to_byte_buffer(&self) -> Vec<u8> {
+        let len = self.len();
+        let mut buf = vec![self.next_header];
+        match &self.body {
+            ExtensionHeaderBody::HopByHop { options }
+            | ExtensionHeaderBody::DestinationOptions { options } => {
+                buf.push((len / 8 - 1) as u8);
+                buf.append(&mut options.clone());
+            }
+            ExtensionHeaderBody::Routing {
+                routing_type,
+                segments_left,
+                data,
+            } => {
+                buf.push((len / 8 - 1) as u8);
+                buf.push(*routing_type);
+                buf.push(*segments_left);
+                buf.append(&mut data.clone());
+            }
+            ExtensionHeaderBody::Fragment {
+                fragment_offset,
+                more_fragments,
+                identification,
+            } => {
+                buf.push(0x0); // Reserved
+                buf.append(
+                    &mut ((fragment_offset << 3) + *more_fragments as u16)
+                        .to_be_bytes()
+                        .to_vec(),
+                );
+                buf.append(&mut identification.to_be_bytes().to_vec());
+            }
+        }
+        // Pad with Pad1 options to a multiple of 8 octets
+        buf.resize(len, 0x0);
+        buf
+    }
+    /// The next header value used to refer to this header
+    pub fn header_type(&self) -> u8 {
+        match self.body {
+            ExtensionHeaderBody::HopByHop { .. } => HOP_BY_HOP,
+            ExtensionHeaderBody::Routing { .. } => ROUTING,
+            ExtensionHeaderBody::Fragment { .. } => FRAGMENT,
+            ExtensionHeaderBody::DestinationOptions { .. } => DESTINATION_OPTIONS,
+        }
+    }
+    pub fn is_fragment(&self) -> bool {
+        matches!(self.body, ExtensionHeaderBody::Fragment { .. })
+    }
+    /// Length in bytes, always a multiple of 8
+    pub fn len(&self) -> usize {
+        let unpadded_len = match &self.body {
+            ExtensionHeaderBody::HopByHop { options }
+            | ExtensionHeaderBody::DestinationOptions { options } => 2 + options.len(),
+            ExtensionHeaderBody::Routing { data, .. } => 4 + data.len(),
+            ExtensionHeaderBody::Fragment { .. } => 8,
+        };
+        unpadded_len.div_ceil(8) * 8
+    }
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtensionHeaderBody {
+    HopByHop {
+        options: Vec<u8>,
+    },
+    Routing {
+        routing_type: u8,
+        segments_left: u8,
+        data: Vec<u8>,
+    },
+    Fragment {
+        fragment_offset: u16, // 13 bits, in 8 octet units
+        more_fragments: bool,
+        identification: u32,
+    },
+    DestinationOptions {
+        options: Vec<u8>,
+    },
+}
+
+impl fmt::Display for ExtensionHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.body {
+            ExtensionHeaderBody::HopByHop { options } => {
+                write!(f, "Hop-by-Hop options {} bytes", options.len())
+            }
+            ExtensionHeaderBody::Routing {
+                routing_type,
+                segments_left,
+                ..
+            } => write!(
+                f,
+                "Routing type {}, segments left {}",
+                routing_type, segments_left
+            ),
+            ExtensionHeaderBody::Fragment {
+                fragment_offset,
+                more_fragments,
+                identification,
+            } => write!(
+                f,
+                "Fragment id {:#x}, offset {}{}",
+                identification,
+                fragment_offset * 8,
+                if *more_fragments { ", more" } else { "" }
+            ),
+            ExtensionHeaderBody::DestinationOptions { options } => {
+                write!(f, "Destination options {} bytes", options.len())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragment_round_trip() {
+        let buf = [0x6, 0x0, 0x0, 0xb9, 0xde, 0xad, 0xbe, 0xef];
+        let extension_header = ExtensionHeader::from_byte_buffer(FRAGMENT, &buf).unwrap();
+        assert_eq!(extension_header.next_header, 6);
+        assert_eq!(
+            extension_header.body,
+            ExtensionHeaderBody::Fragment {
+                fragment_offset: 23,
+                more_fragments: true,
+                identification: 0xdeadbeef,
+            }
+        );
+        assert_eq!(extension_header.to_byte_buffer(), buf);
+    }
+
+    #[test]
+    fn routing_round_trip() {
+        let mut buf = vec![0x3a, 0x2, 0x0, 0x1, 0x0, 0x0, 0x0, 0x0];
+        buf.append(&mut vec![0xfd; 16]);
+        let extension_header = ExtensionHeader::from_byte_buffer(ROUTING, &buf).unwrap();
+        assert_eq!(extension_header.len(), 24);
+        assert_eq!(extension_header.header_type(), ROUTING);
+        assert_eq!(extension_header.to_byte_buffer(), buf);
+    }
+
+    #[test]
+    fn walk_chain_stops_at_upper_layer() {
+        let buf = [
+            // Hop-by-Hop -> Destination Options
+            60, 0x0, 0x1, 0x4, 0x0, 0x0, 0x0, 0x0, // Destination Options -> TCP
+            6, 0x0, 0x1, 0x4, 0x0, 0x0, 0x0, 0x0, // TCP body
+            0xbd, 0x4a,
+        ];
+        let (extension_headers, protocol, offset) =
+            ExtensionHeader::walk_chain(HOP_BY_HOP, &buf).unwrap();
+        assert_eq!(extension_headers.len(), 2);
+        assert_eq!(protocol, 6);
+        assert_eq!(offset, 16);
+    }
+
+    #[test]
+    fn walk_chain_rejects_late_hop_by_hop() {
+        let buf = [
+            HOP_BY_HOP, 0x0, 0x1, 0x4, 0x0, 0x0, 0x0, 0x0, 6, 0x0, 0x1, 0x4, 0x0, 0x0, 0x0, 0x0,
+        ];
+        assert!(ExtensionHeader::walk_chain(DESTINATION_OPTIONS, &buf).is_err());
+    }
+}
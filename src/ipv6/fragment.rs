@@ -0,0 +1,576 @@
+use std::{
+    collections::HashMap,
+    net::Ipv6Addr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ip::{IPPacketError, IPPacketErrorKind},
+    protocol::{icmpv6, ICMPv6, ICMPv6Body, IPBody},
+};
+
+use super::{
+    extension::{ExtensionHeaderBody, FRAGMENT, HOP_BY_HOP, ROUTING},
+    ExtensionHeader, IPv6Header, IPv6Packet,
+};
+
+/// RFC 8200 4.5, fragments that are not completed within this time are discarded
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+/// RFC 8201 5.3, learnt path MTUs are forgotten after this time
+pub const PMTU_TIMEOUT: Duration = Duration::from_secs(600);
+/// Every IPv6 link has to support this MTU (RFC 8200 5)
+pub const MINIMUM_MTU: u32 = 1280;
+/// Packets waiting for more fragments, the oldest is dropped to make room for a new one so
+/// fragments which never complete can not take up unbounded memory
+pub const MAX_PARTIAL_PACKETS: usize = 64;
+
+/// Identifies the fragments belonging to the same original packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    source_addr: Ipv6Addr,
+    destination_addr: Ipv6Addr,
+    identification: u32,
+}
+
+struct PartialPacket {
+    /// Fixed header and the extension headers before the Fragment header, taken from the first fragment
+    unfragmentable: Option<Vec<u8>>,
+    /// The next header of the fragmentable part, from the first fragment
+    next_header: u8,
+    /// The whole first fragment, quoted in the Time Exceeded message if reassembly times out
+    first_fragment: Option<Vec<u8>>,
+    /// (Offset in bytes, data) sorted by offset
+    fragments: Vec<(usize, Vec<u8>)>,
+    /// Known once the fragment without the M flag arrives
+    total_length: Option<usize>,
+    started: Instant,
+}
+impl PartialPacket {
+    fn new(now: Instant) -> Self {
+        Self {
+            unfragmentable: None,
+            next_header: 0,
+            first_fragment: None,
+            fragments: Vec::new(),
+            total_length: None,
+            started: now,
+        }
+    }
+    /// Returns false if the fragment overlaps one already received
+    fn insert(&mut self, offset: usize, data: Vec<u8>) -> bool {
+        let end = offset + data.len();
+        let overlaps = self.fragments.iter().any(|(other_offset, other_data)| {
+            offset < other_offset + other_data.len() && *other_offset < end
+        });
+        if overlaps {
+            return false;
+        }
+        let index = self
+            .fragments
+            .partition_point(|(other_offset, _)| *other_offset < offset);
+        self.fragments.insert(index, (offset, data));
+        true
+    }
+    fn is_complete(&self) -> bool {
+        let (Some(total_length), Some(_)) = (self.total_length, &self.unfragmentable) else {
+            return false;
+        };
+        let mut expected_offset = 0;
+        for (offset, data) in &self.fragments {
+            if *offset != expected_offset {
+                return false;
+            }
+            expected_offset += data.len();
+        }
+        expected_offset == total_length
+    }
+}
+
+/// Reassembles fragmented packets (RFC 8200 4.5), rejecting overlapping fragments (RFC 5722)
+#[derive(Default)]
+pub struct Reassembler {
+    partial_packets: HashMap<FragmentKey, PartialPacket>,
+}
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Number of packets waiting for more fragments
+    pub fn pending(&self) -> usize {
+        self.partial_packets.len()
+    }
+    /// Takes the raw bytes of a received packet
+    /// Packets without a Fragment header are returned untouched, fragments are held until the
+    /// packet is complete and then returned as a packet without the Fragment header
+    pub fn reassemble(
+        &mut self,
+        buf: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, IPPacketError> {
+        let header = IPv6Header::from_byte_buffer(buf)?;
+        let end = IPv6Header::LEN + header.payload_length as usize;
+        if buf.len() < end {
+            return Err(IPPacketError::new(IPPacketErrorKind::IPv6HeaderError));
+        }
+        let (extension_headers, next_header, offset) =
+            ExtensionHeader::walk_chain(header.next_header, &buf[IPv6Header::LEN..end])?;
+        let Some(ExtensionHeader {
+            body:
+                ExtensionHeaderBody::Fragment {
+                    fragment_offset,
+                    more_fragments,
+                    identification,
+                },
+            ..
+        }) = extension_headers.last()
+        else {
+            return Ok(Some(buf[..end].to_vec()));
+        };
+        let fragment_header_start = IPv6Header::LEN + offset - 8;
+        let fragment_offset = *fragment_offset as usize * 8;
+        let data = buf[IPv6Header::LEN + offset..end].to_vec();
+        if *more_fragments && !data.len().is_multiple_of(8) || fragment_offset + data.len() > 0xFFFF
+        {
+            return Err(IPPacketError::new(IPPacketErrorKind::IPv6HeaderError));
+        }
+        if fragment_offset == 0 && !more_fragments {
+            // Atomic fragment, processed in isolation (RFC 6946)
+            return Ok(Some(Self::rebuild(
+                &buf[..fragment_header_start],
+                next_header,
+                &data,
+            )));
+        }
+        let key = FragmentKey {
+            source_addr: header.source_addr,
+            destination_addr: header.destination_addr,
+            identification: *identification,
+        };
+        if !self.partial_packets.contains_key(&key)
+            && self.partial_packets.len() >= MAX_PARTIAL_PACKETS
+        {
+            self.evict_oldest();
+        }
+        let partial_packet = self
+            .partial_packets
+            .entry(key)
+            .or_insert_with(|| PartialPacket::new(now));
+        if fragment_offset == 0 {
+            partial_packet.unfragmentable = Some(buf[..fragment_header_start].to_vec());
+            partial_packet.next_header = next_header;
+            partial_packet.first_fragment = Some(buf[..end].to_vec());
+        }
+        if !more_fragments {
+            if partial_packet.total_length.is_some() {
+                // Two last fragments
+                self.partial_packets.remove(&key);
+                return Ok(None);
+            }
+            partial_packet.total_length = Some(fragment_offset + data.len());
+        }
+        let exceeds_total_length = partial_packet
+            .total_length
+            .is_some_and(|total_length| fragment_offset + data.len() > total_length);
+        if exceeds_total_length || !partial_packet.insert(fragment_offset, data) {
+            // The whole packet is silently discarded
+            self.partial_packets.remove(&key);
+            return Ok(None);
+        }
+        if !partial_packet.is_complete() {
+            return Ok(None);
+        }
+        let partial_packet = self
+            .partial_packets
+            .remove(&key)
+            .expect("Checked the packet exists");
+        let data: Vec<u8> = partial_packet
+            .fragments
+            .into_iter()
+            .flat_map(|(_, data)| data)
+            .collect();
+        Ok(Some(Self::rebuild(
+            &partial_packet
+                .unfragmentable
+                .expect("Complete packets have a first fragment"),
+            partial_packet.next_header,
+            &data,
+        )))
+    }
+    /// When the oldest partial packet times out
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.partial_packets
+            .values()
+            .map(|partial_packet| partial_packet.started + REASSEMBLY_TIMEOUT)
+            .min()
+    }
+    /// Drops packets which timed out, returning the Time Exceeded messages to send back
+    pub fn poll(&mut self, now: Instant) -> Vec<IPv6Packet> {
+        let expired: Vec<FragmentKey> = self
+            .partial_packets
+            .iter()
+            .filter(|(_, partial_packet)| now >= partial_packet.started + REASSEMBLY_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+        let mut messages = Vec::new();
+        for key in expired {
+            let partial_packet = self.partial_packets.remove(&key).expect("Key was found");
+            // Only sent if the first fragment arrived (RFC 8200 4.5)
+            if let Some(first_fragment) = partial_packet.first_fragment {
+                let icmp = ICMPv6::new(
+                    &key.destination_addr,
+                    &key.source_addr,
+                    1, // Fragment reassembly time exceeded
+                    ICMPv6Body::TimeExceeded {
+                        invoking_packet: ICMPv6Body::invoking_packet(&first_fragment),
+                    },
+                );
+                let header = IPv6Header::new(
+                    0,
+                    0,
+                    0,
+                    icmpv6::PROTOCOL,
+                    64,
+                    key.destination_addr,
+                    key.source_addr,
+                );
+                messages.push(IPv6Packet::new(
+                    header,
+                    Vec::new(),
+                    icmpv6::PROTOCOL,
+                    IPBody::ICMPv6(icmp),
+                ));
+            }
+        }
+        messages
+    }
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .partial_packets
+            .iter()
+            .min_by_key(|(_, partial_packet)| partial_packet.started)
+            .map(|(key, _)| *key);
+        if let Some(oldest) = oldest {
+            self.partial_packets.remove(&oldest);
+        }
+    }
+    /// Joins the unfragmentable part with the data, fixing up the header chain and payload length
+    fn rebuild(unfragmentable: &[u8], next_header: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = unfragmentable.to_vec();
+        // The header which pointed at the Fragment header now points at what came after it
+        let mut next_header_index = 6;
+        let mut header_type = buf[6];
+        let mut offset = IPv6Header::LEN;
+        while header_type != FRAGMENT {
+            next_header_index = offset;
+            header_type = buf[offset];
+            offset += (buf[offset + 1] as usize + 1) * 8;
+        }
+        buf[next_header_index] = next_header;
+        buf.extend_from_slice(data);
+        let payload_length = (buf.len() - IPv6Header::LEN) as u16;
+        buf[4..6].copy_from_slice(&payload_length.to_be_bytes());
+        buf
+    }
+}
+
+/// Splits outgoing packets which are too large for the path MTU (RFC 8200 5)
+#[derive(Default)]
+pub struct Fragmenter {
+    /// Path MTU learnt from Packet Too Big messages per destination
+    path_mtu: HashMap<Ipv6Addr, (u32, Instant)>,
+    next_identification: u32,
+}
+impl Fragmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The MTU learnt for the destination, or the one of the link it is reached through
+    pub fn path_mtu(&self, destination_addr: &Ipv6Addr, link_mtu: u32, now: Instant) -> u32 {
+        // IPv6 links have to carry at least the minimum MTU
+        let link_mtu = link_mtu.max(MINIMUM_MTU);
+        match self.path_mtu.get(destination_addr) {
+            Some((mtu, learnt)) if now < *learnt + PMTU_TIMEOUT => (*mtu).min(link_mtu),
+            _ => link_mtu,
+        }
+    }
+    /// Lowers the path MTU towards the destination from a received Packet Too Big message
+    pub fn handle_packet_too_big(&mut self, destination_addr: Ipv6Addr, mtu: u32, now: Instant) {
+        // Never go below the minimum MTU, a smaller value is handled by fragmenting at 1280
+        let mtu = mtu.max(MINIMUM_MTU);
+        if mtu < self.path_mtu(&destination_addr, u32::MAX, now) {
+            self.path_mtu.insert(destination_addr, (mtu, now));
+        }
+    }
+    /// Splits the raw packet in `buf` to fit the path MTU, `link_mtu` being the MTU of the
+    /// interface it leaves through
+    /// Returns None for malformed packets and when the headers repeated in every fragment leave
+    /// no room for data
+    pub fn fragment(&mut self, buf: &[u8], link_mtu: usize, now: Instant) -> Option<Vec<Vec<u8>>> {
+        let header = IPv6Header::from_byte_buffer(buf).ok()?;
+        let buf = buf.get(..IPv6Header::LEN + header.payload_length as usize)?;
+        let mtu = self.path_mtu(&header.destination_addr, link_mtu as u32, now) as usize;
+        if buf.len() <= mtu {
+            return Some(vec![buf.to_vec()]);
+        }
+        let (extension_headers, _, _) =
+            ExtensionHeader::walk_chain(header.next_header, &buf[IPv6Header::LEN..]).ok()?;
+        // Hop-by-Hop and Routing headers (and anything before them) are repeated in each fragment
+        let unfragmentable_count = extension_headers
+            .iter()
+            .rposition(|extension_header| {
+                matches!(extension_header.header_type(), HOP_BY_HOP | ROUTING)
+            })
+            .map_or(0, |index| index + 1);
+        let unfragmentable_len = IPv6Header::LEN
+            + extension_headers[..unfragmentable_count]
+                .iter()
+                .map(|extension_header| extension_header.len())
+                .sum::<usize>();
+        let next_header = match unfragmentable_count {
+            0 => header.next_header,
+            count => extension_headers[count - 1].next_header,
+        };
+        let fragmentable = &buf[unfragmentable_len..];
+        // Every fragment except the last carries a multiple of 8 bytes
+        let max_data_len = mtu.checked_sub(unfragmentable_len + 8)? / 8 * 8;
+        if max_data_len == 0 {
+            return None;
+        }
+        let identification = self.next_identification;
+        self.next_identification = self.next_identification.wrapping_add(1);
+
+        let mut fragments = Vec::new();
+        for (index, data) in fragmentable.chunks(max_data_len).enumerate() {
+            let offset = index * max_data_len;
+            let fragment_header = ExtensionHeader {
+                next_header,
+                body: ExtensionHeaderBody::Fragment {
+                    fragment_offset: (offset / 8) as u16,
+                    more_fragments: offset + data.len() < fragmentable.len(),
+                    identification,
+                },
+            };
+            let mut fragment = buf[..unfragmentable_len].to_vec();
+            match unfragmentable_count {
+                0 => fragment[6] = FRAGMENT,
+                _ => {
+                    let last_unfragmentable_start =
+                        unfragmentable_len - extension_headers[unfragmentable_count - 1].len();
+                    fragment[last_unfragmentable_start] = FRAGMENT;
+                }
+            }
+            fragment.append(&mut fragment_header.to_byte_buffer());
+            fragment.extend_from_slice(data);
+            let payload_length = (fragment.len() - IPv6Header::LEN) as u16;
+            fragment[4..6].copy_from_slice(&payload_length.to_be_bytes());
+            fragments.push(fragment);
+        }
+        Some(fragments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo_request(data_len: usize, extension_headers: Vec<ExtensionHeader>) -> IPv6Packet {
+        let source: Ipv6Addr = "fd00::1".parse().unwrap();
+        let destination: Ipv6Addr = "fd00::2".parse().unwrap();
+        let icmp = ICMPv6::new(
+            &source,
+            &destination,
+            0,
+            ICMPv6Body::EchoRequest {
+                identifier: 0x1d,
+                sequence_number: 1,
+                data: (0..data_len).map(|i| i as u8).collect(),
+            },
+        );
+        IPv6Packet::new(
+            IPv6Header::new(0, 0, 0, 0, 64, source, destination),
+            extension_headers,
+            icmpv6::PROTOCOL,
+            IPBody::ICMPv6(icmp),
+        )
+    }
+
+    #[test]
+    fn fragment_and_reassemble() {
+        let now = Instant::now();
+        let packet = echo_request(3000, Vec::new());
+        let mut fragmenter = Fragmenter::new();
+        let fragments = fragmenter
+            .fragment(&packet.to_byte_buffer(), 1500, now)
+            .unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 1500));
+
+        let mut reassembler = Reassembler::new();
+        // Out of order arrival
+        assert!(reassembler
+            .reassemble(&fragments[2], now)
+            .unwrap()
+            .is_none());
+        assert!(reassembler
+            .reassemble(&fragments[0], now)
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembler.pending(), 1);
+        let buf = reassembler.reassemble(&fragments[1], now).unwrap().unwrap();
+        assert_eq!(buf, packet.to_byte_buffer());
+        assert_eq!(reassembler.pending(), 0);
+        assert!(IPv6Packet::from_byte_buffer(&buf).is_ok());
+    }
+
+    #[test]
+    fn hop_by_hop_is_repeated_in_each_fragment() {
+        let now = Instant::now();
+        let hop_by_hop = ExtensionHeader::new(ExtensionHeaderBody::HopByHop {
+            options: vec![0x1, 0x4, 0x0, 0x0, 0x0, 0x0],
+        });
+        let packet = echo_request(2000, vec![hop_by_hop]);
+        let fragments = Fragmenter::new()
+            .fragment(&packet.to_byte_buffer(), 1280, now)
+            .unwrap();
+        assert_eq!(fragments.len(), 2);
+        for fragment in &fragments {
+            assert_eq!(fragment[6], HOP_BY_HOP);
+            assert_eq!(fragment[40], FRAGMENT);
+        }
+        let mut reassembler = Reassembler::new();
+        reassembler.reassemble(&fragments[0], now).unwrap();
+        let buf = reassembler.reassemble(&fragments[1], now).unwrap().unwrap();
+        assert_eq!(buf, packet.to_byte_buffer());
+    }
+
+    #[test]
+    fn overlapping_fragments_are_discarded() {
+        let now = Instant::now();
+        let packet = echo_request(3000, Vec::new());
+        let fragments = Fragmenter::new()
+            .fragment(&packet.to_byte_buffer(), 1500, now)
+            .unwrap();
+        let mut overlapping = fragments[1].clone();
+        // Move the second fragment 8 bytes back so it overlaps the first
+        let offset = u16::from_be_bytes([overlapping[42], overlapping[43]]) - (1 << 3);
+        overlapping[42..44].copy_from_slice(&offset.to_be_bytes());
+
+        let mut reassembler = Reassembler::new();
+        reassembler.reassemble(&fragments[0], now).unwrap();
+        assert!(reassembler.reassemble(&overlapping, now).unwrap().is_none());
+        assert_eq!(reassembler.pending(), 0);
+        // The rest of the fragments cannot complete the packet anymore
+        reassembler.reassemble(&fragments[1], now).unwrap();
+        assert!(reassembler
+            .reassemble(&fragments[2], now)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn atomic_fragment_is_processed_in_isolation() {
+        let now = Instant::now();
+        let packet = echo_request(8, Vec::new());
+        let mut buf = packet.to_byte_buffer();
+        // Insert a Fragment header with offset 0 and M clear
+        let fragment_header = ExtensionHeader {
+            next_header: icmpv6::PROTOCOL,
+            body: ExtensionHeaderBody::Fragment {
+                fragment_offset: 0,
+                more_fragments: false,
+                identification: 7,
+            },
+        };
+        buf.splice(40..40, fragment_header.to_byte_buffer());
+        buf[6] = FRAGMENT;
+        let payload_length = (buf.len() - 40) as u16;
+        buf[4..6].copy_from_slice(&payload_length.to_be_bytes());
+
+        let mut reassembler = Reassembler::new();
+        let reassembled = reassembler.reassemble(&buf, now).unwrap().unwrap();
+        assert_eq!(reassembled, packet.to_byte_buffer());
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn timeout_sends_time_exceeded() {
+        let now = Instant::now();
+        let packet = echo_request(3000, Vec::new());
+        let fragments = Fragmenter::new()
+            .fragment(&packet.to_byte_buffer(), 1500, now)
+            .unwrap();
+        let mut reassembler = Reassembler::new();
+        reassembler.reassemble(&fragments[0], now).unwrap();
+        reassembler.reassemble(&fragments[1], now).unwrap();
+        assert!(reassembler.poll(now + Duration::from_secs(59)).is_empty());
+
+        let messages = reassembler.poll(now + REASSEMBLY_TIMEOUT);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(
+            messages[0].header.destination_addr,
+            packet.header.source_addr
+        );
+        match &messages[0].body {
+            IPBody::ICMPv6(icmp) => {
+                assert_eq!(icmp._type, 3);
+                assert_eq!(icmp.code, 1);
+            }
+            _ => panic!("Should be an ICMPv6 message"),
+        }
+
+        // No message if the first fragment never arrived
+        reassembler.reassemble(&fragments[1], now).unwrap();
+        assert!(reassembler.poll(now + REASSEMBLY_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn path_mtu_from_packet_too_big() {
+        let now = Instant::now();
+        let destination: Ipv6Addr = "fd00::2".parse().unwrap();
+        let mut fragmenter = Fragmenter::new();
+        fragmenter.handle_packet_too_big(destination, 1400, now);
+        assert_eq!(fragmenter.path_mtu(&destination, 1500, now), 1400);
+        // Cannot go below the IPv6 minimum
+        fragmenter.handle_packet_too_big(destination, 576, now);
+        assert_eq!(fragmenter.path_mtu(&destination, 1500, now), MINIMUM_MTU);
+        assert_eq!(
+            fragmenter.path_mtu(&destination, 1500, now + PMTU_TIMEOUT),
+            1500
+        );
+
+        let packet = echo_request(1400, Vec::new()).to_byte_buffer();
+        assert_eq!(fragmenter.fragment(&packet, 1500, now).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unfragmentable_part_too_large() {
+        let now = Instant::now();
+        // Routing headers are repeated in every fragment, this one leaves no room for data
+        let routing = ExtensionHeader::new(ExtensionHeaderBody::Routing {
+            routing_type: 0,
+            segments_left: 0,
+            data: vec![0x0; 1244],
+        });
+        let packet = echo_request(1400, vec![routing]).to_byte_buffer();
+        assert!(Fragmenter::new().fragment(&packet, 1280, now).is_none());
+    }
+
+    #[test]
+    fn oldest_partial_packet_is_evicted() {
+        let now = Instant::now();
+        let mut fragmenter = Fragmenter::new();
+        let mut reassembler = Reassembler::new();
+        let packet = echo_request(3000, Vec::new()).to_byte_buffer();
+        for sent in 0..MAX_PARTIAL_PACKETS + 1 {
+            let fragments = fragmenter.fragment(&packet, 1500, now).unwrap();
+            let arrival = now + Duration::from_millis(sent as u64);
+            reassembler.reassemble(&fragments[0], arrival).unwrap();
+        }
+        assert_eq!(reassembler.pending(), MAX_PARTIAL_PACKETS);
+        // The first packet was evicted, so it times out without a message
+        let messages = reassembler.poll(now + REASSEMBLY_TIMEOUT);
+        assert_eq!(messages.len(), 0);
+        assert_eq!(reassembler.pending(), MAX_PARTIAL_PACKETS);
+    }
+}
@@ -1,6 +1,27 @@
+pub mod buffer;
+pub mod capture;
 pub mod checksum;
+pub mod config;
+pub mod connection;
+pub mod control;
+pub mod device;
+pub mod event;
+pub mod filter;
+pub mod forward;
 pub mod ip;
+pub mod ipv6;
+pub mod nat;
 pub mod protocol;
+pub mod qdisc;
+pub mod route;
+#[cfg(feature = "tokio")]
+pub mod runtime;
 pub mod server;
+pub mod stack;
+pub mod stats;
+#[cfg(test)]
+mod test_util;
+pub mod timer;
 
 pub use ip::IPPacket;
+pub use ipv6::IPv6Packet;
@@ -1,115 +1,92 @@
-use std::io;
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    process, thread,
+    time::Duration,
+};
+
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
 
 use nust::{
-    ip::IPHeader,
-    protocol::{ICMPBody, IPBody, TCPControlBits, ICMP, TCP},
-    IPPacket,
+    capture::{CaptureDevice, CaptureFormat, CaptureWriter, LinkType},
+    config::{Config, USAGE},
+    control::Control,
+    device::{configure_interface, NetDevice, TunDevice},
+    event::{stop_on_signals, EventLoop},
+    timer::SystemClock,
 };
-use tun_tap::{Iface, Mode};
-
-const INTERFACE_NAME: &str = "tun0";
 
 fn main() -> io::Result<()> {
-    let iface = Iface::new(INTERFACE_NAME, Mode::Tun)?;
-    let mut buf = vec![0u8; 1504]; // MTU + 4 for the header
-    loop {
-        let bytes_copied_to_buffer = iface.recv(&mut buf)?; // Wait until a packet arrives
-        let protocol = u16::from_be_bytes([buf[2], buf[3]]);
-        if protocol == 0x0800 {
-            println!("Front 4 bytes: {:x?}", &buf[..4]);
-            if let Ok(ip_packet) = IPPacket::from_byte_buffer(&buf[4..bytes_copied_to_buffer]) {
-                println!("{:#x}", ip_packet.header.protocol);
-                let header = ip_packet.header;
-                let body = ip_packet.body;
-                #[allow(clippy::single_match)]
-                let reply_ip_packet = match &body {
-                    IPBody::ICMP(icmp) => match &icmp.body {
-                        ICMPBody::Echo {
-                            identifier: _,
-                            sequence_number: _,
-                            data: _,
-                        } => {
-                            let ip_body =
-                                IPBody::ICMP(ICMP::new(0x0, icmp.code, icmp.body.clone()));
-                            let ip_header = IPHeader::from_body(
-                                header.version,
-                                header.type_of_service,
-                                header.identification,
-                                header.flags,
-                                header.fragment_offset,
-                                header.time_to_live,
-                                header.protocol,
-                                header.destination_addr,
-                                header.source_addr,
-                                header.options,
-                                ip_body.len() as u16,
-                            );
-                            Some(IPPacket::new(ip_header, ip_body))
-                        }
-                        _ => None,
-                    },
-                    IPBody::TCP(tcp) => match &tcp.control_bits {
-                        // SYN
-                        0b10 => {
-                            // Need to SYN ACK the request
-                            let ip_body = IPBody::TCP(TCP::new(
-                                &header.destination_addr,
-                                &header.source_addr,
-                                header.protocol,
-                                tcp.destination_port,
-                                tcp.source_port,
-                                0,
-                                tcp.sequence_number + 1,
-                                0,
-                                TCPControlBits::SYN.to_u8() + TCPControlBits::ACK.to_u8(),
-                                tcp.window,
-                                0,
-                                tcp.options.clone(),
-                                Vec::new(),
-                            ));
-                            let ip_header = IPHeader::from_body(
-                                header.version,
-                                header.type_of_service,
-                                header.identification,
-                                header.flags,
-                                header.fragment_offset,
-                                header.time_to_live,
-                                header.protocol,
-                                header.destination_addr,
-                                header.source_addr,
-                                header.options,
-                                ip_body.len() as u16,
-                            );
-                            Some(IPPacket::new(ip_header, ip_body))
-                        }
-                        // ACK
-                        0b10000 => {
-                            println!(
-                                "TCP ACK: <SEQ={}><ACK={}>",
-                                tcp.sequence_number, tcp.acknowledgment_number
-                            );
-                            None
-                        }
-                        _ => {
-                            // Not implemented yet, so ignore the request
-                            None
-                        }
-                    },
-                    _ => {
-                        // Ignore anything we don't want to handle
-                        None
-                    }
-                };
-                if let Some(reply_ip_packet) = reply_ip_packet {
-                    let mut res_buf: Vec<u8> = vec![0x0, 0x0, 0x8, 0x0];
-                    res_buf.append(&mut reply_ip_packet.to_byte_buffer());
-                    println!("original: {:x?}", &buf[4..bytes_copied_to_buffer]);
-                    println!("reply: {:x?}", res_buf);
-                    iface.send(&res_buf)?;
-                }
-            };
-        } else {
-            println!("Not a ipv4 packet to parse")
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let config = Config::from_args(&args).unwrap_or_else(|error| {
+        eprintln!("{}\n{}", error, USAGE);
+        process::exit(1);
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_env("NUST_LOG").unwrap_or_else(|_| EnvFilter::new(&config.log)),
+        )
+        .init();
+    let mut devices: Vec<Box<dyn NetDevice>> = Vec::new();
+    for interface in &config.interfaces {
+        let tun = TunDevice::new(&interface.name, interface.mtu)?;
+        tun.set_nonblocking()?;
+        if let Err(error) =
+            configure_interface(&interface.name, interface.mtu, &interface.host_addresses)
+        {
+            // Without CAP_NET_ADMIN the interface has to be set up with ip addr and ip link instead
+            warn!(interface = interface.name, %error, "could not configure the interface");
+        }
+        if config.capture.is_empty() {
+            devices.push(Box::new(tun));
+            continue;
         }
+        let writer = CaptureWriter::new(
+            BufWriter::new(File::create(capture_path(&config, &interface.name))?),
+            CaptureFormat::Pcapng,
+            LinkType::RawIP,
+        )?;
+        devices.push(Box::new(CaptureDevice::new(tun, writer)));
+    }
+    let stack = config.stack()?;
+    if !config.control_socket.is_empty() {
+        Control::new(
+            stack.connections.clone(),
+            stack.egress.clone(),
+            stack.router.stats.clone(),
+        )
+        .spawn(Path::new(&config.control_socket))?;
+    }
+    if config.stats_interval > 0 {
+        let interval = Duration::from_secs(config.stats_interval);
+        let stats = stack.router.stats.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            info!("Statistics\n{}", stats);
+        });
+    }
+    // Ctrl-C would otherwise lose the frames the captures still buffer
+    stop_on_signals()?;
+    EventLoop::new(stack, devices, SystemClock)?.run()
+}
+
+/// The capture file, with the interface name added when there is more than one interface
+fn capture_path(config: &Config, interface: &str) -> PathBuf {
+    let path = PathBuf::from(&config.capture);
+    if config.interfaces.len() == 1 {
+        return path;
     }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, interface, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, interface),
+    };
+    path.with_file_name(name)
 }
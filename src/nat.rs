@@ -0,0 +1,642 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    checksum::{incremental_update, incremental_update_u32, ones_complement_sum_byte_buffer},
+    protocol::{tcp, udp, TCPControlBits, TcpSegment},
+};
+
+/// RFC 5382 REQ-5, established TCP mappings live for at least 2 hours and 4 minutes
+pub const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(7440);
+/// RFC 5382 REQ-5, mappings for connections being opened or closed
+pub const TCP_TRANSITORY_TIMEOUT: Duration = Duration::from_secs(240);
+/// RFC 4787 REQ-5 recommends 5 minutes
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(300);
+/// RFC 5508 REQ-1
+pub const ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Outside ports (or ICMP identifiers) handed out to mappings
+const PORT_RANGE: RangeInclusive<u16> = 49152..=65535;
+
+const ICMP: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct InsideKey {
+    protocol: u8,
+    addr: Ipv4Addr,
+    /// Source port, or the identifier for ICMP echo
+    port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TCPMappingState {
+    /// SYN seen, waiting for the other side to answer
+    Opening,
+    Established,
+    /// FIN or RST seen
+    Closing,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    pub protocol: u8,
+    pub inside_addr: Ipv4Addr,
+    pub inside_port: u16,
+    pub outside_port: u16,
+    pub last_used: Instant,
+    pub tcp_state: TCPMappingState,
+}
+impl Mapping {
+    fn timeout(&self) -> Duration {
+        match (self.protocol, self.tcp_state) {
+            (tcp::PROTOCOL, TCPMappingState::Established) => TCP_ESTABLISHED_TIMEOUT,
+            (tcp::PROTOCOL, _) => TCP_TRANSITORY_TIMEOUT,
+            (udp::PROTOCOL, _) => UDP_TIMEOUT,
+            _ => ICMP_TIMEOUT,
+        }
+    }
+    fn update_tcp_state(&mut self, control_bits: TCPControlBits, outbound: bool) {
+        let previous = self.tcp_state;
+        if control_bits.is_fin_or_rst() {
+            self.tcp_state = TCPMappingState::Closing;
+        } else if !outbound && self.tcp_state == TCPMappingState::Opening {
+            self.tcp_state = TCPMappingState::Established;
+        }
+        if self.tcp_state != previous {
+            debug!(
+                outside_port = self.outside_port,
+                from = ?previous,
+                to = ?self.tcp_state,
+                "NAT mapping state transition"
+            );
+        }
+    }
+}
+
+/// Where the port like field and checksum of a transport header are
+struct Layer4 {
+    /// Offset of the source port, the destination port follows it for TCP and UDP
+    port_offset: usize,
+    checksum_offset: usize,
+    /// Length of the header without options, shorter packets are not translated
+    min_len: usize,
+    /// Whether the checksum covers the IP addresses through the pseudo header
+    pseudo_header: bool,
+}
+impl Layer4 {
+    fn new(protocol: u8, icmp_type: Option<u8>) -> Option<Self> {
+        match (protocol, icmp_type) {
+            (tcp::PROTOCOL, _) => Some(Self {
+                port_offset: 0,
+                checksum_offset: 16,
+                min_len: 20,
+                pseudo_header: true,
+            }),
+            (udp::PROTOCOL, _) => Some(Self {
+                port_offset: 0,
+                checksum_offset: 6,
+                min_len: 8,
+                pseudo_header: true,
+            }),
+            // Echo request and reply, the identifier is used as the port
+            (ICMP, Some(0 | 8)) => Some(Self {
+                port_offset: 4,
+                checksum_offset: 2,
+                min_len: 8,
+                pseudo_header: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Network address and port translation (RFC 3022) of IPv4 packets leaving through one interface
+pub struct Nat {
+    pub outside_interface: String,
+    /// Source address of translated packets, replies come back to this address
+    pub outside_addr: Ipv4Addr,
+    mappings: HashMap<InsideKey, Mapping>,
+    outside_ports: HashMap<(u8, u16), InsideKey>,
+    next_port: u16,
+    /// No mapping expires before this, mappings used since may live longer
+    next_expiry: Option<Instant>,
+}
+impl Nat {
+    pub fn new(outside_interface: &str, outside_addr: Ipv4Addr) -> Self {
+        Self {
+            outside_interface: outside_interface.to_string(),
+            outside_addr,
+            mappings: HashMap::new(),
+            outside_ports: HashMap::new(),
+            next_port: *PORT_RANGE.start(),
+            next_expiry: None,
+        }
+    }
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.values()
+    }
+    /// Rewrites the source of a raw IPv4 packet leaving through the outside interface
+    /// Returns false if the packet cannot be translated and should be dropped
+    pub fn translate_outbound(&mut self, packet: &mut [u8], now: Instant) -> bool {
+        let header_len = (packet[0] & 0b1111) as usize * 4;
+        let protocol = packet[9];
+        if !Self::is_first_fragment(packet) || packet.len() < header_len + 8 {
+            // Later fragments carry no ports to translate
+            return false;
+        }
+        let Some(layer4) = Layer4::new(protocol, packet.get(header_len).copied()) else {
+            return false;
+        };
+        if protocol == ICMP && packet[header_len] != 8 || packet.len() < header_len + layer4.min_len
+        {
+            return false;
+        }
+        let inside_addr = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let port_offset = header_len + layer4.port_offset;
+        let key = InsideKey {
+            protocol,
+            addr: inside_addr,
+            port: u16::from_be_bytes([packet[port_offset], packet[port_offset + 1]]),
+        };
+        let Some(outside_port) = self.mapping_for(key, now) else {
+            return false;
+        };
+        let mapping = self.mappings.get_mut(&key).expect("Mapping was just found");
+        mapping.last_used = now;
+        if protocol == tcp::PROTOCOL {
+            let segment = TcpSegment::new_unchecked(&packet[header_len..]);
+            mapping.update_tcp_state(segment.control_bits(), true);
+            // Closing shortens the timeout
+            let expiry = mapping.last_used + mapping.timeout();
+            self.schedule_expiry(expiry);
+        }
+        Self::rewrite_addr(packet, 12, self.outside_addr, &layer4, header_len);
+        Self::rewrite_port(packet, port_offset, outside_port, &layer4, header_len);
+        true
+    }
+    /// Rewrites the destination of a raw IPv4 packet received on the outside address
+    /// Returns false if no mapping matches, the packet is then meant for us or should be dropped
+    pub fn translate_inbound(&mut self, packet: &mut [u8], now: Instant) -> bool {
+        let header_len = (packet[0] & 0b1111) as usize * 4;
+        let protocol = packet[9];
+        if packet[16..20] != self.outside_addr.octets()
+            || !Self::is_first_fragment(packet)
+            || packet.len() < header_len + 8
+        {
+            return false;
+        }
+        if protocol == ICMP && matches!(packet[header_len], 3 | 11 | 12) {
+            return self.translate_inbound_icmp_error(packet, header_len, now);
+        }
+        let Some(layer4) = Layer4::new(protocol, packet.get(header_len).copied()) else {
+            return false;
+        };
+        if protocol == ICMP && packet[header_len] != 0 || packet.len() < header_len + layer4.min_len
+        {
+            return false;
+        }
+        // Destination port for TCP and UDP, the identifier for ICMP echo replies
+        let port_offset = header_len + layer4.port_offset + if protocol == ICMP { 0 } else { 2 };
+        let outside_port = u16::from_be_bytes([packet[port_offset], packet[port_offset + 1]]);
+        let Some(key) = self.outside_ports.get(&(protocol, outside_port)).copied() else {
+            return false;
+        };
+        let mapping = self
+            .mappings
+            .get_mut(&key)
+            .expect("Reverse map is kept in sync");
+        mapping.last_used = now;
+        if protocol == tcp::PROTOCOL {
+            let segment = TcpSegment::new_unchecked(&packet[header_len..]);
+            mapping.update_tcp_state(segment.control_bits(), false);
+            let expiry = mapping.last_used + mapping.timeout();
+            self.schedule_expiry(expiry);
+        }
+        Self::rewrite_addr(packet, 16, key.addr, &layer4, header_len);
+        Self::rewrite_port(packet, port_offset, key.port, &layer4, header_len);
+        true
+    }
+    /// When `expire` has mappings to check
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_expiry
+    }
+    /// Forgets mappings which have not been used within their timeout, only looking through them
+    /// once the earliest one may have timed out
+    pub fn expire(&mut self, now: Instant) {
+        if self.next_expiry.is_none_or(|next_expiry| now < next_expiry) {
+            return;
+        }
+        let outside_ports = &mut self.outside_ports;
+        self.mappings.retain(|_, mapping| {
+            let alive = now < mapping.last_used + mapping.timeout();
+            if !alive {
+                debug!(
+                    protocol = mapping.protocol,
+                    outside_port = mapping.outside_port,
+                    "NAT mapping expired"
+                );
+                outside_ports.remove(&(mapping.protocol, mapping.outside_port));
+            }
+            alive
+        });
+        self.next_expiry = self
+            .mappings
+            .values()
+            .map(|mapping| mapping.last_used + mapping.timeout())
+            .min();
+    }
+    fn schedule_expiry(&mut self, expiry: Instant) {
+        self.next_expiry = Some(self.next_expiry.map_or(expiry, |next| next.min(expiry)));
+    }
+    /// ICMP errors quote the packet we translated, which has to be translated back as well (RFC 5508 4.2)
+    fn translate_inbound_icmp_error(
+        &mut self,
+        packet: &mut [u8],
+        header_len: usize,
+        now: Instant,
+    ) -> bool {
+        let inner_start = header_len + 8;
+        if packet.len() < inner_start + 20 {
+            return false;
+        }
+        let inner_header_len = (packet[inner_start] & 0b1111) as usize * 4;
+        let inner_protocol = packet[inner_start + 9];
+        let inner_layer4_start = inner_start + inner_header_len;
+        if packet.len() < inner_layer4_start + 8
+            || packet[inner_start + 12..inner_start + 16] != self.outside_addr.octets()
+        {
+            return false;
+        }
+        let Some(inner_layer4) =
+            Layer4::new(inner_protocol, packet.get(inner_layer4_start).copied())
+        else {
+            return false;
+        };
+        let port_offset = inner_layer4_start + inner_layer4.port_offset;
+        let outside_port = u16::from_be_bytes([packet[port_offset], packet[port_offset + 1]]);
+        let Some(key) = self
+            .outside_ports
+            .get(&(inner_protocol, outside_port))
+            .copied()
+        else {
+            return false;
+        };
+        if let Some(mapping) = self.mappings.get_mut(&key) {
+            mapping.last_used = now;
+        }
+        // Quoted packet, the TCP checksum is not within the 8 quoted bytes
+        let inner = &mut packet[inner_start..];
+        let inner_layer4 = Layer4 {
+            checksum_offset: if inner_layer4.checksum_offset < 8 {
+                inner_layer4.checksum_offset
+            } else {
+                usize::MAX
+            },
+            ..inner_layer4
+        };
+        Self::rewrite_addr(inner, 12, key.addr, &inner_layer4, inner_header_len);
+        Self::rewrite_port(
+            inner,
+            inner_header_len + inner_layer4.port_offset,
+            key.port,
+            &inner_layer4,
+            inner_header_len,
+        );
+        // Outer destination, the ICMP checksum has no pseudo header
+        let outer_layer4 = Layer4 {
+            port_offset: 0,
+            checksum_offset: usize::MAX,
+            min_len: 8,
+            pseudo_header: false,
+        };
+        Self::rewrite_addr(packet, 16, key.addr, &outer_layer4, header_len);
+        // The quoted bytes changed, so the ICMP checksum is recomputed over the whole message
+        let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        let end = total_length.min(packet.len());
+        let icmp = &mut packet[header_len..end];
+        icmp[2..4].copy_from_slice(&[0x0, 0x0]);
+        let icmp_checksum = !ones_complement_sum_byte_buffer(icmp);
+        icmp[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+        true
+    }
+    fn mapping_for(&mut self, key: InsideKey, now: Instant) -> Option<u16> {
+        if let Some(mapping) = self.mappings.get(&key) {
+            return Some(mapping.outside_port);
+        }
+        // Search for a free port, wrapping around the range once
+        let range_len = PORT_RANGE.end() - PORT_RANGE.start() + 1;
+        for _ in 0..range_len {
+            let port = self.next_port;
+            self.next_port = if port == *PORT_RANGE.end() {
+                *PORT_RANGE.start()
+            } else {
+                port + 1
+            };
+            if self.outside_ports.contains_key(&(key.protocol, port)) {
+                continue;
+            }
+            self.outside_ports.insert((key.protocol, port), key);
+            debug!(
+                protocol = key.protocol,
+                inside = %key.addr,
+                inside_port = key.port,
+                outside_port = port,
+                "NAT mapping created"
+            );
+            let mapping = Mapping {
+                protocol: key.protocol,
+                inside_addr: key.addr,
+                inside_port: key.port,
+                outside_port: port,
+                last_used: now,
+                tcp_state: TCPMappingState::Opening,
+            };
+            self.schedule_expiry(now + mapping.timeout());
+            self.mappings.insert(key, mapping);
+            return Some(port);
+        }
+        warn!(protocol = key.protocol, "NAT port range exhausted");
+        None
+    }
+    fn is_first_fragment(packet: &[u8]) -> bool {
+        u16::from_be_bytes([packet[6], packet[7]]) & 0x1FFF == 0
+    }
+    /// Replaces the address at `offset`, fixing up the IP header and pseudo header checksums
+    fn rewrite_addr(
+        packet: &mut [u8],
+        offset: usize,
+        addr: Ipv4Addr,
+        layer4: &Layer4,
+        header_len: usize,
+    ) {
+        let old = u32::from_be_bytes([
+            packet[offset],
+            packet[offset + 1],
+            packet[offset + 2],
+            packet[offset + 3],
+        ]);
+        let new = addr.to_bits();
+        packet[offset..offset + 4].copy_from_slice(&addr.octets());
+        let update = |checksum| incremental_update_u32(checksum, old, new);
+        Self::update_checksum(packet, 10, false, update);
+        if layer4.pseudo_header {
+            let is_udp = layer4.checksum_offset == 6;
+            Self::update_checksum(
+                packet,
+                header_len.saturating_add(layer4.checksum_offset),
+                is_udp,
+                update,
+            );
+        }
+    }
+    fn rewrite_port(
+        packet: &mut [u8],
+        offset: usize,
+        port: u16,
+        layer4: &Layer4,
+        header_len: usize,
+    ) {
+        let old = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+        packet[offset..offset + 2].copy_from_slice(&port.to_be_bytes());
+        let is_udp = layer4.pseudo_header && layer4.checksum_offset == 6;
+        Self::update_checksum(
+            packet,
+            header_len.saturating_add(layer4.checksum_offset),
+            is_udp,
+            |checksum| incremental_update(checksum, old, port),
+        );
+    }
+    /// Applies the incremental `update` to the checksum at `offset`
+    fn update_checksum(
+        packet: &mut [u8],
+        offset: usize,
+        is_udp: bool,
+        update: impl FnOnce(u16) -> u16,
+    ) {
+        if offset.saturating_add(2) > packet.len() {
+            return;
+        }
+        let checksum = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+        if is_udp && checksum == 0 {
+            // No checksum was sent
+            return;
+        }
+        let mut checksum = update(checksum);
+        if is_udp && checksum == 0 {
+            checksum = 0xFFFF;
+        }
+        packet[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddrV4};
+
+    use super::*;
+    use crate::{
+        ip::IPHeader,
+        protocol::{ICMPBody, IPBody, ICMP, UDP},
+        test_util::tcp_packet,
+        IPPacket,
+    };
+
+    const INSIDE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const OUTSIDE: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    fn packet(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, ip_body: IPBody) -> Vec<u8> {
+        let ip_header = IPHeader::from_body(
+            4,
+            0,
+            1,
+            0,
+            0,
+            64,
+            protocol,
+            source,
+            destination,
+            None,
+            ip_body.len() as u16,
+        );
+        IPPacket::new(ip_header, ip_body).to_byte_buffer()
+    }
+
+    fn udp(source: (Ipv4Addr, u16), destination: (Ipv4Addr, u16)) -> Vec<u8> {
+        let ip_body = IPBody::UDP(
+            UDP::new(
+                &IpAddr::V4(source.0),
+                &IpAddr::V4(destination.0),
+                source.1,
+                destination.1,
+                vec![0x1, 0x2, 0x3],
+            )
+            .unwrap(),
+        );
+        packet(source.0, destination.0, udp::PROTOCOL, ip_body)
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        let now = Instant::now();
+        let mut nat = Nat::new("wan", OUTSIDE);
+        let mut buf = udp((INSIDE, 5000), (SERVER, 53));
+        assert!(nat.translate_outbound(&mut buf, now));
+        // Checksums are still valid after the incremental updates
+        let translated = IPPacket::from_byte_buffer(&buf).unwrap();
+        assert_eq!(translated.header.source_addr, OUTSIDE);
+        let IPBody::UDP(datagram) = translated.body else {
+            panic!("Should be UDP");
+        };
+        assert_eq!(datagram.source_port, *PORT_RANGE.start());
+
+        let mut reply = udp((SERVER, 53), (OUTSIDE, datagram.source_port));
+        assert!(nat.translate_inbound(&mut reply, now));
+        let translated = IPPacket::from_byte_buffer(&reply).unwrap();
+        assert_eq!(translated.header.destination_addr, INSIDE);
+        let IPBody::UDP(datagram) = translated.body else {
+            panic!("Should be UDP");
+        };
+        assert_eq!(datagram.destination_port, 5000);
+
+        // Same flow reuses the mapping, unknown ports are not translated
+        let mut buf = udp((INSIDE, 5000), (SERVER, 53));
+        assert!(nat.translate_outbound(&mut buf, now));
+        assert_eq!(nat.mappings().count(), 1);
+        let mut unsolicited = udp((SERVER, 53), (OUTSIDE, 1234));
+        assert!(!nat.translate_inbound(&mut unsolicited, now));
+    }
+
+    #[test]
+    fn truncated_tcp_is_not_translated() {
+        let now = Instant::now();
+        let mut nat = Nat::new("wan", OUTSIDE);
+        // Ports fit but the control bits and checksum do not
+        let mut buf = udp((INSIDE, 5000), (SERVER, 80));
+        buf[9] = tcp::PROTOCOL;
+        buf.truncate(30);
+        assert!(!nat.translate_outbound(&mut buf, now));
+        assert_eq!(nat.mappings().count(), 0);
+        let mut buf = udp((SERVER, 80), (OUTSIDE, *PORT_RANGE.start()));
+        buf[9] = tcp::PROTOCOL;
+        buf.truncate(30);
+        assert!(!nat.translate_inbound(&mut buf, now));
+    }
+
+    #[test]
+    fn tcp_checksum_and_state() {
+        let now = Instant::now();
+        let mut nat = Nat::new("wan", OUTSIDE);
+        let segment = |source: (Ipv4Addr, u16), destination: (Ipv4Addr, u16), control_bits| {
+            tcp_packet(
+                SocketAddrV4::new(source.0, source.1),
+                SocketAddrV4::new(destination.0, destination.1),
+                100,
+                0,
+                control_bits,
+                &[],
+            )
+        };
+        let mut syn = segment((INSIDE, 40000), (SERVER, 80), TCPControlBits::SYN);
+        assert!(nat.translate_outbound(&mut syn, now));
+        assert!(IPPacket::from_byte_buffer(&syn).is_ok());
+        let outside_port = u16::from_be_bytes([syn[20], syn[21]]);
+        assert_eq!(
+            nat.mappings().next().unwrap().tcp_state,
+            TCPMappingState::Opening
+        );
+
+        let mut syn_ack = segment(
+            (SERVER, 80),
+            (OUTSIDE, outside_port),
+            TCPControlBits::SYN | TCPControlBits::ACK,
+        );
+        assert!(nat.translate_inbound(&mut syn_ack, now));
+        assert!(IPPacket::from_byte_buffer(&syn_ack).is_ok());
+        assert_eq!(
+            nat.mappings().next().unwrap().tcp_state,
+            TCPMappingState::Established
+        );
+        nat.expire(now + TCP_TRANSITORY_TIMEOUT);
+        assert_eq!(nat.mappings().count(), 1);
+
+        let mut fin = segment(
+            (INSIDE, 40000),
+            (SERVER, 80),
+            TCPControlBits::FIN | TCPControlBits::ACK,
+        );
+        assert!(nat.translate_outbound(&mut fin, now));
+        nat.expire(now + TCP_TRANSITORY_TIMEOUT);
+        assert_eq!(nat.mappings().count(), 0);
+    }
+
+    #[test]
+    fn icmp_echo_identifier() {
+        let now = Instant::now();
+        let mut nat = Nat::new("wan", OUTSIDE);
+        let echo = |_type, source, destination, identifier| {
+            let body = ICMPBody::Echo {
+                identifier,
+                sequence_number: 1,
+                data: vec![0xab; 8],
+            };
+            packet(
+                source,
+                destination,
+                ICMP,
+                IPBody::ICMP(ICMP::new(_type, 0, body)),
+            )
+        };
+        let mut request = echo(8, INSIDE, SERVER, 0x1d);
+        assert!(nat.translate_outbound(&mut request, now));
+        assert!(IPPacket::from_byte_buffer(&request).is_ok());
+        let identifier = u16::from_be_bytes([request[24], request[25]]);
+        assert_ne!(identifier, 0x1d);
+
+        let mut reply = echo(0, SERVER, OUTSIDE, identifier);
+        assert!(nat.translate_inbound(&mut reply, now));
+        assert!(IPPacket::from_byte_buffer(&reply).is_ok());
+        assert_eq!(reply[24..26], 0x1d_u16.to_be_bytes());
+
+        nat.expire(now + ICMP_TIMEOUT);
+        let mut reply = echo(0, SERVER, OUTSIDE, identifier);
+        assert!(!nat.translate_inbound(&mut reply, now));
+    }
+
+    #[test]
+    fn icmp_error_quotes_are_translated() {
+        let now = Instant::now();
+        let mut nat = Nat::new("wan", OUTSIDE);
+        let mut buf = udp((INSIDE, 5000), (SERVER, 53));
+        nat.translate_outbound(&mut buf, now);
+
+        // Port unreachable from the server, quoting our translated datagram
+        let icmp = ICMP::new(
+            3,
+            3,
+            ICMPBody::DestinationUnreachable {
+                next_hop_mtu: 0,
+                data: ICMPBody::original_datagram(&buf),
+            },
+        );
+        let mut error = packet(SERVER, OUTSIDE, ICMP, IPBody::ICMP(icmp));
+        assert!(nat.translate_inbound(&mut error, now));
+        let translated = IPPacket::from_byte_buffer(&error).unwrap();
+        assert_eq!(translated.header.destination_addr, INSIDE);
+        let IPBody::ICMP(ICMP {
+            body: ICMPBody::DestinationUnreachable { data, .. },
+            ..
+        }) = translated.body
+        else {
+            panic!("Should be an ICMP error");
+        };
+        let quoted_header = IPHeader::from_byte_buffer(&data[..20]).unwrap();
+        assert_eq!(quoted_header.source_addr, INSIDE);
+        assert_eq!(data[20..22], 5000_u16.to_be_bytes());
+    }
+}
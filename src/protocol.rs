@@ -1,25 +1,60 @@
 pub mod icmp;
+pub mod icmpv6;
 pub mod tcp;
+pub mod udp;
 
 pub use icmp::{ICMPBody, ICMP};
-pub use tcp::{TCPControlBits, TCP};
+pub use icmpv6::{ICMPv6, ICMPv6Body};
+pub use tcp::{TCPBuilder, TCPControlBits, TCPOption, TcpSegment, TCP};
+pub use udp::{UdpDatagram, UDP};
 
-use crate::ip::{IPHeader, IPPacketError, IPPacketErrorKind};
+use std::{fmt, net::IpAddr};
+
+use crate::{
+    buffer::PacketBuffer,
+    ip::{IPHeader, IPPacketError, IPPacketErrorKind},
+};
 
 pub enum IPBody {
     ICMP(ICMP),
+    ICMPv6(ICMPv6),
     TCP(TCP),
+    UDP(UDP),
 }
 impl IPBody {
     /// body_buf (The IP packet's body buffer of bytes starts at 0)
     pub fn from_byte_buffer(ip_header: &IPHeader, body_buf: &[u8]) -> Result<Self, IPPacketError> {
-        match ip_header.protocol {
+        Self::from_protocol(
+            ip_header.protocol,
+            &IpAddr::V4(ip_header.source_addr),
+            &IpAddr::V4(ip_header.destination_addr),
+            body_buf,
+        )
+    }
+    /// Parses the body of either an IPv4 or IPv6 packet given the upper layer protocol number
+    pub fn from_protocol(
+        protocol: u8,
+        source_addr: &IpAddr,
+        destination_addr: &IpAddr,
+        body_buf: &[u8],
+    ) -> Result<Self, IPPacketError> {
+        match protocol {
             1 => Ok(Self::ICMP(ICMP::from_byte_buffer(body_buf)?)),
+            icmpv6::PROTOCOL => Ok(Self::ICMPv6(ICMPv6::from_byte_buffer(
+                body_buf,
+                source_addr,
+                destination_addr,
+            )?)),
             6 => Ok(Self::TCP(TCP::from_byte_buffer(
                 body_buf,
-                &ip_header.source_addr,
-                &ip_header.destination_addr,
-                ip_header.protocol,
+                source_addr,
+                destination_addr,
+                protocol,
+            )?)),
+            udp::PROTOCOL => Ok(Self::UDP(UDP::from_byte_buffer(
+                body_buf,
+                source_addr,
+                destination_addr,
             )?)),
             _ => Err(IPPacketError::new(IPPacketErrorKind::NotImplementedYet)),
         }
@@ -27,15 +62,82 @@ impl IPBody {
     pub fn to_byte_buffer(&self) -> Vec<u8> {
         match self {
             Self::ICMP(icmp) => icmp.to_byte_buffer(),
+            Self::ICMPv6(icmp) => icmp.to_byte_buffer(),
             Self::TCP(tcp) => tcp.to_byte_buffer(),
-            _ => panic!("Not implemented yet"),
+            Self::UDP(udp) => udp.to_byte_buffer(),
+        }
+    }
+    /// Puts the body in front of the bytes already in `buf`, using its headroom
+    pub fn prepend_to(&self, buf: &mut PacketBuffer) {
+        match self {
+            Self::TCP(tcp) => tcp.prepend_to(buf),
+            Self::UDP(udp) => udp.prepend_to(buf),
+            // ICMP messages are small and rare, so they are serialized first
+            Self::ICMP(_) | Self::ICMPv6(_) => {
+                let body = self.to_byte_buffer();
+                buf.prepend(body.len()).copy_from_slice(&body);
+            }
         }
     }
     pub fn len(&self) -> usize {
         match self {
             Self::ICMP(icmp) => icmp.len(),
+            Self::ICMPv6(icmp) => icmp.len(),
             Self::TCP(tcp) => tcp.len(),
-            _ => panic!("Not implemented yet"),
+            Self::UDP(udp) => udp.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Protocol number of the body in the IP header
+    pub fn protocol(&self) -> u8 {
+        match self {
+            Self::ICMP(_) => 1,
+            Self::ICMPv6(_) => icmpv6::PROTOCOL,
+            Self::TCP(_) => tcp::PROTOCOL,
+            Self::UDP(_) => udp::PROTOCOL,
         }
     }
+    /// Source and destination ports for protocols which have them
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        match self {
+            Self::TCP(tcp) => Some((tcp.source_port, tcp.destination_port)),
+            Self::UDP(udp) => Some((udp.source_port, udp.destination_port)),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for IPBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ICMP(icmp) => fmt::Display::fmt(icmp, f),
+            Self::ICMPv6(icmp) => fmt::Display::fmt(icmp, f),
+            Self::TCP(tcp) => fmt::Display::fmt(tcp, f),
+            Self::UDP(udp) => fmt::Display::fmt(udp, f),
+        }
+    }
+}
+
+/// Name of an IP protocol number, as used in the dissections
+pub fn protocol_name(protocol: u8) -> &'static str {
+    match protocol {
+        0 => "Hop-by-Hop",
+        1 => "ICMP",
+        6 => "TCP",
+        17 => "UDP",
+        43 => "Routing",
+        44 => "Fragment",
+        58 => "ICMPv6",
+        60 => "Destination Options",
+        _ => "unknown",
+    }
+}
+
+/// Indents every line of the verbose view of an inner layer
+pub(crate) fn indent(text: &str) -> String {
+    text.lines()
+        .map(|line| format!("  {}", line))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
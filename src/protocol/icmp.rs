@@ -1,5 +1,7 @@
+use std::fmt;
+
 use crate::checksum::ones_complement_sum_byte_buffer;
-use crate::ip::{IPPacketError, IPPacketErrorKind};
+use crate::ip::{IPHeader, IPPacketError, IPPacketErrorKind};
 
 #[derive(Debug, Clone)]
 pub struct ICMP {
@@ -25,6 +27,9 @@ impl ICMP {
         if ones_complement_sum_byte_buffer(buf) != 0xFFFF {
             return Err(IPPacketError::new(IPPacketErrorKind::ICMPChecksumError));
         }
+        if buf.len() < 4 {
+            return Err(IPPacketError::new(IPPacketErrorKind::ICMPLengthError));
+        }
         let _type = buf[0];
         let code = buf[1];
         let checksum = u16::from_be_bytes([buf[2], buf[3]]);
@@ -32,7 +37,7 @@ impl ICMP {
             _type,
             code,
             checksum,
-            body: ICMPBody::from_byte_buffer(_type, &buf[4..]),
+            body: ICMPBody::from_byte_buffer(_type, &buf[4..])?,
         })
     }
     pub fn to_byte_buffer(&self) -> Vec<u8> {
@@ -44,6 +49,9 @@ impl ICMP {
     pub fn len(&self) -> usize {
         4 + self.body.len()
     }
+    pub fn is_empty(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -53,7 +61,11 @@ pub enum ICMPBody {
         sequence_number: u16,
         data: Vec<u8>,
     },
-    DestinationUnreachable,
+    /// `next_hop_mtu` is only used by code 4, fragmentation needed and DF set (RFC 1191)
+    DestinationUnreachable {
+        next_hop_mtu: u16,
+        data: Vec<u8>,
+    },
     SourceQuench,
     Redirect,
     Echo {
@@ -61,7 +73,9 @@ pub enum ICMPBody {
         sequence_number: u16,
         data: Vec<u8>,
     },
-    TimeExceeded,
+    TimeExceeded {
+        data: Vec<u8>,
+    },
     ParameterProblem,
     Timestamp,
     TimestampReply,
@@ -69,6 +83,11 @@ pub enum ICMPBody {
     InformationReply,
 }
 impl ICMPBody {
+    /// Error messages quote the internet header and the first 64 bits of the original datagram
+    pub fn original_datagram(buf: &[u8]) -> Vec<u8> {
+        let ihl = (buf[0] & 0b1111) as usize;
+        buf[..buf.len().min(ihl * 4 + 8)].to_vec()
+    }
     pub fn to_byte_buffer(&self) -> Vec<u8> {
         match self {
             Self::Echo {
@@ -86,17 +105,35 @@ impl ICMPBody {
                 buf.append(&mut data.clone());
                 buf
             }
+            Self::DestinationUnreachable { next_hop_mtu, data } => {
+                let mut buf = vec![0x0, 0x0]; // Unused
+                buf.append(&mut next_hop_mtu.to_be_bytes().to_vec());
+                buf.append(&mut data.clone());
+                buf
+            }
+            Self::TimeExceeded { data } => {
+                let mut buf = vec![0x0; 4]; // Unused
+                buf.append(&mut data.clone());
+                buf
+            }
             _ => panic!("Not implemented yet"),
         }
     }
-    pub fn from_byte_buffer(_type: u8, body_buf: &[u8]) -> Self {
-        match _type {
+    pub fn from_byte_buffer(_type: u8, body_buf: &[u8]) -> Result<Self, IPPacketError> {
+        // Echo and the error messages start with 4 bytes of identifiers, MTU or unused
+        if matches!(_type, 0 | 3 | 8 | 11) && body_buf.len() < 4 {
+            return Err(IPPacketError::new(IPPacketErrorKind::ICMPLengthError));
+        }
+        Ok(match _type {
             0 => Self::EchoReply {
                 identifier: u16::from_be_bytes([body_buf[0], body_buf[1]]),
                 sequence_number: u16::from_be_bytes([body_buf[2], body_buf[3]]),
                 data: body_buf[4..].to_vec(),
             },
-            3 => Self::DestinationUnreachable,
+            3 => Self::DestinationUnreachable {
+                next_hop_mtu: u16::from_be_bytes([body_buf[2], body_buf[3]]),
+                data: body_buf[4..].to_vec(),
+            },
             4 => Self::SourceQuench,
             5 => Self::Redirect,
             8 => Self::Echo {
@@ -104,14 +141,16 @@ impl ICMPBody {
                 sequence_number: u16::from_be_bytes([body_buf[2], body_buf[3]]),
                 data: body_buf[4..].to_vec(),
             },
-            11 => Self::TimeExceeded,
+            11 => Self::TimeExceeded {
+                data: body_buf[4..].to_vec(),
+            },
             12 => Self::ParameterProblem,
             13 => Self::Timestamp,
             14 => Self::TimestampReply,
             15 => Self::InformationRequest,
             16 => Self::InformationReply,
-            _ => panic!("ICMP should not be able to have this type"),
-        }
+            _ => return Err(IPPacketError::new(IPPacketErrorKind::NotImplementedYet)),
+        })
     }
     pub fn len(&self) -> usize {
         match self {
@@ -125,9 +164,92 @@ impl ICMPBody {
                 sequence_number: _,
                 data,
             } => 4 + data.len(), // 4 bytes from identifier and sequence_number
+            Self::DestinationUnreachable { data, .. } | Self::TimeExceeded { data } => {
+                4 + data.len() // 4 bytes unused or next hop MTU
+            }
             _ => panic!("Not implemented yet"),
         }
     }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Display for ICMP {
+    /// `ICMP echo request, id 15, seq 1, length 64`, or a tree of every field with `{:#}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match &self.body {
+            ICMPBody::EchoReply {
+                identifier,
+                sequence_number,
+                ..
+            } => format!("echo reply, id {}, seq {}", identifier, sequence_number),
+            ICMPBody::Echo {
+                identifier,
+                sequence_number,
+                ..
+            } => format!("echo request, id {}, seq {}", identifier, sequence_number),
+            ICMPBody::DestinationUnreachable { next_hop_mtu, .. } => match self.code {
+                0 => "net unreachable".to_string(),
+                1 => "host unreachable".to_string(),
+                2 => "protocol unreachable".to_string(),
+                3 => "port unreachable".to_string(),
+                4 => format!("need to frag (mtu {})", next_hop_mtu),
+                code => format!("unreachable, code {}", code),
+            },
+            ICMPBody::TimeExceeded { .. } => match self.code {
+                0 => "time exceeded in-transit".to_string(),
+                _ => "ip reassembly time exceeded".to_string(),
+            },
+            ICMPBody::SourceQuench => "source quench".to_string(),
+            ICMPBody::Redirect => "redirect".to_string(),
+            ICMPBody::ParameterProblem => "parameter problem".to_string(),
+            ICMPBody::Timestamp => "time stamp request".to_string(),
+            ICMPBody::TimestampReply => "time stamp reply".to_string(),
+            ICMPBody::InformationRequest => "information request".to_string(),
+            ICMPBody::InformationReply => "information reply".to_string(),
+        };
+        if !f.alternate() {
+            return match &self.body {
+                ICMPBody::EchoReply { .. }
+                | ICMPBody::Echo { .. }
+                | ICMPBody::DestinationUnreachable { .. }
+                | ICMPBody::TimeExceeded { .. } => {
+                    write!(f, "ICMP {}, length {}", description, self.len())
+                }
+                // The body of the other messages is not kept
+                _ => write!(
+                    f,
+                    "ICMP {}, type {}, code {}",
+                    description, self._type, self.code
+                ),
+            };
+        }
+        writeln!(
+            f,
+            "ICMP type {}, code {}, checksum {:#06x}",
+            self._type, self.code, self.checksum
+        )?;
+        write!(f, "  {}", description)?;
+        match &self.body {
+            ICMPBody::EchoReply { data, .. } | ICMPBody::Echo { data, .. } => {
+                write!(f, "\n  data {} bytes", data.len())
+            }
+            ICMPBody::DestinationUnreachable { data, .. } | ICMPBody::TimeExceeded { data } => {
+                // The quoted header of the datagram which caused the error
+                let header_len = data.first().map_or(0, |byte| (byte & 0b1111) as usize * 4);
+                match data
+                    .get(..header_len)
+                    .filter(|_| header_len >= 20)
+                    .and_then(|header| IPHeader::from_byte_buffer(header).ok())
+                {
+                    Some(header) => write!(f, "\n  quoted {}", header),
+                    None => write!(f, "\n  quoted {} bytes", data.len()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -143,7 +265,7 @@ mod tests {
             0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d, 0x2e, 0x2f, 0x30, 0x31, 0x32,
             0x33, 0x34, 0x35, 0x36, 0x37,
         ];
-        let icmp = ICMP::from_byte_buffer(&buf).unwrap();
+        ICMP::from_byte_buffer(&buf).unwrap();
     }
 
     #[test]
@@ -162,6 +284,30 @@ mod tests {
         assert_eq!(icmp.checksum, 0xc866);
     }
 
+    #[test]
+    fn display_bodies_without_data() {
+        for _type in [4, 5, 12, 13, 14, 15, 16] {
+            let icmp = ICMP {
+                _type,
+                code: 0,
+                checksum: 0x0,
+                body: ICMPBody::from_byte_buffer(_type, &[]).unwrap(),
+            };
+            assert!(icmp
+                .to_string()
+                .ends_with(&format!("type {}, code 0", _type)));
+            assert!(format!("{:#}", icmp).starts_with(&format!("ICMP type {}", _type)));
+        }
+    }
+
+    #[test]
+    fn display_quoted_header_too_short() {
+        // An IHL of 1 claims a 4 byte header
+        let data = vec![0x41; 8];
+        let icmp = ICMP::new(11, 0, ICMPBody::TimeExceeded { data });
+        assert!(format!("{:#}", icmp).ends_with("quoted 8 bytes"));
+    }
+
     mod icmp_body_tests {
         use crate::protocol::icmp::ICMPBody;
 
@@ -175,7 +321,7 @@ mod tests {
                 0x27, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d, 0x2e, 0x2f, 0x30, 0x31, 0x32, 0x33, 0x34,
                 0x35, 0x36, 0x37,
             ];
-            let icmp_body = ICMPBody::from_byte_buffer(_type, &buf);
+            let icmp_body = ICMPBody::from_byte_buffer(_type, &buf).unwrap();
             assert_eq!(
                 icmp_body,
                 ICMPBody::Echo {
@@ -0,0 +1,529 @@
+pub mod ndp;
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use crate::checksum::{pseudo_header_sum, Checksum};
+use crate::ip::{IPPacketError, IPPacketErrorKind};
+
+pub use ndp::NDOption;
+
+/// Next header value for ICMPv6
+pub const PROTOCOL: u8 = 58;
+/// Error messages must not make the reply exceed the minimum IPv6 MTU (RFC 4443 2.4)
+const MAX_INVOKING_PACKET_LEN: usize = 1280 - 40 - 8;
+
+#[derive(Debug, Clone)]
+pub struct ICMPv6 {
+    pub _type: u8,
+    pub code: u8,
+    pub checksum: u16,
+    pub body: ICMPv6Body,
+}
+impl ICMPv6 {
+    /// Creates a new ICMPv6 message, the checksum covers the IPv6 pseudo header
+    pub fn new(
+        source_address: &Ipv6Addr,
+        destination_address: &Ipv6Addr,
+        code: u8,
+        body: ICMPv6Body,
+    ) -> Self {
+        let mut icmp = Self {
+            _type: body.message_type(),
+            code,
+            checksum: 0x0,
+            body,
+        };
+        let buf = icmp.to_byte_buffer();
+        icmp.checksum = Checksum::new()
+            .add_ipv6_pseudo_header(
+                source_address,
+                destination_address,
+                PROTOCOL,
+                buf.len() as u32,
+            )
+            .add(&buf)
+            .finish();
+        icmp
+    }
+    pub fn from_byte_buffer(
+        buf: &[u8],
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
+    ) -> Result<Self, IPPacketError> {
+        if buf.len() < 4 {
+            return Err(IPPacketError::new(IPPacketErrorKind::ICMPv6LengthError));
+        }
+        if !pseudo_header_sum(source_address, destination_address, PROTOCOL, buf)
+            .is_ok_and(|sum| sum == 0xFFFF)
+        {
+            return Err(IPPacketError::new(IPPacketErrorKind::ICMPv6ChecksumError));
+        }
+        let _type = buf[0];
+        Ok(Self {
+            _type,
+            code: buf[1],
+            checksum: u16::from_be_bytes([buf[2], buf[3]]),
+            body: ICMPv6Body::from_byte_buffer(_type, &buf[4..])?,
+        })
+    }
+    pub fn to_byte_buffer(&self) -> Vec<u8> {
+        let mut buf = vec![self._type, self.code];
+        buf.append(&mut self.checksum.to_be_bytes().to_vec());
+        buf.append(&mut self.body.to_byte_buffer());
+        buf
+    }
+    pub fn len(&self) -> usize {
+        4 + self.body.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ICMPv6Body {
+    DestinationUnreachable {
+        invoking_packet: Vec<u8>,
+    },
+    PacketTooBig {
+        mtu: u32,
+        invoking_packet: Vec<u8>,
+    },
+    TimeExceeded {
+        invoking_packet: Vec<u8>,
+    },
+    ParameterProblem {
+        pointer: u32,
+        invoking_packet: Vec<u8>,
+    },
+    EchoRequest {
+        identifier: u16,
+        sequence_number: u16,
+        data: Vec<u8>,
+    },
+    EchoReply {
+        identifier: u16,
+        sequence_number: u16,
+        data: Vec<u8>,
+    },
+    RouterSolicitation {
+        options: Vec<NDOption>,
+    },
+    RouterAdvertisement {
+        cur_hop_limit: u8,
+        managed: bool,
+        other: bool,
+        router_lifetime: u16,
+        reachable_time: u32,
+        retrans_timer: u32,
+        options: Vec<NDOption>,
+    },
+    NeighborSolicitation {
+        target_addr: Ipv6Addr,
+        options: Vec<NDOption>,
+    },
+    NeighborAdvertisement {
+        router: bool,
+        solicited: bool,
+        override_flag: bool,
+        target_addr: Ipv6Addr,
+        options: Vec<NDOption>,
+    },
+}
+impl ICMPv6Body {
+    /// Truncates the offending packet so the error message fits in the minimum MTU
+    pub fn invoking_packet(buf: &[u8]) -> Vec<u8> {
+        buf[..buf.len().min(MAX_INVOKING_PACKET_LEN)].to_vec()
+    }
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Self::DestinationUnreachable { .. } => 1,
+            Self::PacketTooBig { .. } => 2,
+            Self::TimeExceeded { .. } => 3,
+            Self::ParameterProblem { .. } => 4,
+            Self::EchoRequest { .. } => 128,
+            Self::EchoReply { .. } => 129,
+            Self::RouterSolicitation { .. } => 133,
+            Self::RouterAdvertisement { .. } => 134,
+            Self::NeighborSolicitation { .. } => 135,
+            Self::NeighborAdvertisement { .. } => 136,
+        }
+    }
+    /// Error messages have a type below 128 (RFC 4443 2.1)
+    pub fn is_error(&self) -> bool {
+        self.message_type() < 128
+    }
+    pub fn to_byte_buffer(&self) -> Vec<u8> {
+        match self {
+            Self::DestinationUnreachable { invoking_packet }
+            | Self::TimeExceeded { invoking_packet } => {
+                let mut buf = vec![0x0; 4]; // Unused
+                buf.append(&mut invoking_packet.clone());
+                buf
+            }
+            Self::PacketTooBig {
+                mtu: value,
+                invoking_packet,
+            }
+            | Self::ParameterProblem {
+                pointer: value,
+                invoking_packet,
+            } => {
+                let mut buf = value.to_be_bytes().to_vec();
+                buf.append(&mut invoking_packet.clone());
+                buf
+            }
+            Self::EchoRequest {
+                identifier,
+                sequence_number,
+                data,
+            }
+            | Self::EchoReply {
+                identifier,
+                sequence_number,
+                data,
+            } => {
+                let mut buf = identifier.to_be_bytes().to_vec();
+                buf.append(&mut sequence_number.to_be_bytes().to_vec());
+                buf.append(&mut data.clone());
+                buf
+            }
+            Self::RouterSolicitation { options } => {
+                let mut buf = vec![0x0; 4]; // Reserved
+                buf.append(&mut NDOption::to_byte_buffer_all(options));
+                buf
+            }
+            Self::RouterAdvertisement {
+                cur_hop_limit,
+                managed,
+                other,
+                router_lifetime,
+                reachable_time,
+                retrans_timer,
+                options,
+            } => {
+                let mut buf = vec![
+                    *cur_hop_limit,
+                    ((*managed as u8) << 7) + ((*other as u8) << 6),
+                ];
+                buf.append(&mut router_lifetime.to_be_bytes().to_vec());
+                buf.append(&mut reachable_time.to_be_bytes().to_vec());
+                buf.append(&mut retrans_timer.to_be_bytes().to_vec());
+                buf.append(&mut NDOption::to_byte_buffer_all(options));
+                buf
+            }
+            Self::NeighborSolicitation {
+                target_addr,
+                options,
+            } => {
+                let mut buf = vec![0x0; 4]; // Reserved
+                buf.append(&mut target_addr.octets().to_vec());
+                buf.append(&mut NDOption::to_byte_buffer_all(options));
+                buf
+            }
+            Self::NeighborAdvertisement {
+                router,
+                solicited,
+                override_flag,
+                target_addr,
+                options,
+            } => {
+                let mut buf = vec![
+                    ((*router as u8) << 7)
+                        + ((*solicited as u8) << 6)
+                        + ((*override_flag as u8) << 5),
+                    0x0,
+                    0x0,
+                    0x0,
+                ];
+                buf.append(&mut target_addr.octets().to_vec());
+                buf.append(&mut NDOption::to_byte_buffer_all(options));
+                buf
+            }
+        }
+    }
+    pub fn from_byte_buffer(_type: u8, body_buf: &[u8]) -> Result<Self, IPPacketError> {
+        let min_len = match _type {
+            1..=4 | 128 | 129 | 133 => 4,
+            134 => 12,
+            135 | 136 => 20,
+            _ => return Err(IPPacketError::new(IPPacketErrorKind::NotImplementedYet)),
+        };
+        if body_buf.len() < min_len {
+            return Err(IPPacketError::new(IPPacketErrorKind::ICMPv6LengthError));
+        }
+        let word = u32::from_be_bytes([body_buf[0], body_buf[1], body_buf[2], body_buf[3]]);
+        Ok(match _type {
+            1 => Self::DestinationUnreachable {
+                invoking_packet: body_buf[4..].to_vec(),
+            },
+            2 => Self::PacketTooBig {
+                mtu: word,
+                invoking_packet: body_buf[4..].to_vec(),
+            },
+            3 => Self::TimeExceeded {
+                invoking_packet: body_buf[4..].to_vec(),
+            },
+            4 => Self::ParameterProblem {
+                pointer: word,
+                invoking_packet: body_buf[4..].to_vec(),
+            },
+            128 => Self::EchoRequest {
+                identifier: (word >> 16) as u16,
+                sequence_number: word as u16,
+                data: body_buf[4..].to_vec(),
+            },
+            129 => Self::EchoReply {
+                identifier: (word >> 16) as u16,
+                sequence_number: word as u16,
+                data: body_buf[4..].to_vec(),
+            },
+            133 => Self::RouterSolicitation {
+                options: NDOption::from_byte_buffer_all(&body_buf[4..])?,
+            },
+            134 => Self::RouterAdvertisement {
+                cur_hop_limit: body_buf[0],
+                managed: body_buf[1] >> 7 == 1,
+                other: (body_buf[1] >> 6) & 0b1 == 1,
+                router_lifetime: u16::from_be_bytes([body_buf[2], body_buf[3]]),
+                reachable_time: u32::from_be_bytes([
+                    body_buf[4],
+                    body_buf[5],
+                    body_buf[6],
+                    body_buf[7],
+                ]),
+                retrans_timer: u32::from_be_bytes([
+                    body_buf[8],
+                    body_buf[9],
+                    body_buf[10],
+                    body_buf[11],
+                ]),
+                options: NDOption::from_byte_buffer_all(&body_buf[12..])?,
+            },
+            135 => Self::NeighborSolicitation {
+                target_addr: ndp::ipv6_addr_from_slice(&body_buf[4..20]),
+                options: NDOption::from_byte_buffer_all(&body_buf[20..])?,
+            },
+            136 => Self::NeighborAdvertisement {
+                router: body_buf[0] >> 7 == 1,
+                solicited: (body_buf[0] >> 6) & 0b1 == 1,
+                override_flag: (body_buf[0] >> 5) & 0b1 == 1,
+                target_addr: ndp::ipv6_addr_from_slice(&body_buf[4..20]),
+                options: NDOption::from_byte_buffer_all(&body_buf[20..])?,
+            },
+            _ => unreachable!("Checked when finding the minimum length"),
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.to_byte_buffer().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Display for ICMPv6 {
+    /// `ICMP6, echo request, id 15, seq 1, length 64`, or a tree of every field with `{:#}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match &self.body {
+            ICMPv6Body::DestinationUnreachable { .. } => match self.code {
+                0 => "unreachable route".to_string(),
+                1 => "unreachable prohibited".to_string(),
+                3 => "unreachable address".to_string(),
+                4 => "unreachable port".to_string(),
+                code => format!("unreachable, code {}", code),
+            },
+            ICMPv6Body::PacketTooBig { mtu, .. } => format!("packet too big, mtu {}", mtu),
+            ICMPv6Body::TimeExceeded { .. } => match self.code {
+                0 => "time exceeded in-transit".to_string(),
+                _ => "reassembly time exceeded".to_string(),
+            },
+            ICMPv6Body::ParameterProblem { pointer, .. } => {
+                format!("parameter problem, pointer {}", pointer)
+            }
+            ICMPv6Body::EchoRequest {
+                identifier,
+                sequence_number,
+                ..
+            } => format!("echo request, id {}, seq {}", identifier, sequence_number),
+            ICMPv6Body::EchoReply {
+                identifier,
+                sequence_number,
+                ..
+            } => format!("echo reply, id {}, seq {}", identifier, sequence_number),
+            ICMPv6Body::RouterSolicitation { .. } => "router solicitation".to_string(),
+            ICMPv6Body::RouterAdvertisement {
+                router_lifetime, ..
+            } => format!("router advertisement, lifetime {}s", router_lifetime),
+            ICMPv6Body::NeighborSolicitation { target_addr, .. } => {
+                format!("neighbor solicitation, who has {}", target_addr)
+            }
+            ICMPv6Body::NeighborAdvertisement { target_addr, .. } => {
+                format!("neighbor advertisement, tgt is {}", target_addr)
+            }
+        };
+        if !f.alternate() {
+            return write!(f, "ICMP6, {}, length {}", description, self.len());
+        }
+        writeln!(
+            f,
+            "ICMP6 type {}, code {}, checksum {:#06x}",
+            self._type, self.code, self.checksum
+        )?;
+        write!(f, "  {}", description)?;
+        match &self.body {
+            ICMPv6Body::EchoRequest { data, .. } | ICMPv6Body::EchoReply { data, .. } => {
+                write!(f, "\n  data {} bytes", data.len())
+            }
+            ICMPv6Body::DestinationUnreachable { invoking_packet }
+            | ICMPv6Body::PacketTooBig {
+                invoking_packet, ..
+            }
+            | ICMPv6Body::TimeExceeded { invoking_packet }
+            | ICMPv6Body::ParameterProblem {
+                invoking_packet, ..
+            } => write!(f, "\n  invoking packet {} bytes", invoking_packet.len()),
+            ICMPv6Body::RouterSolicitation { options }
+            | ICMPv6Body::RouterAdvertisement { options, .. }
+            | ICMPv6Body::NeighborSolicitation { options, .. }
+            | ICMPv6Body::NeighborAdvertisement { options, .. } => {
+                for option in options {
+                    write!(f, "\n  option {:?}", option)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ping -6 fd00::2 from fd00::1, identifier 0x1d, sequence 1
+    const ECHO_REQUEST: [u8; 16] = [
+        0x80, 0x0, 0xe5, 0xf0, 0x0, 0x1d, 0x0, 0x1, 0xde, 0xad, 0xbe, 0xef, 0x0, 0x1, 0x2, 0x3,
+    ];
+
+    fn addrs() -> (Ipv6Addr, Ipv6Addr) {
+        ("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap())
+    }
+
+    #[test]
+    fn echo_request_from_byte_buffer() {
+        let (source, destination) = addrs();
+        let icmp =
+            ICMPv6::from_byte_buffer(&ECHO_REQUEST, &IpAddr::V6(source), &IpAddr::V6(destination))
+                .unwrap();
+        assert_eq!(
+            icmp.body,
+            ICMPv6Body::EchoRequest {
+                identifier: 0x1d,
+                sequence_number: 1,
+                data: vec![0xde, 0xad, 0xbe, 0xef, 0x0, 0x1, 0x2, 0x3],
+            }
+        );
+        assert_eq!(icmp.to_byte_buffer(), ECHO_REQUEST);
+        // Checksum covers the pseudo header, so swapping addresses must not validate
+        assert!(ICMPv6::from_byte_buffer(
+            &ECHO_REQUEST,
+            &IpAddr::V6(destination),
+            &IpAddr::V6("fd00::3".parse().unwrap())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn echo_reply_checksum() {
+        let (source, destination) = addrs();
+        let reply = ICMPv6::new(
+            &destination,
+            &source,
+            0,
+            ICMPv6Body::EchoReply {
+                identifier: 0x1d,
+                sequence_number: 1,
+                data: vec![0xde, 0xad, 0xbe, 0xef, 0x0, 0x1, 0x2, 0x3],
+            },
+        );
+        assert_eq!(reply._type, 129);
+        let parsed = ICMPv6::from_byte_buffer(
+            &reply.to_byte_buffer(),
+            &IpAddr::V6(destination),
+            &IpAddr::V6(source),
+        )
+        .unwrap();
+        assert_eq!(parsed.body, reply.body);
+    }
+
+    #[test]
+    fn error_messages_round_trip() {
+        let (source, destination) = addrs();
+        let invoking_packet = ICMPv6Body::invoking_packet(&[0x60; 2000]);
+        assert_eq!(invoking_packet.len(), MAX_INVOKING_PACKET_LEN);
+        for body in [
+            ICMPv6Body::DestinationUnreachable {
+                invoking_packet: invoking_packet.clone(),
+            },
+            ICMPv6Body::PacketTooBig {
+                mtu: 1280,
+                invoking_packet: invoking_packet.clone(),
+            },
+            ICMPv6Body::TimeExceeded {
+                invoking_packet: invoking_packet.clone(),
+            },
+            ICMPv6Body::ParameterProblem {
+                pointer: 6,
+                invoking_packet: invoking_packet.clone(),
+            },
+        ] {
+            assert!(body.is_error());
+            let icmp = ICMPv6::new(&source, &destination, 0, body);
+            assert_eq!(icmp.len(), 1280 - 40);
+            let parsed = ICMPv6::from_byte_buffer(
+                &icmp.to_byte_buffer(),
+                &IpAddr::V6(source),
+                &IpAddr::V6(destination),
+            )
+            .unwrap();
+            assert_eq!(parsed.body, icmp.body);
+        }
+    }
+
+    #[test]
+    fn router_advertisement_round_trip() {
+        let (source, destination) = addrs();
+        let body = ICMPv6Body::RouterAdvertisement {
+            cur_hop_limit: 64,
+            managed: false,
+            other: true,
+            router_lifetime: 1800,
+            reachable_time: 0,
+            retrans_timer: 0,
+            options: vec![
+                NDOption::SourceLinkLayerAddress(vec![0x2, 0x0, 0x0, 0x0, 0x0, 0x1]),
+                NDOption::MTU(1500),
+                NDOption::PrefixInformation {
+                    prefix_length: 64,
+                    on_link: true,
+                    autonomous: true,
+                    valid_lifetime: 86400,
+                    preferred_lifetime: 14400,
+                    prefix: "2001:db8::".parse().unwrap(),
+                },
+            ],
+        };
+        let icmp = ICMPv6::new(&source, &destination, 0, body);
+        let parsed = ICMPv6::from_byte_buffer(
+            &icmp.to_byte_buffer(),
+            &IpAddr::V6(source),
+            &IpAddr::V6(destination),
+        )
+        .unwrap();
+        assert_eq!(parsed._type, 134);
+        assert_eq!(parsed.body, icmp.body);
+    }
+}
@@ -0,0 +1,679 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::Ipv6Addr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ip::{IPPacketError, IPPacketErrorKind},
+    ipv6::IPv6Header,
+    protocol::IPBody,
+    IPv6Packet,
+};
+
+use super::{ICMPv6, ICMPv6Body, PROTOCOL};
+
+/// All ND messages are sent and must be received with this hop limit (RFC 4861 7.1)
+pub const ND_HOP_LIMIT: u8 = 255;
+/// RFC 4862 5.1 DupAddrDetectTransmits
+const DUP_ADDR_DETECT_TRANSMITS: u8 = 1;
+/// RFC 4861 10 RETRANS_TIMER
+const RETRANS_TIMER: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum NDOption {
+    SourceLinkLayerAddress(Vec<u8>),
+    TargetLinkLayerAddress(Vec<u8>),
+    PrefixInformation {
+        prefix_length: u8,
+        on_link: bool,
+        autonomous: bool,
+        valid_lifetime: u32,
+        preferred_lifetime: u32,
+        prefix: Ipv6Addr,
+    },
+    MTU(u32),
+    Unknown {
+        _type: u8,
+        data: Vec<u8>,
+    },
+}
+impl NDOption {
+    /// Parses all options until the end of the buffer
+    pub fn from_byte_buffer_all(buf: &[u8]) -> Result<Vec<Self>, IPPacketError> {
+        let mut options = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            // Length is in units of 8 octets, including the type and length fields
+            let len = *buf
+                .get(offset + 1)
+                .ok_or(IPPacketError::new(IPPacketErrorKind::ICMPv6LengthError))?
+                as usize
+                * 8;
+            if len == 0 || offset + len > buf.len() {
+                return Err(IPPacketError::new(IPPacketErrorKind::ICMPv6LengthError));
+            }
+            options.push(Self::from_byte_buffer(&buf[offset..offset + len]));
+            offset += len;
+        }
+        Ok(options)
+    }
+    /// `buf` is exactly one option, including the type and length fields
+    fn from_byte_buffer(buf: &[u8]) -> Self {
+        match (buf[0], buf.len()) {
+            (1, _) => Self::SourceLinkLayerAddress(Self::trim_link_layer_address(&buf[2..])),
+            (2, _) => Self::TargetLinkLayerAddress(Self::trim_link_layer_address(&buf[2..])),
+            (3, 32) => Self::PrefixInformation {
+                prefix_length: buf[2],
+                on_link: buf[3] >> 7 == 1,
+                autonomous: (buf[3] >> 6) & 0b1 == 1,
+                valid_lifetime: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+                preferred_lifetime: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+                prefix: ipv6_addr_from_slice(&buf[16..32]),
+            },
+            (5, 8) => Self::MTU(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]])),
+            (_type, _) => Self::Unknown {
+                _type,
+                data: buf[2..].to_vec(),
+            },
+        }
+    }
+    /// Ethernet addresses are 6 bytes, padded with 0 up to the 8 octet boundary
+    fn trim_link_layer_address(buf: &[u8]) -> Vec<u8> {
+        buf[..buf.len().min(6)].to_vec()
+    }
+    pub fn to_byte_buffer(&self) -> Vec<u8> {
+        let (_type, mut data) = match self {
+            Self::SourceLinkLayerAddress(address) => (1, address.clone()),
+            Self::TargetLinkLayerAddress(address) => (2, address.clone()),
+            Self::PrefixInformation {
+                prefix_length,
+                on_link,
+                autonomous,
+                valid_lifetime,
+                preferred_lifetime,
+                prefix,
+            } => {
+                let mut data = vec![
+                    *prefix_length,
+                    ((*on_link as u8) << 7) + ((*autonomous as u8) << 6),
+                ];
+                data.append(&mut valid_lifetime.to_be_bytes().to_vec());
+                data.append(&mut preferred_lifetime.to_be_bytes().to_vec());
+                data.append(&mut vec![0x0; 4]); // Reserved
+                data.append(&mut prefix.octets().to_vec());
+                (3, data)
+            }
+            Self::MTU(mtu) => {
+                let mut data = vec![0x0; 2]; // Reserved
+                data.append(&mut mtu.to_be_bytes().to_vec());
+                (5, data)
+            }
+            Self::Unknown { _type, data } => (*_type, data.clone()),
+        };
+        let len = (2 + data.len()).div_ceil(8) * 8;
+        let mut buf = vec![_type, (len / 8) as u8];
+        buf.append(&mut data);
+        buf.resize(len, 0x0);
+        buf
+    }
+    pub fn to_byte_buffer_all(options: &[Self]) -> Vec<u8> {
+        options
+            .iter()
+            .flat_map(|option| option.to_byte_buffer())
+            .collect()
+    }
+}
+
+pub fn ipv6_addr_from_slice(buf: &[u8]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&buf[..16]);
+    Ipv6Addr::from(octets)
+}
+
+/// ff02::1:ffXX:XXXX using the low 24 bits of the address (RFC 4291 2.7.1)
+pub fn solicited_node_multicast(addr: &Ipv6Addr) -> Ipv6Addr {
+    let octets = addr.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 + octets[13] as u16,
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
+/// Modified EUI-64 interface identifier from a 48 bit MAC address (RFC 4291 Appendix A)
+pub fn eui64_interface_identifier(mac: [u8; 6]) -> [u8; 8] {
+    [
+        mac[0] ^ 0b10, // Flip the universal/local bit
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]
+}
+
+/// Interface identifier for interfaces without a MAC address like TUN, the same across restarts
+/// as long as the interface keeps its name (in the spirit of RFC 7217)
+pub fn stable_interface_identifier(interface: &str) -> [u8; 8] {
+    let mut hasher = DefaultHasher::new();
+    interface.hash(&mut hasher);
+    let mut identifier = hasher.finish().to_be_bytes();
+    // Locally administered, the universal/local bit is inverted in modified EUI-64
+    identifier[0] &= !0b10;
+    identifier
+}
+
+/// Forms an address from an advertised prefix (RFC 4862 5.5.3)
+/// Only /64 prefixes can be combined with a 64 bit interface identifier
+pub fn slaac_address(
+    prefix: &Ipv6Addr,
+    prefix_length: u8,
+    interface_identifier: [u8; 8],
+) -> Option<Ipv6Addr> {
+    if prefix_length != 64 {
+        return None;
+    }
+    let mut octets = prefix.octets();
+    octets[8..].copy_from_slice(&interface_identifier);
+    Some(Ipv6Addr::from(octets))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AddressState {
+    /// Duplicate address detection is still running
+    Tentative {
+        transmits_left: u8,
+        next_transmit: Instant,
+    },
+    Preferred,
+    Duplicate,
+}
+
+/// Neighbor Discovery (RFC 4861) and stateless address autoconfiguration (RFC 4862) for an interface
+pub struct NeighborDiscovery {
+    interface_identifier: [u8; 8],
+    link_layer_address: Option<[u8; 6]>,
+    addresses: Vec<(Ipv6Addr, AddressState)>,
+    /// Link layer addresses learnt from ND options
+    neighbors: HashMap<Ipv6Addr, Vec<u8>>,
+    /// Routers which advertised themselves with a non zero lifetime
+    pub default_routers: Vec<Ipv6Addr>,
+    pub mtu: Option<u32>,
+    /// A Router Solicitation is sent once the link local address passed DAD
+    solicit_routers: bool,
+}
+impl NeighborDiscovery {
+    /// TUN interfaces have no link layer address, so `link_layer_address` is optional
+    pub fn new(interface_identifier: [u8; 8], link_layer_address: Option<[u8; 6]>) -> Self {
+        Self {
+            interface_identifier,
+            link_layer_address,
+            addresses: Vec::new(),
+            neighbors: HashMap::new(),
+            default_routers: Vec::new(),
+            mtu: None,
+            solicit_routers: false,
+        }
+    }
+    pub fn from_mac(mac: [u8; 6]) -> Self {
+        Self::new(eui64_interface_identifier(mac), Some(mac))
+    }
+    /// Brings the interface up (RFC 4862 5.3): the first `poll` from `now` on starts DAD for the
+    /// link local address, then routers are solicited for prefixes to configure addresses from
+    pub fn autoconfigure(&mut self, now: Instant) {
+        let address = self.link_local_address();
+        self.addresses.retain(|(existing, _)| *existing != address);
+        self.addresses.push((
+            address,
+            AddressState::Tentative {
+                transmits_left: DUP_ADDR_DETECT_TRANSMITS,
+                next_transmit: now,
+            },
+        ));
+        self.solicit_routers = true;
+    }
+    pub fn link_local_address(&self) -> Ipv6Addr {
+        slaac_address(
+            &Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0),
+            64,
+            self.interface_identifier,
+        )
+        .expect("Link local prefix is a /64")
+    }
+    pub fn address_state(&self, addr: &Ipv6Addr) -> Option<&AddressState> {
+        self.addresses
+            .iter()
+            .find(|(address, _)| address == addr)
+            .map(|(_, state)| state)
+    }
+    /// Addresses which passed duplicate address detection and can be used
+    pub fn preferred_addresses(&self) -> impl Iterator<Item = &Ipv6Addr> {
+        self.addresses
+            .iter()
+            .filter(|(_, state)| *state == AddressState::Preferred)
+            .map(|(address, _)| address)
+    }
+    pub fn neighbor(&self, addr: &Ipv6Addr) -> Option<&Vec<u8>> {
+        self.neighbors.get(addr)
+    }
+    /// Adds the address as tentative and returns the first DAD Neighbor Solicitation to send
+    pub fn start_dad(&mut self, addr: Ipv6Addr, now: Instant) -> IPv6Packet {
+        self.addresses.retain(|(address, _)| *address != addr);
+        self.addresses.push((
+            addr,
+            AddressState::Tentative {
+                transmits_left: DUP_ADDR_DETECT_TRANSMITS - 1,
+                next_transmit: now + RETRANS_TIMER,
+            },
+        ));
+        Self::dad_solicitation(addr)
+    }
+    /// When `poll` has a DAD probe to send or an address to mark as preferred
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.addresses
+            .iter()
+            .filter_map(|(_, state)| match state {
+                AddressState::Tentative { next_transmit, .. } => Some(*next_transmit),
+                _ => None,
+            })
+            .min()
+    }
+    /// Sends the remaining DAD probes and marks addresses without conflicts as preferred
+    pub fn poll(&mut self, now: Instant) -> Vec<IPv6Packet> {
+        let mut packets = Vec::new();
+        for (address, state) in self.addresses.iter_mut() {
+            if let AddressState::Tentative {
+                transmits_left,
+                next_transmit,
+            } = state
+            {
+                if now < *next_transmit {
+                    continue;
+                }
+                if *transmits_left == 0 {
+                    *state = AddressState::Preferred;
+                } else {
+                    *transmits_left -= 1;
+                    *next_transmit = now + RETRANS_TIMER;
+                    packets.push(Self::dad_solicitation(*address));
+                }
+            }
+        }
+        let link_local = self.link_local_address();
+        if self.solicit_routers && self.address_state(&link_local) == Some(&AddressState::Preferred)
+        {
+            self.solicit_routers = false;
+            packets.push(self.router_solicitation());
+        }
+        packets
+    }
+    pub fn router_solicitation(&self) -> IPv6Packet {
+        let source = self.link_local_address();
+        let destination = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2); // All routers
+        let options = self
+            .link_layer_address
+            .map(|mac| vec![NDOption::SourceLinkLayerAddress(mac.to_vec())])
+            .unwrap_or_default();
+        Self::craft(
+            source,
+            destination,
+            ICMPv6Body::RouterSolicitation { options },
+        )
+    }
+    /// Whether the message is one of the ND messages `handle` takes (RFC 4861 4)
+    pub fn is_nd_message(icmp: &ICMPv6) -> bool {
+        matches!(
+            icmp.body,
+            ICMPv6Body::RouterSolicitation { .. }
+                | ICMPv6Body::RouterAdvertisement { .. }
+                | ICMPv6Body::NeighborSolicitation { .. }
+                | ICMPv6Body::NeighborAdvertisement { .. }
+        )
+    }
+    /// Handles a received ND message, returning the packets to send in response
+    pub fn handle(&mut self, header: &IPv6Header, icmp: &ICMPv6, now: Instant) -> Vec<IPv6Packet> {
+        if header.hop_limit != ND_HOP_LIMIT || icmp.code != 0 {
+            // Could have been forwarded by a router, RFC 4861 7.1
+            return Vec::new();
+        }
+        match &icmp.body {
+            ICMPv6Body::NeighborSolicitation {
+                target_addr,
+                options,
+            } => {
+                let from_dad = header.source_addr.is_unspecified();
+                match self.address_state(target_addr) {
+                    Some(AddressState::Tentative { .. }) if from_dad => {
+                        // Someone else is trying to use the same address
+                        self.mark_duplicate(target_addr);
+                        Vec::new()
+                    }
+                    Some(AddressState::Preferred) => {
+                        self.learn(&header.source_addr, options);
+                        let destination = if from_dad {
+                            Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1) // All nodes
+                        } else {
+                            header.source_addr
+                        };
+                        let options = self
+                            .link_layer_address
+                            .map(|mac| vec![NDOption::TargetLinkLayerAddress(mac.to_vec())])
+                            .unwrap_or_default();
+                        vec![Self::craft(
+                            *target_addr,
+                            destination,
+                            ICMPv6Body::NeighborAdvertisement {
+                                router: false,
+                                solicited: !from_dad,
+                                override_flag: true,
+                                target_addr: *target_addr,
+                                options,
+                            },
+                        )]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            ICMPv6Body::NeighborAdvertisement {
+                target_addr,
+                options,
+                ..
+            } => {
+                if let Some(AddressState::Tentative { .. }) = self.address_state(target_addr) {
+                    self.mark_duplicate(target_addr);
+                } else {
+                    self.learn(target_addr, options);
+                }
+                Vec::new()
+            }
+            ICMPv6Body::RouterAdvertisement {
+                router_lifetime,
+                options,
+                ..
+            } => {
+                self.learn(&header.source_addr, options);
+                self.default_routers
+                    .retain(|router| *router != header.source_addr);
+                if *router_lifetime != 0 {
+                    self.default_routers.push(header.source_addr);
+                }
+                let mut solicitations = Vec::new();
+                for option in options {
+                    match option {
+                        NDOption::MTU(mtu) => self.mtu = Some(*mtu),
+                        NDOption::PrefixInformation {
+                            prefix_length,
+                            autonomous: true,
+                            valid_lifetime,
+                            preferred_lifetime,
+                            prefix,
+                            ..
+                        } if preferred_lifetime <= valid_lifetime
+                            && !prefix.is_unicast_link_local() =>
+                        {
+                            if let Some(address) =
+                                slaac_address(prefix, *prefix_length, self.interface_identifier)
+                            {
+                                if self.address_state(&address).is_none() {
+                                    solicitations.push(self.start_dad(address, now));
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                solicitations
+            }
+            _ => Vec::new(),
+        }
+    }
+    fn mark_duplicate(&mut self, addr: &Ipv6Addr) {
+        for (address, state) in self.addresses.iter_mut() {
+            if address == addr {
+                *state = AddressState::Duplicate;
+            }
+        }
+    }
+    fn learn(&mut self, addr: &Ipv6Addr, options: &[NDOption]) {
+        if addr.is_unspecified() {
+            return;
+        }
+        for option in options {
+            if let NDOption::SourceLinkLayerAddress(link_layer_address)
+            | NDOption::TargetLinkLayerAddress(link_layer_address) = option
+            {
+                self.neighbors.insert(*addr, link_layer_address.clone());
+            }
+        }
+    }
+    fn dad_solicitation(addr: Ipv6Addr) -> IPv6Packet {
+        // DAD probes come from the unspecified address without link layer options (RFC 4862 5.4.2)
+        Self::craft(
+            Ipv6Addr::UNSPECIFIED,
+            solicited_node_multicast(&addr),
+            ICMPv6Body::NeighborSolicitation {
+                target_addr: addr,
+                options: Vec::new(),
+            },
+        )
+    }
+    fn craft(source: Ipv6Addr, destination: Ipv6Addr, body: ICMPv6Body) -> IPv6Packet {
+        let icmp = ICMPv6::new(&source, &destination, 0, body);
+        let header = IPv6Header::new(0, 0, 0, PROTOCOL, ND_HOP_LIMIT, source, destination);
+        IPv6Packet::new(header, Vec::new(), PROTOCOL, IPBody::ICMPv6(icmp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(packet: &IPv6Packet) -> (IPv6Header, ICMPv6) {
+        let packet = IPv6Packet::from_byte_buffer(&packet.to_byte_buffer()).unwrap();
+        match packet.body {
+            IPBody::ICMPv6(icmp) => (packet.header, icmp),
+            _ => panic!("Should be an ICMPv6 packet"),
+        }
+    }
+
+    #[test]
+    fn eui64_and_solicited_node() {
+        let mac = [0x52, 0x54, 0x0, 0x12, 0x34, 0x56];
+        let nd = NeighborDiscovery::from_mac(mac);
+        assert_eq!(
+            nd.link_local_address(),
+            "fe80::5054:ff:fe12:3456".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            solicited_node_multicast(&nd.link_local_address()),
+            "ff02::1:ff12:3456".parse::<Ipv6Addr>().unwrap()
+        );
+        assert!(slaac_address(&"2001:db8::".parse().unwrap(), 48, [0; 8]).is_none());
+    }
+
+    #[test]
+    fn dad_succeeds_without_conflict() {
+        let now = Instant::now();
+        let mut nd = NeighborDiscovery::new([0, 0, 0, 0, 0, 0, 0, 2], None);
+        let address = nd.link_local_address();
+        let (header, icmp) = parse(&nd.start_dad(address, now));
+        assert!(header.source_addr.is_unspecified());
+        assert_eq!(header.destination_addr, solicited_node_multicast(&address));
+        assert_eq!(header.hop_limit, ND_HOP_LIMIT);
+        assert_eq!(icmp._type, 135);
+        assert_eq!(nd.preferred_addresses().count(), 0);
+
+        assert!(nd.poll(now + Duration::from_millis(500)).is_empty());
+        assert!(nd.poll(now + RETRANS_TIMER).is_empty());
+        assert_eq!(nd.preferred_addresses().collect::<Vec<_>>(), [&address]);
+    }
+
+    #[test]
+    fn dad_detects_duplicate() {
+        let now = Instant::now();
+        let mut nd = NeighborDiscovery::new([0, 0, 0, 0, 0, 0, 0, 2], None);
+        let address = nd.link_local_address();
+        nd.start_dad(address, now);
+
+        // Another node defends the address
+        let mut other = NeighborDiscovery::new([0, 0, 0, 0, 0, 0, 0, 2], None);
+        other.start_dad(address, now);
+        other.poll(now + RETRANS_TIMER);
+        let (header, icmp) = parse(&nd.start_dad(address, now));
+        let (header, icmp) = parse(&other.handle(&header, &icmp, now)[0]);
+        assert_eq!(icmp._type, 136);
+        assert!(nd.handle(&header, &icmp, now).is_empty());
+        assert_eq!(nd.address_state(&address), Some(&AddressState::Duplicate));
+        nd.poll(now + RETRANS_TIMER);
+        assert_eq!(nd.preferred_addresses().count(), 0);
+    }
+
+    #[test]
+    fn neighbor_solicitation_is_answered() {
+        let now = Instant::now();
+        let mac = [0x2, 0x0, 0x0, 0x0, 0x0, 0x2];
+        let mut nd = NeighborDiscovery::from_mac(mac);
+        let address = nd.link_local_address();
+        nd.start_dad(address, now);
+        nd.poll(now + RETRANS_TIMER);
+
+        let source: Ipv6Addr = "fe80::1".parse().unwrap();
+        let solicitation = NeighborDiscovery::craft(
+            source,
+            solicited_node_multicast(&address),
+            ICMPv6Body::NeighborSolicitation {
+                target_addr: address,
+                options: vec![NDOption::SourceLinkLayerAddress(vec![
+                    0x2, 0x0, 0x0, 0x0, 0x0, 0x1,
+                ])],
+            },
+        );
+        let (header, icmp) = parse(&solicitation);
+        let (reply_header, reply) = parse(&nd.handle(&header, &icmp, now)[0]);
+        assert_eq!(reply_header.destination_addr, source);
+        assert_eq!(
+            reply.body,
+            ICMPv6Body::NeighborAdvertisement {
+                router: false,
+                solicited: true,
+                override_flag: true,
+                target_addr: address,
+                options: vec![NDOption::TargetLinkLayerAddress(mac.to_vec())],
+            }
+        );
+        assert_eq!(
+            nd.neighbor(&source),
+            Some(&vec![0x2, 0x0, 0x0, 0x0, 0x0, 0x1])
+        );
+
+        // Forwarded ND messages are dropped
+        let mut header = header;
+        header.hop_limit = 64;
+        assert!(nd.handle(&header, &icmp, now).is_empty());
+    }
+
+    #[test]
+    fn router_advertisement_configures_slaac_address() {
+        let now = Instant::now();
+        let mut nd = NeighborDiscovery::new([0, 0, 0, 0, 0, 0, 0, 2], None);
+        let (_, solicitation) = parse(&nd.router_solicitation());
+        assert_eq!(solicitation._type, 133);
+
+        let router: Ipv6Addr = "fe80::1".parse().unwrap();
+        let advertisement = NeighborDiscovery::craft(
+            router,
+            Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1),
+            ICMPv6Body::RouterAdvertisement {
+                cur_hop_limit: 64,
+                managed: false,
+                other: false,
+                router_lifetime: 1800,
+                reachable_time: 0,
+                retrans_timer: 0,
+                options: vec![
+                    NDOption::MTU(1400),
+                    NDOption::PrefixInformation {
+                        prefix_length: 64,
+                        on_link: true,
+                        autonomous: true,
+                        valid_lifetime: 86400,
+                        preferred_lifetime: 14400,
+                        prefix: "2001:db8:1::".parse().unwrap(),
+                    },
+                    NDOption::PrefixInformation {
+                        prefix_length: 64,
+                        on_link: true,
+                        autonomous: true,
+                        valid_lifetime: 86400,
+                        preferred_lifetime: 14400,
+                        prefix: "2001:db8:2::".parse().unwrap(),
+                    },
+                ],
+            },
+        );
+        let (header, icmp) = parse(&advertisement);
+        let addresses: [Ipv6Addr; 2] = [
+            "2001:db8:1::2".parse().unwrap(),
+            "2001:db8:2::2".parse().unwrap(),
+        ];
+        // One DAD probe per prefix
+        let probes = nd.handle(&header, &icmp, now);
+        assert_eq!(probes.len(), 2);
+        for (probe, address) in probes.iter().zip(addresses) {
+            let (_, probe) = parse(probe);
+            assert!(matches!(
+                probe.body,
+                ICMPv6Body::NeighborSolicitation { target_addr, .. } if target_addr == address
+            ));
+        }
+        assert_eq!(nd.default_routers, [router]);
+        assert_eq!(nd.mtu, Some(1400));
+        nd.poll(now + RETRANS_TIMER);
+        assert_eq!(
+            nd.preferred_addresses().collect::<Vec<_>>(),
+            [&addresses[0], &addresses[1]]
+        );
+    }
+
+    #[test]
+    fn autoconfigure_solicits_routers_after_dad() {
+        let now = Instant::now();
+        let mut nd = NeighborDiscovery::new(stable_interface_identifier("tun0"), None);
+        nd.autoconfigure(now);
+        assert_eq!(nd.next_deadline(), Some(now));
+        let probes = nd.poll(now);
+        assert_eq!(probes.len(), 1);
+        let (_, probe) = parse(&probes[0]);
+        assert_eq!(probe._type, 135);
+        assert_eq!(nd.next_deadline(), Some(now + RETRANS_TIMER));
+
+        let solicitations = nd.poll(now + RETRANS_TIMER);
+        assert_eq!(solicitations.len(), 1);
+        let (header, solicitation) = parse(&solicitations[0]);
+        assert_eq!(header.source_addr, nd.link_local_address());
+        assert_eq!(solicitation._type, 133);
+        assert_eq!(nd.next_deadline(), None);
+        assert!(nd.poll(now + RETRANS_TIMER * 2).is_empty());
+    }
+
+    #[test]
+    fn truncated_options() {
+        // A Neighbor Solicitation whose only option claims 16 bytes but has 8
+        let mut body = vec![0x0; 4];
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(&[1, 2, 0x2, 0x0, 0x0, 0x0, 0x0, 0x1]);
+        let error = ICMPv6Body::from_byte_buffer(135, &body).unwrap_err();
+        assert!(matches!(error.kind(), IPPacketErrorKind::ICMPv6LengthError));
+        let error = ICMPv6Body::from_byte_buffer(135, &body[..12]).unwrap_err();
+        assert!(matches!(error.kind(), IPPacketErrorKind::ICMPv6LengthError));
+    }
+}
@@ -1,16 +1,24 @@
-use std::net::Ipv4Addr;
+use std::{fmt, net::IpAddr};
 
-use crate::checksum::ones_complement_sum_byte_buffer;
+use bitflags::bitflags;
+
+use crate::buffer::PacketBuffer;
+use crate::checksum::{pseudo_header_sum, Checksum};
 use crate::ip::{IPPacketError, IPPacketErrorKind};
 
+/// Protocol number of TCP in the IP header
+pub const PROTOCOL: u8 = 6;
+/// Room for options within the largest data offset of 15 words
+pub const MAX_OPTIONS_LEN: usize = 40;
+
 pub struct TCP {
     pub source_port: u16,
     pub destination_port: u16,
     pub sequence_number: u32,
     pub acknowledgment_number: u32,
-    pub data_offset: u8,  // 4 bits
-    pub reserved: u8,     // 6 bits
-    pub control_bits: u8, // 6 bits
+    pub data_offset: u8, // 4 bits
+    pub reserved: u8,    // 3 bits
+    pub control_bits: TCPControlBits,
     pub window: u16,
     pub checksum: u16,
     pub urgent_pointer: u16,
@@ -20,20 +28,26 @@ pub struct TCP {
 impl TCP {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        source_address: &Ipv4Addr,
-        destination_address: &Ipv4Addr,
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
         protocol: u8,
         source_port: u16,
         destination_port: u16,
         sequence_number: u32,
         acknowledgment_number: u32,
-        reserved: u8,     // 6 bits
-        control_bits: u8, // 6 bits
+        reserved: u8, // 3 bits
+        control_bits: TCPControlBits,
         window: u16,
         urgent_pointer: u16,
         options: Option<Vec<u8>>,
         data: Vec<u8>,
-    ) -> Self {
+    ) -> Result<Self, IPPacketError> {
+        if options
+            .as_ref()
+            .is_some_and(|options| options.len() > MAX_OPTIONS_LEN)
+        {
+            return Err(IPPacketError::new(IPPacketErrorKind::TCPOptionsTooLong));
+        }
         let data_offset: u8 = 5 + if let Some(option) = &options {
             option.len() as u8 / 4 + if option.len() % 4 == 0 { 0 } else { 1 }
         } else {
@@ -53,90 +67,81 @@ impl TCP {
             options,
             data,
         };
-        let tcp_length = tcp.len();
-        let mut pseudo_header = Self::craft_pseudo_header(
-            source_address,
-            destination_address,
-            protocol,
-            tcp_length as u16,
-        );
-        pseudo_header.append(&mut tcp.to_byte_buffer());
-        let checksum = !ones_complement_sum_byte_buffer(&pseudo_header);
-        tcp.checksum = checksum;
-        tcp
+        // Summed from the header and data as they are, without serializing the segment
+        let mut header = [0x0; 60];
+        let header = &mut header[..tcp.header_len()];
+        tcp.write_header(header);
+        tcp.checksum = Checksum::new()
+            .add_pseudo_header(
+                source_address,
+                destination_address,
+                protocol,
+                tcp.len() as u32,
+            )?
+            .add(header)
+            .add(&tcp.data)
+            .finish();
+        Ok(tcp)
     }
     pub fn to_byte_buffer(&self) -> Vec<u8> {
-        let mut buf = self.source_port.to_be_bytes().to_vec();
-        buf.append(&mut self.destination_port.to_be_bytes().to_vec());
-        buf.append(&mut self.sequence_number.to_be_bytes().to_vec());
-        buf.append(&mut self.acknowledgment_number.to_be_bytes().to_vec());
-        buf.push((self.data_offset << 4) + (self.reserved >> 2));
-        buf.push((self.reserved << 6) + self.control_bits);
-        buf.append(&mut self.window.to_be_bytes().to_vec());
-        buf.append(&mut self.checksum.to_be_bytes().to_vec());
-        buf.append(&mut self.urgent_pointer.to_be_bytes().to_vec());
-        if let Some(options) = &self.options {
-            buf.append(&mut options.clone());
-            if options.len() % 4 != 0 {
-                // Pad options with 0
-                buf.append(&mut vec![0u8; 4 - (options.len() % 4)]);
-            }
-        }
-        buf.append(&mut self.data.clone());
+        let mut buf = vec![0x0; self.header_len()];
+        self.write_header(&mut buf);
+        buf.extend_from_slice(&self.data);
         buf
     }
+    /// Puts the segment in front of the bytes already in `buf`, using its headroom
+    pub fn prepend_to(&self, buf: &mut PacketBuffer) {
+        buf.prepend(self.data.len()).copy_from_slice(&self.data);
+        self.write_header(buf.prepend(self.header_len()));
+    }
+    /// Writes the header and its options into `out`, which is `header_len` bytes long
+    fn write_header(&self, out: &mut [u8]) {
+        out[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        out[2..4].copy_from_slice(&self.destination_port.to_be_bytes());
+        out[4..8].copy_from_slice(&self.sequence_number.to_be_bytes());
+        out[8..12].copy_from_slice(&self.acknowledgment_number.to_be_bytes());
+        let control_bits = self.control_bits.bits();
+        out[12] = (self.data_offset << 4) + (self.reserved << 1) + (control_bits >> 8) as u8;
+        out[13] = control_bits as u8;
+        out[14..16].copy_from_slice(&self.window.to_be_bytes());
+        out[16..18].copy_from_slice(&self.checksum.to_be_bytes());
+        out[18..20].copy_from_slice(&self.urgent_pointer.to_be_bytes());
+        let options = self.options.as_deref().unwrap_or_default();
+        out[20..20 + options.len()].copy_from_slice(options);
+        // Pad options with 0
+        out[20 + options.len()..].fill(0x0);
+    }
+    fn header_len(&self) -> usize {
+        self.len() - self.data.len()
+    }
+    /// Copies the options and data out of `buf`, `TcpSegment` reads them in place
     pub fn from_byte_buffer(
         buf: &[u8],
-        source_address: &Ipv4Addr,
-        destination_address: &Ipv4Addr,
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
         protocol: u8,
     ) -> Result<TCP, IPPacketError> {
-        let mut pseudo_header = Self::craft_pseudo_header(
-            source_address,
-            destination_address,
-            protocol,
-            buf.len() as u16,
-        );
-        pseudo_header.append(&mut buf.to_vec());
-        if ones_complement_sum_byte_buffer(&pseudo_header) != 0xFFFF {
+        let segment = TcpSegment::new_checked(buf)?;
+        if !segment.verify_checksum(source_address, destination_address, protocol) {
             return Err(IPPacketError::new(IPPacketErrorKind::TCPChecksumError));
         }
-        let data_reserved_control_seg = u16::from_be_bytes([buf[12], buf[13]]);
-        let data_offset = (data_reserved_control_seg >> 12) as u8;
-        let options = if data_offset == 5 {
-            // No options
-            None
-        } else {
-            Some(buf[20..(data_offset) as usize * 4].to_vec())
-        };
+        let options = segment.options();
         Ok(Self {
-            source_port: u16::from_be_bytes([buf[0], buf[1]]),
-            destination_port: u16::from_be_bytes([buf[2], buf[3]]),
-            sequence_number: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
-            acknowledgment_number: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
-            data_offset,
-            reserved: ((data_reserved_control_seg & 0xFFF) >> 6) as u8,
-            control_bits: (data_reserved_control_seg & 0x3F) as u8,
-            window: u16::from_be_bytes([buf[14], buf[15]]),
-            checksum: u16::from_be_bytes([buf[16], buf[17]]),
-            urgent_pointer: u16::from_be_bytes([buf[18], buf[19]]),
-            options,
-            data: buf[(data_offset) as usize * 4..].to_vec(),
+            source_port: segment.source_port(),
+            destination_port: segment.destination_port(),
+            sequence_number: segment.sequence_number(),
+            acknowledgment_number: segment.acknowledgment_number(),
+            data_offset: segment.data_offset(),
+            reserved: segment.reserved(),
+            control_bits: segment.control_bits(),
+            window: segment.window(),
+            checksum: segment.checksum(),
+            urgent_pointer: segment.urgent_pointer(),
+            // No options
+            options: (!options.is_empty()).then(|| options.to_vec()),
+            data: segment.data().to_vec(),
         })
     }
-    fn craft_pseudo_header(
-        source_address: &Ipv4Addr,
-        destination_address: &Ipv4Addr,
-        protocol: u8,
-        tcp_length: u16,
-    ) -> Vec<u8> {
-        let mut pseudo_header = source_address.to_bits().to_be_bytes().to_vec();
-        pseudo_header.append(&mut destination_address.to_bits().to_be_bytes().to_vec());
-        pseudo_header.push(0x0);
-        pseudo_header.push(protocol);
-        pseudo_header.append(&mut tcp_length.to_be_bytes().to_vec());
-        pseudo_header
-    }
     pub fn len(&self) -> usize {
         20 // 5*4 bytes in header
             + self.data.len()
@@ -146,43 +151,403 @@ impl TCP {
                 0
             }
     }
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+    /// Builder defaulting to no sequence or acknowledgment number, control bits, window, options
+    /// or data
+    pub fn builder(source_port: u16, destination_port: u16) -> TCPBuilder {
+        TCPBuilder {
+            source_port,
+            destination_port,
+            sequence_number: 0,
+            acknowledgment_number: 0,
+            control_bits: TCPControlBits::empty(),
+            window: 0,
+            urgent_pointer: 0,
+            options: Vec::new(),
+            data: Vec::new(),
+        }
+    }
 }
 
-pub enum TCPControlBits {
-    URG,
-    ACK,
-    PSH,
-    RST,
-    SYN,
-    FIN,
+/// Borrowed view of a raw TCP segment, reading and writing the fields in place without parsing
+/// it into a `TCP`
+#[derive(Debug, Clone, Copy)]
+pub struct TcpSegment<T> {
+    buffer: T,
 }
-impl TCPControlBits {
-    pub fn from_u8(control_bit: u8) -> Self {
-        match control_bit {
-            0b100000 => Self::URG,
-            0b10000 => Self::ACK,
-            0b1000 => Self::PSH,
-            0b100 => Self::RST,
-            0b10 => Self::SYN,
-            0b1 => Self::FIN,
-            _ => panic!("Corrupted TCP ControlBit Packet"),
+impl<T: AsRef<[u8]>> TcpSegment<T> {
+    /// Field accesses panic if the buffer is shorter than the header
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+    /// Checks the buffer holds the header and the options
+    pub fn new_checked(buffer: T) -> Result<Self, IPPacketError> {
+        let segment = Self::new_unchecked(buffer);
+        let len = segment.buffer.as_ref().len();
+        if len < 20 || segment.header_len() < 20 || segment.header_len() > len {
+            return Err(IPPacketError::new(IPPacketErrorKind::TCPLengthError));
         }
+        Ok(segment)
+    }
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+    fn field(&self, offset: usize) -> u16 {
+        let buf = self.buffer.as_ref();
+        u16::from_be_bytes([buf[offset], buf[offset + 1]])
+    }
+    fn field_u32(&self, offset: usize) -> u32 {
+        let buf = self.buffer.as_ref();
+        u32::from_be_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ])
+    }
+    pub fn source_port(&self) -> u16 {
+        self.field(0)
+    }
+    pub fn destination_port(&self) -> u16 {
+        self.field(2)
+    }
+    pub fn sequence_number(&self) -> u32 {
+        self.field_u32(4)
+    }
+    pub fn acknowledgment_number(&self) -> u32 {
+        self.field_u32(8)
+    }
+    pub fn data_offset(&self) -> u8 {
+        self.buffer.as_ref()[12] >> 4
+    }
+    pub fn header_len(&self) -> usize {
+        self.data_offset() as usize * 4
+    }
+    pub fn reserved(&self) -> u8 {
+        (self.buffer.as_ref()[12] >> 1) & 0b111
+    }
+    pub fn control_bits(&self) -> TCPControlBits {
+        TCPControlBits::from_bits_retain(self.field(12) & 0x1FF)
+    }
+    pub fn window(&self) -> u16 {
+        self.field(14)
+    }
+    pub fn checksum(&self) -> u16 {
+        self.field(16)
+    }
+    pub fn urgent_pointer(&self) -> u16 {
+        self.field(18)
+    }
+    pub fn options(&self) -> &[u8] {
+        &self.buffer.as_ref()[20..self.header_len()]
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len()..]
+    }
+    pub fn verify_checksum(
+        &self,
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
+        protocol: u8,
+    ) -> bool {
+        let buf = self.buffer.as_ref();
+        pseudo_header_sum(source_address, destination_address, protocol, buf)
+            .is_ok_and(|sum| sum == 0xFFFF)
+    }
+}
+impl<T: AsRef<[u8]> + AsMut<[u8]>> TcpSegment<T> {
+    fn set_field(&mut self, offset: usize, value: u16) {
+        self.buffer.as_mut()[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+    fn set_field_u32(&mut self, offset: usize, value: u32) {
+        self.buffer.as_mut()[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    pub fn set_source_port(&mut self, port: u16) {
+        self.set_field(0, port);
+    }
+    pub fn set_destination_port(&mut self, port: u16) {
+        self.set_field(2, port);
+    }
+    pub fn set_sequence_number(&mut self, sequence_number: u32) {
+        self.set_field_u32(4, sequence_number);
+    }
+    pub fn set_acknowledgment_number(&mut self, acknowledgment_number: u32) {
+        self.set_field_u32(8, acknowledgment_number);
+    }
+    pub fn set_control_bits(&mut self, control_bits: TCPControlBits) {
+        let field = (self.field(12) & !0x1FF) | control_bits.bits();
+        self.set_field(12, field);
+    }
+    pub fn set_window(&mut self, window: u16) {
+        self.set_field(14, window);
+    }
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.set_field(16, checksum);
+    }
+    pub fn set_urgent_pointer(&mut self, urgent_pointer: u16) {
+        self.set_field(18, urgent_pointer);
+    }
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        let header_len = self.header_len();
+        &mut self.buffer.as_mut()[header_len..]
+    }
+    /// Recalculates the checksum after fields or data were changed
+    pub fn fill_checksum(
+        &mut self,
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
+        protocol: u8,
+    ) -> Result<(), IPPacketError> {
+        self.set_checksum(0x0);
+        let buf = self.buffer.as_ref();
+        let checksum = !pseudo_header_sum(source_address, destination_address, protocol, buf)?;
+        self.set_checksum(checksum);
+        Ok(())
+    }
+}
+
+bitflags! {
+    /// Control bits of the TCP header (RFC 9293 3.1), including the ECN bits (RFC 3168 6.1.1)
+    /// and the nonce sum bit (RFC 3540)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct TCPControlBits: u16 {
+        const FIN = 0x001;
+        const SYN = 0x002;
+        const RST = 0x004;
+        const PSH = 0x008;
+        const ACK = 0x010;
+        const URG = 0x020;
+        const ECE = 0x040;
+        const CWR = 0x080;
+        const NS = 0x100;
+    }
+}
+impl TCPControlBits {
+    /// Opens a connection, without acknowledging anything yet
+    pub fn is_syn_only(&self) -> bool {
+        self.intersection(Self::SYN | Self::ACK | Self::RST | Self::FIN) == Self::SYN
+    }
+    pub fn is_syn_ack(&self) -> bool {
+        self.contains(Self::SYN | Self::ACK) && !self.intersects(Self::RST | Self::FIN)
+    }
+    /// Ends the connection in either direction, with a FIN or a RST
+    pub fn is_fin_or_rst(&self) -> bool {
+        self.intersects(Self::FIN | Self::RST)
+    }
+    /// Sequence numbers taken up besides the data, SYN and FIN are one each
+    pub fn sequence_len(&self) -> u32 {
+        self.intersection(Self::SYN | Self::FIN).bits().count_ones()
     }
-    pub fn to_u8(&self) -> u8 {
+}
+
+/// Options from RFC 9293 and RFC 7323
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TCPOption {
+    EndOfList,
+    NoOperation,
+    MSS(u16),
+    WindowScale(u8),
+    SACKPermitted,
+    Timestamps { value: u32, echo_reply: u32 },
+}
+impl TCPOption {
+    /// Appends the kind, length and value of the option to `buf`
+    pub fn write(&self, buf: &mut Vec<u8>) {
         match self {
-            Self::URG => 0b100000,
-            Self::ACK => 0b10000,
-            Self::PSH => 0b1000,
-            Self::RST => 0b100,
-            Self::SYN => 0b10,
-            Self::FIN => 0b1,
+            Self::EndOfList => buf.push(0),
+            Self::NoOperation => buf.push(1),
+            Self::MSS(mss) => {
+                buf.extend_from_slice(&[2, 4]);
+                buf.extend_from_slice(&mss.to_be_bytes());
+            }
+            Self::WindowScale(shift) => buf.extend_from_slice(&[3, 3, *shift]),
+            Self::SACKPermitted => buf.extend_from_slice(&[4, 2]),
+            Self::Timestamps { value, echo_reply } => {
+                buf.extend_from_slice(&[8, 10]);
+                buf.extend_from_slice(&value.to_be_bytes());
+                buf.extend_from_slice(&echo_reply.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Builds a `TCP` segment, computing the data offset and checksum
+#[derive(Debug, Clone)]
+pub struct TCPBuilder {
+    source_port: u16,
+    destination_port: u16,
+    sequence_number: u32,
+    acknowledgment_number: u32,
+    control_bits: TCPControlBits,
+    window: u16,
+    urgent_pointer: u16,
+    options: Vec<u8>,
+    data: Vec<u8>,
+}
+impl TCPBuilder {
+    pub fn sequence_number(mut self, sequence_number: u32) -> Self {
+        self.sequence_number = sequence_number;
+        self
+    }
+    pub fn acknowledgment_number(mut self, acknowledgment_number: u32) -> Self {
+        self.acknowledgment_number = acknowledgment_number;
+        self
+    }
+    /// Sets control bits, on top of the ones already set
+    pub fn flag(mut self, flag: TCPControlBits) -> Self {
+        self.control_bits |= flag;
+        self
+    }
+    /// Replaces all control bits
+    pub fn control_bits(mut self, control_bits: TCPControlBits) -> Self {
+        self.control_bits = control_bits;
+        self
+    }
+    pub fn window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+    pub fn urgent_pointer(mut self, urgent_pointer: u16) -> Self {
+        self.urgent_pointer = urgent_pointer;
+        self
+    }
+    /// Appends an option, the options are padded to 32 bits with zeros
+    pub fn option(mut self, option: TCPOption) -> Self {
+        option.write(&mut self.options);
+        self
+    }
+    pub fn data(mut self, data: Vec<u8>) -> Self {
+        self.data = data;
+        self
+    }
+    /// Segment sent from `source_address` to `destination_address`, which are part of the checksum
+    /// Fails if the options are longer than MAX_OPTIONS_LEN
+    pub fn build(
+        self,
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
+    ) -> Result<TCP, IPPacketError> {
+        TCP::new(
+            source_address,
+            destination_address,
+            PROTOCOL,
+            self.source_port,
+            self.destination_port,
+            self.sequence_number,
+            self.acknowledgment_number,
+            0,
+            self.control_bits,
+            self.window,
+            self.urgent_pointer,
+            (!self.options.is_empty()).then_some(self.options),
+            self.data,
+        )
+    }
+}
+
+impl fmt::Display for TCP {
+    /// `Flags [S.], seq 1, ack 2, win 64240, length 0`, or a tree of every field with `{:#}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = flags_to_string(self.control_bits);
+        if f.alternate() {
+            writeln!(f, "TCP {} > {}", self.source_port, self.destination_port)?;
+            writeln!(
+                f,
+                "  seq {}, ack {}, data offset {}",
+                self.sequence_number, self.acknowledgment_number, self.data_offset
+            )?;
+            writeln!(
+                f,
+                "  flags [{}], win {}, checksum {:#06x}, urgent {}",
+                flags, self.window, self.checksum, self.urgent_pointer
+            )?;
+            if let Some(options) = &self.options {
+                writeln!(f, "  options [{}]", options_to_string(options))?;
+            }
+            return write!(f, "  data {} bytes", self.data.len());
+        }
+        write!(f, "Flags [{}], seq {}", flags, self.sequence_number)?;
+        if self.control_bits.contains(TCPControlBits::ACK) {
+            write!(f, ", ack {}", self.acknowledgment_number)?;
+        }
+        write!(f, ", win {}", self.window)?;
+        if let Some(options) = &self.options {
+            write!(f, ", options [{}]", options_to_string(options))?;
+        }
+        write!(f, ", length {}", self.data.len())
+    }
+}
+
+/// Control bits the way tcpdump prints them, `S.` for a SYN-ACK
+pub fn flags_to_string(control_bits: TCPControlBits) -> String {
+    let flags = [
+        (TCPControlBits::FIN, 'F'),
+        (TCPControlBits::SYN, 'S'),
+        (TCPControlBits::RST, 'R'),
+        (TCPControlBits::PSH, 'P'),
+        (TCPControlBits::ACK, '.'),
+        (TCPControlBits::URG, 'U'),
+        (TCPControlBits::ECE, 'E'),
+        (TCPControlBits::CWR, 'W'),
+        (TCPControlBits::NS, 'e'),
+    ];
+    let flags: String = flags
+        .iter()
+        .filter(|(flag, _)| control_bits.contains(*flag))
+        .map(|(_, letter)| letter)
+        .collect();
+    if flags.is_empty() {
+        "none".to_string()
+    } else {
+        flags
+    }
+}
+
+/// Decodes the option kinds from RFC 9293 and RFC 7323, e.g. `mss 1460,sackOK,nop,wscale 7`
+fn options_to_string(options: &[u8]) -> String {
+    let mut decoded = Vec::new();
+    let mut index = 0;
+    while index < options.len() {
+        let kind = options[index];
+        match kind {
+            0 => break,
+            1 => {
+                decoded.push("nop".to_string());
+                index += 1;
+                continue;
+            }
+            _ => {}
         }
+        let Some(&len) = options.get(index + 1) else {
+            break;
+        };
+        let len = len as usize;
+        let Some(value) = options.get(index + 2..index + len.max(2)) else {
+            decoded.push(format!("bad opt {}", kind));
+            break;
+        };
+        decoded.push(match (kind, value.len()) {
+            (2, 2) => format!("mss {}", u16::from_be_bytes([value[0], value[1]])),
+            (3, 1) => format!("wscale {}", value[0]),
+            (4, 0) => "sackOK".to_string(),
+            (8, 8) => format!(
+                "TS val {} ecr {}",
+                u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                u32::from_be_bytes([value[4], value[5], value[6], value[7]])
+            ),
+            _ => format!("opt-{} len {}", kind, len),
+        });
+        index += len.max(2);
     }
+    decoded.join(",")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
 
     #[test]
     fn from_byte_buffer() {
@@ -191,8 +556,8 @@ mod tests {
             0xf0, 0xce, 0x13, 0x0, 0x0, 0x2, 0x4, 0x5, 0xb4, 0x4, 0x2, 0x8, 0xa, 0x82, 0x7a, 0xb1,
             0xc1, 0x0, 0x0, 0x0, 0x0, 0x1, 0x3, 0x3, 0x7,
         ];
-        let source_address = Ipv4Addr::new(192, 168, 0, 1);
-        let destination_address = Ipv4Addr::new(192, 168, 0, 2);
+        let source_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        let destination_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
         let protocol = 6;
         let tcp =
             TCP::from_byte_buffer(&buf, &source_address, &destination_address, protocol).unwrap();
@@ -202,4 +567,111 @@ mod tests {
         assert_eq!(buf.to_vec(), tcp.to_byte_buffer());
         assert_eq!(buf.len(), tcp.len());
     }
+
+    #[test]
+    fn builder() {
+        let buf: [u8; 40] = [
+            0xbd, 0x4a, 0x0, 0x50, 0x84, 0x78, 0x87, 0x58, 0x0, 0x0, 0x0, 0x0, 0xa0, 0x2, 0xfa,
+            0xf0, 0xce, 0x13, 0x0, 0x0, 0x2, 0x4, 0x5, 0xb4, 0x4, 0x2, 0x8, 0xa, 0x82, 0x7a, 0xb1,
+            0xc1, 0x0, 0x0, 0x0, 0x0, 0x1, 0x3, 0x3, 0x7,
+        ];
+        let tcp = TCP::builder(48458, 80)
+            .sequence_number(2222491480)
+            .flag(TCPControlBits::SYN)
+            .window(64240)
+            .option(TCPOption::MSS(1460))
+            .option(TCPOption::SACKPermitted)
+            .option(TCPOption::Timestamps {
+                value: 2189078977,
+                echo_reply: 0,
+            })
+            .option(TCPOption::NoOperation)
+            .option(TCPOption::WindowScale(7))
+            .build(
+                &IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+                &IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            )
+            .unwrap();
+        assert_eq!(tcp.to_byte_buffer(), buf);
+
+        // 44 bytes of options do not fit the data offset
+        let Err(error) = (0..11)
+            .fold(TCP::builder(48458, 80), |builder, _| {
+                builder.option(TCPOption::MSS(1460))
+            })
+            .build(
+                &IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+                &IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            )
+        else {
+            panic!("Should not fit");
+        };
+        assert!(matches!(error.kind(), IPPacketErrorKind::TCPOptionsTooLong));
+    }
+
+    #[test]
+    fn segment_view() {
+        let mut buf: [u8; 40] = [
+            0xbd, 0x4a, 0x0, 0x50, 0x84, 0x78, 0x87, 0x58, 0x0, 0x0, 0x0, 0x0, 0xa0, 0x2, 0xfa,
+            0xf0, 0xce, 0x13, 0x0, 0x0, 0x2, 0x4, 0x5, 0xb4, 0x4, 0x2, 0x8, 0xa, 0x82, 0x7a, 0xb1,
+            0xc1, 0x0, 0x0, 0x0, 0x0, 0x1, 0x3, 0x3, 0x7,
+        ];
+        let source_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        let destination_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        let segment = TcpSegment::new_checked(&buf[..]).unwrap();
+        assert!(segment.verify_checksum(&source_address, &destination_address, PROTOCOL));
+        assert_eq!(segment.source_port(), 48458);
+        assert_eq!(segment.sequence_number(), 2222491480);
+        assert_eq!(segment.control_bits(), TCPControlBits::SYN);
+        assert_eq!(segment.options()[..4], [0x2, 0x4, 0x5, 0xb4]);
+        assert!(segment.data().is_empty());
+
+        let mut segment = TcpSegment::new_checked(&mut buf[..]).unwrap();
+        segment.set_destination_port(8080);
+        segment.set_acknowledgment_number(1);
+        segment.set_control_bits(TCPControlBits::SYN | TCPControlBits::ACK);
+        segment
+            .fill_checksum(&source_address, &destination_address, PROTOCOL)
+            .unwrap();
+        let tcp =
+            TCP::from_byte_buffer(&buf, &source_address, &destination_address, PROTOCOL).unwrap();
+        assert_eq!(tcp.destination_port, 8080);
+        assert_eq!(
+            (tcp.acknowledgment_number, tcp.control_bits),
+            (1, TCPControlBits::SYN | TCPControlBits::ACK)
+        );
+
+        // Data offset beyond the buffer
+        assert!(TcpSegment::new_checked(&buf[..24]).is_err());
+    }
+
+    #[test]
+    fn display() {
+        let buf: [u8; 40] = [
+            0xbd, 0x4a, 0x0, 0x50, 0x84, 0x78, 0x87, 0x58, 0x0, 0x0, 0x0, 0x0, 0xa0, 0x2, 0xfa,
+            0xf0, 0xce, 0x13, 0x0, 0x0, 0x2, 0x4, 0x5, 0xb4, 0x4, 0x2, 0x8, 0xa, 0x82, 0x7a, 0xb1,
+            0xc1, 0x0, 0x0, 0x0, 0x0, 0x1, 0x3, 0x3, 0x7,
+        ];
+        let source_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        let destination_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        let tcp = TCP::from_byte_buffer(&buf, &source_address, &destination_address, 6).unwrap();
+        assert_eq!(
+            tcp.to_string(),
+            "Flags [S], seq 2222491480, win 64240, \
+             options [mss 1460,sackOK,TS val 2189078977 ecr 0,nop,wscale 7], length 0"
+        );
+        assert_eq!(
+            flags_to_string(TCPControlBits::SYN | TCPControlBits::ACK),
+            "S."
+        );
+        assert_eq!(
+            flags_to_string(TCPControlBits::from_bits_retain(0b11001)),
+            "FP."
+        );
+        let ecn_setup = TCPControlBits::SYN | TCPControlBits::ECE | TCPControlBits::CWR;
+        assert_eq!(flags_to_string(ecn_setup), "SEW");
+        assert!(ecn_setup.is_syn_only());
+        assert!(!(TCPControlBits::SYN | TCPControlBits::ACK).is_syn_only());
+        assert!(format!("{:#}", tcp).contains("\n  flags [S], win 64240, checksum 0xce13"));
+    }
 }
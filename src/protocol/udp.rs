@@ -0,0 +1,257 @@
+use std::{fmt, net::IpAddr};
+
+use crate::buffer::PacketBuffer;
+use crate::checksum::{pseudo_header_sum, Checksum};
+use crate::ip::{IPPacketError, IPPacketErrorKind};
+
+/// Protocol number of UDP in the IP header
+pub const PROTOCOL: u8 = 17;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UDP {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub length: u16,
+    pub checksum: u16,
+    pub data: Vec<u8>,
+}
+impl UDP {
+    /// Creates a new datagram, calculating the length and checksum for you
+    pub fn new(
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
+        source_port: u16,
+        destination_port: u16,
+        data: Vec<u8>,
+    ) -> Result<Self, IPPacketError> {
+        let mut udp = Self {
+            source_port,
+            destination_port,
+            length: 8 + data.len() as u16,
+            checksum: 0x0,
+            data,
+        };
+        let mut header = [0x0; 8];
+        udp.write_header(&mut header);
+        let checksum = Checksum::new()
+            .add_pseudo_header(
+                source_address,
+                destination_address,
+                PROTOCOL,
+                udp.len() as u32,
+            )?
+            .add(&header)
+            .add(&udp.data)
+            .finish();
+        // 0 means no checksum was computed, so it is sent as all ones (RFC 768)
+        udp.checksum = if checksum == 0 { 0xFFFF } else { checksum };
+        Ok(udp)
+    }
+    pub fn from_byte_buffer(
+        buf: &[u8],
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
+    ) -> Result<Self, IPPacketError> {
+        let datagram = UdpDatagram::new_checked(buf)?;
+        if !datagram.verify_checksum(source_address, destination_address) {
+            return Err(IPPacketError::new(IPPacketErrorKind::UDPChecksumError));
+        }
+        Ok(Self {
+            source_port: datagram.source_port(),
+            destination_port: datagram.destination_port(),
+            length: datagram.length(),
+            checksum: datagram.checksum(),
+            data: datagram.data().to_vec(),
+        })
+    }
+    pub fn to_byte_buffer(&self) -> Vec<u8> {
+        let mut buf = vec![0x0; 8];
+        self.write_header(&mut buf);
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+    /// Puts the datagram in front of the bytes already in `buf`, using its headroom
+    pub fn prepend_to(&self, buf: &mut PacketBuffer) {
+        buf.prepend(self.data.len()).copy_from_slice(&self.data);
+        self.write_header(buf.prepend(8));
+    }
+    fn write_header(&self, out: &mut [u8]) {
+        out[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        out[2..4].copy_from_slice(&self.destination_port.to_be_bytes());
+        out[4..6].copy_from_slice(&self.length.to_be_bytes());
+        out[6..8].copy_from_slice(&self.checksum.to_be_bytes());
+    }
+    pub fn len(&self) -> usize {
+        8 + self.data.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+/// Borrowed view of a raw UDP datagram, reading and writing the fields in place without parsing
+/// it into a `UDP`
+#[derive(Debug, Clone, Copy)]
+pub struct UdpDatagram<T> {
+    buffer: T,
+}
+impl<T: AsRef<[u8]>> UdpDatagram<T> {
+    /// Field accesses panic if the buffer is shorter than the length field says
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+    /// Checks the buffer holds the header and the length the header gives
+    pub fn new_checked(buffer: T) -> Result<Self, IPPacketError> {
+        let datagram = Self::new_unchecked(buffer);
+        let len = datagram.buffer.as_ref().len();
+        if len < 8 || (datagram.length() as usize) < 8 || datagram.length() as usize > len {
+            return Err(IPPacketError::new(IPPacketErrorKind::UDPLengthError));
+        }
+        Ok(datagram)
+    }
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+    fn field(&self, offset: usize) -> u16 {
+        let buf = self.buffer.as_ref();
+        u16::from_be_bytes([buf[offset], buf[offset + 1]])
+    }
+    pub fn source_port(&self) -> u16 {
+        self.field(0)
+    }
+    pub fn destination_port(&self) -> u16 {
+        self.field(2)
+    }
+    pub fn length(&self) -> u16 {
+        self.field(4)
+    }
+    pub fn checksum(&self) -> u16 {
+        self.field(6)
+    }
+    /// The data up to the length, without anything the buffer has after the datagram
+    pub fn data(&self) -> &[u8] {
+        &self.buffer.as_ref()[8..self.length() as usize]
+    }
+    /// The checksum is optional over IPv4 but mandatory over IPv6
+    pub fn verify_checksum(&self, source_address: &IpAddr, destination_address: &IpAddr) -> bool {
+        if self.checksum() == 0 && source_address.is_ipv4() {
+            return true;
+        }
+        let buf = &self.buffer.as_ref()[..self.length() as usize];
+        pseudo_header_sum(source_address, destination_address, PROTOCOL, buf)
+            .is_ok_and(|sum| sum == 0xFFFF)
+    }
+}
+impl<T: AsRef<[u8]> + AsMut<[u8]>> UdpDatagram<T> {
+    fn set_field(&mut self, offset: usize, value: u16) {
+        self.buffer.as_mut()[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+    pub fn set_source_port(&mut self, port: u16) {
+        self.set_field(0, port);
+    }
+    pub fn set_destination_port(&mut self, port: u16) {
+        self.set_field(2, port);
+    }
+    pub fn set_length(&mut self, length: u16) {
+        self.set_field(4, length);
+    }
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.set_field(6, checksum);
+    }
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        let length = self.length() as usize;
+        &mut self.buffer.as_mut()[8..length]
+    }
+    /// Recalculates the checksum after fields or data were changed
+    pub fn fill_checksum(
+        &mut self,
+        source_address: &IpAddr,
+        destination_address: &IpAddr,
+    ) -> Result<(), IPPacketError> {
+        self.set_checksum(0x0);
+        let buf = &self.buffer.as_ref()[..self.length() as usize];
+        let checksum = !pseudo_header_sum(source_address, destination_address, PROTOCOL, buf)?;
+        // 0 means no checksum was computed, so it is sent as all ones (RFC 768)
+        self.set_checksum(if checksum == 0 { 0xFFFF } else { checksum });
+        Ok(())
+    }
+}
+
+impl fmt::Display for UDP {
+    /// `UDP, length 4`, or a tree of every field with `{:#}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            writeln!(f, "UDP {} > {}", self.source_port, self.destination_port)?;
+            writeln!(
+                f,
+                "  length {}, checksum {:#06x}",
+                self.length, self.checksum
+            )?;
+            return write!(f, "  data {} bytes", self.data.len());
+        }
+        write!(f, "UDP, length {}", self.data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn from_byte_buffer() {
+        // dig query from 192.168.0.1:53123 to 192.168.0.2:53, payload trimmed
+        let buf: [u8; 12] = [
+            0xcf, 0x83, 0x0, 0x35, 0x0, 0xc, 0x9b, 0x95, 0x12, 0x34, 0x1, 0x0,
+        ];
+        let source_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        let destination_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        let udp = UDP::from_byte_buffer(&buf, &source_address, &destination_address).unwrap();
+        assert_eq!(udp.source_port, 53123);
+        assert_eq!(udp.destination_port, 53);
+        assert_eq!(udp.data, [0x12, 0x34, 0x1, 0x0]);
+        assert_eq!(udp.to_byte_buffer(), buf);
+
+        let new_udp = UDP::new(
+            &source_address,
+            &destination_address,
+            udp.source_port,
+            udp.destination_port,
+            udp.data.clone(),
+        )
+        .unwrap();
+        assert_eq!(new_udp, udp);
+    }
+
+    #[test]
+    fn datagram_view() {
+        let mut buf: [u8; 14] = [
+            0xcf, 0x83, 0x0, 0x35, 0x0, 0xc, 0x9b, 0x95, 0x12, 0x34, 0x1, 0x0, 0xff, 0xff,
+        ];
+        let source_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        let destination_address = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        let mut datagram = UdpDatagram::new_checked(&mut buf[..]).unwrap();
+        assert!(datagram.verify_checksum(&source_address, &destination_address));
+        // Trailing bytes are not part of the datagram
+        assert_eq!(datagram.data(), [0x12, 0x34, 0x1, 0x0]);
+        datagram.set_source_port(5353);
+        datagram.data_mut()[0] = 0x56;
+        datagram
+            .fill_checksum(&source_address, &destination_address)
+            .unwrap();
+        let udp = UDP::from_byte_buffer(&buf, &source_address, &destination_address).unwrap();
+        assert_eq!((udp.source_port, udp.data[0]), (5353, 0x56));
+        assert!(UdpDatagram::new_checked(&buf[..10]).is_err());
+    }
+
+    #[test]
+    fn zero_checksum_is_optional_over_ipv4() {
+        let buf: [u8; 10] = [0x0, 0x7, 0x0, 0x7, 0x0, 0xa, 0x0, 0x0, 0xab, 0xcd];
+        let source_address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let destination_address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert!(UDP::from_byte_buffer(&buf, &source_address, &destination_address).is_ok());
+        let source_address = IpAddr::V6("fd00::1".parse().unwrap());
+        let destination_address = IpAddr::V6("fd00::2".parse().unwrap());
+        assert!(UDP::from_byte_buffer(&buf, &source_address, &destination_address).is_err());
+    }
+}
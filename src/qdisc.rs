@@ -0,0 +1,742 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    hash::{DefaultHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    buffer::PacketBuffer,
+    device::DEFAULT_MTU,
+    ip::{mark_ce, Ipv4Packet},
+    protocol::{tcp, udp},
+    stats::Counter,
+};
+
+/// Packets a queue holds before dropping, like the txqueuelen of Linux
+pub const DEFAULT_LIMIT: usize = 1000;
+/// Bands of `Prio`, from the most urgent
+pub const BANDS: usize = 3;
+/// Flow queues of `FqCodel` and the bytes each may send per round (RFC 8290 5)
+const FLOWS: usize = 1024;
+const QUANTUM: i64 = DEFAULT_MTU as i64 + 14;
+/// Acceptable standing queue delay and the window it has to persist in before CoDel acts
+/// (RFC 8289 4.3, 4.4)
+const TARGET: Duration = Duration::from_millis(5);
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// Counters of a class, read with `Qdisc::classes`
+#[derive(Debug, Default)]
+pub struct ClassStats {
+    pub sent_packets: Counter,
+    pub sent_bytes: Counter,
+    pub drops: Counter,
+    /// Packets the shaper held back for lack of tokens
+    pub overlimits: Counter,
+    /// Marked CE instead of being dropped
+    pub ecn_marks: Counter,
+}
+impl ClassStats {
+    fn sent(&self, packet: &PacketBuffer) {
+        self.sent_packets.increment();
+        self.sent_bytes.add(packet.len() as u64);
+    }
+}
+
+/// An egress queueing discipline, holding the packets routed to an interface until they can go
+pub trait Qdisc: Send {
+    /// Queues a packet, returning false when it was dropped instead
+    fn enqueue(&mut self, packet: PacketBuffer, now: Instant) -> bool;
+    /// The next packet to send, `None` when the queue is empty or holding its packets back
+    fn dequeue(&mut self, now: Instant) -> Option<PacketBuffer>;
+    /// When a packet held back will be let go, so the caller knows when to dequeue again
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+    /// Packets queued
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The counters of each class, named like `tc` names them
+    fn classes(&self) -> Vec<(String, &ClassStats)>;
+}
+
+/// Differentiated services codepoint of a raw IPv4 or IPv6 packet (RFC 2474 3)
+pub fn dscp(ip_packet: &[u8]) -> u8 {
+    match ip_packet.first().map(|byte| byte >> 4) {
+        Some(4) if ip_packet.len() >= 2 => ip_packet[1] >> 2,
+        // The traffic class straddles the first two bytes
+        Some(6) if ip_packet.len() >= 2 => ((ip_packet[0] & 0x0F) << 2) | (ip_packet[1] >> 6),
+        _ => 0,
+    }
+}
+
+/// Tail drop first in, first out queue, pfifo in Linux
+#[derive(Debug)]
+pub struct Fifo {
+    queue: VecDeque<PacketBuffer>,
+    limit: usize,
+    stats: ClassStats,
+}
+impl Fifo {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            limit,
+            stats: ClassStats::default(),
+        }
+    }
+}
+impl Qdisc for Fifo {
+    fn enqueue(&mut self, packet: PacketBuffer, _now: Instant) -> bool {
+        if self.queue.len() >= self.limit {
+            self.stats.drops.increment();
+            return false;
+        }
+        self.queue.push_back(packet);
+        true
+    }
+    fn dequeue(&mut self, _now: Instant) -> Option<PacketBuffer> {
+        let packet = self.queue.pop_front()?;
+        self.stats.sent(&packet);
+        Some(packet)
+    }
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+    fn classes(&self) -> Vec<(String, &ClassStats)> {
+        vec![("fifo".to_string(), &self.stats)]
+    }
+}
+
+/// Strict priority between `BANDS` queues picked by DSCP, a band is only served while the more
+/// urgent ones are empty
+pub struct Prio {
+    bands: [Box<dyn Qdisc>; BANDS],
+}
+impl Prio {
+    pub fn new(bands: [Box<dyn Qdisc>; BANDS]) -> Self {
+        Self { bands }
+    }
+    /// Band 0 takes network control, expedited forwarding and voice admit (CS5 and above),
+    /// band 2 lower effort and CS1 (RFC 8622), band 1 everything else
+    pub fn band(dscp: u8) -> usize {
+        match dscp {
+            1 | 8 => 2,
+            40.. => 0,
+            _ => 1,
+        }
+    }
+}
+impl Qdisc for Prio {
+    fn enqueue(&mut self, packet: PacketBuffer, now: Instant) -> bool {
+        let band = Self::band(dscp(&packet));
+        self.bands[band].enqueue(packet, now)
+    }
+    fn dequeue(&mut self, now: Instant) -> Option<PacketBuffer> {
+        self.bands.iter_mut().find_map(|band| band.dequeue(now))
+    }
+    fn next_deadline(&self) -> Option<Instant> {
+        self.bands
+            .iter()
+            .filter_map(|band| band.next_deadline())
+            .min()
+    }
+    fn len(&self) -> usize {
+        self.bands.iter().map(|band| band.len()).sum()
+    }
+    fn classes(&self) -> Vec<(String, &ClassStats)> {
+        let mut classes = Vec::new();
+        for (index, band) in self.bands.iter().enumerate() {
+            for (name, stats) in band.classes() {
+                classes.push((format!("band {} {}", index, name), stats));
+            }
+        }
+        classes
+    }
+}
+
+/// Token bucket shaper in front of another discipline, releasing `rate` bytes per second after
+/// an initial burst, tbf in Linux
+pub struct TokenBucket {
+    inner: Box<dyn Qdisc>,
+    /// Bytes per second
+    rate: u64,
+    /// Size of the bucket in bytes
+    burst: usize,
+    tokens: f64,
+    refilled: Option<Instant>,
+    /// Taken from the inner discipline, waiting for enough tokens
+    held: Option<PacketBuffer>,
+    stats: ClassStats,
+}
+impl TokenBucket {
+    /// `rate` in bytes per second, the bucket starts full
+    pub fn new(inner: Box<dyn Qdisc>, rate: u64, burst: usize) -> Self {
+        Self {
+            inner,
+            rate: rate.max(1),
+            burst,
+            tokens: burst as f64,
+            refilled: None,
+            held: None,
+            stats: ClassStats::default(),
+        }
+    }
+    fn refill(&mut self, now: Instant) {
+        if let Some(refilled) = self.refilled {
+            let elapsed = now.saturating_duration_since(refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        }
+        self.refilled = Some(now);
+    }
+    /// Tokens a packet takes, a packet larger than the bucket goes once it is full
+    fn cost(&self, packet: &PacketBuffer) -> f64 {
+        packet.len().min(self.burst) as f64
+    }
+}
+impl Qdisc for TokenBucket {
+    fn enqueue(&mut self, packet: PacketBuffer, now: Instant) -> bool {
+        self.inner.enqueue(packet, now)
+    }
+    fn dequeue(&mut self, now: Instant) -> Option<PacketBuffer> {
+        self.refill(now);
+        let packet = match self.held.take() {
+            Some(packet) => packet,
+            None => self.inner.dequeue(now)?,
+        };
+        let cost = self.cost(&packet);
+        if self.tokens < cost {
+            self.stats.overlimits.increment();
+            self.held = Some(packet);
+            return None;
+        }
+        self.tokens -= cost;
+        self.stats.sent(&packet);
+        Some(packet)
+    }
+    fn next_deadline(&self) -> Option<Instant> {
+        let Some(packet) = &self.held else {
+            return self.inner.next_deadline();
+        };
+        let missing = (self.cost(packet) - self.tokens).max(0.0);
+        let refilled = self
+            .refilled
+            .expect("Refilled when the packet was held back");
+        // Rounded up, so the tokens are there when it is due
+        let wait = (missing * 1e9 / self.rate as f64).ceil() as u64;
+        Some(refilled + Duration::from_nanos(wait))
+    }
+    fn len(&self) -> usize {
+        self.inner.len() + self.held.is_some() as usize
+    }
+    fn classes(&self) -> Vec<(String, &ClassStats)> {
+        let mut classes = vec![("tbf".to_string(), &self.stats)];
+        classes.extend(self.inner.classes());
+        classes
+    }
+}
+
+/// A flow queue of `FqCodel` with its CoDel state (RFC 8289 5)
+#[derive(Debug, Default)]
+struct Flow {
+    /// Packets and when they were queued
+    queue: VecDeque<(PacketBuffer, Instant)>,
+    /// Bytes queued
+    backlog: usize,
+    /// Bytes the flow may still send this round
+    deficit: i64,
+    /// Whether the flow is on the new or old list
+    active: bool,
+    /// When the sojourn time will have been above the target for an interval
+    first_above_time: Option<Instant>,
+    drop_next: Option<Instant>,
+    /// Drops or marks since entering the dropping state, and when it was last left
+    count: u32,
+    last_count: u32,
+    dropping: bool,
+}
+impl Flow {
+    /// Takes the head packet, telling whether the queue stood above the target long enough for
+    /// it to be dropped
+    fn pop(&mut self, now: Instant) -> Option<(PacketBuffer, bool)> {
+        let Some((packet, queued)) = self.queue.pop_front() else {
+            self.first_above_time = None;
+            return None;
+        };
+        self.backlog -= packet.len();
+        // A queue of less than a packet is no standing queue
+        if now.saturating_duration_since(queued) < TARGET || self.backlog <= DEFAULT_MTU {
+            self.first_above_time = None;
+            return Some((packet, false));
+        }
+        match self.first_above_time {
+            None => {
+                self.first_above_time = Some(now + INTERVAL);
+                Some((packet, false))
+            }
+            Some(first_above_time) => Some((packet, now >= first_above_time)),
+        }
+    }
+    /// Drops get closer together the longer the queue stands (RFC 8289 5.5)
+    fn control_law(&self, t: Instant) -> Instant {
+        t + INTERVAL.div_f64((self.count as f64).sqrt())
+    }
+    /// CoDel dequeue, marking ECN capable packets instead of dropping them (RFC 8289 5.5, 5.6)
+    fn dequeue(&mut self, now: Instant, stats: &ClassStats) -> Option<PacketBuffer> {
+        let Some((mut packet, mut ok_to_drop)) = self.pop(now) else {
+            self.dropping = false;
+            return None;
+        };
+        if self.dropping {
+            if !ok_to_drop {
+                self.dropping = false;
+            }
+            while self.dropping && self.drop_next.is_some_and(|drop_next| now >= drop_next) {
+                self.count += 1;
+                let drop_next = self.drop_next.expect("Checked above");
+                if mark_ce(&mut packet) {
+                    stats.ecn_marks.increment();
+                    self.drop_next = Some(self.control_law(drop_next));
+                    break;
+                }
+                stats.drops.increment();
+                (packet, ok_to_drop) = self.pop(now)?;
+                if ok_to_drop {
+                    self.drop_next = Some(self.control_law(drop_next));
+                } else {
+                    self.dropping = false;
+                }
+            }
+        } else if ok_to_drop {
+            self.dropping = true;
+            // Picks up where the last dropping state left off if it ended recently
+            let delta = self.count.saturating_sub(self.last_count);
+            let recent = self
+                .drop_next
+                .is_some_and(|drop_next| now.saturating_duration_since(drop_next) < INTERVAL * 16);
+            self.count = if delta > 1 && recent { delta } else { 1 };
+            self.drop_next = Some(self.control_law(now));
+            self.last_count = self.count;
+            if mark_ce(&mut packet) {
+                stats.ecn_marks.increment();
+            } else {
+                stats.drops.increment();
+                (packet, _) = self.pop(now)?;
+            }
+        }
+        Some(packet)
+    }
+}
+
+/// Flow queuing with CoDel on each flow (RFC 8290), flows are hashed from their addresses,
+/// protocol and ports and served round robin, new flows first, so a bulk transfer neither starves
+/// sparse flows nor builds a standing queue
+pub struct FqCodel {
+    flows: Vec<Flow>,
+    new_flows: VecDeque<usize>,
+    old_flows: VecDeque<usize>,
+    limit: usize,
+    len: usize,
+    stats: ClassStats,
+}
+impl FqCodel {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            flows: (0..FLOWS).map(|_| Flow::default()).collect(),
+            new_flows: VecDeque::new(),
+            old_flows: VecDeque::new(),
+            limit,
+            len: 0,
+            stats: ClassStats::default(),
+        }
+    }
+    /// Flow queue of a packet, by addresses, protocol and ports when there are any
+    fn flow(ip_packet: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        let (addresses, protocol, transport) = match ip_packet[0] >> 4 {
+            4 => match Ipv4Packet::new_checked(ip_packet) {
+                // Later fragments have no ports
+                Ok(packet) if packet.fragment_offset() == 0 => {
+                    (&ip_packet[12..20], packet.protocol(), packet.header_len())
+                }
+                Ok(packet) => (&ip_packet[12..20], packet.protocol(), ip_packet.len()),
+                Err(_) => (&ip_packet[..0], 0, ip_packet.len()),
+            },
+            6 if ip_packet.len() >= 40 => (&ip_packet[8..40], ip_packet[6], 40),
+            _ => (&ip_packet[..0], 0, ip_packet.len()),
+        };
+        hasher.write(addresses);
+        hasher.write_u8(protocol);
+        if matches!(protocol, tcp::PROTOCOL | udp::PROTOCOL) && ip_packet.len() >= transport + 4 {
+            hasher.write(&ip_packet[transport..transport + 4]);
+        }
+        (hasher.finish() % FLOWS as u64) as usize
+    }
+    /// Takes the flow being served off the front of its list
+    fn leave_list(&mut self, is_new: bool) {
+        match is_new {
+            true => self.new_flows.pop_front(),
+            false => self.old_flows.pop_front(),
+        };
+    }
+    /// Makes room by dropping the head of the flow with the largest backlog (RFC 8290 4.1),
+    /// returning that flow
+    fn drop_from_fattest(&mut self) -> usize {
+        let fattest = (0..self.flows.len())
+            .max_by_key(|index| self.flows[*index].backlog)
+            .expect("There are flows");
+        let flow = &mut self.flows[fattest];
+        if let Some((packet, _)) = flow.queue.pop_front() {
+            flow.backlog -= packet.len();
+            self.len -= 1;
+            self.stats.drops.increment();
+        }
+        fattest
+    }
+}
+impl Qdisc for FqCodel {
+    fn enqueue(&mut self, packet: PacketBuffer, now: Instant) -> bool {
+        let index = Self::flow(&packet);
+        let flow = &mut self.flows[index];
+        flow.backlog += packet.len();
+        flow.queue.push_back((packet, now));
+        if !flow.active {
+            flow.active = true;
+            flow.deficit = QUANTUM;
+            self.new_flows.push_back(index);
+        }
+        self.len += 1;
+        // Only a drop from the flow of the packet tells its sender to back off
+        self.len <= self.limit || self.drop_from_fattest() != index
+    }
+    fn dequeue(&mut self, now: Instant) -> Option<PacketBuffer> {
+        loop {
+            let (index, is_new) = match self.new_flows.front() {
+                Some(index) => (*index, true),
+                None => (*self.old_flows.front()?, false),
+            };
+            let flow = &mut self.flows[index];
+            if flow.deficit <= 0 {
+                flow.deficit += QUANTUM;
+                self.leave_list(is_new);
+                self.old_flows.push_back(index);
+                continue;
+            }
+            let queued = flow.queue.len();
+            let packet = flow.dequeue(now, &self.stats);
+            self.len -= queued - flow.queue.len();
+            let Some(packet) = packet else {
+                self.leave_list(is_new);
+                // An emptied new flow goes through the old list once, so it can not become new
+                // again right away (RFC 8290 4.2)
+                match is_new && !self.old_flows.is_empty() {
+                    true => self.old_flows.push_back(index),
+                    false => self.flows[index].active = false,
+                }
+                continue;
+            };
+            flow.deficit -= packet.len() as i64;
+            self.stats.sent(&packet);
+            return Some(packet);
+        }
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn classes(&self) -> Vec<(String, &ClassStats)> {
+        vec![("fq_codel".to_string(), &self.stats)]
+    }
+}
+
+/// Which discipline an interface gets
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QdiscKind {
+    Fifo,
+    /// A FIFO per DSCP priority band
+    Prio,
+    FqCodel,
+}
+
+/// Queueing discipline of an interface, a shaper in front when a rate is given
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QdiscConfig {
+    pub kind: QdiscKind,
+    /// Packets held, per band with prio
+    pub limit: usize,
+    /// Bits per second the interface is shaped to, 0 to send at the speed of the device
+    pub rate: u64,
+    /// Bytes sent back to back after being idle, at least an MTU
+    pub burst: usize,
+}
+impl Default for QdiscConfig {
+    fn default() -> Self {
+        Self {
+            kind: QdiscKind::FqCodel,
+            limit: DEFAULT_LIMIT,
+            rate: 0,
+            burst: 10 * DEFAULT_MTU,
+        }
+    }
+}
+impl QdiscConfig {
+    pub fn build(&self) -> Box<dyn Qdisc> {
+        let qdisc: Box<dyn Qdisc> = match self.kind {
+            QdiscKind::Fifo => Box::new(Fifo::new(self.limit)),
+            QdiscKind::Prio => Box::new(Prio::new(std::array::from_fn(|_| {
+                Box::new(Fifo::new(self.limit)) as Box<dyn Qdisc>
+            }))),
+            QdiscKind::FqCodel => Box::new(FqCodel::new(self.limit)),
+        };
+        match self.rate {
+            0 => qdisc,
+            rate => Box::new(TokenBucket::new(qdisc, rate / 8, self.burst)),
+        }
+    }
+}
+
+/// What `Egress::enqueue` did with a packet
+#[derive(Debug)]
+pub enum Enqueued {
+    Queued,
+    /// The discipline was full and dropped from the flow of the packet
+    Dropped,
+    /// The interface has no discipline, the packet goes straight to its device
+    Bypassed(PacketBuffer),
+}
+
+/// The queueing disciplines of the interfaces, packets to an interface without one go straight
+/// to its device
+#[derive(Default)]
+pub struct Egress {
+    qdiscs: BTreeMap<String, Box<dyn Qdisc>>,
+    /// Drops of the disciplines already handed out by `take_drops`
+    reported_drops: u64,
+}
+impl Egress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Replaces the discipline of the interface, dropping what it held
+    pub fn attach(&mut self, interface: &str, qdisc: Box<dyn Qdisc>) {
+        if let Some(old) = self.qdiscs.insert(interface.to_string(), qdisc) {
+            self.forget_drops(&*old);
+        }
+    }
+    pub fn detach(&mut self, interface: &str) -> Option<Box<dyn Qdisc>> {
+        let qdisc = self.qdiscs.remove(interface)?;
+        self.forget_drops(&*qdisc);
+        Some(qdisc)
+    }
+    pub fn get(&self, interface: &str) -> Option<&dyn Qdisc> {
+        self.qdiscs.get(interface).map(|qdisc| &**qdisc)
+    }
+    /// Queues the packet in the discipline of the interface, handing it back when there is none
+    pub fn enqueue(&mut self, interface: &str, packet: PacketBuffer, now: Instant) -> Enqueued {
+        let Some(qdisc) = self.qdiscs.get_mut(interface) else {
+            return Enqueued::Bypassed(packet);
+        };
+        match qdisc.enqueue(packet, now) {
+            true => Enqueued::Queued,
+            false => Enqueued::Dropped,
+        }
+    }
+    pub fn dequeue(&mut self, interface: &str, now: Instant) -> Option<PacketBuffer> {
+        self.qdiscs.get_mut(interface)?.dequeue(now)
+    }
+    /// When a shaped interface can send again
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.qdiscs
+            .values()
+            .filter_map(|qdisc| qdisc.next_deadline())
+            .min()
+    }
+    /// Packets the disciplines dropped since the last call, the ones refused by `enqueue` as
+    /// well as the ones they dropped from their queues, counted as ipOutDiscards (RFC 1213 6)
+    pub fn take_drops(&mut self) -> u64 {
+        let drops = self.qdiscs.values().map(|qdisc| drops(&**qdisc)).sum();
+        let taken = drops - self.reported_drops;
+        self.reported_drops = drops;
+        taken
+    }
+    /// Stops counting the drops of a discipline taken off its interface
+    fn forget_drops(&mut self, qdisc: &dyn Qdisc) {
+        self.reported_drops = self.reported_drops.saturating_sub(drops(qdisc));
+    }
+}
+
+/// Drops of all the classes of a discipline
+fn drops(qdisc: &dyn Qdisc) -> u64 {
+    qdisc
+        .classes()
+        .iter()
+        .map(|(_, stats)| stats.drops.get())
+        .sum()
+}
+impl fmt::Display for Egress {
+    /// The classes of every interface with their counters, like `tc -s class show`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (interface, qdisc) in &self.qdiscs {
+            writeln!(f, "{}: backlog {}p", interface, qdisc.len())?;
+            for (name, stats) in qdisc.classes() {
+                writeln!(
+                    f,
+                    "  {}: sent {} bytes {} pkt (dropped {}, overlimits {}, marked {})",
+                    name,
+                    stats.sent_bytes.get(),
+                    stats.sent_packets.get(),
+                    stats.drops.get(),
+                    stats.overlimits.get(),
+                    stats.ecn_marks.get()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ip::{IPHeader, ECN},
+        protocol::{IPBody, UDP},
+        IPPacket,
+    };
+    use std::net::Ipv4Addr;
+
+    /// 1000 byte UDP packet of the flow from `source_port`
+    fn packet(source_port: u16, type_of_service: u8) -> PacketBuffer {
+        let (source_addr, destination_addr) =
+            (Ipv4Addr::new(192, 168, 0, 2), Ipv4Addr::new(10, 0, 0, 1));
+        let udp = UDP::new(
+            &source_addr.into(),
+            &destination_addr.into(),
+            source_port,
+            80,
+            vec![0; 972],
+        )
+        .unwrap();
+        let header =
+            IPHeader::builder(source_addr, destination_addr).type_of_service(type_of_service);
+        PacketBuffer::from(IPPacket::from_body(header, IPBody::UDP(udp)).to_byte_buffer())
+    }
+
+    fn source_port(packet: &PacketBuffer) -> u16 {
+        u16::from_be_bytes([packet[20], packet[21]])
+    }
+
+    #[test]
+    fn prio_by_dscp() {
+        let mut prio = Prio::new(std::array::from_fn(|_| {
+            Box::new(Fifo::new(2)) as Box<dyn Qdisc>
+        }));
+        let now = Instant::now();
+        // Best effort, lower effort (CS1) and expedited forwarding (EF)
+        for (port, dscp) in [(1, 0), (2, 8), (3, 46), (4, 0), (5, 0)] {
+            prio.enqueue(packet(port, dscp << 2), now);
+        }
+        assert_eq!(prio.len(), 4);
+        let order: Vec<u16> = std::iter::from_fn(|| prio.dequeue(now))
+            .map(|packet| source_port(&packet))
+            .collect();
+        assert_eq!(order, [3, 1, 4, 2]);
+        let classes = prio.classes();
+        assert_eq!(classes[1].0, "band 1 fifo");
+        assert_eq!(
+            (classes[1].1.sent_packets.get(), classes[1].1.drops.get()),
+            (2, 1)
+        );
+        // The traffic class of IPv6
+        assert_eq!(dscp(&[0x6b, 0x80, 0x0, 0x0]), 46);
+    }
+
+    #[test]
+    fn token_bucket_shapes() {
+        let mut egress = Egress::new();
+        let shaper = TokenBucket::new(Box::new(Fifo::new(DEFAULT_LIMIT)), 1000, 1500);
+        egress.attach("tun0", Box::new(shaper));
+        let now = Instant::now();
+        for port in 0..3 {
+            assert!(matches!(
+                egress.enqueue("tun0", packet(port, 0), now),
+                Enqueued::Queued
+            ));
+        }
+        assert!(matches!(
+            egress.enqueue("eth0", packet(3, 0), now),
+            Enqueued::Bypassed(_)
+        ));
+        egress.attach("eth0", Box::new(Fifo::new(1)));
+        egress.enqueue("eth0", packet(3, 0), now);
+        assert!(matches!(
+            egress.enqueue("eth0", packet(4, 0), now),
+            Enqueued::Dropped
+        ));
+        egress.detach("eth0");
+
+        // The burst lets one packet through, then one per second
+        assert!(egress.dequeue("tun0", now).is_some());
+        assert!(egress.dequeue("tun0", now).is_none());
+        let deadline = egress.next_deadline().unwrap();
+        assert_eq!(deadline, now + Duration::from_millis(500));
+        assert!(egress.dequeue("tun0", deadline).is_some());
+        assert!(egress.dequeue("tun0", deadline).is_none());
+        assert_eq!(
+            egress.next_deadline(),
+            Some(deadline + Duration::from_secs(1))
+        );
+        assert_eq!(
+            egress.to_string(),
+            "tun0: backlog 1p\n  \
+             tbf: sent 2000 bytes 2 pkt (dropped 0, overlimits 2, marked 0)\n  \
+             fifo: sent 3000 bytes 3 pkt (dropped 0, overlimits 0, marked 0)\n"
+        );
+    }
+
+    #[test]
+    fn fq_codel() {
+        let mut fq_codel = FqCodel::new(DEFAULT_LIMIT);
+        let now = Instant::now();
+        // A bulk transfer sending ECT(0), then a sparse flow
+        for _ in 0..20 {
+            fq_codel.enqueue(packet(1000, ECN::ECT0 as u8), now);
+        }
+        fq_codel.enqueue(packet(2000, 0), now);
+
+        // The sparse flow goes once the bulk one used up its quantum
+        let order: Vec<u16> = (0..3)
+            .map(|_| source_port(&fq_codel.dequeue(now).unwrap()))
+            .collect();
+        assert_eq!(order, [1000, 1000, 2000]);
+
+        // A queue standing above the target for an interval gets marked
+        let sent = fq_codel.dequeue(now + Duration::from_millis(200)).unwrap();
+        assert_eq!(Ipv4Packet::new_checked(&sent[..]).unwrap().ecn(), ECN::ECT0);
+        let sent = fq_codel.dequeue(now + INTERVAL * 3).unwrap();
+        let marked = Ipv4Packet::new_checked(&sent[..]).unwrap();
+        assert_eq!(marked.ecn(), ECN::CE);
+        assert!(marked.verify_checksum());
+        assert_eq!(fq_codel.classes()[0].1.ecn_marks.get(), 1);
+        assert_eq!(fq_codel.len(), 16);
+
+        // Tail drop from the fattest flow, only refusing the packet when that is its own
+        let mut fq_codel = FqCodel::new(2);
+        let queued: Vec<bool> = [1, 1, 2]
+            .into_iter()
+            .map(|port| fq_codel.enqueue(packet(port, 0), now))
+            .collect();
+        assert_eq!(queued, [true, true, true]);
+        assert!(!fq_codel.enqueue(packet(1, 0), now));
+        let order: Vec<u16> = std::iter::from_fn(|| fq_codel.dequeue(now))
+            .map(|packet| source_port(&packet))
+            .collect();
+        assert_eq!(order, [1, 2]);
+        assert_eq!(fq_codel.classes()[0].1.drops.get(), 2);
+    }
+}
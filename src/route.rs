@@ -0,0 +1,398 @@
+use std::{cmp::Reverse, net::IpAddr, str::FromStr};
+
+use serde::Deserialize;
+
+/// An address assigned to an interface together with the prefix length of its subnet
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct InterfaceAddress {
+    pub addr: IpAddr,
+    pub prefix_length: u8,
+}
+impl InterfaceAddress {
+    pub fn new(addr: IpAddr, prefix_length: u8) -> Self {
+        Self {
+            addr,
+            prefix_length,
+        }
+    }
+}
+/// Parses CIDR notation like 192.168.0.2/24 or fd00::2/64
+impl FromStr for InterfaceAddress {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_length) = s
+            .split_once('/')
+            .ok_or_else(|| format!("{} is missing a /prefix length", s))?;
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{} is not an IP address", addr))?;
+        let max_prefix_length = if addr.is_ipv4() { 32 } else { 128 };
+        match prefix_length.parse() {
+            Ok(prefix_length) if prefix_length <= max_prefix_length => {
+                Ok(Self::new(addr, prefix_length))
+            }
+            _ => Err(format!("{} is not a valid prefix length", prefix_length)),
+        }
+    }
+}
+impl TryFrom<String> for InterfaceAddress {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    pub mtu: usize,
+    pub addresses: Vec<InterfaceAddress>,
+}
+impl Interface {
+    pub fn new(name: &str, mtu: usize, addresses: Vec<InterfaceAddress>) -> Self {
+        Self {
+            name: name.to_string(),
+            mtu,
+            addresses,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteKind {
+    /// Subnet of an address assigned to an interface, reachable without a gateway
+    Connected,
+    Static,
+    Default,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub destination: IpAddr,
+    pub prefix_length: u8,
+    /// None when the destination is directly reachable on the interface
+    pub gateway: Option<IpAddr>,
+    pub interface: String,
+    /// Lower is preferred between routes with the same prefix length
+    pub metric: u32,
+    pub kind: RouteKind,
+}
+
+/// Result of a route lookup, where and through whom to send the packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct NextHop<'a> {
+    pub interface: &'a Interface,
+    /// The gateway, or the destination itself if it is on a connected subnet
+    pub addr: IpAddr,
+    /// Address of the outgoing interface to use as the source of locally generated packets
+    pub source_addr: Option<IpAddr>,
+}
+
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    interfaces: Vec<Interface>,
+    routes: Vec<Route>,
+}
+impl RouteTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn interfaces(&self) -> &[Interface] {
+        &self.interfaces
+    }
+    pub fn interface(&self, name: &str) -> Option<&Interface> {
+        self.interfaces
+            .iter()
+            .find(|interface| interface.name == name)
+    }
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+    /// Adds the interface along with a connected route for each of its addresses
+    pub fn add_interface(&mut self, interface: Interface) {
+        for address in &interface.addresses {
+            self.routes.push(Route {
+                destination: mask(&address.addr, address.prefix_length),
+                prefix_length: address.prefix_length,
+                gateway: None,
+                interface: interface.name.clone(),
+                metric: 0,
+                kind: RouteKind::Connected,
+            });
+        }
+        self.interfaces.push(interface);
+    }
+    /// Assigns another address to an existing interface, returning false if there is no such interface
+    pub fn add_address(&mut self, interface: &str, address: InterfaceAddress) -> bool {
+        let Some(existing) = self
+            .interfaces
+            .iter_mut()
+            .find(|existing| existing.name == interface)
+        else {
+            return false;
+        };
+        existing.addresses.push(address);
+        self.routes.push(Route {
+            destination: mask(&address.addr, address.prefix_length),
+            prefix_length: address.prefix_length,
+            gateway: None,
+            interface: interface.to_string(),
+            metric: 0,
+            kind: RouteKind::Connected,
+        });
+        true
+    }
+    pub fn add_static_route(
+        &mut self,
+        destination: IpAddr,
+        prefix_length: u8,
+        gateway: Option<IpAddr>,
+        interface: &str,
+        metric: u32,
+    ) {
+        self.routes.push(Route {
+            destination: mask(&destination, prefix_length),
+            prefix_length,
+            gateway,
+            interface: interface.to_string(),
+            metric,
+            kind: RouteKind::Static,
+        });
+    }
+    pub fn add_default_route(&mut self, gateway: IpAddr, interface: &str, metric: u32) {
+        let destination = match gateway {
+            IpAddr::V4(_) => IpAddr::V4(0.into()),
+            IpAddr::V6(_) => IpAddr::V6(0.into()),
+        };
+        self.routes.push(Route {
+            destination,
+            prefix_length: 0,
+            gateway: Some(gateway),
+            interface: interface.to_string(),
+            metric,
+            kind: RouteKind::Default,
+        });
+    }
+    /// Removes all routes to the prefix, returning how many were removed
+    pub fn remove_route(&mut self, destination: IpAddr, prefix_length: u8) -> usize {
+        let destination = mask(&destination, prefix_length);
+        let before = self.routes.len();
+        self.routes.retain(|route| {
+            route.destination != destination || route.prefix_length != prefix_length
+        });
+        before - self.routes.len()
+    }
+    /// Longest prefix match, ties are broken by the lowest metric
+    pub fn lookup(&self, destination: &IpAddr) -> Option<NextHop<'_>> {
+        let route = self
+            .routes
+            .iter()
+            .filter(|route| {
+                route.destination.is_ipv4() == destination.is_ipv4()
+                    && mask(destination, route.prefix_length) == route.destination
+            })
+            .min_by_key(|route| (u8::MAX - route.prefix_length, route.metric))?;
+        let interface = self.interface(&route.interface)?;
+        let source_addr = source_addr(&interface.addresses, destination);
+        Some(NextHop {
+            interface,
+            addr: route.gateway.unwrap_or(*destination),
+            source_addr,
+        })
+    }
+    /// Whether the address is assigned to one of our interfaces
+    pub fn is_local(&self, addr: &IpAddr) -> bool {
+        self.interfaces.iter().any(|interface| {
+            interface
+                .addresses
+                .iter()
+                .any(|address| address.addr == *addr)
+        })
+    }
+}
+
+/// Picks the address of the same family whose scope fits the destination best, then the one
+/// sharing the longest prefix with it (RFC 6724 5 rules 2 and 8)
+fn source_addr(addresses: &[InterfaceAddress], destination: &IpAddr) -> Option<IpAddr> {
+    let destination_scope = scope(destination);
+    addresses
+        .iter()
+        .map(|address| address.addr)
+        .filter(|addr| addr.is_ipv4() == destination.is_ipv4())
+        .min_by_key(|addr| {
+            // A scope smaller than the destination's cannot reach it, so the largest of those
+            // comes last, otherwise the smallest scope that still reaches it is preferred
+            let scope = scope(addr);
+            let rank = match scope < destination_scope {
+                true => (true, u8::MAX - scope),
+                false => (false, scope),
+            };
+            (rank, Reverse(common_prefix_length(addr, destination)))
+        })
+}
+
+/// Scope of an address, using the multicast scope values for unicast too (RFC 6724 3.1)
+fn scope(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(addr) if addr.is_loopback() || addr.is_link_local() => 0x2,
+        IpAddr::V4(_) => 0xE,
+        IpAddr::V6(addr) if addr.is_multicast() => addr.octets()[1] & 0x0F,
+        IpAddr::V6(addr) if addr.is_loopback() || addr.is_unicast_link_local() => 0x2,
+        IpAddr::V6(_) => 0xE,
+    }
+}
+
+/// Number of leading bits the two addresses have in common
+fn common_prefix_length(a: &IpAddr, b: &IpAddr) -> u32 {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (a.to_bits() ^ b.to_bits()).leading_zeros(),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (a.to_bits() ^ b.to_bits()).leading_zeros(),
+        _ => 0,
+    }
+}
+
+/// Zeroes the host bits of the address
+pub fn mask(addr: &IpAddr, prefix_length: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix_length.min(32) as u32)
+                .unwrap_or(0);
+            IpAddr::V4((addr.to_bits() & mask).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix_length.min(128) as u32)
+                .unwrap_or(0);
+            IpAddr::V6((addr.to_bits() & mask).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route_table() -> RouteTable {
+        let mut route_table = RouteTable::new();
+        route_table.add_interface(Interface::new(
+            "tun0",
+            1500,
+            vec![
+                InterfaceAddress::new("192.168.0.2".parse().unwrap(), 24),
+                InterfaceAddress::new("fd00::2".parse().unwrap(), 64),
+            ],
+        ));
+        route_table.add_interface(Interface::new(
+            "tun1",
+            1400,
+            vec![InterfaceAddress::new("10.0.0.1".parse().unwrap(), 8)],
+        ));
+        route_table.add_default_route("192.168.0.1".parse().unwrap(), "tun0", 100);
+        route_table
+    }
+
+    #[test]
+    fn mask_prefix() {
+        assert_eq!(
+            mask(&"192.168.23.45".parse().unwrap(), 20),
+            "192.168.16.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            mask(&"10.1.1.1".parse().unwrap(), 0),
+            "0.0.0.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            mask(&"fd00::1:2:3".parse().unwrap(), 96),
+            "fd00::1:0:0".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn connected_and_default_routes() {
+        let route_table = route_table();
+        let next_hop = route_table.lookup(&"10.2.3.4".parse().unwrap()).unwrap();
+        assert_eq!(next_hop.interface.name, "tun1");
+        assert_eq!(next_hop.addr, "10.2.3.4".parse::<IpAddr>().unwrap());
+        assert_eq!(next_hop.source_addr, Some("10.0.0.1".parse().unwrap()));
+
+        let next_hop = route_table.lookup(&"8.8.8.8".parse().unwrap()).unwrap();
+        assert_eq!(next_hop.interface.name, "tun0");
+        assert_eq!(next_hop.addr, "192.168.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(next_hop.source_addr, Some("192.168.0.2".parse().unwrap()));
+
+        // No default route for IPv6
+        let next_hop = route_table.lookup(&"fd00::9".parse().unwrap()).unwrap();
+        assert_eq!(next_hop.source_addr, Some("fd00::2".parse().unwrap()));
+        assert!(route_table
+            .lookup(&"2001:db8::1".parse().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn longest_prefix_then_metric() {
+        let mut route_table = route_table();
+        route_table.add_static_route(
+            "10.1.0.0".parse().unwrap(),
+            16,
+            Some("192.168.0.3".parse().unwrap()),
+            "tun0",
+            10,
+        );
+        route_table.add_static_route(
+            "10.1.0.0".parse().unwrap(),
+            16,
+            Some("10.0.0.254".parse().unwrap()),
+            "tun1",
+            5,
+        );
+        let next_hop = route_table.lookup(&"10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(next_hop.interface.name, "tun1");
+        assert_eq!(next_hop.addr, "10.0.0.254".parse::<IpAddr>().unwrap());
+
+        assert_eq!(
+            route_table.remove_route("10.1.99.0".parse().unwrap(), 16),
+            2
+        );
+        let next_hop = route_table.lookup(&"10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(next_hop.addr, "10.1.2.3".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn source_by_scope_and_prefix() {
+        let mut route_table = RouteTable::new();
+        route_table.add_interface(Interface::new(
+            "tun0",
+            1500,
+            vec![
+                InterfaceAddress::new("fe80::2".parse().unwrap(), 64),
+                InterfaceAddress::new("fd00::2".parse().unwrap(), 64),
+                InterfaceAddress::new("2001:db8:1::2".parse().unwrap(), 64),
+            ],
+        ));
+        route_table.add_default_route("fe80::1".parse().unwrap(), "tun0", 100);
+        let source_addr = |destination: &str| {
+            route_table
+                .lookup(&destination.parse().unwrap())
+                .unwrap()
+                .source_addr
+                .unwrap()
+        };
+        // The link-local address only answers link-local destinations
+        assert_eq!(source_addr("fe80::9"), "fe80::2".parse::<IpAddr>().unwrap());
+        assert_eq!(source_addr("fd00::9"), "fd00::2".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            source_addr("2001:db8:2::9"),
+            "2001:db8:1::2".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn local_addresses() {
+        let route_table = route_table();
+        assert!(route_table.is_local(&"192.168.0.2".parse().unwrap()));
+        assert!(route_table.is_local(&"fd00::2".parse().unwrap()));
+        assert!(!route_table.is_local(&"192.168.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,666 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    os::fd::RawFd,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
+
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, oneshot, Notify},
+    time,
+};
+
+use crate::{
+    connection::{ConnectionKey, ConnectionTable, TCPState},
+    device::{NetDevice, PACKET_INFO_LEN},
+    event::BUDGET,
+    forward::{DropReason, Verdict},
+    protocol::IPBody,
+    server::{Binding, Request, Service},
+    stack::Stack,
+};
+
+/// Datagrams queued on a UDP socket before further ones are dropped
+pub const UDP_QUEUE: usize = 256;
+
+/// What the handles ask of the driver
+enum Command {
+    Close(ConnectionKey),
+    Bind(Binding, Box<dyn Service>, oneshot::Sender<io::Result<()>>),
+    Unbind(Binding),
+    SendTo {
+        local: SocketAddr,
+        remote: SocketAddr,
+        data: Vec<u8>,
+        reply: oneshot::Sender<io::Result<usize>>,
+    },
+}
+
+/// Runs the stack on tokio, the async counterpart of `EventLoop`
+/// Has to be created inside a runtime with IO and time enabled
+pub struct Driver {
+    stack: Stack,
+    devices: Vec<Box<dyn NetDevice + Send>>,
+    /// Readiness of the descriptor of each device
+    readiness: Vec<AsyncFd<RawFd>>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    /// Data was written to a connection
+    written: Arc<Notify>,
+    buf: Vec<u8>,
+}
+impl Driver {
+    /// Devices have to be non blocking and backed by a descriptor
+    pub fn new(
+        stack: Stack,
+        devices: Vec<Box<dyn NetDevice + Send>>,
+    ) -> io::Result<(Self, Handle)> {
+        let readiness = devices
+            .iter()
+            .map(|device| {
+                let fd = device.raw_fd().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} has no descriptor to wait on", device.name()),
+                    )
+                })?;
+                AsyncFd::new(fd)
+            })
+            .collect::<io::Result<_>>()?;
+        let mtu = devices.iter().map(|device| device.mtu()).max().unwrap_or(0);
+        let (sender, commands) = mpsc::unbounded_channel();
+        let handle = Handle {
+            connections: stack.connections.clone(),
+            commands: sender,
+            written: Arc::new(Notify::new()),
+        };
+        let driver = Self {
+            stack,
+            devices,
+            readiness,
+            commands,
+            written: handle.written.clone(),
+            buf: vec![0u8; mtu + PACKET_INFO_LEN],
+        };
+        Ok((driver, handle))
+    }
+    /// Receives from the devices, fires the timers and carries out what the handles ask for,
+    /// until a device fails
+    pub async fn run(mut self) -> io::Result<()> {
+        let mut handles_open = true;
+        loop {
+            let deadline = self.stack.next_deadline();
+            let sleep = time::sleep_until(deadline.unwrap_or_else(Instant::now).into());
+            tokio::select! {
+                ready = poll_fn(|cx| poll_readable(&self.readiness, cx)) => {
+                    self.drain(ready?).await?;
+                }
+                command = self.commands.recv(), if handles_open => match command {
+                    Some(command) => self.command(command)?,
+                    None => handles_open = false,
+                },
+                _ = self.written.notified() => {}
+                _ = sleep, if deadline.is_some() => {}
+            }
+            let now = Instant::now();
+            let verdicts = self.stack.poll(now);
+            self.stack.transmit(&mut self.devices, verdicts, now)?;
+        }
+    }
+    /// Receives what the device at `index` has waiting, up to the budget
+    async fn drain(&mut self, index: usize) -> io::Result<()> {
+        for _ in 0..BUDGET {
+            let len = match self.devices[index].recv(&mut self.buf) {
+                Ok(len) => len,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    self.readiness[index].readable().await?.clear_ready();
+                    return Ok(());
+                }
+                Err(error) => return Err(error),
+            };
+            if len <= PACKET_INFO_LEN {
+                continue;
+            }
+            let now = Instant::now();
+            let verdicts = self.stack.receive(
+                self.devices[index].name(),
+                &self.buf[PACKET_INFO_LEN..len],
+                now,
+            );
+            self.stack.transmit(&mut self.devices, verdicts, now)?;
+        }
+        Ok(())
+    }
+    fn command(&mut self, command: Command) -> io::Result<()> {
+        let now = Instant::now();
+        match command {
+            Command::Close(key) => {
+                let verdicts = self.stack.close(key, now);
+                self.stack.transmit(&mut self.devices, verdicts, now)?;
+            }
+            Command::Bind(binding, service, reply) => {
+                let result = match self.stack.services.is_registered(binding) {
+                    true => Err(io::Error::from(io::ErrorKind::AddrInUse)),
+                    false => {
+                        self.stack.services.register(binding, service);
+                        Ok(())
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            Command::Unbind(binding) => {
+                self.stack.services.unregister(binding);
+            }
+            Command::SendTo {
+                local,
+                remote,
+                data,
+                reply,
+            } => {
+                let len = data.len();
+                let result = match self.stack.send_udp(local, remote, data, now) {
+                    Verdict::Drop(DropReason::NoRoute) => {
+                        Err(io::Error::from(io::ErrorKind::NetworkUnreachable))
+                    }
+                    verdict => self
+                        .stack
+                        .transmit(&mut self.devices, vec![verdict], now)
+                        .map(|()| len),
+                };
+                let _ = reply.send(result);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Index of a device which polls readable
+fn poll_readable(readiness: &[AsyncFd<RawFd>], cx: &mut Context) -> Poll<io::Result<usize>> {
+    for (index, fd) in readiness.iter().enumerate() {
+        if let Poll::Ready(guard) = fd.poll_read_ready(cx) {
+            // Readiness is kept until a receive would block
+            return Poll::Ready(guard.map(|_| index));
+        }
+    }
+    Poll::Pending
+}
+
+/// Lets tasks use the stack the driver runs
+#[derive(Clone)]
+pub struct Handle {
+    connections: Arc<Mutex<ConnectionTable>>,
+    commands: mpsc::UnboundedSender<Command>,
+    written: Arc<Notify>,
+}
+impl Handle {
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "The driver stopped"))
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, ConnectionTable> {
+        self.connections
+            .lock()
+            .expect("Connection table lock is not poisoned")
+    }
+}
+
+/// Accepts TCP connections to a local address
+pub struct AsyncTcpListener {
+    local: SocketAddr,
+    handle: Handle,
+}
+impl AsyncTcpListener {
+    /// `local` may have an unspecified address to listen on all of ours
+    pub fn bind(handle: &Handle, local: SocketAddr) -> io::Result<Self> {
+        let mut connections = handle.lock();
+        if connections
+            .listeners()
+            .iter()
+            .any(|listener| listener.local == local)
+        {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+        let backlog = connections.config().backlog;
+        connections.listen(local, backlog);
+        Ok(Self {
+            local,
+            handle: handle.clone(),
+        })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+    pub fn poll_accept(&self, cx: &mut Context) -> Poll<io::Result<(AsyncTcpStream, SocketAddr)>> {
+        let mut connections = self.handle.lock();
+        match connections.accept(self.local) {
+            Some(key) => {
+                let stream = AsyncTcpStream {
+                    key,
+                    handle: self.handle.clone(),
+                    shutdown: false,
+                };
+                Poll::Ready(Ok((stream, key.1)))
+            }
+            None => {
+                connections.wake_on_accept(self.local, cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+    /// Waits for an established connection, returning it and the address of the peer
+    pub async fn accept(&self) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+impl Drop for AsyncTcpListener {
+    fn drop(&mut self) {
+        let orphans = self.handle.lock().unlisten(self.local);
+        for key in orphans {
+            let _ = self.handle.send(Command::Close(key));
+        }
+    }
+}
+
+/// An accepted TCP connection, closed with a FIN once shut down or dropped
+pub struct AsyncTcpStream {
+    key: ConnectionKey,
+    handle: Handle,
+    shutdown: bool,
+}
+impl AsyncTcpStream {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.key.0
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.key.1
+    }
+}
+impl AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let mut connections = self.handle.lock();
+        // A connection which went away reads as the end of the stream
+        let Some(tcb) = connections.get(self.key) else {
+            return Poll::Ready(Ok(()));
+        };
+        if tcb.receive_queue.is_empty() {
+            if tcb.fin_received() {
+                return Poll::Ready(Ok(()));
+            }
+            connections.wake_on_change(self.key, cx.waker());
+            return Poll::Pending;
+        }
+        let len = connections.read_into(self.key, buf.initialize_unfilled());
+        buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+impl AsyncWrite for AsyncTcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut connections = self.handle.lock();
+        let writable = connections
+            .get(self.key)
+            .is_some_and(|tcb| matches!(tcb.state, TCPState::Established | TCPState::CloseWait));
+        if !writable || self.shutdown {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        let len = connections.write(self.key, buf);
+        if len == 0 && !buf.is_empty() {
+            // The send buffer is full until the peer acknowledges some of it
+            connections.wake_on_change(self.key, cx.waker());
+            return Poll::Pending;
+        }
+        self.handle.written.notify_one();
+        Poll::Ready(Ok(len))
+    }
+    /// Written data is already with the stack
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    /// Sends our FIN after the data written so far
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        if !self.shutdown {
+            self.shutdown = true;
+            self.handle.send(Command::Close(self.key))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+impl Drop for AsyncTcpStream {
+    fn drop(&mut self) {
+        if !self.shutdown {
+            let _ = self.handle.send(Command::Close(self.key));
+        }
+    }
+}
+
+/// Datagrams received on a UDP socket
+#[derive(Default)]
+struct Inbox {
+    datagrams: VecDeque<(SocketAddr, Vec<u8>)>,
+    wakers: Vec<Waker>,
+}
+
+/// Queues the datagrams for a socket
+struct UDPSocketService {
+    local: SocketAddr,
+    inbox: Arc<Mutex<Inbox>>,
+}
+impl Service for UDPSocketService {
+    fn handle(&mut self, request: &Request) -> Option<IPBody> {
+        let IPBody::UDP(udp) = request.body else {
+            return None;
+        };
+        if !self.local.ip().is_unspecified() && self.local.ip() != request.destination_addr {
+            return None;
+        }
+        let mut inbox = self.inbox.lock().expect("Inbox lock is not poisoned");
+        if inbox.datagrams.len() < UDP_QUEUE {
+            let source = SocketAddr::new(request.source_addr, udp.source_port);
+            inbox.datagrams.push_back((source, udp.data.clone()));
+            inbox.wakers.drain(..).for_each(Waker::wake);
+        }
+        None
+    }
+}
+
+/// Sends and receives UDP datagrams on a local port
+pub struct AsyncUdpSocket {
+    local: SocketAddr,
+    handle: Handle,
+    inbox: Arc<Mutex<Inbox>>,
+}
+impl AsyncUdpSocket {
+    /// `local` may have an unspecified address to receive on all of ours, port 0 is not allowed
+    /// as it would take every port nothing else is bound to
+    pub async fn bind(handle: &Handle, local: SocketAddr) -> io::Result<Self> {
+        if local.port() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "UDP sockets need a port",
+            ));
+        }
+        let inbox = Arc::new(Mutex::new(Inbox::default()));
+        let service = UDPSocketService {
+            local,
+            inbox: inbox.clone(),
+        };
+        let (reply, result) = oneshot::channel();
+        handle.send(Command::Bind(
+            Binding::UDP(local.port()),
+            Box::new(service),
+            reply,
+        ))?;
+        result
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "The driver stopped"))??;
+        Ok(Self {
+            local,
+            handle: handle.clone(),
+            inbox,
+        })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+    /// Takes the oldest datagram, truncated to the space in `buf`, returning where it came from
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<SocketAddr>> {
+        let mut inbox = self.inbox.lock().expect("Inbox lock is not poisoned");
+        let Some((source, data)) = inbox.datagrams.pop_front() else {
+            if !inbox.wakers.iter().any(|other| other.will_wake(cx.waker())) {
+                inbox.wakers.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        };
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        Poll::Ready(Ok(source))
+    }
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut buf = ReadBuf::new(buf);
+        let source = poll_fn(|cx| self.poll_recv_from(cx, &mut buf)).await?;
+        Ok((buf.filled().len(), source))
+    }
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let (reply, result) = oneshot::channel();
+        self.handle.send(Command::SendTo {
+            local: self.local,
+            remote: target,
+            data: buf.to_vec(),
+            reply,
+        })?;
+        result
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "The driver stopped"))?
+    }
+}
+impl Drop for AsyncUdpSocket {
+    fn drop(&mut self) {
+        let _ = self
+            .handle
+            .send(Command::Unbind(Binding::UDP(self.local.port())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        os::{fd::AsRawFd, unix::net::UnixDatagram as StdUnixDatagram},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixDatagram,
+    };
+
+    use super::*;
+    use crate::{
+        connection::{ConnectionTable, TCPConfig},
+        device::{frame_ip_packet, DEFAULT_MTU},
+        forward::Router,
+        ip::IPHeader,
+        protocol::{TCPControlBits, UDP},
+        route::{Interface, InterfaceAddress, RouteTable},
+        test_util::{tcp_packet, CLIENT, SERVER},
+        IPPacket,
+    };
+
+    /// One end of a datagram socket pair standing in for a TUN device
+    struct SocketDevice {
+        socket: StdUnixDatagram,
+    }
+    impl NetDevice for SocketDevice {
+        fn name(&self) -> &str {
+            "tun0"
+        }
+        fn mtu(&self) -> usize {
+            DEFAULT_MTU
+        }
+        fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.socket.recv(buf)
+        }
+        fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+            self.socket.send(frame)
+        }
+        fn raw_fd(&self) -> Option<RawFd> {
+            Some(self.socket.as_raw_fd())
+        }
+    }
+
+    fn packet(ip_body: IPBody) -> Vec<u8> {
+        let ip_header = IPHeader::from_body(
+            4,
+            0,
+            0,
+            0,
+            0,
+            64,
+            ip_body.protocol(),
+            "192.168.0.1".parse().unwrap(),
+            "192.168.0.2".parse().unwrap(),
+            None,
+            ip_body.len() as u16,
+        );
+        frame_ip_packet(&IPPacket::new(ip_header, ip_body).to_byte_buffer())
+    }
+
+    fn segment(
+        sequence_number: u32,
+        ack: u32,
+        control_bits: TCPControlBits,
+        data: &[u8],
+    ) -> Vec<u8> {
+        frame_ip_packet(&tcp_packet(
+            CLIENT,
+            SERVER,
+            sequence_number,
+            ack,
+            control_bits,
+            data,
+        ))
+    }
+
+    async fn receive(peer: &UnixDatagram) -> IPBody {
+        let mut buf = [0u8; DEFAULT_MTU + PACKET_INFO_LEN];
+        let len = peer.recv(&mut buf).await.unwrap();
+        IPPacket::from_byte_buffer(&buf[PACKET_INFO_LEN..len])
+            .unwrap()
+            .body
+    }
+
+    #[tokio::test]
+    async fn streams_and_sockets() {
+        let mut route_table = RouteTable::new();
+        route_table.add_interface(Interface::new(
+            "tun0",
+            DEFAULT_MTU,
+            vec![InterfaceAddress::new("192.168.0.2".parse().unwrap(), 24)],
+        ));
+        let connections = ConnectionTable::with_config(TCPConfig::default());
+        let stack = Stack::with_connections(Router::new(route_table, false), connections);
+        let (socket, peer) = StdUnixDatagram::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        peer.set_nonblocking(true).unwrap();
+        let peer = UnixDatagram::from_std(peer).unwrap();
+        let device: Box<dyn NetDevice + Send> = Box::new(SocketDevice { socket });
+        let (driver, handle) = Driver::new(stack, vec![device]).unwrap();
+        tokio::spawn(driver.run());
+
+        let listener = AsyncTcpListener::bind(&handle, "0.0.0.0:80".parse().unwrap()).unwrap();
+        assert_eq!(
+            AsyncTcpListener::bind(&handle, "0.0.0.0:80".parse().unwrap())
+                .err()
+                .map(|error| error.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+        let accept = tokio::spawn(async move { listener.accept().await });
+        peer.send(&segment(1000, 0, TCPControlBits::SYN, &[]))
+            .await
+            .unwrap();
+        let IPBody::TCP(syn_ack) = receive(&peer).await else {
+            panic!("SYN not answered");
+        };
+        assert_eq!(
+            syn_ack.control_bits,
+            TCPControlBits::SYN | TCPControlBits::ACK
+        );
+        let iss = syn_ack.sequence_number;
+        peer.send(&segment(
+            1001,
+            iss.wrapping_add(1),
+            TCPControlBits::PSH | TCPControlBits::ACK,
+            b"hello",
+        ))
+        .await
+        .unwrap();
+        let (mut stream, remote) = accept.await.unwrap().unwrap();
+        assert_eq!(remote, "192.168.0.1:48458".parse().unwrap());
+        let mut buf = [0u8; 16];
+        let len = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        stream.write_all(b"world").await.unwrap();
+        let IPBody::TCP(data) = receive(&peer).await else {
+            panic!("No data sent");
+        };
+        assert_eq!(
+            (data.control_bits, data.data.as_slice()),
+            (TCPControlBits::PSH | TCPControlBits::ACK, &b"world"[..])
+        );
+        // Wakes the pending read
+        let read = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.map(|_| buf)
+        });
+        peer.send(&segment(
+            1006,
+            iss.wrapping_add(6),
+            TCPControlBits::PSH | TCPControlBits::ACK,
+            b"bye",
+        ))
+        .await
+        .unwrap();
+        peer.send(&segment(
+            1009,
+            iss.wrapping_add(6),
+            TCPControlBits::FIN | TCPControlBits::ACK,
+            &[],
+        ))
+        .await
+        .unwrap();
+        assert_eq!(read.await.unwrap().unwrap(), b"bye");
+
+        let socket = AsyncUdpSocket::bind(&handle, "0.0.0.0:5353".parse().unwrap())
+            .await
+            .unwrap();
+        let (source_addr, destination_addr): (IpAddr, IpAddr) = (
+            "192.168.0.1".parse().unwrap(),
+            "192.168.0.2".parse().unwrap(),
+        );
+        let datagram = UDP::new(
+            &source_addr,
+            &destination_addr,
+            40000,
+            5353,
+            b"ping".to_vec(),
+        )
+        .unwrap();
+        peer.send(&packet(IPBody::UDP(datagram))).await.unwrap();
+        let (len, source) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            (&buf[..len], source),
+            (&b"ping"[..], "192.168.0.1:40000".parse().unwrap())
+        );
+        assert_eq!(socket.send_to(b"pong", source).await.unwrap(), 4);
+        // After the ACKs and the FIN of the connection
+        let reply = loop {
+            if let IPBody::UDP(udp) = receive(&peer).await {
+                break udp;
+            }
+        };
+        assert_eq!(reply.source_port, 5353);
+        assert_eq!(reply.data, b"pong");
+        assert_eq!(
+            socket
+                .send_to(b"lost", "10.0.0.1:53".parse().unwrap())
+                .await
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NetworkUnreachable
+        );
+    }
+}
@@ -1 +1,250 @@
-pub struct ICMPServer{}
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    connection::ConnectionTable,
+    ip::ECN,
+    protocol::{ICMPBody, ICMPv6, ICMPv6Body, IPBody, ICMP, UDP},
+};
+
+/// UDP echo port (RFC 862)
+pub const ECHO_PORT: u16 = 7;
+
+/// What a service is registered for, port 0 takes every port nothing else is registered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Binding {
+    ICMP,
+    ICMPv6,
+    TCP(u16),
+    UDP(u16),
+}
+impl Binding {
+    /// The binding a packet addressed to us is looked up by
+    pub fn of(ip_body: &IPBody) -> Self {
+        match ip_body {
+            IPBody::ICMP(_) => Self::ICMP,
+            IPBody::ICMPv6(_) => Self::ICMPv6,
+            IPBody::TCP(tcp) => Self::TCP(tcp.destination_port),
+            IPBody::UDP(udp) => Self::UDP(udp.destination_port),
+        }
+    }
+    fn any_port(self) -> Option<Self> {
+        match self {
+            Self::TCP(port) if port != 0 => Some(Self::TCP(0)),
+            Self::UDP(port) if port != 0 => Some(Self::UDP(0)),
+            _ => None,
+        }
+    }
+}
+
+/// A packet addressed to us
+pub struct Request<'a> {
+    pub source_addr: IpAddr,
+    pub destination_addr: IpAddr,
+    /// ECN codepoint of the packet
+    pub ecn: ECN,
+    pub body: &'a IPBody,
+    /// MTU of the interface the packet came in through
+    pub mtu: usize,
+    pub now: Instant,
+}
+
+/// Something answering packets addressed to us
+pub trait Service: Send {
+    /// The body of the reply, sent back to where the request came from
+    fn handle(&mut self, request: &Request) -> Option<IPBody>;
+}
+
+/// Hands packets addressed to us to the service registered for them
+#[derive(Default)]
+pub struct Services {
+    services: HashMap<Binding, Box<dyn Service>>,
+}
+impl Services {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Replaces whatever was registered for the binding
+    pub fn register(&mut self, binding: Binding, service: Box<dyn Service>) {
+        self.services.insert(binding, service);
+    }
+    pub fn unregister(&mut self, binding: Binding) -> Option<Box<dyn Service>> {
+        self.services.remove(&binding)
+    }
+    pub fn is_registered(&self, binding: Binding) -> bool {
+        self.services.contains_key(&binding)
+    }
+    /// Whether a service takes the packet
+    pub fn is_bound(&self, ip_body: &IPBody) -> bool {
+        let binding = Binding::of(ip_body);
+        self.services.contains_key(&binding)
+            || binding
+                .any_port()
+                .is_some_and(|binding| self.services.contains_key(&binding))
+    }
+    pub fn dispatch(&mut self, request: &Request) -> Option<IPBody> {
+        let binding = Binding::of(request.body);
+        let binding = match binding.any_port() {
+            Some(any_port) if !self.services.contains_key(&binding) => any_port,
+            _ => binding,
+        };
+        self.services.get_mut(&binding)?.handle(request)
+    }
+}
+
+/// Answers ICMP and ICMPv6 echo requests (RFC 792, RFC 4443 4.1)
+pub struct ICMPServer {}
+impl Service for ICMPServer {
+    fn handle(&mut self, request: &Request) -> Option<IPBody> {
+        match (request.body, request.source_addr, request.destination_addr) {
+            (IPBody::ICMP(icmp), _, _) => match &icmp.body {
+                ICMPBody::Echo { .. } => {
+                    Some(IPBody::ICMP(ICMP::new(0x0, icmp.code, icmp.body.clone())))
+                }
+                _ => None,
+            },
+            (IPBody::ICMPv6(icmp), IpAddr::V6(source_addr), IpAddr::V6(destination_addr)) => {
+                match &icmp.body {
+                    ICMPv6Body::EchoRequest {
+                        identifier,
+                        sequence_number,
+                        data,
+                    } => Some(IPBody::ICMPv6(ICMPv6::new(
+                        &destination_addr,
+                        &source_addr,
+                        icmp.code,
+                        ICMPv6Body::EchoReply {
+                            identifier: *identifier,
+                            sequence_number: *sequence_number,
+                            data: data.clone(),
+                        },
+                    ))),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Hands segments to the connection table, which answers for the listeners and connections
+pub struct TCPServer {
+    pub connections: Arc<Mutex<ConnectionTable>>,
+}
+impl Service for TCPServer {
+    fn handle(&mut self, request: &Request) -> Option<IPBody> {
+        let IPBody::TCP(tcp) = request.body else {
+            return None;
+        };
+        self.connections
+            .lock()
+            .expect("Connection table lock is not poisoned")
+            .segment(
+                request.source_addr,
+                request.destination_addr,
+                tcp,
+                request.ecn,
+                request.mtu,
+                request.now,
+            )
+            .map(IPBody::TCP)
+    }
+}
+
+/// Sends every datagram back (RFC 862)
+pub struct UDPEchoServer {}
+impl Service for UDPEchoServer {
+    fn handle(&mut self, request: &Request) -> Option<IPBody> {
+        let IPBody::UDP(udp) = request.body else {
+            return None;
+        };
+        UDP::new(
+            &request.destination_addr,
+            &request.source_addr,
+            udp.destination_port,
+            udp.source_port,
+            udp.data.clone(),
+        )
+        .ok()
+        .map(IPBody::UDP)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEFAULT_MTU;
+
+    /// Takes packets without answering them
+    struct Sink;
+    impl Service for Sink {
+        fn handle(&mut self, _request: &Request) -> Option<IPBody> {
+            None
+        }
+    }
+
+    fn udp(destination_port: u16) -> IPBody {
+        IPBody::UDP(
+            UDP::new(
+                &"192.168.0.1".parse().unwrap(),
+                &"192.168.0.2".parse().unwrap(),
+                40000,
+                destination_port,
+                b"hello".to_vec(),
+            )
+            .unwrap(),
+        )
+    }
+
+    fn request(body: &IPBody) -> Request<'_> {
+        Request {
+            source_addr: "192.168.0.1".parse().unwrap(),
+            destination_addr: "192.168.0.2".parse().unwrap(),
+            ecn: ECN::NotECT,
+            body,
+            mtu: DEFAULT_MTU,
+            now: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut services = Services::new();
+        services.register(Binding::ICMP, Box::new(ICMPServer {}));
+        services.register(Binding::UDP(ECHO_PORT), Box::new(UDPEchoServer {}));
+
+        let ping = IPBody::ICMP(ICMP::new(
+            0x8,
+            0,
+            ICMPBody::Echo {
+                identifier: 1,
+                sequence_number: 2,
+                data: vec![0xab; 8],
+            },
+        ));
+        let Some(IPBody::ICMP(reply)) = services.dispatch(&request(&ping)) else {
+            panic!("Echo request not answered");
+        };
+        assert_eq!(reply._type, 0x0);
+
+        let echo = udp(ECHO_PORT);
+        let Some(IPBody::UDP(reply)) = services.dispatch(&request(&echo)) else {
+            panic!("UDP echo not answered");
+        };
+        assert_eq!((reply.source_port, reply.destination_port), (7, 40000));
+        assert_eq!(reply.data, b"hello");
+
+        // Nothing on port 9 until a service takes every port
+        let discard = udp(9);
+        assert!(!services.is_bound(&discard));
+        assert!(services.dispatch(&request(&discard)).is_none());
+        services.register(Binding::UDP(0), Box::new(Sink));
+        assert!(services.is_bound(&discard));
+        assert!(services.dispatch(&request(&discard)).is_none());
+        assert!(services.dispatch(&request(&echo)).is_some());
+    }
+}
@@ -0,0 +1,774 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, IoSlice},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tracing::{debug, info, info_span, trace, warn, Span};
+
+use crate::{
+    buffer::PacketBuffer,
+    connection::{ConnectionKey, ConnectionTable},
+    device::{packet_info, NetDevice, DEFAULT_MTU, PACKET_INFO_LEN},
+    filter::PacketInfo,
+    forward::{DropReason, Router, Verdict},
+    ip::{IPHeader, IPPacketErrorKind, ECN, FLAG_DONT_FRAGMENT},
+    ipv6::{IPv6Header, Reassembler},
+    protocol::{
+        icmpv6::{self, ndp::NeighborDiscovery},
+        protocol_name, tcp, udp, ICMPv6, ICMPv6Body, IPBody, TCPControlBits, TCP, UDP,
+    },
+    qdisc::{Egress, Enqueued},
+    route::InterfaceAddress,
+    server::{Binding, ICMPServer, Request, Services, TCPServer},
+    IPPacket, IPv6Packet,
+};
+
+/// TTL or hop limit of packets we originate
+const DEFAULT_TTL: u8 = 64;
+/// Metric of default routes learnt from Router Advertisements, the one Linux uses
+const RA_ROUTE_METRIC: u32 = 1024;
+
+/// The host itself, answering pings and TCP segments addressed to it and routing everything else
+pub struct Stack {
+    pub router: Router,
+    /// Shared so the connections can be listed while the stack runs
+    pub connections: Arc<Mutex<ConnectionTable>>,
+    /// Upper layer protocol numbers we answer, packets of any other protocol are delivered
+    /// and then ignored
+    pub protocols: Vec<u8>,
+    /// Answer the packets addressed to us
+    pub services: Services,
+    /// Queueing disciplines the packets go through on their way to the devices, shared so their
+    /// counters can be shown while the stack runs
+    pub egress: Arc<Mutex<Egress>>,
+    /// Interfaces whose IPv6 addresses are configured with neighbor discovery, by name
+    pub neighbor_discovery: BTreeMap<String, NeighborDiscovery>,
+    reassembler: Reassembler,
+}
+impl Stack {
+    pub fn new(router: Router) -> Self {
+        Self::with_connections(router, ConnectionTable::new())
+    }
+    /// Answers pings and hands TCP segments to the connection table
+    pub fn with_connections(router: Router, mut connections: ConnectionTable) -> Self {
+        connections.set_stats(router.stats.clone());
+        let connections = Arc::new(Mutex::new(connections));
+        let mut services = Services::new();
+        services.register(Binding::ICMP, Box::new(ICMPServer {}));
+        services.register(Binding::ICMPv6, Box::new(ICMPServer {}));
+        services.register(
+            Binding::TCP(0),
+            Box::new(TCPServer {
+                connections: connections.clone(),
+            }),
+        );
+        Self {
+            router,
+            connections,
+            protocols: vec![1, icmpv6::PROTOCOL, tcp::PROTOCOL, udp::PROTOCOL],
+            services,
+            egress: Arc::new(Mutex::new(Egress::new())),
+            neighbor_discovery: BTreeMap::new(),
+            reassembler: Reassembler::new(),
+        }
+    }
+    /// Configures the IPv6 addresses of the interface with neighbor discovery from `now` on,
+    /// the addresses are added to the interface once they passed DAD
+    pub fn autoconfigure(&mut self, interface: &str, mut nd: NeighborDiscovery, now: Instant) {
+        nd.autoconfigure(now);
+        self.neighbor_discovery.insert(interface.to_string(), nd);
+    }
+    /// Handles one raw IP packet received on `interface`, returning where the resulting packets
+    /// should go
+    /// Forwarded packets stay in the buffer, packets addressed to us are parsed into owned
+    /// packets for the services
+    pub fn receive(&mut self, interface: &str, ip_packet: &[u8], now: Instant) -> Vec<Verdict> {
+        let verdict = connection_span(ip_packet).in_scope(|| {
+            debug!(len = ip_packet.len(), "packet received");
+            let verdict = match self.router.route(ip_packet, now) {
+                Verdict::Local => {
+                    self.router.stats.ip.in_delivers.increment();
+                    self.reply(interface, ip_packet, now)
+                }
+                verdict => Some(verdict),
+            };
+            if let Some(Verdict::Drop(reason)) = &verdict {
+                debug!(?reason, "packet dropped");
+            }
+            verdict
+        });
+        // Timers are not part of the packet's flow
+        let mut verdicts: Vec<Verdict> = verdict.into_iter().collect();
+        verdicts.extend(self.poll(now));
+        verdicts
+    }
+    /// Fires the timers which are due and sends queued data, returning where the packets go
+    pub fn poll(&mut self, now: Instant) -> Vec<Verdict> {
+        self.router.poll(now);
+        let mut connections = self
+            .connections
+            .lock()
+            .expect("Connection table lock is not poisoned");
+        let retransmits = connections.retransmits();
+        let segments = connections.poll(now);
+        let retransmits = connections.retransmits() - retransmits;
+        drop(connections);
+        self.router.stats.tcp.retrans_segs.add(retransmits);
+        let mut verdicts: Vec<Verdict> = segments
+            .into_iter()
+            .map(|(key, tcp, ecn)| {
+                tcp_connection_span(&key).in_scope(|| self.send_tcp(key, tcp, ecn, now))
+            })
+            .collect();
+        for time_exceeded in self.reassembler.poll(now) {
+            self.router.stats.ip.reasm_fails.increment();
+            self.count_out(&time_exceeded.body);
+            let mut packet = self.router.pool.get();
+            time_exceeded.prepend_to(&mut packet);
+            verdicts.push(self.router.output(packet, now));
+        }
+        let interfaces: Vec<String> = self.neighbor_discovery.keys().cloned().collect();
+        for interface in interfaces {
+            let packets = match self.neighbor_discovery.get_mut(&interface) {
+                Some(nd) => nd.poll(now),
+                None => continue,
+            };
+            verdicts.extend(self.neighbor_discovery_output(&interface, packets));
+        }
+        for verdict in &verdicts {
+            if let Verdict::Drop(reason) = verdict {
+                debug!(?reason, "packet dropped");
+            }
+        }
+        verdicts
+    }
+    /// When `poll` has something to do next
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let connections = self
+            .connections
+            .lock()
+            .expect("Connection table lock is not poisoned")
+            .next_deadline();
+        [
+            connections,
+            self.reassembler.next_deadline(),
+            self.egress
+                .lock()
+                .expect("Egress lock is not poisoned")
+                .next_deadline(),
+            self.router.next_deadline(),
+        ]
+        .into_iter()
+        .chain(
+            self.neighbor_discovery
+                .values()
+                .map(NeighborDiscovery::next_deadline),
+        )
+        .flatten()
+        .min()
+    }
+    /// Starts closing the connection, returning where its FIN goes
+    pub fn close(&mut self, key: ConnectionKey, now: Instant) -> Vec<Verdict> {
+        let segments = self
+            .connections
+            .lock()
+            .expect("Connection table lock is not poisoned")
+            .close(key, now);
+        segments
+            .into_iter()
+            .map(|(tcp, ecn)| self.send_tcp(key, tcp, ecn, now))
+            .collect()
+    }
+    /// Sends a datagram from `local`, which may have an unspecified address to use the one of
+    /// the outgoing interface
+    pub fn send_udp(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Verdict {
+        let source_addr = match local.ip().is_unspecified() {
+            true => self
+                .router
+                .route_table
+                .lookup(&remote.ip())
+                .and_then(|next_hop| next_hop.source_addr),
+            false => Some(local.ip()),
+        };
+        // A source of the other IP version has no route to the destination either
+        let Some((source_addr, udp)) = source_addr.and_then(|source_addr| {
+            UDP::new(
+                &source_addr,
+                &remote.ip(),
+                local.port(),
+                remote.port(),
+                data,
+            )
+            .ok()
+            .map(|udp| (source_addr, udp))
+        }) else {
+            self.router.stats.ip.out_no_routes.increment();
+            return Verdict::Drop(DropReason::NoRoute);
+        };
+        self.send(source_addr, remote.ip(), IPBody::UDP(udp), ECN::NotECT, now)
+    }
+    /// Wraps a segment of the connection in an IP packet and routes it
+    fn send_tcp(&mut self, key: ConnectionKey, tcp: TCP, ecn: ECN, now: Instant) -> Verdict {
+        let (local, remote) = key;
+        self.send(local.ip(), remote.ip(), IPBody::TCP(tcp), ecn, now)
+    }
+    /// Wraps a body we originate in an IP packet and routes it
+    fn send(
+        &mut self,
+        source_addr: IpAddr,
+        destination_addr: IpAddr,
+        ip_body: IPBody,
+        ecn: ECN,
+        now: Instant,
+    ) -> Verdict {
+        self.count_out(&ip_body);
+        let protocol = ip_body.protocol();
+        let mut packet = self.router.pool.get();
+        match (source_addr, destination_addr) {
+            (IpAddr::V4(source_addr), IpAddr::V4(destination_addr)) => {
+                // Segments are sized to the path, datagrams may have to be fragmented
+                let flags = match ip_body {
+                    IPBody::TCP(_) => FLAG_DONT_FRAGMENT,
+                    _ => 0,
+                };
+                let ip_header = IPHeader::builder(source_addr, destination_addr)
+                    .flags(flags)
+                    .ecn(ecn);
+                IPPacket::from_body(ip_header, ip_body).prepend_to(&mut packet);
+            }
+            (IpAddr::V6(source_addr), IpAddr::V6(destination_addr)) => {
+                let ip_header = IPv6Header::new(
+                    ecn.apply(0),
+                    0,
+                    0,
+                    protocol,
+                    DEFAULT_TTL,
+                    source_addr,
+                    destination_addr,
+                );
+                IPv6Packet::new(ip_header, Vec::new(), protocol, ip_body).prepend_to(&mut packet);
+            }
+            _ => unreachable!("Both addresses are of the same IP version"),
+        }
+        self.router.output(packet, now)
+    }
+    /// Receives one frame from the device at `index`, sending out whatever the stack produces
+    pub fn poll_device<D: NetDevice>(
+        &mut self,
+        devices: &mut [D],
+        index: usize,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let len = devices[index].recv(buf)?;
+        if len <= PACKET_INFO_LEN {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let interface = devices[index].name().to_string();
+        let verdicts = self.receive(&interface, &buf[PACKET_INFO_LEN..len], now);
+        self.transmit(devices, verdicts, now)
+    }
+    /// Queues the packets to be transmitted in the discipline of the interface they were routed
+    /// to, or sends them right away when it has none, then sends whatever the disciplines let go
+    pub fn transmit<D: NetDevice>(
+        &mut self,
+        devices: &mut [D],
+        verdicts: Vec<Verdict>,
+        now: Instant,
+    ) -> io::Result<()> {
+        let mut egress = self.egress.lock().expect("Egress lock is not poisoned");
+        for verdict in verdicts {
+            if let Verdict::Transmit { interface, packets } = verdict {
+                let Some(device) = devices.iter_mut().find(|device| device.name() == interface)
+                else {
+                    continue;
+                };
+                for packet in packets {
+                    match egress.enqueue(&interface, packet, now) {
+                        // Drops are counted below with those from inside the disciplines
+                        Enqueued::Queued | Enqueued::Dropped => {}
+                        Enqueued::Bypassed(packet) => send(device, &packet)?,
+                    }
+                }
+            }
+        }
+        for device in devices {
+            while let Some(packet) = egress.dequeue(device.name(), now) {
+                send(device, &packet)?;
+            }
+        }
+        self.router.stats.ip.out_discards.add(egress.take_drops());
+        Ok(())
+    }
+    /// Answers a packet addressed to us, if there is anything to answer
+    fn reply(&mut self, interface: &str, ip_packet: &[u8], now: Instant) -> Option<Verdict> {
+        let mtu = self
+            .router
+            .route_table
+            .interface(interface)
+            .map_or(DEFAULT_MTU, |interface| interface.mtu);
+        match ip_packet[0] >> 4 {
+            4 => {
+                let ip_packet = IPPacket::from_byte_buffer(ip_packet)
+                    .map_err(|error| self.parse_error(error.kind()))
+                    .ok()?;
+                trace!(packet = %ip_packet, "received");
+                self.count_in(&ip_packet.body);
+                let reply = self.reply_ipv4(ip_packet, mtu, now)?;
+                trace!(packet = %reply, "reply");
+                self.count_out(&reply.body);
+                let mut packet = self.router.pool.get();
+                reply.prepend_to(&mut packet);
+                Some(self.router.output(packet, now))
+            }
+            _ => {
+                let pending = self.reassembler.pending();
+                let reassembled = self.reassembler.reassemble(ip_packet, now);
+                let stats = &self.router.stats;
+                match (&reassembled, self.reassembler.pending() < pending) {
+                    (Ok(Some(_)), true) => stats.ip.reasm_oks.increment(),
+                    (Ok(None), true) => stats.ip.reasm_fails.increment(),
+                    (Err(error), _) => self.parse_error(error.kind()),
+                    _ => {}
+                }
+                // Waiting for more fragments or malformed
+                let Ok(Some(ip_packet)) = reassembled else {
+                    return None;
+                };
+                let ip_packet = IPv6Packet::from_byte_buffer(&ip_packet)
+                    .map_err(|error| self.parse_error(error.kind()))
+                    .ok()?;
+                trace!(packet = %ip_packet, "received");
+                self.count_in(&ip_packet.body);
+                if let IPBody::ICMPv6(ICMPv6 {
+                    body:
+                        ICMPv6Body::PacketTooBig {
+                            mtu,
+                            invoking_packet,
+                        },
+                    ..
+                }) = &ip_packet.body
+                {
+                    self.packet_too_big(*mtu, invoking_packet, now);
+                }
+                if let (IPBody::ICMPv6(icmp), Some(nd)) =
+                    (&ip_packet.body, self.neighbor_discovery.get_mut(interface))
+                {
+                    if NeighborDiscovery::is_nd_message(icmp) {
+                        let packets = nd.handle(&ip_packet.header, icmp, now);
+                        return self.neighbor_discovery_output(interface, packets);
+                    }
+                }
+                let reply = self.reply_ipv6(ip_packet, mtu, now)?;
+                trace!(packet = %reply, "reply");
+                self.count_out(&reply.body);
+                let mut packet = self.router.pool.get();
+                reply.prepend_to(&mut packet);
+                Some(self.router.output(packet, now))
+            }
+        }
+    }
+    /// Lowers the path MTU towards the destination of a packet we sent (RFC 8201 4)
+    fn packet_too_big(&mut self, mtu: u32, invoking_packet: &[u8], now: Instant) {
+        let Ok(invoking_header) = IPv6Header::from_byte_buffer(invoking_packet) else {
+            return;
+        };
+        if !self
+            .router
+            .route_table
+            .is_local(&IpAddr::V6(invoking_header.source_addr))
+        {
+            return;
+        }
+        debug!(destination = %invoking_header.destination_addr, mtu, "packet too big");
+        self.router
+            .fragmenter
+            .handle_packet_too_big(invoking_header.destination_addr, mtu, now);
+    }
+    /// Adds the addresses which passed DAD to the interface and sends the ND packets straight
+    /// out of it, they are link local and mostly multicast so they are not routed
+    fn neighbor_discovery_output(
+        &mut self,
+        interface: &str,
+        packets: Vec<IPv6Packet>,
+    ) -> Option<Verdict> {
+        let nd = &self.neighbor_discovery[interface];
+        let route_table = &mut self.router.route_table;
+        let configured: Vec<Ipv6Addr> = nd
+            .preferred_addresses()
+            .filter(|address| !route_table.is_local(&IpAddr::V6(**address)))
+            .copied()
+            .collect();
+        for address in configured {
+            info!(interface, %address, "address configured");
+            route_table.add_address(interface, InterfaceAddress::new(IpAddr::V6(address), 64));
+        }
+        if let Some(router) = nd.default_routers.first() {
+            let has_default_route = route_table
+                .routes()
+                .iter()
+                .any(|route| route.prefix_length == 0 && route.destination.is_ipv6());
+            if !has_default_route {
+                info!(interface, %router, "default router");
+                route_table.add_default_route(IpAddr::V6(*router), interface, RA_ROUTE_METRIC);
+            }
+        }
+        if packets.is_empty() {
+            return None;
+        }
+        let packets = packets
+            .into_iter()
+            .map(|nd_packet| {
+                self.count_out(&nd_packet.body);
+                let mut packet = self.router.pool.get();
+                nd_packet.prepend_to(&mut packet);
+                packet
+            })
+            .collect();
+        Some(Verdict::Transmit {
+            interface: interface.to_string(),
+            packets,
+        })
+    }
+    fn parse_error(&self, kind: &IPPacketErrorKind) {
+        warn!(?kind, "parse error");
+        self.router.stats.count_parse_error(kind);
+    }
+    fn count_in(&self, ip_body: &IPBody) {
+        let stats = &self.router.stats;
+        match ip_body {
+            IPBody::ICMP(icmp) => stats.icmp.count_in(icmp._type),
+            IPBody::ICMPv6(icmp) => stats.icmpv6.count_in(icmp._type),
+            IPBody::TCP(_) => stats.tcp.in_segs.increment(),
+            IPBody::UDP(_) => {
+                stats.udp.in_datagrams.increment();
+                if !self.services.is_bound(ip_body) {
+                    stats.udp.no_ports.increment();
+                }
+            }
+        }
+    }
+    fn count_out(&self, ip_body: &IPBody) {
+        let stats = &self.router.stats;
+        match ip_body {
+            IPBody::ICMP(icmp) => stats.icmp.count_out(icmp._type),
+            IPBody::ICMPv6(icmp) => stats.icmpv6.count_out(icmp._type),
+            IPBody::TCP(tcp) => {
+                stats.tcp.out_segs.increment();
+                if tcp.control_bits.contains(TCPControlBits::RST) {
+                    stats.tcp.out_rsts.increment();
+                }
+            }
+            IPBody::UDP(_) => stats.udp.out_datagrams.increment(),
+        }
+    }
+    fn reply_ipv4(&mut self, ip_packet: IPPacket, mtu: usize, now: Instant) -> Option<IPPacket> {
+        let header = &ip_packet.header;
+        if !self.protocols.contains(&header.protocol) {
+            return None;
+        }
+        let ip_body = self.services.dispatch(&Request {
+            source_addr: IpAddr::V4(header.source_addr),
+            destination_addr: IpAddr::V4(header.destination_addr),
+            ecn: header.ecn(),
+            body: &ip_packet.body,
+            mtu,
+            now,
+        })?;
+        Some(IPPacket::reply_to(&ip_packet, ip_body))
+    }
+    fn reply_ipv6(
+        &mut self,
+        ip_packet: IPv6Packet,
+        mtu: usize,
+        now: Instant,
+    ) -> Option<IPv6Packet> {
+        if !self.protocols.contains(&ip_packet.protocol()) {
+            return None;
+        }
+        let header = &ip_packet.header;
+        let ip_body = self.services.dispatch(&Request {
+            source_addr: IpAddr::V6(header.source_addr),
+            destination_addr: IpAddr::V6(header.destination_addr),
+            ecn: header.ecn(),
+            body: &ip_packet.body,
+            mtu,
+            now,
+        })?;
+        Some(IPv6Packet::reply_to(&ip_packet, ip_body))
+    }
+}
+
+/// Sends a packet out of the device with the packet information in front of it
+fn send<D: NetDevice>(device: &mut D, packet: &PacketBuffer) -> io::Result<()> {
+    let packet_info = packet_info(packet);
+    device.send_vectored(&[IoSlice::new(&packet_info), IoSlice::new(packet)])?;
+    Ok(())
+}
+
+/// Span grouping the events of one flow, so a single 4-tuple can be filtered with e.g.
+/// NUST_LOG='nust[connection{source=192.168.0.1:48458}]=trace'
+fn connection_span(ip_packet: &[u8]) -> Span {
+    match PacketInfo::from_byte_buffer(ip_packet).and_then(|info| info.flow()) {
+        Some(flow) => info_span!(
+            "connection",
+            protocol = protocol_name(flow.protocol),
+            source = %SocketAddr::from(flow.source),
+            destination = %SocketAddr::from(flow.destination),
+        ),
+        None => Span::none(),
+    }
+}
+
+/// The span of the segments the connection receives, so the ones its timers send are grouped
+/// with them
+fn tcp_connection_span(key: &ConnectionKey) -> Span {
+    let (local, remote) = key;
+    info_span!(
+        "connection",
+        protocol = protocol_name(tcp::PROTOCOL),
+        source = %remote,
+        destination = %local,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::Ipv4Addr, time::Duration};
+
+    use super::*;
+    use crate::{
+        capture::{PcapReader, ReplayDevice},
+        checksum,
+        connection::DEFAULT_BACKLOG,
+        device::VirtualDevice,
+        ip::Ipv4Packet,
+        protocol::icmpv6::ndp::NDOption,
+        qdisc::FqCodel,
+        route::{Interface, InterfaceAddress, RouteTable},
+    };
+
+    fn stack() -> Stack {
+        let mut route_table = RouteTable::new();
+        route_table.add_interface(Interface::new(
+            "tun0",
+            DEFAULT_MTU,
+            vec![
+                InterfaceAddress::new("192.168.0.2".parse().unwrap(), 24),
+                InterfaceAddress::new("fd00::2".parse().unwrap(), 64),
+            ],
+        ));
+        let stack = Stack::new(Router::new(route_table, false));
+        let mut connections = stack.connections.lock().unwrap();
+        connections.set_iss_secret(0);
+        connections.listen("0.0.0.0:80".parse().unwrap(), DEFAULT_BACKLOG);
+        connections.listen("[::]:80".parse().unwrap(), DEFAULT_BACKLOG);
+        drop(connections);
+        stack
+    }
+
+    /// Replays the capture in tests/data through the stack and compares the replies with the golden file
+    /// Run with NUST_BLESS=1 to rewrite the golden file after an intended change
+    fn replay(name: &str) -> Stack {
+        let input = fs::read(format!("tests/data/{}.pcap", name)).unwrap();
+        let golden_path = format!("tests/data/{}_replies.pcap", name);
+        let device = ReplayDevice::new("tun0", DEFAULT_MTU, input.as_slice(), Vec::new()).unwrap();
+        let mut devices = [device];
+        let mut stack = stack();
+        let mut buf = [0u8; DEFAULT_MTU + PACKET_INFO_LEN];
+        while stack.poll_device(&mut devices, 0, &mut buf).is_ok() {}
+        let [device] = devices;
+        let replies = device.into_writer();
+        if env::var("NUST_BLESS").is_ok() {
+            fs::write(&golden_path, &replies).unwrap();
+        }
+        assert_eq!(replies, fs::read(&golden_path).unwrap());
+        stack
+    }
+
+    #[test]
+    fn replay_ping_and_syn() {
+        replay("ping_syn");
+    }
+
+    #[test]
+    fn stats() {
+        let stats = replay("ping_syn").router.stats;
+        assert_eq!(stats.ip.in_receives.get(), 2);
+        assert_eq!(stats.ip.in_delivers.get(), 2);
+        assert_eq!(stats.ip.out_requests.get(), 2);
+        assert_eq!(stats.icmp.in_types[8].get(), 1);
+        assert_eq!(stats.icmp.out_types[0].get(), 1);
+        assert_eq!(stats.tcp.in_segs.get(), 1);
+        assert_eq!(stats.tcp.passive_opens.get(), 1);
+
+        let mut stack = stack();
+        let capture = fs::read("tests/data/ping_syn.pcap").unwrap();
+        let mut reader = PcapReader::new(capture.as_slice()).unwrap();
+        reader.next_frame().unwrap();
+        let (_, mut syn) = reader.next_frame().unwrap().unwrap();
+        // Corrupt the TCP checksum
+        syn[36] ^= 0xFF;
+        stack.receive("tun0", &syn, Instant::now());
+        assert_eq!(stack.router.stats.tcp.in_errs.get(), 1);
+        assert_eq!(stack.router.stats.tcp.in_segs.get(), 0);
+    }
+
+    #[test]
+    fn short_icmp_errors() {
+        let mut stack = stack();
+        for _type in [3, 11] {
+            // A Destination Unreachable or Time Exceeded without its 4 unused bytes
+            let mut buf = vec![0x0; 26];
+            buf[0] = 0x45;
+            let mut packet = Ipv4Packet::new_unchecked(&mut buf[..]);
+            packet.set_total_length(26);
+            packet.set_time_to_live(64);
+            packet.set_protocol(1);
+            packet.set_source_addr(Ipv4Addr::new(192, 168, 0, 1));
+            packet.set_destination_addr(Ipv4Addr::new(192, 168, 0, 2));
+            packet.fill_checksum();
+            let icmp = packet.payload_mut();
+            icmp[0] = _type;
+            let checksum = !checksum::ones_complement_sum_byte_buffer(icmp);
+            icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+            assert!(stack.receive("tun0", &buf, Instant::now()).is_empty());
+        }
+        assert_eq!(stack.router.stats.icmp.in_errors.get(), 2);
+    }
+
+    #[test]
+    fn fq_codel_drops_are_discards() {
+        let now = Instant::now();
+        let mut stack = stack();
+        let mut egress = stack.egress.lock().unwrap();
+        egress.attach("tun0", Box::new(FqCodel::new(2)));
+        drop(egress);
+        let (device, mut peer) = VirtualDevice::pair("tun0", "peer", DEFAULT_MTU);
+        let mut devices = [device];
+        let remote: SocketAddr = "192.168.0.1:53".parse().unwrap();
+        let mut send = |ports: [u16; 3]| {
+            let verdicts = ports
+                .into_iter()
+                .map(|port| {
+                    let local = SocketAddr::new(Ipv4Addr::new(192, 168, 0, 2).into(), port);
+                    stack.send_udp(local, remote, vec![0x0; 100], now)
+                })
+                .collect();
+            stack.transmit(&mut devices, verdicts, now).unwrap();
+            stack.router.stats.ip.out_discards.get()
+        };
+        // The flow overflowing the queue loses a packet, so does the fattest flow when a sparse
+        // one comes in
+        assert_eq!(send([1000, 1000, 1000]), 1);
+        assert_eq!(send([1000, 1000, 2000]), 2);
+        let mut buf = [0u8; DEFAULT_MTU + PACKET_INFO_LEN];
+        assert_eq!(std::iter::from_fn(|| peer.recv(&mut buf).ok()).count(), 4);
+    }
+
+    #[test]
+    fn autoconfiguration() {
+        let now = Instant::now();
+        let mut stack = stack();
+        let nd = NeighborDiscovery::new([0, 0, 0, 0, 0, 0, 0, 2], None);
+        let link_local: Ipv6Addr = "fe80::2".parse().unwrap();
+        stack.autoconfigure("tun0", nd, now);
+        assert_eq!(stack.next_deadline(), Some(now));
+        let sent = |verdicts: Vec<Verdict>| -> Vec<IPv6Packet> {
+            verdicts
+                .into_iter()
+                .flat_map(|verdict| match verdict {
+                    Verdict::Transmit { interface, packets } => {
+                        assert_eq!(interface, "tun0");
+                        packets
+                    }
+                    verdict => panic!("Should be transmitted, not {:?}", verdict),
+                })
+                .map(|packet| IPv6Packet::from_byte_buffer(&packet).unwrap())
+                .collect()
+        };
+        // DAD for the link local address, then a Router Solicitation from it
+        let probe = sent(stack.poll(now));
+        assert!(probe[0].header.source_addr.is_unspecified());
+        let later = now + Duration::from_secs(1);
+        let solicitation = sent(stack.poll(later));
+        assert_eq!(solicitation[0].header.source_addr, link_local);
+        assert!(stack.router.route_table.is_local(&IpAddr::V6(link_local)));
+
+        let router: Ipv6Addr = "fe80::1".parse().unwrap();
+        let body = ICMPv6Body::RouterAdvertisement {
+            cur_hop_limit: 64,
+            managed: false,
+            other: false,
+            router_lifetime: 1800,
+            reachable_time: 0,
+            retrans_timer: 0,
+            options: vec![NDOption::PrefixInformation {
+                prefix_length: 64,
+                on_link: true,
+                autonomous: true,
+                valid_lifetime: 86400,
+                preferred_lifetime: 14400,
+                prefix: "2001:db8:1::".parse().unwrap(),
+            }],
+        };
+        let all_nodes = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+        let icmp = ICMPv6::new(&router, &all_nodes, 0, body);
+        let header = IPv6Header::new(0, 0, 0, icmpv6::PROTOCOL, 255, router, all_nodes);
+        let advertisement =
+            IPv6Packet::new(header, Vec::new(), icmpv6::PROTOCOL, IPBody::ICMPv6(icmp));
+        let probe = sent(stack.receive("tun0", &advertisement.to_byte_buffer(), later));
+        assert!(probe[0].header.source_addr.is_unspecified());
+        let address = IpAddr::V6("2001:db8:1::2".parse().unwrap());
+        assert!(!stack.router.route_table.is_local(&address));
+        assert!(sent(stack.poll(later + Duration::from_secs(1))).is_empty());
+        assert!(stack.router.route_table.is_local(&address));
+        let next_hop = stack
+            .router
+            .route_table
+            .lookup(&"2001:db8:9::1".parse().unwrap())
+            .unwrap();
+        assert_eq!(next_hop.addr, IpAddr::V6(router));
+    }
+
+    #[test]
+    fn fragments_to_the_path_mtu() {
+        let now = Instant::now();
+        let mut stack = stack();
+        let local: SocketAddr = "[fd00::2]:7".parse().unwrap();
+        let remote: SocketAddr = "[fd00::1]:40000".parse().unwrap();
+        let sent = |verdict: Verdict| match verdict {
+            Verdict::Transmit { packets, .. } => packets,
+            verdict => panic!("Should be transmitted, not {:?}", verdict),
+        };
+        let packets = sent(stack.send_udp(local, remote, vec![0x0; 1400], now));
+        assert_eq!(packets.len(), 1);
+
+        // A router on the way has a smaller MTU
+        let body = ICMPv6Body::PacketTooBig {
+            mtu: 1280,
+            invoking_packet: packets[0][..1232].to_vec(),
+        };
+        let router: Ipv6Addr = "fd00::1".parse().unwrap();
+        let destination: Ipv6Addr = "fd00::2".parse().unwrap();
+        let icmp = ICMPv6::new(&router, &destination, 0, body);
+        let header = IPv6Header::new(0, 0, 0, icmpv6::PROTOCOL, 64, router, destination);
+        let packet_too_big =
+            IPv6Packet::new(header, Vec::new(), icmpv6::PROTOCOL, IPBody::ICMPv6(icmp));
+        stack.receive("tun0", &packet_too_big.to_byte_buffer(), now);
+
+        let packets = sent(stack.send_udp(local, remote, vec![0x0; 1400], now));
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|packet| packet.len() <= 1280));
+        assert_eq!(stack.router.stats.ip.frag_creates.get(), 2);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    array, fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::ip::IPPacketErrorKind;
+
+/// A counter which can be read from another thread while the stack increments it
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+impl Counter {
+    pub fn increment(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// ipGroup of MIB-II (RFC 1213 6), shared by IPv4 and IPv6
+#[derive(Debug, Default)]
+pub struct IPStats {
+    pub in_receives: Counter,
+    pub in_hdr_errors: Counter,
+    /// Not addressed to us while forwarding is disabled
+    pub in_addr_errors: Counter,
+    pub in_unknown_protos: Counter,
+    /// Dropped by the packet filter
+    pub in_discards: Counter,
+    pub in_delivers: Counter,
+    pub forw_datagrams: Counter,
+    pub out_requests: Counter,
+    /// Dropped by the queueing disciplines
+    pub out_discards: Counter,
+    pub out_no_routes: Counter,
+    pub reasm_oks: Counter,
+    pub reasm_fails: Counter,
+    pub frag_oks: Counter,
+    pub frag_fails: Counter,
+    pub frag_creates: Counter,
+}
+
+/// icmpGroup of MIB-II, with a counter per message type instead of per named message
+#[derive(Debug)]
+pub struct ICMPStats {
+    pub in_msgs: Counter,
+    pub in_errors: Counter,
+    pub out_msgs: Counter,
+    pub in_types: [Counter; 256],
+    pub out_types: [Counter; 256],
+}
+impl Default for ICMPStats {
+    fn default() -> Self {
+        Self {
+            in_msgs: Counter::default(),
+            in_errors: Counter::default(),
+            out_msgs: Counter::default(),
+            in_types: array::from_fn(|_| Counter::default()),
+            out_types: array::from_fn(|_| Counter::default()),
+        }
+    }
+}
+impl ICMPStats {
+    pub fn count_in(&self, _type: u8) {
+        self.in_msgs.increment();
+        self.in_types[_type as usize].increment();
+    }
+    pub fn count_out(&self, _type: u8) {
+        self.out_msgs.increment();
+        self.out_types[_type as usize].increment();
+    }
+}
+
+/// tcpGroup of MIB-II, without tcpActiveOpens as the stack never opens a connection itself
+#[derive(Debug, Default)]
+pub struct TCPStats {
+    pub passive_opens: Counter,
+    pub in_segs: Counter,
+    pub out_segs: Counter,
+    pub retrans_segs: Counter,
+    pub in_errs: Counter,
+    pub out_rsts: Counter,
+}
+
+/// udpGroup of MIB-II
+#[derive(Debug, Default)]
+pub struct UDPStats {
+    pub in_datagrams: Counter,
+    pub no_ports: Counter,
+    pub in_errors: Counter,
+    pub out_datagrams: Counter,
+}
+
+/// Counters of the whole stack, printed like `netstat -s`
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub ip: IPStats,
+    pub icmp: ICMPStats,
+    pub icmpv6: ICMPStats,
+    pub tcp: TCPStats,
+    pub udp: UDPStats,
+}
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Counts a packet addressed to us which could not be parsed
+    pub fn count_parse_error(&self, kind: &IPPacketErrorKind) {
+        match kind {
+            IPPacketErrorKind::IPHeaderChecksumError
+            | IPPacketErrorKind::IPLengthError
+            | IPPacketErrorKind::IPv6HeaderError
+            | IPPacketErrorKind::AddressFamilyMismatch => self.ip.in_hdr_errors.increment(),
+            IPPacketErrorKind::ICMPChecksumError | IPPacketErrorKind::ICMPLengthError => {
+                self.icmp.in_errors.increment()
+            }
+            IPPacketErrorKind::ICMPv6ChecksumError | IPPacketErrorKind::ICMPv6LengthError => {
+                self.icmpv6.in_errors.increment()
+            }
+            IPPacketErrorKind::TCPChecksumError
+            | IPPacketErrorKind::TCPLengthError
+            | IPPacketErrorKind::TCPOptionsTooLong => self.tcp.in_errs.increment(),
+            IPPacketErrorKind::UDPChecksumError | IPPacketErrorKind::UDPLengthError => {
+                self.udp.in_errors.increment()
+            }
+            IPPacketErrorKind::NotImplementedYet => self.ip.in_unknown_protos.increment(),
+        }
+    }
+}
+
+fn write_counters(
+    f: &mut fmt::Formatter,
+    group: &str,
+    counters: &[(&str, &Counter)],
+) -> fmt::Result {
+    writeln!(f, "{}:", group)?;
+    for (name, counter) in counters {
+        writeln!(f, "    {}: {}", name, counter.get())?;
+    }
+    Ok(())
+}
+
+fn write_icmp(f: &mut fmt::Formatter, group: &str, icmp: &ICMPStats) -> fmt::Result {
+    write_counters(
+        f,
+        group,
+        &[
+            ("InMsgs", &icmp.in_msgs),
+            ("InErrors", &icmp.in_errors),
+            ("OutMsgs", &icmp.out_msgs),
+        ],
+    )?;
+    // Only the types which were seen, like the histograms of netstat
+    for (direction, types) in [("In", &icmp.in_types), ("Out", &icmp.out_types)] {
+        for (_type, counter) in types.iter().enumerate() {
+            if counter.get() != 0 {
+                writeln!(f, "    {}Type{}: {}", direction, _type, counter.get())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ip = &self.ip;
+        write_counters(
+            f,
+            "Ip",
+            &[
+                ("InReceives", &ip.in_receives),
+                ("InHdrErrors", &ip.in_hdr_errors),
+                ("InAddrErrors", &ip.in_addr_errors),
+                ("InUnknownProtos", &ip.in_unknown_protos),
+                ("InDiscards", &ip.in_discards),
+                ("InDelivers", &ip.in_delivers),
+                ("ForwDatagrams", &ip.forw_datagrams),
+                ("OutRequests", &ip.out_requests),
+                ("OutDiscards", &ip.out_discards),
+                ("OutNoRoutes", &ip.out_no_routes),
+                ("ReasmOKs", &ip.reasm_oks),
+                ("ReasmFails", &ip.reasm_fails),
+                ("FragOKs", &ip.frag_oks),
+                ("FragFails", &ip.frag_fails),
+                ("FragCreates", &ip.frag_creates),
+            ],
+        )?;
+        write_icmp(f, "Icmp", &self.icmp)?;
+        write_icmp(f, "Icmp6", &self.icmpv6)?;
+        let tcp = &self.tcp;
+        write_counters(
+            f,
+            "Tcp",
+            &[
+                ("PassiveOpens", &tcp.passive_opens),
+                ("InSegs", &tcp.in_segs),
+                ("OutSegs", &tcp.out_segs),
+                ("RetransSegs", &tcp.retrans_segs),
+                ("InErrs", &tcp.in_errs),
+                ("OutRsts", &tcp.out_rsts),
+            ],
+        )?;
+        let udp = &self.udp;
+        write_counters(
+            f,
+            "Udp",
+            &[
+                ("InDatagrams", &udp.in_datagrams),
+                ("NoPorts", &udp.no_ports),
+                ("InErrors", &udp.in_errors),
+                ("OutDatagrams", &udp.out_datagrams),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let stats = Stats::new();
+        stats.ip.in_receives.add(3);
+        stats.icmp.count_in(8);
+        stats.icmp.count_out(0);
+        stats.count_parse_error(&IPPacketErrorKind::TCPChecksumError);
+        let dump = stats.to_string();
+        assert!(dump.starts_with("Ip:\n    InReceives: 3\n"));
+        assert!(dump.contains("Icmp:\n    InMsgs: 1\n    InErrors: 0\n    OutMsgs: 1\n    InType8: 1\n    OutType0: 1\nIcmp6:\n"));
+        assert!(dump.contains("    InErrs: 1\n"));
+        assert!(dump.ends_with("    OutDatagrams: 0\n"));
+    }
+}
@@ -0,0 +1,34 @@
+//! Packets built by the tests of several modules
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::{
+    ip::IPHeader,
+    protocol::{IPBody, TCPControlBits, TCP},
+    IPPacket,
+};
+
+/// Ends of the connection most tests open, the host side of tun0 and us
+pub const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 48458);
+pub const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 80);
+
+/// Raw IPv4 packet carrying a TCP segment from `source` to `destination`, with a 64240 byte window
+pub fn tcp_packet(
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    sequence_number: u32,
+    ack: u32,
+    control_bits: TCPControlBits,
+    data: &[u8],
+) -> Vec<u8> {
+    let tcp = TCP::builder(source.port(), destination.port())
+        .sequence_number(sequence_number)
+        .acknowledgment_number(ack)
+        .control_bits(control_bits)
+        .window(64240)
+        .data(data.to_vec())
+        .build(&(*source.ip()).into(), &(*destination.ip()).into())
+        .expect("Both addresses are IPv4");
+    let ip_header = IPHeader::builder(*source.ip(), *destination.ip());
+    IPPacket::from_body(ip_header, IPBody::TCP(tcp)).to_byte_buffer()
+}
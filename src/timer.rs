@@ -0,0 +1,243 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Resolution of the timer wheel
+pub const TICK: Duration = Duration::from_millis(10);
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+/// Timers further out than this, about 46.6 hours, fire at the horizon instead
+const HORIZON: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+
+/// Where the time comes from, so tests can drive timers without sleeping
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when told to, clones share the same time
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+impl MockClock {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().expect("Clock lock is not poisoned") += duration;
+    }
+}
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("Clock lock is not poisoned")
+    }
+}
+
+/// Hierarchical timer wheel (Varghese and Lauck), 4 levels of 64 slots with 10ms ticks
+/// Timers can not be cancelled, owners check whether a timer that fired is still wanted
+#[derive(Debug)]
+pub struct TimerWheel<T> {
+    /// Instant of tick 0, when the first timer was inserted
+    origin: Option<Instant>,
+    /// Every timer expiring up to and including this tick has fired
+    current: u64,
+    /// Timers are on the level of the highest 6 bit digit in which their expiry tick differs from
+    /// the current tick, in the slot of that digit
+    levels: Vec<Vec<Vec<(u64, T)>>>,
+    /// Timers which were already due when inserted
+    due: Vec<T>,
+    /// Timers within the horizon whose expiry tick differs from the current tick above the top
+    /// level, they are placed again once the current tick crosses into their range
+    overflow: Vec<(u64, T)>,
+    len: usize,
+}
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        Self {
+            origin: None,
+            current: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            due: Vec::new(),
+            overflow: Vec::new(),
+            len: 0,
+        }
+    }
+}
+impl<T> TimerWheel<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Fires `value` once `deadline` has passed, rounded up to the next tick
+    pub fn insert(&mut self, now: Instant, deadline: Instant, value: T) {
+        let origin = *self.origin.get_or_insert(now);
+        let since_origin = deadline.saturating_duration_since(origin);
+        let expiry = since_origin.as_nanos().div_ceil(TICK.as_nanos()) as u64;
+        self.len += 1;
+        self.place(expiry.min(self.current + HORIZON - 1), value);
+    }
+    /// Earliest instant a timer is due, timers already due give the current tick
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let origin = self.origin?;
+        if !self.due.is_empty() {
+            return Some(origin + TICK * self.current as u32);
+        }
+        // Every timer on a lower level expires before any timer on a higher level, and within a
+        // level the slots after the digit of the current tick are in expiry order
+        for (level, slots) in self.levels.iter().enumerate() {
+            let digit = Self::digit(self.current, level);
+            if let Some(slot) = slots[digit + 1..].iter().find(|slot| !slot.is_empty()) {
+                let expiry = slot.iter().map(|(expiry, _)| *expiry).min()?;
+                return Some(origin + TICK * expiry as u32);
+            }
+        }
+        // Past every timer on the levels
+        let expiry = self.overflow.iter().map(|(expiry, _)| *expiry).min()?;
+        Some(origin + TICK * expiry as u32)
+    }
+    /// Takes every timer due at `now`, in expiry order
+    pub fn advance(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = std::mem::take(&mut self.due);
+        let Some(origin) = self.origin else {
+            return expired;
+        };
+        let target = (now.saturating_duration_since(origin).as_nanos() / TICK.as_nanos()) as u64;
+        while self.current < target {
+            // Jump straight to the next tick with something to do
+            let next = self
+                .next_deadline()
+                .map(|deadline| (deadline - origin).as_nanos().div_ceil(TICK.as_nanos()) as u64)
+                .map_or(target, |next| next.clamp(self.current + 1, target));
+            self.current = next;
+            self.cascade();
+            expired.append(&mut self.due);
+        }
+        self.len -= expired.len();
+        expired
+    }
+    fn digit(tick: u64, level: usize) -> usize {
+        (tick >> (SLOT_BITS * level as u32)) as usize & (SLOTS - 1)
+    }
+    fn place(&mut self, expiry: u64, value: T) {
+        if expiry <= self.current {
+            self.due.push(value);
+            return;
+        }
+        let level = ((63 - (expiry ^ self.current).leading_zeros()) / SLOT_BITS) as usize;
+        match self.levels.get_mut(level) {
+            Some(slots) => slots[Self::digit(expiry, level)].push((expiry, value)),
+            None => self.overflow.push((expiry, value)),
+        }
+    }
+    /// Moves the timers of the slots the current tick has reached down a level, or to due
+    fn cascade(&mut self) {
+        for level in (0..LEVELS).rev() {
+            let digit = Self::digit(self.current, level);
+            let mut slot = std::mem::take(&mut self.levels[level][digit]);
+            slot.sort_by_key(|(expiry, _)| *expiry);
+            for (expiry, value) in slot {
+                self.place(expiry, value);
+            }
+        }
+        for (expiry, value) in std::mem::take(&mut self.overflow) {
+            self.place(expiry, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_in_order() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new();
+        wheel.insert(start, start, "now");
+        for (millis, value) in [
+            (7_200_000, "keepalive"),
+            (200, "delayed ack"),
+            (1000, "rto"),
+            (60_000, "msl"),
+            (1005, "rto 2"),
+        ] {
+            wheel.insert(start, start + Duration::from_millis(millis), value);
+        }
+        assert_eq!(wheel.len(), 6);
+        assert_eq!(wheel.advance(start), ["now"]);
+        assert_eq!(
+            wheel.next_deadline(),
+            Some(start + Duration::from_millis(200))
+        );
+        assert!(wheel.advance(start + Duration::from_millis(199)).is_empty());
+        assert_eq!(
+            wheel.advance(start + Duration::from_millis(1010)),
+            ["delayed ack", "rto", "rto 2"]
+        );
+        assert_eq!(wheel.next_deadline(), Some(start + Duration::from_secs(60)));
+        // Inserted while the wheel is past its origin
+        let now = start + Duration::from_millis(1010);
+        wheel.insert(now, start + Duration::from_millis(1500), "late");
+        wheel.insert(now, start, "overdue");
+        assert_eq!(
+            wheel.advance(start + Duration::from_secs(3600)),
+            ["overdue", "late", "msl"]
+        );
+        assert_eq!(
+            wheel.advance(start + Duration::from_secs(7200)),
+            ["keepalive"]
+        );
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn crosses_the_top_level() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new();
+        wheel.insert(start, start, "origin");
+        let now = start + TICK * (HORIZON - 10) as u32;
+        assert_eq!(wheel.advance(now), ["origin"]);
+        wheel.insert(now, now + Duration::from_millis(200), "delayed ack");
+        wheel.insert(now, now + Duration::from_secs(3600), "later");
+        assert_eq!(
+            wheel.next_deadline(),
+            Some(now + Duration::from_millis(200))
+        );
+        assert!(wheel.advance(now + Duration::from_millis(190)).is_empty());
+        assert_eq!(
+            wheel.advance(now + Duration::from_millis(200)),
+            ["delayed ack"]
+        );
+        assert_eq!(wheel.next_deadline(), Some(now + Duration::from_secs(3600)));
+        assert_eq!(wheel.advance(now + Duration::from_secs(3600)), ["later"]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn mock_clock() {
+        let start = Instant::now();
+        let clock = MockClock::new(start);
+        clock.clone().advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), start + Duration::from_secs(1));
+    }
+}